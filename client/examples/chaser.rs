@@ -12,7 +12,7 @@ use tokyo::{
     analyzer::Analyzer,
     behavior::{Behavior, Chase, FireAt, Sequence, Target},
     models::*,
-    Handler,
+    Backoff, Handler,
 };
 
 #[derive(Default)]
@@ -49,6 +49,11 @@ impl Handler for Player {
             self.current_behavior.next_command(&self.analyzer)
         }
     }
+
+    fn reconnected(&mut self) {
+        // The game may have moved on while we were gone, start over.
+        *self = Player::default();
+    }
 }
 
 fn main() {
@@ -56,5 +61,6 @@ fn main() {
     let team_name = &env::var("TEAM_NAME").unwrap_or("a".into());
 
     println!("starting up...");
-    tokyo::run(api_key, team_name, Player::default()).unwrap();
+    // Keep playing through server restarts.
+    tokyo::Client::new(api_key, team_name).reconnect(Backoff::default()).run(Player::default()).unwrap();
 }
@@ -10,6 +10,7 @@ use std::{
 
 pub mod bullet;
 pub mod player;
+pub mod tactics;
 
 /// Collision detection etc is done at this compute interval.
 pub const ANALYSIS_INTERVAL: Duration = Duration::from_millis(10);
@@ -135,7 +136,8 @@ impl Analyzer {
     }
 
     /// Returns an `Iterator` of `Bullet`s that are shot by you and are still
-    /// inside the arena. You can have at most 4 bullets at a time.
+    /// inside the arena. You can have at most 4 bullets at a time, unless the
+    /// server is set up with a different `max_bullets`.
     pub fn own_bullets<'a>(&'a self) -> impl Iterator<Item = &'a Bullet> {
         self.bullets.iter().filter(move |bullet| bullet.player_id == self.own_player_id)
     }
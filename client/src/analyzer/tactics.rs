@@ -0,0 +1,380 @@
+//! Answers to the questions most bots ask every tick, worked out straight
+//! from a `GameState`: where the nearest enemy is, how far away the walls are,
+//! which bullets are about to hit, where to aim at a moving target and which
+//! way to dodge. Unlike `Analyzer` these don't need to be fed every state.
+//!
+//! Angles are in radians like everywhere else, see `geom::Radian`, so
+//! `GameCommand::Rotate(angle.get())` turns towards one.
+
+use crate::{
+    geom::*,
+    models::{
+        BulletState, GameState, ItemKind, PlayerState, BULLET_RADIUS, BULLET_SPEED, PLAYER_BASE_SPEED, PLAYER_RADIUS,
+        SPEED_BOOST_MULTIPLIER,
+    },
+};
+use std::time::Duration;
+
+// Dodging is judged over at least this long, so a bullet that's about to hit
+// doesn't make any direction look as good as the next.
+const MIN_DODGE_TIME: f32 = 0.25;
+
+/// Where the player is.
+pub fn position(player: &PlayerState) -> Point {
+    Point::new(player.x, player.y)
+}
+
+/// How fast the player is moving and which way, in pixels per second.
+pub fn velocity(player: &PlayerState) -> Vector {
+    let speed = if player.has_effect(ItemKind::SpeedBoost) {
+        PLAYER_BASE_SPEED * SPEED_BOOST_MULTIPLIER
+    } else {
+        PLAYER_BASE_SPEED
+    };
+    Vector::with_angle(Radian::new(player.angle)) * speed * player.throttle
+}
+
+/// How fast the bullet is moving and which way, in pixels per second.
+pub fn bullet_velocity(bullet: &BulletState) -> Vector {
+    Vector::with_angle(Radian::new(bullet.angle)) * BULLET_SPEED
+}
+
+/// Whether `me` can shoot `other`: anyone else, unless they're on the same
+/// team in the teams mode.
+pub fn is_enemy(me: &PlayerState, other: &PlayerState) -> bool {
+    other.id != me.id && (me.team.is_none() || other.team != me.team)
+}
+
+/// The living players `me` can shoot.
+pub fn enemies<'a>(me: &'a PlayerState, state: &'a GameState) -> impl Iterator<Item = &'a PlayerState> {
+    state.players.iter().filter(move |other| is_enemy(me, other))
+}
+
+/// The enemy closest to `me`, if there's anyone left to shoot.
+pub fn nearest_enemy<'a>(me: &PlayerState, state: &'a GameState) -> Option<&'a PlayerState> {
+    state
+        .players
+        .iter()
+        .filter(|other| is_enemy(me, other))
+        .min_by(|a, b| distance(me, a).partial_cmp(&distance(me, b)).unwrap())
+}
+
+/// The distance between the centers of two players.
+pub fn distance(me: &PlayerState, other: &PlayerState) -> f32 {
+    position(me).distance(&position(other))
+}
+
+/// The angle `me` has to face to point at `target`.
+pub fn angle_to(me: &PlayerState, target: Point) -> Radian {
+    position(me).angle_to(&target)
+}
+
+/// The angle `me` has to face to point at the nearest enemy.
+pub fn angle_to_nearest_enemy(me: &PlayerState, state: &GameState) -> Option<Radian> {
+    nearest_enemy(me, state).map(|enemy| angle_to(me, position(enemy)))
+}
+
+/// How much room the player has before touching a wall, either an edge of
+/// the arena or an obstacle. Zero or less when touching one.
+pub fn distance_to_walls(me: &PlayerState, state: &GameState) -> f32 {
+    let (bound_x, bound_y) = state.bounds;
+    let edges = me.x.min(me.y).min(bound_x - me.x).min(bound_y - me.y);
+    let obstacles = state
+        .obstacles
+        .iter()
+        .map(|obstacle| {
+            let (x, y) = obstacle.closest_point(me.x, me.y);
+            position(me).distance(&Point::new(x, y))
+        })
+        .fold(f32::INFINITY, f32::min);
+
+    edges.min(obstacles) - PLAYER_RADIUS
+}
+
+/// In how many seconds the bullet hits the player, if both keep going the way
+/// they are. `None` if it misses.
+pub fn time_to_impact(bullet: &BulletState, player: &PlayerState) -> Option<f32> {
+    let offset = Point::new(bullet.x, bullet.y) - position(player);
+    let relative_velocity = bullet_velocity(bullet) - velocity(player);
+    let reach = PLAYER_RADIUS + BULLET_RADIUS;
+
+    // Solve |offset + relative_velocity * t| = reach for the earliest t.
+    let a = relative_velocity.square_length();
+    let b = 2.0 * offset.dot(relative_velocity);
+    let c = offset.square_length() - reach * reach;
+    if c <= 0.0 {
+        return Some(0.0);
+    }
+    if a == 0.0 {
+        return None;
+    }
+
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    let t = (-b - discriminant.sqrt()) / (2.0 * a);
+    if t >= 0.0 {
+        Some(t)
+    } else {
+        None
+    }
+}
+
+/// Whether the bullet hits the player within `during`, if both keep going the
+/// way they are.
+pub fn will_hit(bullet: &BulletState, player: &PlayerState, during: Duration) -> bool {
+    time_to_impact(bullet, player).is_some_and(|t| t <= during.as_secs_f32())
+}
+
+/// Whether the bullet can hurt the player at all: it's someone else's, and
+/// not a teammate's when friendly fire is off.
+pub fn is_dangerous(bullet: &BulletState, player: &PlayerState) -> bool {
+    bullet.player_id != player.id && (bullet.team.is_none() || bullet.team != player.team)
+}
+
+/// The bullets that will hit `me` within `during`, soonest first, with the
+/// seconds until each hits.
+pub fn threats<'a>(me: &PlayerState, state: &'a GameState, during: Duration) -> Vec<(&'a BulletState, f32)> {
+    let mut threats: Vec<(&BulletState, f32)> = state
+        .bullets
+        .iter()
+        .filter(|bullet| is_dangerous(bullet, me))
+        .filter_map(|bullet| time_to_impact(bullet, me).map(|t| (bullet, t)))
+        .filter(|(_, t)| *t <= during.as_secs_f32())
+        .collect();
+    threats.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+
+    threats
+}
+
+/// The bullet that will hit `me` first within `during`, with the seconds
+/// until it does.
+pub fn nearest_threat<'a>(me: &PlayerState, state: &'a GameState, during: Duration) -> Option<(&'a BulletState, f32)> {
+    threats(me, state, during).into_iter().next()
+}
+
+/// The angle to shoot at to hit `target` where it will be when the bullet
+/// gets there, if it keeps moving the way it is. `None` if a bullet can't
+/// catch up with it.
+pub fn lead_angle(me: &PlayerState, target: &PlayerState) -> Option<Radian> {
+    let offset = position(target) - position(me);
+    let target_velocity = velocity(target);
+
+    // Solve |offset + target_velocity * t| = BULLET_SPEED * t for the earliest t.
+    let a = target_velocity.square_length() - BULLET_SPEED * BULLET_SPEED;
+    let b = 2.0 * offset.dot(target_velocity);
+    let c = offset.square_length();
+    let t = if a.abs() < f32::EPSILON {
+        -c / b
+    } else {
+        let discriminant = b * b - 4.0 * a * c;
+        if discriminant < 0.0 {
+            return None;
+        }
+        let (t1, t2) = ((-b - discriminant.sqrt()) / (2.0 * a), (-b + discriminant.sqrt()) / (2.0 * a));
+        match (t1 >= 0.0, t2 >= 0.0) {
+            (true, true) => t1.min(t2),
+            (true, false) => t1,
+            (false, true) => t2,
+            (false, false) => return None,
+        }
+    };
+    if !(t >= 0.0 && t.is_finite()) {
+        return None;
+    }
+
+    Some(angle_to(me, position(target) + target_velocity * t))
+}
+
+/// Which way to move to get out of the way of the first bullet that would hit
+/// within `during`: across its path, to the side `me` is already on unless
+/// that runs into a wall before the bullet passes. `None` if nothing is
+/// about to hit.
+pub fn dodge_direction(me: &PlayerState, state: &GameState, during: Duration) -> Option<Vector> {
+    let (bullet, t) = nearest_threat(me, state, during)?;
+    let path = bullet_velocity(bullet) / BULLET_SPEED;
+    let across = Vector::new(-path.y, path.x);
+
+    // The side of the bullet's path we're on goes first, it's the shorter way out.
+    let offset = position(me) - Point::new(bullet.x, bullet.y);
+    let sides = if offset.dot(across) >= 0.0 { [across, -across] } else { [-across, across] };
+
+    let escape_time = t.max(MIN_DODGE_TIME);
+    let escapes = |side: &Vector| {
+        let moved = *side * PLAYER_BASE_SPEED * escape_time;
+        let dodged = PlayerState { x: me.x + moved.x, y: me.y + moved.y, throttle: 0.0, ..me.clone() };
+        distance_to_walls(&dodged, state) > 0.0 && time_to_impact(bullet, &dodged).is_none()
+    };
+
+    Some(sides.iter().find(|side| escapes(side)).cloned().unwrap_or(sides[0]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{ObstacleShape, ObstacleState};
+    use std::f32::consts::PI;
+
+    const TOLERANCE: f32 = 1e-3;
+
+    fn player(id: u32, x: f32, y: f32) -> PlayerState {
+        PlayerState { x, y, ..PlayerState::new(id) }
+    }
+
+    fn bullet(id: u32, player_id: u32, x: f32, y: f32, angle: f32) -> BulletState {
+        BulletState { id, player_id, angle, x, y, team: None }
+    }
+
+    fn arena(players: Vec<PlayerState>, bullets: Vec<BulletState>) -> GameState {
+        GameState { players, bullets, ..GameState::new((1000.0, 1000.0)) }
+    }
+
+    #[test]
+    fn nearest_enemy_skips_me_and_teammates() {
+        let mut me = player(0, 500.0, 500.0);
+        me.team = Some(1);
+        let mut teammate = player(1, 510.0, 500.0);
+        teammate.team = Some(1);
+        let mut far = player(2, 800.0, 500.0);
+        far.team = Some(2);
+        let mut near = player(3, 500.0, 600.0);
+        near.team = Some(2);
+        let state = arena(vec![me.clone(), teammate, far, near], vec![]);
+
+        assert_eq!(nearest_enemy(&me, &state).map(|enemy| enemy.id), Some(3));
+        assert_eq!(enemies(&me, &state).count(), 2);
+    }
+
+    #[test]
+    fn angle_to_nearest_enemy_points_at_them() {
+        let me = player(0, 500.0, 500.0);
+        let state = arena(vec![me.clone(), player(1, 500.0, 700.0), player(2, 100.0, 100.0)], vec![]);
+
+        let angle = angle_to_nearest_enemy(&me, &state).unwrap();
+        assert!((angle.get() - PI / 2.0).abs() < TOLERANCE);
+        assert!(angle_to_nearest_enemy(&me, &arena(vec![me.clone()], vec![])).is_none());
+    }
+
+    #[test]
+    fn distance_to_walls_takes_the_closest_edge_or_obstacle() {
+        let me = player(0, 100.0, 500.0);
+        let mut state = arena(vec![me.clone()], vec![]);
+        assert!((distance_to_walls(&me, &state) - (100.0 - PLAYER_RADIUS)).abs() < TOLERANCE);
+
+        state.obstacles.push(ObstacleState {
+            id: 0,
+            x: 150.0,
+            y: 400.0,
+            width: 50.0,
+            height: 200.0,
+            shape: ObstacleShape::Rect,
+        });
+        assert!((distance_to_walls(&me, &state) - (50.0 - PLAYER_RADIUS)).abs() < TOLERANCE);
+    }
+
+    #[test]
+    fn head_on_bullet_hits_when_it_reaches_the_player() {
+        let me = player(0, 0.0, 0.0);
+        let incoming = bullet(0, 1, 100.0, 0.0, PI);
+
+        let t = time_to_impact(&incoming, &me).unwrap();
+        let expected = (100.0 - PLAYER_RADIUS - BULLET_RADIUS) / BULLET_SPEED;
+        assert!((t - expected).abs() < TOLERANCE);
+        assert!(will_hit(&incoming, &me, Duration::from_millis(200)));
+        assert!(!will_hit(&incoming, &me, Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn bullets_passing_by_or_flying_away_miss() {
+        let me = player(0, 0.0, 0.0);
+
+        assert!(time_to_impact(&bullet(0, 1, 100.0, 50.0, PI), &me).is_none());
+        assert!(time_to_impact(&bullet(1, 1, 100.0, 0.0, 0.0), &me).is_none());
+    }
+
+    #[test]
+    fn moving_out_of_the_way_avoids_a_hit() {
+        let mut me = player(0, 0.0, 0.0);
+        let incoming = bullet(0, 1, 600.0, 0.0, PI);
+        assert!(time_to_impact(&incoming, &me).is_some());
+
+        me.angle = PI / 2.0;
+        me.throttle = 1.0;
+        assert!(time_to_impact(&incoming, &me).is_none());
+    }
+
+    #[test]
+    fn threats_are_sorted_and_leave_out_harmless_bullets() {
+        let mut me = player(0, 500.0, 500.0);
+        me.team = Some(1);
+        let mut teammates_bullet = bullet(3, 5, 520.0, 500.0, PI);
+        teammates_bullet.team = Some(1);
+        let bullets = vec![
+            bullet(0, 1, 700.0, 500.0, PI),
+            bullet(1, 1, 550.0, 500.0, PI),
+            bullet(2, 0, 530.0, 500.0, PI),
+            teammates_bullet,
+            bullet(4, 1, 500.0, 900.0, -PI / 2.0),
+        ];
+        let state = arena(vec![me.clone()], bullets);
+
+        let ids: Vec<u32> = threats(&me, &state, Duration::from_millis(500)).iter().map(|(b, _)| b.id).collect();
+        assert_eq!(ids, vec![1, 0]);
+        assert_eq!(nearest_threat(&me, &state, Duration::from_millis(500)).map(|(b, _)| b.id), Some(1));
+    }
+
+    #[test]
+    fn lead_angle_at_a_standing_target_is_straight_at_it() {
+        let me = player(0, 0.0, 0.0);
+        let target = player(1, 100.0, 100.0);
+
+        let angle = lead_angle(&me, &target).unwrap();
+        assert!((angle.get() - PI / 4.0).abs() < TOLERANCE);
+    }
+
+    #[test]
+    fn lead_angle_meets_a_moving_target() {
+        let me = player(0, 0.0, 0.0);
+        let mut target = player(1, 300.0, 0.0);
+        target.angle = PI / 2.0;
+        target.throttle = 1.0;
+
+        let angle = lead_angle(&me, &target).unwrap();
+        assert!(angle.get() > 0.0);
+
+        // Fly a bullet along the angle and check the target is there when it arrives.
+        let shot = bullet(0, 0, 0.0, 0.0, angle.get());
+        let t = time_to_impact(&shot, &target).unwrap();
+        let bullet_at = Point::new(0.0, 0.0) + bullet_velocity(&shot) * t;
+        let target_at = position(&target) + velocity(&target) * t;
+        assert!(bullet_at.distance(&target_at) <= PLAYER_RADIUS + BULLET_RADIUS + TOLERANCE);
+    }
+
+    #[test]
+    fn dodge_goes_across_the_path_away_from_it() {
+        let me = player(0, 500.0, 505.0);
+        let state = arena(vec![me.clone()], vec![bullet(0, 1, 700.0, 500.0, PI)]);
+
+        let dodge = dodge_direction(&me, &state, Duration::from_secs(1)).unwrap();
+        assert!(dodge.x.abs() < TOLERANCE);
+        assert!((dodge.y - 1.0).abs() < TOLERANCE);
+    }
+
+    #[test]
+    fn dodge_avoids_running_into_a_wall() {
+        let me = player(0, 500.0, 985.0);
+        let state = arena(vec![me.clone()], vec![bullet(0, 1, 700.0, 980.0, PI)]);
+
+        let dodge = dodge_direction(&me, &state, Duration::from_secs(1)).unwrap();
+        assert!((dodge.y + 1.0).abs() < TOLERANCE);
+    }
+
+    #[test]
+    fn nothing_to_dodge_without_threats() {
+        let me = player(0, 500.0, 500.0);
+        let state = arena(vec![me.clone()], vec![bullet(0, 1, 700.0, 100.0, PI)]);
+
+        assert!(dodge_direction(&me, &state, Duration::from_secs(1)).is_none());
+    }
+}
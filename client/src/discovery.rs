@@ -0,0 +1,45 @@
+use crate::models::{MDNS_SERVICE_TYPE, PROTOCOL_VERSION};
+use failure::Error;
+use mdns_sd::{ServiceDaemon, ServiceEvent};
+use std::time::{Duration, Instant};
+
+/// Looks for tokyo-rs servers announced over mDNS on the local network,
+/// waiting up to `timeout` for them to show up. Returns `host:port` strings
+/// that can be used as the `SERVER_HOST`, only servers speaking our protocol
+/// version are included.
+///
+/// # Example
+///
+/// ```no_run
+/// let servers = tokyo::discover(std::time::Duration::from_secs(2)).unwrap();
+/// println!("found {:?}", servers);
+/// ```
+pub fn discover(timeout: Duration) -> Result<Vec<String>, Error> {
+    let mdns = ServiceDaemon::new()?;
+    let events = mdns.browse(MDNS_SERVICE_TYPE)?;
+
+    let deadline = Instant::now() + timeout;
+    let mut servers = Vec::new();
+
+    // Timing out just means nobody else is going to show up.
+    while let Ok(event) = events.recv_deadline(deadline) {
+        let service = match event {
+            ServiceEvent::ServiceResolved(service) => service,
+            _ => continue,
+        };
+        let protocol_version = service.get_property_val_str("protocol_version").and_then(|v| v.parse().ok());
+        if protocol_version != Some(PROTOCOL_VERSION) {
+            continue;
+        }
+
+        for ip in service.get_addresses_v4() {
+            let server = format!("{}:{}", ip, service.get_port());
+            if !servers.contains(&server) {
+                servers.push(server);
+            }
+        }
+    }
+
+    let _ = mdns.shutdown();
+    Ok(servers)
+}
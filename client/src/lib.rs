@@ -8,16 +8,27 @@ extern crate serde_derive;
 
 pub mod analyzer;
 pub mod behavior;
+pub mod discovery;
 pub mod geom;
 pub mod models;
 
-use crate::models::{ClientState, GameCommand, GameState, ServerToClient, MIN_COMMAND_INTERVAL};
+pub use crate::discovery::discover;
+
+use crate::models::{
+    msgpack, ClientCommand, ClientState, GameCommand, PhaseChange, ServerToClient, ThrottleNotice,
+    MIN_COMMAND_INTERVAL, PROTOCOL_VERSION,
+};
 use failure::Error;
 use futures::{Future, Sink, Stream};
 use std::{
     env,
     fmt::Debug,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
 };
 use tokio_tungstenite as tokio_ws;
 use tokio_ws::tungstenite as ws;
@@ -33,6 +44,20 @@ pub trait Handler {
     /// state of the world and do a single action based on its state. It's not
     /// called when the player is dead and waiting to be respawn.
     fn tick(&mut self, state: &ClientState) -> Option<GameCommand>;
+
+    /// Called before the next `tick` for every command the server dropped for
+    /// coming too soon, with how long until trying again would work. Useful
+    /// to slow down instead of wasting commands.
+    fn throttled(&mut self, _notice: &ThrottleNotice) {}
+
+    /// Called before the next `tick` whenever the round moves on, when the
+    /// server plays rounds. The final standings come with the results.
+    fn phase_changed(&mut self, _change: &PhaseChange) {}
+
+    /// Called after the connection dropped and the client connected again,
+    /// see `Client::reconnect`. A good time to forget anything remembered
+    /// about the game, it may have been reset while we were gone.
+    fn reconnected(&mut self) {}
 }
 
 fn log_err<E: Debug>(e: E) {
@@ -46,7 +71,7 @@ fn is_player_alive(state: &ClientState) -> bool {
 fn build_game_loop<H, S, D>(
     sink: S,
     client_state: Arc<Mutex<ClientState>>,
-    mut handler: H,
+    handler: Arc<Mutex<H>>,
 ) -> impl Future<Item = (), Error = ()>
 where
     H: Handler + Send + 'static,
@@ -57,17 +82,23 @@ where
     tokio::timer::Interval::new_interval(MIN_COMMAND_INTERVAL)
         // Give the user a chance to take a turn
         .filter_map(move |_| {
-            let client_state = &*client_state.lock().unwrap();
-            if is_player_alive(client_state) {
-                handler.tick(client_state)
-            } else {
-                None
+            let client_state = &mut *client_state.lock().unwrap();
+            let handler = &mut *handler.lock().unwrap();
+            for notice in client_state.throttled.drain(..) {
+                handler.throttled(&notice);
+            }
+            for change in client_state.phase_changes.drain(..) {
+                handler.phase_changed(&change);
             }
+            let command = if is_player_alive(client_state) { handler.tick(client_state) } else { None };
+            client_state.emotes.clear();
+            client_state.chat.clear();
+            client_state.events.clear();
+
+            Some(client_state.track_command(command?))
         })
         // Convert their command to a websocket message
-        .map(move |command: GameCommand| {
-            ws::Message::Text(serde_json::to_string(&command).unwrap())
-        })
+        .map(move |command: ClientCommand| ws::Message::Binary(msgpack::to_vec(&command).unwrap()))
         // Satisfy the type gods.
         .map_err(log_err)
         // And send the message out.
@@ -84,18 +115,48 @@ where
     D: Debug,
 {
     stream
-        // We only care about text websocket messages.
-        .filter_map(|message| message.into_text().ok())
-        // We especially only care about proper JSON messages.
-        .filter_map(|message| serde_json::from_str(&message).ok())
+        // We only care about proper MessagePack messages, or JSON from older servers.
+        .filter_map(|message| match message {
+            ws::Message::Binary(message) => msgpack::from_slice(&message).ok(),
+            ws::Message::Text(message) => serde_json::from_str(&message).ok(),
+            _ => None,
+        })
         // Update the our game state to the most recent reported by the server.
         .for_each(move |server_to_client_msg| {
             match server_to_client_msg {
                 ServerToClient::Id(player_id) => {
-                    (*client_state).lock().unwrap().id = player_id;
+                    let client_state = &mut *client_state.lock().unwrap();
+                    client_state.id = player_id;
+                    client_state.waitlist = None;
                 },
                 ServerToClient::GameState(state) => {
-                    (*client_state).lock().unwrap().game_state = state;
+                    (*client_state).lock().unwrap().update(state);
+                },
+                ServerToClient::StateDelta(delta) => {
+                    (*client_state).lock().unwrap().apply_delta(&delta);
+                },
+                ServerToClient::Emote(emote) => {
+                    (*client_state).lock().unwrap().emotes.push(emote);
+                },
+                ServerToClient::Chat(chat) => {
+                    (*client_state).lock().unwrap().chat.push(chat);
+                },
+                ServerToClient::Events(events) => {
+                    (*client_state).lock().unwrap().events.extend(events);
+                },
+                ServerToClient::Throttled(notice) => {
+                    (*client_state).lock().unwrap().throttled.push(notice);
+                },
+                ServerToClient::QueueStatus(status) => {
+                    (*client_state).lock().unwrap().queue_status = Some(status);
+                },
+                ServerToClient::Waitlist(status) => {
+                    (*client_state).lock().unwrap().waitlist = Some(status);
+                },
+                ServerToClient::Phase(change) => {
+                    let client_state = &mut *client_state.lock().unwrap();
+                    client_state.phase = Some(change.phase);
+                    client_state.phase_changes.push(change);
                 },
                 _ => {},
             }
@@ -105,25 +166,194 @@ where
         .map_err(log_err)
 }
 
+/// Picks the server to connect to: `SERVER_HOST` if set, otherwise the first
+/// server found on the local network if asked to look for one.
+fn server_host(lan_discovery: bool) -> String {
+    if let Ok(host) = env::var("SERVER_HOST") {
+        return host;
+    }
+
+    if lan_discovery {
+        match discover(Duration::from_secs(2)) {
+            Ok(ref servers) if !servers.is_empty() => {
+                println!("Discovered server at {}", servers[0]);
+                return servers[0].clone();
+            },
+            Ok(_) => eprintln!("No server found on the local network"),
+            Err(e) => eprintln!("Failed to look for servers on the local network: {}", e),
+        }
+    }
+
+    "192.168.0.199".into()
+}
+
 /// Begin the client-side game loop, using the provided struct that implements `Handler`
 /// to act on behalf of the player.
 pub fn run<H>(key: &str, name: &str, handler: H) -> Result<(), Error>
 where
     H: Handler + Send + 'static,
 {
-    let host = env::var("SERVER_HOST").unwrap_or("192.168.0.199".into());
-    let url = Url::parse(&format!(
-        "ws://{}/socket?key={}&name={}",
-        host,
-        key,
-        utf8_percent_encode(name, DEFAULT_ENCODE_SET).to_string()
-    ))?;
-
-    let client_state =
-        Arc::new(Mutex::new(ClientState { id: 0, game_state: GameState::default() }));
-
-    let client = tokio_ws::connect_async(url)
+    Client::new(key, name).run(handler)
+}
+
+/// Like `run`, but joins through the ranked queue. After the placement matches
+/// `ClientState::queue_status` shows the player's rank tier.
+pub fn run_ranked<H>(key: &str, name: &str, handler: H) -> Result<(), Error>
+where
+    H: Handler + Send + 'static,
+{
+    Client::new(key, name).ranked().run(handler)
+}
+
+/// How long to wait between attempts to connect again, doubling (by default)
+/// after every failed attempt.
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    pub multiplier: f32,
+    // Give up after this many attempts in a row fail, never if `None`.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self { initial: Duration::from_millis(500), max: Duration::from_secs(30), multiplier: 2.0, max_attempts: None }
+    }
+}
+
+impl Backoff {
+    /// The wait before the given attempt, counting from 1.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = self.multiplier.powi(attempt.saturating_sub(1) as i32);
+        let delay = self.initial.as_secs_f32() * factor;
+        if delay.is_finite() && delay < self.max.as_secs_f32() {
+            Duration::from_secs_f32(delay)
+        } else {
+            self.max
+        }
+    }
+}
+
+/// Everything `run` and `run_ranked` do, with more options:
+///
+/// ```no_run
+/// # struct Player;
+/// # impl tokyo::Handler for Player {
+/// #     fn tick(&mut self, _: &tokyo::models::ClientState) -> Option<tokyo::models::GameCommand> { None }
+/// # }
+/// tokyo::Client::new("key", "name").reconnect(tokyo::Backoff::default()).run(Player).unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct Client {
+    key: String,
+    name: String,
+    ranked: bool,
+    reconnect: Option<Backoff>,
+    lan_discovery: bool,
+}
+
+impl Client {
+    pub fn new(key: &str, name: &str) -> Self {
+        Self { key: key.to_string(), name: name.to_string(), ranked: false, reconnect: None, lan_discovery: false }
+    }
+
+    /// Join through the ranked queue, see `run_ranked`.
+    pub fn ranked(mut self) -> Self {
+        self.ranked = true;
+        self
+    }
+
+    /// Look for a server on the local network when `SERVER_HOST` isn't set,
+    /// see `discover`. It takes a couple of seconds.
+    pub fn lan_discovery(mut self) -> Self {
+        self.lan_discovery = true;
+        self
+    }
+
+    /// Connect again when the connection drops or can't be made, instead of
+    /// returning. The handler is told through `Handler::reconnected`.
+    pub fn reconnect(mut self, backoff: Backoff) -> Self {
+        self.reconnect = Some(backoff);
+        self
+    }
+
+    /// Connects and plays until the connection closes, or for as long as
+    /// reconnecting keeps working.
+    pub fn run<H>(self, handler: H) -> Result<(), Error>
+    where
+        H: Handler + Send + 'static,
+    {
+        let url = self.url()?;
+        let handler = Arc::new(Mutex::new(handler));
+        let mut failed_attempts = 0;
+        let mut reconnecting = false;
+
+        loop {
+            let connected = Arc::new(AtomicBool::new(false));
+            tokio::run(play(url.clone(), handler.clone(), reconnecting, connected.clone()));
+
+            let backoff = match self.reconnect {
+                Some(backoff) => backoff,
+                None => return Ok(()),
+            };
+            if connected.load(Ordering::SeqCst) {
+                failed_attempts = 0;
+            }
+            failed_attempts += 1;
+            if backoff.max_attempts.is_some_and(|max| failed_attempts > max) {
+                return Err(failure::err_msg(format!("Gave up reconnecting after {} attempts", failed_attempts - 1)));
+            }
+
+            let delay = backoff.delay(failed_attempts);
+            eprintln!("Disconnected, connecting again in {:?}", delay);
+            thread::sleep(delay);
+            reconnecting = true;
+        }
+    }
+
+    fn url(&self) -> Result<Url, Error> {
+        let host = server_host(self.lan_discovery);
+        let mut url = Url::parse(&format!(
+            "ws://{}/socket?key={}&name={}&version={}&ranked={}&delta=true&format=msgpack",
+            host,
+            self.key,
+            utf8_percent_encode(&self.name, DEFAULT_ENCODE_SET),
+            PROTOCOL_VERSION,
+            self.ranked
+        ))?;
+        // Workshops split up into rooms, everyone else plays in the default one.
+        if let Ok(room) = env::var("SERVER_ROOM") {
+            url.query_pairs_mut().append_pair("room", &room);
+        }
+        if let Ok(team) = env::var("SERVER_TEAM") {
+            url.query_pairs_mut().append_pair("team", &team);
+        }
+
+        Ok(url)
+    }
+}
+
+/// Plays over a single connection, until it closes. Every connection starts
+/// over with a fresh `ClientState`.
+fn play<H>(
+    url: Url,
+    handler: Arc<Mutex<H>>,
+    reconnecting: bool,
+    connected: Arc<AtomicBool>,
+) -> impl Future<Item = (), Error = ()>
+where
+    H: Handler + Send + 'static,
+{
+    let client_state = Arc::new(Mutex::new(ClientState::default()));
+
+    tokio_ws::connect_async(url)
         .and_then(move |(websocket, _)| {
+            connected.store(true, Ordering::SeqCst);
+            if reconnecting {
+                handler.lock().unwrap().reconnected();
+            }
+
             // Allow us to build two futures out of this connection - one for send, one for recv.
             let (sink, stream) = websocket.split();
 
@@ -133,10 +363,7 @@ where
             // Return a future that will finish when either one of the two futures finish.
             state_updater.select(game_loop).then(|_| Ok(()))
         })
-        .map_err(log_err);
-
-    tokio::run(client);
-    Ok(())
+        .map_err(log_err)
 }
 
 #[cfg(test)]
@@ -1,105 +1,153 @@
-use actix::Message;
-use std::{
-    collections::HashMap,
-    time::{Duration, SystemTime},
-};
-
-pub const BULLET_RADIUS: f32 = 2.0;
-pub const BULLET_SPEED: f32 = 600.0; // in pixels-per-second
-pub const PLAYER_RADIUS: f32 = 10.0;
-pub const PLAYER_BASE_SPEED: f32 = 300.0;
-pub const PLAYER_MIN_THROTTLE: f32 = -1.0;
-pub const PLAYER_MAX_THROTTLE: f32 = 1.0;
-
-// Send commands more frequently than this interval, and consequences.
-pub const MIN_COMMAND_INTERVAL: Duration = Duration::from_millis(50);
+//! The wire types live in the `tokyo-protocol` crate and are re-exported here,
+//! so bots can keep using `tokyo::models::*`.
 
-#[derive(Deserialize, Debug, Copy, Clone)]
-pub struct GameConfig {
-    pub bound_x: f32,
-    pub bound_y: f32,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(tag = "e", content = "data")]
-pub enum GameCommand {
-    #[serde(rename = "rotate")]
-    Rotate(f32), // In radians, no punish.
+pub use tokyo_protocol::*;
 
-    #[serde(rename = "throttle")]
-    Throttle(f32), // Between 0.0 and 1.0, otherwise consequences.
-
-    #[serde(rename = "fire")]
-    Fire, // Fire at the current angle.
-}
-
-#[derive(Debug, Serialize, Deserialize, Message)]
-#[serde(tag = "e", content = "data")]
-pub enum ServerToClient {
-    #[serde(rename = "id")]
-    Id(u32), // Tell the client their player ID
-
-    #[serde(rename = "state")]
-    GameState(GameState), // Send the game state to the client
-
-    #[serde(rename = "teamnames")]
-    TeamNames(HashMap<u32, String>), // Send the game state to the client
-}
+use crate::analyzer::tactics::velocity;
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct PlayerState {
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ClientState {
     pub id: u32,
-    pub angle: f32,
-    pub throttle: f32,
-    pub x: f32,
-    pub y: f32,
+    pub game_state: GameState,
+    // The state before `game_state`, to interpolate between the two.
+    pub previous_state: Option<GameState>,
+    // The sequence number of the last command sent.
+    pub last_seq: u32,
+    // Commands sent but not yet applied in `game_state`, oldest first. Replay
+    // them on top of `game_state` to predict where the server is heading.
+    pub pending_commands: Vec<(u32, GameCommand)>,
+    // Smoothed time from sending a numbered command to seeing it acked, which
+    // includes waiting for the tick that applies it.
+    pub round_trip: Option<Duration>,
+    // When each pending command was sent, oldest first.
+    #[serde(skip)]
+    sent_at: VecDeque<(u32, Instant)>,
+    // Emotes sent by anyone since the handler's last tick.
+    pub emotes: Vec<EmoteEvent>,
+    // Chat messages for this player since the handler's last tick.
+    pub chat: Vec<ChatEvent>,
+    // Kills, respawns, pickups and so on since the handler's last tick.
+    pub events: Vec<GameEvent>,
+    // Only sent when playing ranked, see `tokyo::run_ranked`.
+    pub queue_status: Option<QueueStatus>,
+    // Set while the arena is full and this client waits for a slot.
+    pub waitlist: Option<WaitlistStatus>,
+    // Commands the server dropped since the handler's last tick, see `Handler::throttled`.
+    pub throttled: Vec<ThrottleNotice>,
+    // Where the round is at, when the server plays rounds.
+    pub phase: Option<MatchPhase>,
+    // Phases the round went through since the handler's last tick, see `Handler::phase_changed`.
+    pub phase_changes: Vec<PhaseChange>,
 }
 
-impl PlayerState {
-    pub fn new(id: u32) -> Self {
-        Self { id, angle: 0f32, throttle: 0f32, x: 0f32, y: 0f32 }
-    }
+impl ClientState {
+    /// Numbers a command about to be sent and remembers it until it's acked.
+    pub fn track_command(&mut self, cmd: GameCommand) -> ClientCommand {
+        self.last_seq = self.last_seq.wrapping_add(1);
+        self.pending_commands.push((self.last_seq, cmd.clone()));
+        self.sent_at.push_back((self.last_seq, Instant::now()));
 
-    pub fn randomize(&mut self, rng: &mut impl rand::Rng, (bound_right, bound_bottom): (f32, f32)) {
-        self.angle = rng.gen_range(0.0, std::f32::consts::PI * 2.0);
-        self.throttle = 0.0;
-        self.x = rng.gen_range(0.0, bound_right);
-        self.y = rng.gen_range(0.0, bound_bottom);
+        ClientCommand { cmd, seq: Some(self.last_seq) }
     }
-}
 
-#[derive(Clone, Default, Debug, Serialize, Deserialize)]
-pub struct BulletState {
-    pub id: u32,
-    pub player_id: u32,
-    pub angle: f32,
-    pub x: f32,
-    pub y: f32,
-}
+    /// Takes a new state from the server and forgets the commands it includes.
+    pub fn update(&mut self, game_state: GameState) {
+        self.previous_state = Some(std::mem::replace(&mut self.game_state, game_state));
+        self.forget_acked_commands();
+    }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct DeadPlayer {
-    pub respawn: SystemTime,
-    pub player: PlayerState,
-}
+    /// Brings `game_state` up to date with a delta from the server. A delta
+    /// that doesn't fit is dropped, the next full state fixes things up.
+    pub fn apply_delta(&mut self, delta: &GameStateDelta) {
+        let previous_state = self.game_state.clone();
+        if self.game_state.apply(delta) {
+            self.previous_state = Some(previous_state);
+            self.forget_acked_commands();
+        }
+    }
 
-#[derive(Clone, Default, Debug, Serialize, Deserialize, Message)]
-pub struct GameState {
-    pub bounds: (f32, f32),
-    pub players: Vec<PlayerState>,
-    pub dead: Vec<DeadPlayer>,
-    pub bullets: Vec<BulletState>,
-    pub scoreboard: HashMap<u32, u32>,
-}
+    fn forget_acked_commands(&mut self) {
+        let ack = match self.game_state.acks.get(&self.id) {
+            Some(ack) => *ack,
+            None => return,
+        };
+        // Dropped commands go as well, the state will never reflect them.
+        self.pending_commands.retain(|(seq, _)| *seq > ack.seq);
+
+        let mut sent_at = None;
+        while let Some((seq, at)) = self.sent_at.front().cloned() {
+            if seq > ack.seq {
+                break;
+            }
+            sent_at = Some(at);
+            self.sent_at.pop_front();
+        }
+        if let Some(sent_at) = sent_at {
+            let sample = sent_at.elapsed();
+            self.round_trip = Some(match self.round_trip {
+                Some(round_trip) => (round_trip * 7 + sample) / 8,
+                None => sample,
+            });
+        }
+    }
 
-impl GameState {
-    pub fn new(bounds: (f32, f32)) -> Self {
-        Self { bounds, ..Default::default() }
+    /// Our own ship as it will be once the server has applied the pending
+    /// commands, moved on by `ahead`. Half the `round_trip` is a good guess
+    /// for how far the server is ahead of the latest state.
+    pub fn predicted_player(&self, ahead: Duration) -> Option<PlayerState> {
+        let mut me = self.game_state.players.iter().find(|player| player.id == self.id)?.clone();
+        for (_, cmd) in &self.pending_commands {
+            match cmd {
+                GameCommand::Rotate(angle) => me.angle = *angle,
+                GameCommand::Throttle(throttle) => me.throttle = throttle.clamp(0.0, 1.0),
+                _ => {},
+            }
+        }
+
+        let moved = velocity(&me) * ahead.as_secs_f32();
+        let (bound_x, bound_y) = self.game_state.bounds;
+        me.x = (me.x + moved.x).max(PLAYER_RADIUS).min(bound_x - PLAYER_RADIUS);
+        me.y = (me.y + moved.y).max(PLAYER_RADIUS).min(bound_y - PLAYER_RADIUS);
+        Some(me)
     }
-}
 
-#[derive(Clone, Debug, Serialize, Deserialize, Message)]
-pub struct ClientState {
-    pub id: u32,
-    pub game_state: GameState,
+    /// The players at `time_ms` on the server's game clock, between the
+    /// previous state and the latest one. Players that weren't in both are
+    /// where the latest state has them.
+    pub fn interpolated_players(&self, time_ms: u64) -> Vec<PlayerState> {
+        let previous = match &self.previous_state {
+            Some(previous) if previous.time_ms < self.game_state.time_ms => previous,
+            _ => return self.game_state.players.clone(),
+        };
+        let span = (self.game_state.time_ms - previous.time_ms) as f32;
+        let t = (time_ms.saturating_sub(previous.time_ms) as f32 / span).min(1.0);
+
+        self.game_state
+            .players
+            .iter()
+            .map(|player| {
+                let before = match previous.players.iter().find(|before| before.id == player.id) {
+                    Some(before) => before,
+                    None => return player.clone(),
+                };
+                let mut turn = (player.angle - before.angle) % std::f32::consts::TAU;
+                if turn > std::f32::consts::PI {
+                    turn -= std::f32::consts::TAU;
+                } else if turn < -std::f32::consts::PI {
+                    turn += std::f32::consts::TAU;
+                }
+
+                PlayerState {
+                    x: before.x + (player.x - before.x) * t,
+                    y: before.y + (player.y - before.y) * t,
+                    angle: before.angle + turn * t,
+                    ..player.clone()
+                }
+            })
+            .collect()
+    }
 }
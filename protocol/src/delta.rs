@@ -0,0 +1,410 @@
+//! Per-tick diffs of the `GameState`, so clients that ask for them with
+//! `?delta=true` don't get the whole arena 30 times a second.
+
+use crate::{BulletState, CommandAck, DeadPlayer, GameState, ItemState, MarkerState, ObstacleState, PlayerState};
+use std::collections::{HashMap, HashSet};
+
+/// What changed between the state with `base_tick` and the one with `tick`.
+/// Players and bullets are sent when they're new or changed, the rest of the
+/// lists change rarely and are sent whole when they do.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct GameStateDelta {
+    pub tick: u64,
+    pub base_tick: u64,
+    #[serde(default)]
+    pub time_ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bounds: Option<(f32, f32)>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub players: Vec<PlayerState>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub removed_players: Vec<u32>,
+    // Bullets fired since the base state.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub bullets: Vec<BulletState>,
+    // Bullets never turn, so moving one only takes its id and position.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub moved_bullets: Vec<(u32, f32, f32)>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub removed_bullets: Vec<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dead: Option<Vec<DeadPlayer>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub obstacles: Option<Vec<ObstacleState>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub markers: Option<Vec<MarkerState>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub items: Option<Vec<ItemState>>,
+    // Changed scores, and the players that dropped off the scoreboard.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub scoreboard: HashMap<u32, u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub removed_scores: Vec<u32>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub team_scoreboard: HashMap<u32, u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub removed_team_scores: Vec<u32>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub names: HashMap<u32, String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub removed_names: Vec<u32>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub acks: HashMap<u32, CommandAck>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub removed_acks: Vec<u32>,
+    pub checksum: u32,
+}
+
+impl GameState {
+    /// What it takes to turn this state into `next`.
+    pub fn diff(&self, next: &GameState) -> GameStateDelta {
+        let (players, removed_players) = diff_by_id(&self.players, &next.players, |player| player.id);
+
+        let previous_bullets: HashMap<u32, &BulletState> =
+            self.bullets.iter().map(|bullet| (bullet.id, bullet)).collect();
+        let mut bullets = vec![];
+        let mut moved_bullets = vec![];
+        for bullet in &next.bullets {
+            match previous_bullets.get(&bullet.id) {
+                Some(previous) if previous.player_id == bullet.player_id && previous.angle == bullet.angle => {
+                    if previous.x != bullet.x || previous.y != bullet.y {
+                        moved_bullets.push((bullet.id, bullet.x, bullet.y));
+                    }
+                },
+                _ => bullets.push(bullet.clone()),
+            }
+        }
+        let (_, removed_bullets) = diff_by_id(&self.bullets, &next.bullets, |bullet| bullet.id);
+
+        let (scoreboard, removed_scores) = diff_map(&self.scoreboard, &next.scoreboard);
+        let (team_scoreboard, removed_team_scores) = diff_map(&self.team_scoreboard, &next.team_scoreboard);
+        let (names, removed_names) = diff_map(&self.names, &next.names);
+        let (acks, removed_acks) = diff_map(&self.acks, &next.acks);
+
+        GameStateDelta {
+            tick: next.tick,
+            base_tick: self.tick,
+            time_ms: next.time_ms,
+            bounds: if self.bounds != next.bounds { Some(next.bounds) } else { None },
+            players,
+            removed_players,
+            bullets,
+            moved_bullets,
+            removed_bullets,
+            dead: changed(&self.dead, &next.dead),
+            obstacles: changed(&self.obstacles, &next.obstacles),
+            markers: changed(&self.markers, &next.markers),
+            items: changed(&self.items, &next.items),
+            scoreboard,
+            removed_scores,
+            team_scoreboard,
+            removed_team_scores,
+            names,
+            removed_names,
+            acks,
+            removed_acks,
+            checksum: next.checksum,
+        }
+    }
+
+    /// Brings this state up to date with a delta. Returns false, leaving the
+    /// state alone, if the delta was taken against a different state.
+    pub fn apply(&mut self, delta: &GameStateDelta) -> bool {
+        if delta.base_tick != self.tick {
+            return false;
+        }
+
+        self.tick = delta.tick;
+        self.time_ms = delta.time_ms;
+        if let Some(bounds) = delta.bounds {
+            self.bounds = bounds;
+        }
+
+        self.players.retain(|player| !delta.removed_players.contains(&player.id));
+        upsert_by_id(&mut self.players, &delta.players, |player| player.id);
+
+        self.bullets.retain(|bullet| !delta.removed_bullets.contains(&bullet.id));
+        let moved: HashMap<u32, (f32, f32)> = delta.moved_bullets.iter().map(|(id, x, y)| (*id, (*x, *y))).collect();
+        for bullet in &mut self.bullets {
+            if let Some((x, y)) = moved.get(&bullet.id) {
+                bullet.x = *x;
+                bullet.y = *y;
+            }
+        }
+        upsert_by_id(&mut self.bullets, &delta.bullets, |bullet| bullet.id);
+
+        if let Some(dead) = &delta.dead {
+            self.dead = dead.clone();
+        }
+        if let Some(obstacles) = &delta.obstacles {
+            self.obstacles = obstacles.clone();
+        }
+        if let Some(markers) = &delta.markers {
+            self.markers = markers.clone();
+        }
+        if let Some(items) = &delta.items {
+            self.items = items.clone();
+        }
+
+        for player_id in &delta.removed_scores {
+            self.scoreboard.remove(player_id);
+        }
+        self.scoreboard.extend(delta.scoreboard.iter().map(|(id, score)| (*id, *score)));
+        for team in &delta.removed_team_scores {
+            self.team_scoreboard.remove(team);
+        }
+        self.team_scoreboard.extend(delta.team_scoreboard.iter().map(|(team, score)| (*team, *score)));
+        for player_id in &delta.removed_names {
+            self.names.remove(player_id);
+        }
+        self.names.extend(delta.names.iter().map(|(id, name)| (*id, name.clone())));
+        for player_id in &delta.removed_acks {
+            self.acks.remove(player_id);
+        }
+        self.acks.extend(delta.acks.iter().map(|(id, ack)| (*id, *ack)));
+
+        self.checksum = delta.checksum;
+        true
+    }
+}
+
+/// The new or changed items, and the ids of the removed ones.
+fn diff_by_id<T: Clone + PartialEq>(previous: &[T], next: &[T], id: impl Fn(&T) -> u32) -> (Vec<T>, Vec<u32>) {
+    let previous_by_id: HashMap<u32, &T> = previous.iter().map(|item| (id(item), item)).collect();
+    let changed = next
+        .iter()
+        .filter(|item| previous_by_id.get(&id(item)).is_none_or(|previous| *previous != *item))
+        .cloned()
+        .collect();
+
+    let next_ids: HashSet<u32> = next.iter().map(&id).collect();
+    let removed = previous.iter().map(&id).filter(|id| !next_ids.contains(id)).collect();
+
+    (changed, removed)
+}
+
+fn upsert_by_id<T: Clone>(items: &mut Vec<T>, updates: &[T], id: impl Fn(&T) -> u32) {
+    for update in updates {
+        match items.iter_mut().find(|item| id(item) == id(update)) {
+            Some(item) => *item = update.clone(),
+            None => items.push(update.clone()),
+        }
+    }
+}
+
+fn diff_map<T: Clone + PartialEq>(previous: &HashMap<u32, T>, next: &HashMap<u32, T>) -> (HashMap<u32, T>, Vec<u32>) {
+    let changed = next
+        .iter()
+        .filter(|(id, value)| previous.get(id) != Some(value))
+        .map(|(id, value)| (*id, value.clone()))
+        .collect();
+    let removed = previous.keys().filter(|id| !next.contains_key(id)).cloned().collect();
+
+    (changed, removed)
+}
+
+fn changed<T: Clone + PartialEq>(previous: &[T], next: &[T]) -> Option<Vec<T>> {
+    if previous != next {
+        Some(next.to_vec())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ItemKind, ObstacleShape};
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn player(rng: &mut StdRng, id: u32) -> PlayerState {
+        let mut player = PlayerState::new(id);
+        player.randomize(rng, (1000.0, 1000.0));
+        player.throttle = rng.gen_range(0.0, 1.0);
+        player.deflector = rng.gen();
+        player
+    }
+
+    fn bullet(rng: &mut StdRng, id: u32) -> BulletState {
+        BulletState {
+            id,
+            player_id: rng.gen_range(0, 4),
+            angle: rng.gen_range(-3.0, 3.0),
+            x: rng.gen_range(0.0, 1000.0),
+            y: rng.gen_range(0.0, 1000.0),
+            team: None,
+        }
+    }
+
+    /// Some of the ids below `max_id`, each with about even odds.
+    fn some_ids(rng: &mut StdRng, max_id: u32) -> Vec<u32> {
+        (0..max_id).filter(|_| rng.gen()).collect()
+    }
+
+    fn random_state(rng: &mut StdRng, tick: u64) -> GameState {
+        let players: Vec<PlayerState> = some_ids(rng, 8).into_iter().map(|id| player(rng, id)).collect();
+        let mut state = GameState {
+            tick,
+            time_ms: tick * 33,
+            bounds: if rng.gen() { (1000.0, 1000.0) } else { (2000.0, 1500.0) },
+            dead: some_ids(rng, 3)
+                .into_iter()
+                .map(|id| DeadPlayer { respawn: UNIX_EPOCH + Duration::from_secs(u64::from(id)), player: player(rng, id + 8) })
+                .collect(),
+            bullets: some_ids(rng, 12).into_iter().map(|id| bullet(rng, id)).collect(),
+            obstacles: some_ids(rng, 2)
+                .into_iter()
+                .map(|id| ObstacleState { id, x: 1.0, y: 2.0, width: 3.0, height: 4.0, shape: ObstacleShape::Rect })
+                .collect(),
+            markers: some_ids(rng, 2).into_iter().map(|player_id| MarkerState { player_id, x: 1.0, y: 1.0 }).collect(),
+            items: some_ids(rng, 3)
+                .into_iter()
+                .map(|id| ItemState { id, kind: ItemKind::Shield, x: 5.0, y: 5.0 })
+                .collect(),
+            scoreboard: some_ids(rng, 8).into_iter().map(|id| (id, rng.gen_range(0, 3))).collect(),
+            team_scoreboard: some_ids(rng, 3).into_iter().map(|team| (team, rng.gen_range(0, 3))).collect(),
+            names: players.iter().map(|player| (player.id, format!("p{}", rng.gen_range(0, 2)))).collect(),
+            acks: some_ids(rng, 8).into_iter().map(|id| (id, CommandAck { seq: rng.gen_range(0, 3), tick, dropped: rng.gen() })).collect(),
+            players,
+            ..GameState::default()
+        };
+        state.checksum = state.compute_checksum();
+        state
+    }
+
+    /// A state following `previous`: every bullet kept, moved, removed or
+    /// replaced, every player kept, moved or removed, new ones added.
+    fn next_state(rng: &mut StdRng, previous: &GameState) -> GameState {
+        let mut next = random_state(rng, previous.tick + 1);
+
+        next.bullets = previous
+            .bullets
+            .iter()
+            .filter_map(|bullet| match rng.gen_range(0, 4) {
+                0 => None,
+                1 => Some(bullet.clone()),
+                2 => Some(BulletState { x: bullet.x + 5.0, y: bullet.y - 5.0, ..bullet.clone() }),
+                _ => Some(BulletState { angle: bullet.angle + 1.0, ..bullet.clone() }),
+            })
+            .collect();
+        let fired = rng.gen_range(0, 3);
+        next.bullets.extend((12..12 + fired).map(|id| bullet(rng, id)));
+        next.players = previous
+            .players
+            .iter()
+            .filter_map(|player| match rng.gen_range(0, 3) {
+                0 => None,
+                1 => Some(player.clone()),
+                _ => Some(PlayerState { x: player.x + 1.0, ..player.clone() }),
+            })
+            .chain(next.players.iter().filter(|player| !previous.players.iter().any(|p| p.id == player.id)).cloned())
+            .collect();
+        next.checksum = next.compute_checksum();
+        next
+    }
+
+    /// The state as JSON, with players and bullets in id order since a delta
+    /// may leave them in a different order.
+    fn normalized(state: &GameState) -> serde_json::Value {
+        let mut state = state.clone();
+        state.players.sort_by_key(|player| player.id);
+        state.bullets.sort_by_key(|bullet| bullet.id);
+        serde_json::to_value(&state).unwrap()
+    }
+
+    #[test]
+    fn applying_the_diff_turns_one_state_into_the_other() {
+        let mut rng = StdRng::seed_from_u64(752);
+        for _ in 0..500 {
+            let tick = rng.gen_range(0, 1000);
+            let previous = random_state(&mut rng, tick);
+            let next = next_state(&mut rng, &previous);
+
+            let mut state = previous.clone();
+            assert!(state.apply(&previous.diff(&next)));
+            assert!(state.checksum_matches());
+            assert_eq!(normalized(&state), normalized(&next));
+        }
+    }
+
+    #[test]
+    fn diff_of_a_state_with_itself_is_empty() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let state = random_state(&mut rng, 5);
+        let mut next = state.clone();
+        next.tick += 1;
+
+        let delta = state.diff(&next);
+        assert!(delta.players.is_empty() && delta.bullets.is_empty() && delta.moved_bullets.is_empty());
+        assert!(delta.dead.is_none() && delta.items.is_none() && delta.scoreboard.is_empty() && delta.names.is_empty());
+    }
+
+    #[test]
+    fn removals_are_sent_as_ids() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let mut previous = random_state(&mut rng, 1);
+        previous.players = vec![player(&mut rng, 1), player(&mut rng, 2)];
+        previous.bullets = vec![bullet(&mut rng, 1), bullet(&mut rng, 2)];
+        previous.scoreboard = vec![(1, 3), (2, 4)].into_iter().collect();
+        previous.checksum = previous.compute_checksum();
+
+        let mut next = previous.clone();
+        next.tick = 2;
+        next.players.remove(0);
+        next.bullets.remove(1);
+        next.scoreboard.remove(&2);
+        next.checksum = next.compute_checksum();
+
+        let delta = previous.diff(&next);
+        assert_eq!(delta.removed_players, vec![1]);
+        assert_eq!(delta.removed_bullets, vec![2]);
+        assert_eq!(delta.removed_scores, vec![2]);
+        assert!(delta.players.is_empty() && delta.bullets.is_empty() && delta.scoreboard.is_empty());
+
+        let mut state = previous.clone();
+        assert!(state.apply(&delta));
+        assert_eq!(normalized(&state), normalized(&next));
+    }
+
+    #[test]
+    fn moved_bullets_only_send_their_position() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut previous = random_state(&mut rng, 1);
+        previous.bullets = vec![bullet(&mut rng, 1), bullet(&mut rng, 2)];
+
+        let mut next = previous.clone();
+        next.tick = 2;
+        next.bullets[0].x += 10.0;
+        next.bullets[1].angle += 1.0;
+        next.checksum = next.compute_checksum();
+
+        let delta = previous.diff(&next);
+        assert_eq!(delta.moved_bullets, vec![(1, next.bullets[0].x, next.bullets[0].y)]);
+        assert_eq!(delta.bullets, vec![next.bullets[1].clone()]);
+
+        let mut state = previous.clone();
+        assert!(state.apply(&delta));
+        assert!(state.checksum_matches());
+        assert_eq!(state.bullets, next.bullets);
+    }
+
+    #[test]
+    fn rejects_a_delta_against_another_tick() {
+        let mut rng = StdRng::seed_from_u64(4);
+        let previous = random_state(&mut rng, 10);
+        let next = next_state(&mut rng, &previous);
+        let delta = previous.diff(&next);
+
+        let mut stale = random_state(&mut rng, 9);
+        let before = normalized(&stale);
+        assert!(!stale.apply(&delta));
+        assert_eq!(normalized(&stale), before);
+
+        // Nor can the same delta be applied twice.
+        let mut state = previous.clone();
+        assert!(state.apply(&delta));
+        assert!(!state.apply(&delta));
+        assert_eq!(normalized(&state), normalized(&next));
+    }
+}
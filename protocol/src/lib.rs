@@ -0,0 +1,1049 @@
+//! Wire types spoken between the tokyo-rs server and its clients. Both the
+//! server and the `tokyo` client crate depend on this crate, so anything that
+//! goes over the websocket should be defined here and nowhere else.
+//!
+//! Bump `PROTOCOL_VERSION` whenever a change would break an existing client.
+
+#[macro_use]
+extern crate serde_derive;
+
+#[cfg(feature = "actix")]
+use actix::Message;
+use std::{
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+mod delta;
+pub mod msgpack;
+
+pub use delta::GameStateDelta;
+
+/// The version of the wire protocol. Clients pass it as the `version` query
+/// parameter when connecting and the server refuses mismatching clients.
+///
+/// Version 1 only knew the `id`, `state` and `teamnames` events and the
+/// rotate, throttle and fire commands. Version 2 adds the rest of this crate.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// The mDNS service type servers announce themselves as on the local network.
+/// The TXT record carries the server's `protocol_version`.
+pub const MDNS_SERVICE_TYPE: &str = "_tokyo-rs._tcp.local.";
+
+/// How messages are encoded on a connection, picked with `?format=` when
+/// connecting. With MessagePack, messages go out as binary frames.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WireFormat {
+    #[default]
+    Json,
+    MsgPack,
+}
+
+pub const BULLET_RADIUS: f32 = 2.0;
+pub const BULLET_SPEED: f32 = 600.0; // in pixels-per-second
+pub const PLAYER_RADIUS: f32 = 10.0;
+pub const PLAYER_BASE_SPEED: f32 = 300.0;
+pub const PLAYER_MIN_THROTTLE: f32 = -1.0;
+pub const PLAYER_MAX_THROTTLE: f32 = 1.0;
+
+// Send commands more frequently than this interval, and consequences.
+pub const MIN_COMMAND_INTERVAL: Duration = Duration::from_millis(50);
+
+// How long a deflector stays up, and how long until it can be used again.
+pub const DEFLECTOR_DURATION: Duration = Duration::from_millis(500);
+pub const DEFLECTOR_COOLDOWN: Duration = Duration::from_secs(5);
+
+// How long a marker stays on the map. Each player can have one at a time.
+pub const MARKER_DURATION: Duration = Duration::from_secs(3);
+
+// Items are picked up by flying into them, and their effect lasts this long.
+// Picking up another item of the same kind starts the time over.
+pub const ITEM_RADIUS: f32 = 8.0;
+pub const ITEM_EFFECT_DURATION: Duration = Duration::from_secs(10);
+pub const SPEED_BOOST_MULTIPLIER: f32 = 1.5;
+pub const RAPID_FIRE_MULTIPLIER: f32 = 2.0;
+
+// Emotes are numbered from 0 up to this, and a player can send one every so often.
+pub const MAX_EMOTE: u8 = 7;
+pub const EMOTE_INTERVAL: Duration = Duration::from_secs(2);
+
+// Longer chat messages are cut off, and a player can send one every so often.
+pub const MAX_CHAT_LENGTH: usize = 200;
+pub const CHAT_INTERVAL: Duration = Duration::from_secs(1);
+pub const MAX_SPECTATOR_NAME_LENGTH: usize = 32;
+
+// Player names and team affiliations in profiles are cut off after this.
+pub const MAX_PROFILE_NAME_LENGTH: usize = 32;
+
+// Ranked players have no tier until they've played this many matches.
+pub const PLACEMENT_MATCHES: u32 = 5;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct GameConfig {
+    pub bound_x: f32,
+    pub bound_y: f32,
+    // Shots per second a player earns back, and how many can be saved up.
+    pub fire_rate: f32,
+    pub fire_burst: f32,
+    // Bullets a player can have in flight at a time.
+    pub max_bullets: usize,
+    pub weapons: WeaponConfig,
+    // Judge shots against where targets were when the shooter saw them.
+    pub lag_compensation: bool,
+    // How points are earned. Every rule that matches an event counts.
+    pub scoring: Vec<ScoringRule>,
+    // Items spawned per second, up to `max_items` in the arena at a time.
+    pub item_spawn_rate: f32,
+    pub max_items: usize,
+    // How often each kind of item spawns, relative to the others.
+    pub item_weights: ItemWeights,
+    // Walls the arena starts out with.
+    pub obstacles: Vec<ObstacleConfig>,
+    // Bullets bounce off walls instead of being destroyed.
+    pub reflect_bullets: bool,
+    pub game_mode: GameMode,
+    // How many teams players are split into in the teams mode.
+    pub teams: u32,
+    // Whether bullets hurt the shooter's teammates.
+    pub friendly_fire: bool,
+    pub rounds: RoundConfig,
+    // Players in the arena at a time, bots not counted. Whoever connects
+    // once it's full waits for a slot to free up.
+    pub max_players: usize,
+}
+
+impl Default for GameConfig {
+    fn default() -> Self {
+        Self {
+            bound_x: 8000.0,
+            bound_y: 8000.0,
+            fire_rate: 5.0,
+            fire_burst: 4.0,
+            max_bullets: 4,
+            weapons: WeaponConfig::default(),
+            lag_compensation: false,
+            scoring: vec![ScoringRule::new(ScoreEvent::Kill, 1), ScoringRule::new(ScoreEvent::Survive, 1)],
+            item_spawn_rate: 0.25,
+            max_items: 16,
+            item_weights: ItemWeights::default(),
+            obstacles: vec![],
+            reflect_bullets: false,
+            game_mode: GameMode::default(),
+            teams: 2,
+            friendly_fire: false,
+            rounds: RoundConfig::default(),
+            max_players: 50,
+        }
+    }
+}
+
+/// How each weapon fires, and how hot firing gets a ship.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct WeaponConfig {
+    // Seconds between two shots of the standard weapon.
+    pub standard_cooldown: f32,
+    // Seconds between two spread shots, how many bullets each one fans out
+    // into and the angle between them, in radians.
+    pub spread_cooldown: f32,
+    pub spread_bullets: u32,
+    pub spread_angle: f32,
+    // Heat each shot adds. A ship that reaches 1 overheats and can't fire
+    // until it has cooled down completely.
+    pub standard_heat: f32,
+    pub spread_heat: f32,
+    // Heat lost per second.
+    pub cooling_rate: f32,
+}
+
+impl WeaponConfig {
+    pub fn cooldown(&self, weapon: Weapon) -> f32 {
+        match weapon {
+            Weapon::Standard => self.standard_cooldown,
+            Weapon::Spread => self.spread_cooldown,
+        }
+    }
+
+    pub fn heat(&self, weapon: Weapon) -> f32 {
+        match weapon {
+            Weapon::Standard => self.standard_heat,
+            Weapon::Spread => self.spread_heat,
+        }
+    }
+}
+
+impl Default for WeaponConfig {
+    fn default() -> Self {
+        Self {
+            standard_cooldown: 0.15,
+            spread_cooldown: 0.6,
+            spread_bullets: 3,
+            spread_angle: 0.15,
+            standard_heat: 0.1,
+            spread_heat: 0.3,
+            cooling_rate: 0.3,
+        }
+    }
+}
+
+/// Timed rounds. Without them the match goes on until the server is reset.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RoundConfig {
+    pub enabled: bool,
+    // The lobby waits for this many players, bots included, before counting down.
+    pub min_players: usize,
+    pub countdown_secs: f32,
+    pub duration_secs: f32,
+    // How long the results are up before the next round.
+    pub results_secs: f32,
+}
+
+impl Default for RoundConfig {
+    fn default() -> Self {
+        Self { enabled: false, min_players: 2, countdown_secs: 5.0, duration_secs: 180.0, results_secs: 10.0 }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum GameMode {
+    // Everyone against everyone.
+    #[default]
+    FreeForAll,
+    // Players are split into teams and score for their team as well as themselves.
+    Teams,
+}
+
+impl FromStr for GameMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "free_for_all" => Ok(GameMode::FreeForAll),
+            "teams" => Ok(GameMode::Teams),
+            _ => Err(format!("unknown game mode {:?}, expected free_for_all or teams", s)),
+        }
+    }
+}
+
+impl fmt::Display for GameMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            GameMode::FreeForAll => "free_for_all",
+            GameMode::Teams => "teams",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A wall as it's configured. Unlike in the game state, circles are given by
+/// their center.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(tag = "shape", rename_all = "lowercase")]
+pub enum ObstacleConfig {
+    Rect { x: f32, y: f32, width: f32, height: f32 },
+    Circle { x: f32, y: f32, radius: f32 },
+}
+
+impl ObstacleConfig {
+    pub fn to_state(&self, id: u32) -> ObstacleState {
+        match *self {
+            ObstacleConfig::Rect { x, y, width, height } => {
+                ObstacleState { id, x, y, width, height, shape: ObstacleShape::Rect }
+            },
+            ObstacleConfig::Circle { x, y, radius } => ObstacleState {
+                id,
+                x: x - radius,
+                y: y - radius,
+                width: radius * 2.0,
+                height: radius * 2.0,
+                shape: ObstacleShape::Circle,
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(default)]
+pub struct ItemWeights {
+    pub shield: u32,
+    pub rapid_fire: u32,
+    pub speed_boost: u32,
+    pub extra_bullet: u32,
+}
+
+impl ItemWeights {
+    /// Each kind with its weight, in a fixed order.
+    pub fn kinds(&self) -> [(ItemKind, u32); 4] {
+        [
+            (ItemKind::Shield, self.shield),
+            (ItemKind::RapidFire, self.rapid_fire),
+            (ItemKind::SpeedBoost, self.speed_boost),
+            (ItemKind::ExtraBullet, self.extra_bullet),
+        ]
+    }
+}
+
+impl Default for ItemWeights {
+    fn default() -> Self {
+        Self { shield: 1, rapid_fire: 1, speed_boost: 1, extra_bullet: 1 }
+    }
+}
+
+/// What a scoring rule hands out points for.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ScoreEvent {
+    // Scored by the shooter.
+    Kill,
+    // Scored by whoever got shot, usually with negative points.
+    Death,
+    // Scored for every few seconds alive, once a player has survived for a while.
+    Survive,
+}
+
+/// Points for an event, as long as all of the rule's conditions hold.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ScoringRule {
+    pub event: ScoreEvent,
+    pub points: i32,
+    // Only count once the player has at least this many kills since they last died.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_streak: Option<u32>,
+    // Only count kills and deaths where the shooter was this far from their target.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_distance: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_distance: Option<f32>,
+}
+
+impl ScoringRule {
+    pub fn new(event: ScoreEvent, points: i32) -> Self {
+        Self { event, points, min_streak: None, min_distance: None, max_distance: None }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "e", content = "data")]
+pub enum GameCommand {
+    #[serde(rename = "rotate")]
+    Rotate(f32), // In radians, no punish.
+
+    #[serde(rename = "throttle")]
+    Throttle(f32), // Between 0.0 and 1.0, otherwise consequences.
+
+    #[serde(rename = "fire")]
+    Fire, // Fire at the current angle.
+
+    #[serde(rename = "deflect")]
+    Deflect, // Bounce bullets back for a moment, they become yours.
+
+    #[serde(rename = "emote")]
+    Emote(u8), // Show everyone an emote, up to MAX_EMOTE.
+
+    #[serde(rename = "marker")]
+    Marker(f32, f32), // Point out a spot on the map for a few seconds.
+
+    #[serde(rename = "chat")]
+    Chat { scope: ChatScope, text: String }, // Talk to everyone or just your team.
+
+    #[serde(rename = "switch_weapon")]
+    SwitchWeapon(Weapon), // Fire with another weapon from now on.
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Weapon {
+    // One bullet straight ahead.
+    #[default]
+    Standard,
+    // A fan of bullets, slower to fire and quicker to overheat.
+    Spread,
+}
+
+/// Who gets to read a chat message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatScope {
+    All,
+    Team,
+}
+
+/// What clients actually send: a `GameCommand`, optionally numbered so the
+/// client can find out from `GameState::acks` when the server applied it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ClientCommand {
+    #[serde(flatten)]
+    pub cmd: GameCommand,
+    // Should increase with every command sent on a connection.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u32>,
+}
+
+/// The last numbered command the server handled for a player, and the tick
+/// it was handled on.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CommandAck {
+    pub seq: u32,
+    pub tick: u64,
+    // The command didn't change anything, e.g. it was sent while dead or
+    // fired with no shots left. The state doesn't reflect it and never will.
+    #[serde(default)]
+    pub dropped: bool,
+}
+
+/// What spectators can send to change what the server streams to them.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "e", content = "data")]
+pub enum SpectatorCommand {
+    #[serde(rename = "follow")]
+    Follow(Option<u32>), // Only stream the area around this player, `null` to stop.
+
+    #[serde(rename = "region")]
+    Region(Option<ViewRegion>), // Only stream this part of the arena, `null` for all of it.
+
+    #[serde(rename = "minimap")]
+    Minimap(bool), // Leave out bullets and the dead.
+
+    #[serde(rename = "chat")]
+    Chat(String), // Say something to the other spectators.
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct ViewRegion {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl ViewRegion {
+    pub fn centered_on(x: f32, y: f32, width: f32, height: f32) -> Self {
+        Self { x: x - width / 2.0, y: y - height / 2.0, width, height }
+    }
+
+    pub fn contains(&self, x: f32, y: f32) -> bool {
+        x >= self.x && x <= self.x + self.width && y >= self.y && y <= self.y + self.height
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "actix", derive(Message), rtype(result = "()"))]
+#[serde(tag = "e", content = "data")]
+pub enum ServerToClient {
+    #[serde(rename = "id")]
+    Id(u32), // Tell the client their player ID
+
+    #[serde(rename = "state")]
+    GameState(GameState), // Send the game state to the client
+
+    #[serde(rename = "teamnames")]
+    TeamNames(HashMap<u32, String>), // Send the game state to the client
+
+    #[serde(rename = "emote")]
+    Emote(EmoteEvent), // Someone sent an emote
+
+    #[serde(rename = "chat")]
+    Chat(ChatEvent), // Someone said something
+
+    #[serde(rename = "spectator_chat")]
+    SpectatorChat(SpectatorChatEvent), // A spectator said something, only spectators get these
+
+    #[serde(rename = "queue_status")]
+    QueueStatus(QueueStatus), // Where a ranked player stands, on joining and after every match
+
+    #[serde(rename = "appearances")]
+    Appearances(HashMap<u32, Appearance>), // How players chose to look, sent along with the team names
+
+    #[serde(rename = "throttled")]
+    Throttled(ThrottleNotice), // A command was dropped, and when trying again would work
+
+    #[serde(rename = "state_delta")]
+    StateDelta(GameStateDelta), // What changed since the last state, for clients that asked for deltas
+
+    #[serde(rename = "phase")]
+    Phase(PhaseChange), // The round moved on, and on joining when the server plays rounds
+
+    #[serde(rename = "spectator_info")]
+    SpectatorInfo(SpectatorInfo), // The scoreboard and kill feed, only spectators get these
+
+    #[serde(rename = "shutting_down")]
+    ShuttingDown, // The server is about to exit and closes the connection right after
+
+    #[serde(rename = "waitlist")]
+    Waitlist(WaitlistStatus), // The arena is full, sent on joining and every few seconds until a slot frees up
+
+    #[serde(rename = "events")]
+    Events(Vec<GameEvent>), // What happened during the last tick, sent after its state when anything did
+}
+
+/// What spectators are sent on top of the game state, a few times a second.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SpectatorInfo {
+    // Everyone in the game, best first.
+    pub scoreboard: Vec<ScoreboardEntry>,
+    // The latest kills, newest last.
+    pub kill_feed: Vec<KillFeedEntry>,
+    // Left out when the server doesn't play rounds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phase: Option<PhaseChange>,
+}
+
+/// A player's standing in the current match.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ScoreboardEntry {
+    pub player_id: u32,
+    pub name: String,
+    pub score: u32,
+    pub kills: u32,
+    pub deaths: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub team: Option<u32>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KillFeedEntry {
+    pub killer: u32,
+    pub killer_name: String,
+    pub victim: u32,
+    pub victim_name: String,
+    // How long ago the kill happened.
+    pub ago_ms: u64,
+}
+
+/// Something that happened during a tick, so clients don't have to work it
+/// out from the difference between two states.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum GameEvent {
+    // `killer` shot `victim`, who was hit at `x`, `y`.
+    Kill { killer: u32, victim: u32, x: f32, y: f32 },
+    // The player died without anyone to blame, by crashing into another ship.
+    Death { player_id: u32, x: f32, y: f32 },
+    Respawn { player_id: u32, x: f32, y: f32 },
+    ItemPickup { player_id: u32, item_id: u32, kind: ItemKind },
+    // Only when the server plays rounds, see `PhaseChange` for the timings.
+    RoundStarted,
+    RoundEnded { standings: Vec<Standing> },
+}
+
+/// Where the round is at. Nothing moves and commands are dropped outside of
+/// `Playing`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchPhase {
+    // Waiting for enough players.
+    Lobby,
+    Countdown,
+    Playing,
+    // The round is over, the scores are final.
+    Results,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PhaseChange {
+    pub phase: MatchPhase,
+    // How long until the next phase, left out in the lobby.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remaining_ms: Option<u64>,
+    // The final scores, best first. Only sent with the results.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub standings: Vec<Standing>,
+    // Same for the teams, in the teams mode.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub team_standings: Vec<Standing>,
+}
+
+/// A player's or team's final score.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Standing {
+    pub id: u32,
+    pub score: u32,
+}
+
+/// Tells a client one of its commands was dropped for coming too soon.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct ThrottleNotice {
+    pub reason: ThrottleReason,
+    pub retry_after_ms: u64,
+}
+
+impl ThrottleNotice {
+    pub fn new(reason: ThrottleReason, retry_after: Duration) -> Self {
+        let retry_after_ms = retry_after.as_secs() * 1000 + u64::from(retry_after.subsec_millis());
+        // Round up, retrying a bit too early would just get dropped again.
+        let retry_after_ms = if !retry_after.subsec_nanos().is_multiple_of(1_000_000) { retry_after_ms + 1 } else { retry_after_ms };
+        Self { reason, retry_after_ms }
+    }
+
+    pub fn retry_after(&self) -> Duration {
+        Duration::from_millis(self.retry_after_ms)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ThrottleReason {
+    // More messages than the connection may send per second.
+    RateLimit,
+    // Out of shots until the fire rate catches up.
+    Fire,
+    // The deflector is cooling down.
+    Deflect,
+    // The weapon isn't ready for the next shot yet.
+    Cooldown,
+    // The ship overheated and has to cool down before firing again.
+    Overheated,
+    Emote,
+    Chat,
+}
+
+/// What a player picked in their profile, besides their name.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Appearance {
+    // A "#rrggbb" color.
+    pub color: Option<String>,
+    // Who they play for.
+    pub team: Option<String>,
+}
+
+/// Where a client waiting for a slot in a full arena stands in line.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct WaitlistStatus {
+    // Starts at 1 for the next client to get a slot.
+    pub position: u32,
+    pub waiting: u32,
+}
+
+/// A ranked player's rating and how far along their placement matches are.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct QueueStatus {
+    pub rating: f32,
+    pub placement_matches_left: u32,
+    // Only known once the placement matches are done.
+    pub tier: Option<RankTier>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RankTier {
+    Bronze,
+    Silver,
+    Gold,
+    Platinum,
+    Diamond,
+}
+
+impl RankTier {
+    pub fn for_rating(rating: f32) -> RankTier {
+        match rating {
+            r if r < 900.0 => RankTier::Bronze,
+            r if r < 1100.0 => RankTier::Silver,
+            r if r < 1300.0 => RankTier::Gold,
+            r if r < 1500.0 => RankTier::Platinum,
+            _ => RankTier::Diamond,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SpectatorChatEvent {
+    pub name: String,
+    pub text: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChatEvent {
+    pub player_id: u32,
+    pub scope: ChatScope,
+    pub text: String,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct EmoteEvent {
+    pub player_id: u32,
+    pub emote: u8,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PlayerState {
+    pub id: u32,
+    pub angle: f32,
+    pub throttle: f32,
+    pub x: f32,
+    pub y: f32,
+    // Whether the deflector is up.
+    #[serde(default)]
+    pub deflector: bool,
+    // The effects of the items the player picked up, while they last.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub effects: Vec<ItemKind>,
+    // Only set in the teams mode.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub team: Option<u32>,
+    #[serde(default)]
+    pub weapon: Weapon,
+    // Between 0 and 1, firing is locked while overheated until it's back to 0.
+    #[serde(default)]
+    pub heat: f32,
+    #[serde(default)]
+    pub overheated: bool,
+}
+
+impl PlayerState {
+    pub fn new(id: u32) -> Self {
+        Self {
+            id,
+            angle: 0f32,
+            throttle: 0f32,
+            x: 0f32,
+            y: 0f32,
+            deflector: false,
+            effects: vec![],
+            team: None,
+            weapon: Weapon::Standard,
+            heat: 0f32,
+            overheated: false,
+        }
+    }
+
+    pub fn has_effect(&self, kind: ItemKind) -> bool {
+        self.effects.contains(&kind)
+    }
+
+    pub fn randomize(&mut self, rng: &mut impl rand::Rng, (bound_right, bound_bottom): (f32, f32)) {
+        self.angle = rng.gen_range(0.0, std::f32::consts::PI * 2.0);
+        self.throttle = 0.0;
+        self.x = rng.gen_range(0.0, bound_right);
+        self.y = rng.gen_range(0.0, bound_bottom);
+    }
+}
+
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BulletState {
+    pub id: u32,
+    pub player_id: u32,
+    pub angle: f32,
+    pub x: f32,
+    pub y: f32,
+    // The team the bullet can't hurt, set in the teams mode without friendly fire.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub team: Option<u32>,
+}
+
+/// A wall in the arena, blocking players and destroying bullets. `x` and `y`
+/// are the top left corner of its bounding box, circles fill a square one.
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ObstacleState {
+    pub id: u32,
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+    #[serde(default)]
+    pub shape: ObstacleShape,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ObstacleShape {
+    #[default]
+    Rect,
+    Circle,
+}
+
+impl ObstacleState {
+    pub fn center(&self) -> (f32, f32) {
+        (self.x + self.width / 2.0, self.y + self.height / 2.0)
+    }
+
+    /// The point inside the obstacle closest to the given point.
+    pub fn closest_point(&self, x: f32, y: f32) -> (f32, f32) {
+        match self.shape {
+            ObstacleShape::Rect => {
+                (x.max(self.x).min(self.x + self.width), y.max(self.y).min(self.y + self.height))
+            },
+            ObstacleShape::Circle => {
+                let (center_x, center_y) = self.center();
+                let radius = self.width / 2.0;
+                let distance = ((x - center_x).powi(2) + (y - center_y).powi(2)).sqrt();
+                if distance <= radius {
+                    (x, y)
+                } else {
+                    (center_x + (x - center_x) * radius / distance, center_y + (y - center_y) * radius / distance)
+                }
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ItemKind {
+    // Stops the next bullet that would have killed the player.
+    Shield,
+    // Shots are earned back `RAPID_FIRE_MULTIPLIER` times as fast.
+    RapidFire,
+    // Flies `SPEED_BOOST_MULTIPLIER` times as fast.
+    SpeedBoost,
+    // One more bullet can be in flight at a time.
+    ExtraBullet,
+}
+
+/// An item lying in the arena, waiting to be picked up.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ItemState {
+    pub id: u32,
+    pub kind: ItemKind,
+    pub x: f32,
+    pub y: f32,
+}
+
+/// A spot on the map a player pointed out, e.g. for their teammates.
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MarkerState {
+    pub player_id: u32,
+    pub x: f32,
+    pub y: f32,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DeadPlayer {
+    pub respawn: SystemTime,
+    pub player: PlayerState,
+}
+
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "actix", derive(Message), rtype(result = "()"))]
+pub struct GameState {
+    // Counts up by one every server tick.
+    #[serde(default)]
+    pub tick: u64,
+    // The server's game clock at this tick, in milliseconds since the Unix
+    // epoch. It moves on by exactly the time simulated each tick, so two
+    // states are this far apart in game time.
+    #[serde(default)]
+    pub time_ms: u64,
+    pub bounds: (f32, f32),
+    pub players: Vec<PlayerState>,
+    pub dead: Vec<DeadPlayer>,
+    pub bullets: Vec<BulletState>,
+    #[serde(default)]
+    pub obstacles: Vec<ObstacleState>,
+    #[serde(default)]
+    pub markers: Vec<MarkerState>,
+    #[serde(default)]
+    pub items: Vec<ItemState>,
+    pub scoreboard: HashMap<u32, u32>,
+    // Points per team in the teams mode, everything its players scored while on it.
+    #[serde(default)]
+    pub team_scoreboard: HashMap<u32, u32>,
+    // The display name of everyone in the game or on the scoreboard, keyed by player id.
+    #[serde(default)]
+    pub names: HashMap<u32, String>,
+    // Keyed by player id.
+    #[serde(default)]
+    pub acks: HashMap<u32, CommandAck>,
+    // `compute_checksum()` of the state as the server saw it.
+    #[serde(default)]
+    pub checksum: u32,
+}
+
+impl GameState {
+    pub fn new(bounds: (f32, f32)) -> Self {
+        Self { bounds, ..Default::default() }
+    }
+
+    /// A cheap FNV-1a hash over the simulated parts of the state. Compare it
+    /// against `checksum` to detect a locally rebuilt state drifting from the
+    /// server's. Respawn times are wall-clock based and left out.
+    pub fn compute_checksum(&self) -> u32 {
+        let mut hash = Fnv1a::new();
+
+        let mut players: Vec<&PlayerState> = self.players.iter().collect();
+        players.sort_by_key(|player| player.id);
+        for player in players {
+            hash.write_u32(player.id);
+            hash.write_f32(player.angle);
+            hash.write_f32(player.throttle);
+            hash.write_f32(player.x);
+            hash.write_f32(player.y);
+            hash.write_u32(player.deflector as u32);
+            for kind in &player.effects {
+                hash.write_u32(*kind as u32);
+            }
+            if let Some(team) = player.team {
+                hash.write_u32(team);
+            }
+            hash.write_u32(player.weapon as u32);
+            hash.write_f32(player.heat);
+            hash.write_u32(player.overheated as u32);
+        }
+
+        let mut dead: Vec<u32> = self.dead.iter().map(|corpse| corpse.player.id).collect();
+        dead.sort();
+        for player_id in dead {
+            hash.write_u32(player_id);
+        }
+
+        let mut bullets: Vec<&BulletState> = self.bullets.iter().collect();
+        bullets.sort_by_key(|bullet| bullet.id);
+        for bullet in bullets {
+            hash.write_u32(bullet.id);
+            hash.write_u32(bullet.player_id);
+            hash.write_f32(bullet.angle);
+            hash.write_f32(bullet.x);
+            hash.write_f32(bullet.y);
+            if let Some(team) = bullet.team {
+                hash.write_u32(team);
+            }
+        }
+
+        for obstacle in &self.obstacles {
+            hash.write_u32(obstacle.id);
+            hash.write_f32(obstacle.x);
+            hash.write_f32(obstacle.y);
+            hash.write_f32(obstacle.width);
+            hash.write_f32(obstacle.height);
+            hash.write_u32(obstacle.shape as u32);
+        }
+
+        let mut markers: Vec<&MarkerState> = self.markers.iter().collect();
+        markers.sort_by_key(|marker| marker.player_id);
+        for marker in markers {
+            hash.write_u32(marker.player_id);
+            hash.write_f32(marker.x);
+            hash.write_f32(marker.y);
+        }
+
+        let mut items: Vec<&ItemState> = self.items.iter().collect();
+        items.sort_by_key(|item| item.id);
+        for item in items {
+            hash.write_u32(item.id);
+            hash.write_u32(item.kind as u32);
+            hash.write_f32(item.x);
+            hash.write_f32(item.y);
+        }
+
+        let mut scoreboard: Vec<(&u32, &u32)> = self.scoreboard.iter().collect();
+        scoreboard.sort();
+        for (player_id, score) in scoreboard {
+            hash.write_u32(*player_id);
+            hash.write_u32(*score);
+        }
+
+        let mut team_scoreboard: Vec<(&u32, &u32)> = self.team_scoreboard.iter().collect();
+        team_scoreboard.sort();
+        for (team, score) in team_scoreboard {
+            hash.write_u32(*team);
+            hash.write_u32(*score);
+        }
+
+        hash.0
+    }
+
+    pub fn checksum_matches(&self) -> bool {
+        self.compute_checksum() == self.checksum
+    }
+}
+
+struct Fnv1a(u32);
+
+impl Fnv1a {
+    fn new() -> Self {
+        Fnv1a(0x811c_9dc5)
+    }
+
+    fn write_u32(&mut self, value: u32) {
+        for byte in value.to_le_bytes().iter() {
+            self.0 ^= u32::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0100_0193);
+        }
+    }
+
+    fn write_f32(&mut self, value: f32) {
+        self.write_u32(value.to_bits());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn state() -> GameState {
+        let mut player = PlayerState { x: 120.5, y: -3.25, angle: 1.5, throttle: 0.75, ..PlayerState::new(3) };
+        player.effects = vec![ItemKind::Shield];
+        player.team = Some(1);
+
+        let mut state = GameState {
+            players: vec![player, PlayerState::new(4), PlayerState::new(9)],
+            dead: vec![DeadPlayer { respawn: UNIX_EPOCH, player: PlayerState::new(5) }],
+            bullets: vec![
+                BulletState { id: 1, player_id: 3, angle: -0.5, x: 1.0, y: 2.0, team: Some(1) },
+                BulletState { id: 2, player_id: 4, angle: 0.5, x: 3.0, y: 4.0, team: None },
+            ],
+            items: vec![ItemState { id: 1, kind: ItemKind::RapidFire, x: 7.0, y: 8.0 }],
+            scoreboard: vec![(3, 12), (4, 0), (9, 1)].into_iter().collect(),
+            ..GameState::new((8000.0, 8000.0))
+        };
+        state.checksum = state.compute_checksum();
+        state
+    }
+
+    #[test]
+    fn fnv1a_matches_the_reference() {
+        assert_eq!(GameState::default().compute_checksum(), 0x811c_9dc5);
+
+        let mut hash = Fnv1a::new();
+        hash.write_u32(1);
+        assert_eq!(hash.0, 0xfb69_b604);
+    }
+
+    #[test]
+    fn checksum_ignores_order() {
+        let mut shuffled = state();
+        shuffled.players.reverse();
+        shuffled.bullets.reverse();
+        assert!(shuffled.checksum_matches());
+    }
+
+    #[test]
+    fn checksum_ignores_what_isnt_simulated() {
+        let mut state = state();
+        state.tick += 1;
+        state.time_ms += 50;
+        state.names.insert(3, "Ace".to_string());
+        state.acks.insert(3, CommandAck { seq: 1, tick: 1, dropped: false });
+        state.dead[0].respawn += Duration::from_secs(1);
+        assert!(state.checksum_matches());
+    }
+
+    #[test]
+    fn checksum_catches_drift() {
+        let drifts: Vec<fn(&mut GameState)> = vec![
+            |state| state.players[0].x += 0.001,
+            |state| state.players[1].deflector = true,
+            |state| state.players[0].effects.clear(),
+            |state| state.players[0].team = None,
+            |state| state.bullets[1].angle = 0.0,
+            |state| state.bullets.truncate(1),
+            |state| state.dead.clear(),
+            |state| state.items[0].kind = ItemKind::Shield,
+            |state| *state.scoreboard.get_mut(&4).unwrap() += 1,
+            |state| {
+                state.team_scoreboard.insert(1, 12);
+            },
+        ];
+        for (index, drift) in drifts.into_iter().enumerate() {
+            let mut state = state();
+            drift(&mut state);
+            assert!(!state.checksum_matches(), "drift {} went unnoticed", index);
+        }
+    }
+}
@@ -0,0 +1,901 @@
+//! A small MessagePack encoding of the wire types, for clients that connect
+//! with `?format=msgpack` and would rather not spend their CPU on parsing
+//! JSON floats. Structs are written as maps keyed by field name, the same
+//! shape as the JSON, so any MessagePack library can read them.
+
+use serde::{
+    de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, Visitor},
+    ser::{self, Serialize},
+};
+use std::fmt;
+
+// How deep arrays, maps and variants may nest in a message, the same limit as
+// serde_json's. Anything deeper is rejected instead of overflowing the stack.
+const MAX_DEPTH: usize = 128;
+
+#[derive(Debug)]
+pub struct Error(String);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error(msg.to_string())
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error(msg.to_string())
+    }
+}
+
+pub fn to_vec<T: Serialize>(value: &T) -> Result<Vec<u8>, Error> {
+    let mut serializer = Serializer { out: Vec::with_capacity(256) };
+    value.serialize(&mut serializer)?;
+    Ok(serializer.out)
+}
+
+pub fn from_slice<T: DeserializeOwned>(input: &[u8]) -> Result<T, Error> {
+    let mut deserializer = Deserializer { input, depth_left: MAX_DEPTH };
+    let value = T::deserialize(&mut deserializer)?;
+    if !deserializer.input.is_empty() {
+        return Err(Error("trailing bytes after the message".into()));
+    }
+    Ok(value)
+}
+
+struct Serializer {
+    out: Vec<u8>,
+}
+
+impl Serializer {
+    fn write_uint(&mut self, v: u64) {
+        if v < 0x80 {
+            self.out.push(v as u8);
+        } else if v <= u64::from(u8::MAX) {
+            self.out.extend_from_slice(&[0xcc, v as u8]);
+        } else if v <= u64::from(u16::MAX) {
+            self.out.push(0xcd);
+            self.out.extend_from_slice(&(v as u16).to_be_bytes());
+        } else if v <= u64::from(u32::MAX) {
+            self.out.push(0xce);
+            self.out.extend_from_slice(&(v as u32).to_be_bytes());
+        } else {
+            self.out.push(0xcf);
+            self.out.extend_from_slice(&v.to_be_bytes());
+        }
+    }
+
+    fn write_int(&mut self, v: i64) {
+        if v >= 0 {
+            self.write_uint(v as u64);
+        } else if v >= -32 {
+            self.out.push(v as i8 as u8);
+        } else if v >= i64::from(i8::MIN) {
+            self.out.extend_from_slice(&[0xd0, v as i8 as u8]);
+        } else if v >= i64::from(i16::MIN) {
+            self.out.push(0xd1);
+            self.out.extend_from_slice(&(v as i16).to_be_bytes());
+        } else if v >= i64::from(i32::MIN) {
+            self.out.push(0xd2);
+            self.out.extend_from_slice(&(v as i32).to_be_bytes());
+        } else {
+            self.out.push(0xd3);
+            self.out.extend_from_slice(&v.to_be_bytes());
+        }
+    }
+
+    /// Writes a length for one of the three families of markers: fixed size
+    /// (if it has one), 8 bits (if it has one), 16 bits and 32 bits.
+    fn write_len(&mut self, len: usize, fixed: Option<(u8, usize)>, markers: [Option<u8>; 3]) -> Result<(), Error> {
+        match fixed {
+            Some((marker, max)) if len < max => self.out.push(marker | len as u8),
+            _ => match markers {
+                [Some(marker), _, _] if len <= usize::from(u8::MAX) => self.out.extend_from_slice(&[marker, len as u8]),
+                [_, Some(marker), _] if len <= usize::from(u16::MAX) => {
+                    self.out.push(marker);
+                    self.out.extend_from_slice(&(len as u16).to_be_bytes());
+                },
+                [_, _, Some(marker)] if len <= u32::MAX as usize => {
+                    self.out.push(marker);
+                    self.out.extend_from_slice(&(len as u32).to_be_bytes());
+                },
+                _ => return Err(Error(format!("{} is too long for MessagePack", len))),
+            },
+        }
+        Ok(())
+    }
+
+    fn write_str(&mut self, v: &str) -> Result<(), Error> {
+        self.write_len(v.len(), Some((0xa0, 32)), [Some(0xd9), Some(0xda), Some(0xdb)])?;
+        self.out.extend_from_slice(v.as_bytes());
+        Ok(())
+    }
+
+    fn write_array_len(&mut self, len: usize) -> Result<(), Error> {
+        self.write_len(len, Some((0x90, 16)), [None, Some(0xdc), Some(0xdd)])
+    }
+
+    fn write_map_len(&mut self, len: usize) -> Result<(), Error> {
+        self.write_len(len, Some((0x80, 16)), [None, Some(0xde), Some(0xdf)])
+    }
+
+    /// Starts an array or map. Without a length up front the items are
+    /// written aside and counted, to be put behind the header at the end.
+    fn begin(&mut self, len: Option<usize>, map: bool) -> Result<Compound<'_>, Error> {
+        match len {
+            Some(len) if map => self.write_map_len(len)?,
+            Some(len) => self.write_array_len(len)?,
+            None => {
+                let outer = std::mem::take(&mut self.out);
+                return Ok(Compound { ser: self, unknown_len: Some((outer, 0, map)) });
+            },
+        }
+        Ok(Compound { ser: self, unknown_len: None })
+    }
+}
+
+struct Compound<'a> {
+    ser: &'a mut Serializer,
+    // What was written before this compound, the items so far and whether it's a map.
+    unknown_len: Option<(Vec<u8>, usize, bool)>,
+}
+
+impl<'a> Compound<'a> {
+    fn item<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        if let Some((_, count, _)) = &mut self.unknown_len {
+            *count += 1;
+        }
+        value.serialize(&mut *self.ser)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        if let Some((outer, count, map)) = self.unknown_len {
+            let items = std::mem::replace(&mut self.ser.out, outer);
+            if map {
+                self.ser.write_map_len(count)?;
+            } else {
+                self.ser.write_array_len(count)?;
+            }
+            self.ser.out.extend_from_slice(&items);
+        }
+        Ok(())
+    }
+}
+
+impl<'a> ser::Serializer for &'a mut Serializer {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = Compound<'a>;
+    type SerializeTuple = Compound<'a>;
+    type SerializeTupleStruct = Compound<'a>;
+    type SerializeTupleVariant = Compound<'a>;
+    type SerializeMap = Compound<'a>;
+    type SerializeStruct = Compound<'a>;
+    type SerializeStructVariant = Compound<'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<(), Error> {
+        self.out.push(if v { 0xc3 } else { 0xc2 });
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), Error> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), Error> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), Error> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), Error> {
+        self.write_int(v);
+        Ok(())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), Error> {
+        self.serialize_u64(u64::from(v))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), Error> {
+        self.serialize_u64(u64::from(v))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), Error> {
+        self.serialize_u64(u64::from(v))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), Error> {
+        self.write_uint(v);
+        Ok(())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), Error> {
+        self.out.push(0xca);
+        self.out.extend_from_slice(&v.to_bits().to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), Error> {
+        self.out.push(0xcb);
+        self.out.extend_from_slice(&v.to_bits().to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), Error> {
+        self.write_str(v.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), Error> {
+        self.write_str(v)
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), Error> {
+        self.write_len(v.len(), None, [Some(0xc4), Some(0xc5), Some(0xc6)])?;
+        self.out.extend_from_slice(v);
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<(), Error> {
+        self.serialize_unit()
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), Error> {
+        self.out.push(0xc0);
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), Error> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(self, _name: &'static str, _index: u32, variant: &'static str) -> Result<(), Error> {
+        self.write_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(self, _name: &'static str, value: &T) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.write_map_len(1)?;
+        self.write_str(variant)?;
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Compound<'a>, Error> {
+        self.begin(len, false)
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Compound<'a>, Error> {
+        self.begin(Some(len), false)
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<Compound<'a>, Error> {
+        self.begin(Some(len), false)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Compound<'a>, Error> {
+        self.write_map_len(1)?;
+        self.write_str(variant)?;
+        self.begin(Some(len), false)
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Compound<'a>, Error> {
+        self.begin(len, true)
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<Compound<'a>, Error> {
+        self.begin(Some(len), true)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Compound<'a>, Error> {
+        self.write_map_len(1)?;
+        self.write_str(variant)?;
+        self.begin(Some(len), true)
+    }
+}
+
+impl<'a> ser::SerializeSeq for Compound<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        self.item(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Compound::end(self)
+    }
+}
+
+impl<'a> ser::SerializeTuple for Compound<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        self.item(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Compound::end(self)
+    }
+}
+
+impl<'a> ser::SerializeTupleStruct for Compound<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        self.item(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Compound::end(self)
+    }
+}
+
+impl<'a> ser::SerializeTupleVariant for Compound<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        self.item(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Compound::end(self)
+    }
+}
+
+impl<'a> ser::SerializeMap for Compound<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    // The count goes up once per entry, on the key.
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<(), Error> {
+        self.item(key)
+    }
+
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut *self.ser)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Compound::end(self)
+    }
+}
+
+impl<'a> ser::SerializeStruct for Compound<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, key: &'static str, value: &T) -> Result<(), Error> {
+        self.item(key)?;
+        value.serialize(&mut *self.ser)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Compound::end(self)
+    }
+}
+
+impl<'a> ser::SerializeStructVariant for Compound<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, key: &'static str, value: &T) -> Result<(), Error> {
+        self.item(key)?;
+        value.serialize(&mut *self.ser)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Compound::end(self)
+    }
+}
+
+struct Deserializer<'de> {
+    input: &'de [u8],
+    depth_left: usize,
+}
+
+impl<'de> Deserializer<'de> {
+    fn peek(&self) -> Result<u8, Error> {
+        self.input.first().cloned().ok_or_else(|| Error("unexpected end of message".into()))
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'de [u8], Error> {
+        if self.input.len() < len {
+            return Err(Error("unexpected end of message".into()));
+        }
+        let (taken, rest) = self.input.split_at(len);
+        self.input = rest;
+        Ok(taken)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn read_u16(&mut self) -> Result<u16, Error> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn read_u32(&mut self) -> Result<u32, Error> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn read_u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from(self.read_u32()?) << 32 | u64::from(self.read_u32()?))
+    }
+
+    fn read_str(&mut self, len: usize) -> Result<&'de str, Error> {
+        std::str::from_utf8(self.take(len)?).map_err(|e| Error(e.to_string()))
+    }
+
+    /// Reads what's inside an array, map or variant one level deeper.
+    fn nested<T>(&mut self, read: impl FnOnce(&mut Self) -> Result<T, Error>) -> Result<T, Error> {
+        if self.depth_left == 0 {
+            return Err(Error(format!("message nested more than {} levels deep", MAX_DEPTH)));
+        }
+        self.depth_left -= 1;
+        let result = read(self);
+        self.depth_left += 1;
+        result
+    }
+}
+
+impl<'de> de::Deserializer<'de> for &mut Deserializer<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let marker = self.read_u8()?;
+        match marker {
+            0x00..=0x7f => visitor.visit_u64(u64::from(marker)),
+            0x80..=0x8f => self.nested(|de| visitor.visit_map(Items { de, left: usize::from(marker & 0x0f) })),
+            0x90..=0x9f => self.nested(|de| visitor.visit_seq(Items { de, left: usize::from(marker & 0x0f) })),
+            0xa0..=0xbf => visitor.visit_borrowed_str(self.read_str(usize::from(marker & 0x1f))?),
+            0xc0 => visitor.visit_unit(),
+            0xc2 => visitor.visit_bool(false),
+            0xc3 => visitor.visit_bool(true),
+            0xc4 => {
+                let len = self.read_u8()?;
+                visitor.visit_borrowed_bytes(self.take(usize::from(len))?)
+            },
+            0xc5 => {
+                let len = self.read_u16()?;
+                visitor.visit_borrowed_bytes(self.take(usize::from(len))?)
+            },
+            0xc6 => {
+                let len = self.read_u32()?;
+                visitor.visit_borrowed_bytes(self.take(len as usize)?)
+            },
+            0xca => visitor.visit_f32(f32::from_bits(self.read_u32()?)),
+            0xcb => visitor.visit_f64(f64::from_bits(self.read_u64()?)),
+            0xcc => visitor.visit_u64(u64::from(self.read_u8()?)),
+            0xcd => visitor.visit_u64(u64::from(self.read_u16()?)),
+            0xce => visitor.visit_u64(u64::from(self.read_u32()?)),
+            0xcf => visitor.visit_u64(self.read_u64()?),
+            0xd0 => visitor.visit_i64(i64::from(self.read_u8()? as i8)),
+            0xd1 => visitor.visit_i64(i64::from(self.read_u16()? as i16)),
+            0xd2 => visitor.visit_i64(i64::from(self.read_u32()? as i32)),
+            0xd3 => visitor.visit_i64(self.read_u64()? as i64),
+            0xd9 => {
+                let len = self.read_u8()?;
+                visitor.visit_borrowed_str(self.read_str(usize::from(len))?)
+            },
+            0xda => {
+                let len = self.read_u16()?;
+                visitor.visit_borrowed_str(self.read_str(usize::from(len))?)
+            },
+            0xdb => {
+                let len = self.read_u32()?;
+                visitor.visit_borrowed_str(self.read_str(len as usize)?)
+            },
+            0xdc => {
+                let left = usize::from(self.read_u16()?);
+                self.nested(|de| visitor.visit_seq(Items { de, left }))
+            },
+            0xdd => {
+                let left = self.read_u32()? as usize;
+                self.nested(|de| visitor.visit_seq(Items { de, left }))
+            },
+            0xde => {
+                let left = usize::from(self.read_u16()?);
+                self.nested(|de| visitor.visit_map(Items { de, left }))
+            },
+            0xdf => {
+                let left = self.read_u32()? as usize;
+                self.nested(|de| visitor.visit_map(Items { de, left }))
+            },
+            0xe0..=0xff => visitor.visit_i64(i64::from(marker as i8)),
+            _ => Err(Error(format!("unsupported MessagePack marker {:#04x}", marker))),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        if self.peek()? == 0xc0 {
+            self.read_u8()?;
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    // Unit variants are written as their name, the others as a map from the
+    // name to their content.
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        match self.peek()? {
+            0x81 => {
+                self.read_u8()?;
+                self.nested(|de| visitor.visit_enum(Variant { de }))
+            },
+            _ => {
+                let variant: String = de::Deserialize::deserialize(&mut *self)?;
+                let variant: de::value::StringDeserializer<Error> = variant.into_deserializer();
+                visitor.visit_enum(variant)
+            },
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+/// The entries of an array or map.
+struct Items<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+    left: usize,
+}
+
+impl<'a, 'de> de::SeqAccess<'de> for Items<'a, 'de> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, Error> {
+        if self.left == 0 {
+            return Ok(None);
+        }
+        self.left -= 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.left)
+    }
+}
+
+impl<'a, 'de> de::MapAccess<'de> for Items<'a, 'de> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, Error> {
+        if self.left == 0 {
+            return Ok(None);
+        }
+        self.left -= 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        seed.deserialize(&mut *self.de)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.left)
+    }
+}
+
+/// A variant written as a map from its name to its content.
+struct Variant<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+}
+
+impl<'a, 'de> de::EnumAccess<'de> for Variant<'a, 'de> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self), Error> {
+        let variant = seed.deserialize(&mut *self.de)?;
+        Ok((variant, self))
+    }
+}
+
+impl<'a, 'de> de::VariantAccess<'de> for Variant<'a, 'de> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        de::Deserialize::deserialize(self.de)
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        seed.deserialize(self.de)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_any(self.de, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(self, _fields: &'static [&'static str], visitor: V) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_any(self.de, visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+    use serde_json::Value;
+    use std::{
+        collections::HashMap,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    fn state() -> GameState {
+        let mut player = PlayerState { x: 120.5, y: -3.25, angle: 1.5, throttle: 0.75, ..PlayerState::new(3) };
+        player.effects = vec![ItemKind::Shield, ItemKind::SpeedBoost];
+        player.team = Some(1);
+        player.weapon = Weapon::Spread;
+
+        let mut state = GameState {
+            tick: 42,
+            time_ms: 1_560_000_000_123,
+            players: vec![player.clone(), PlayerState::new(70_000)],
+            dead: vec![DeadPlayer { respawn: UNIX_EPOCH + Duration::from_millis(1_560_000_004_000), player }],
+            bullets: vec![BulletState { id: 9, player_id: 3, angle: -0.5, x: 1.0, y: 2.0, team: Some(1) }],
+            obstacles: vec![ObstacleState {
+                id: 0,
+                x: 10.0,
+                y: 20.0,
+                width: 30.0,
+                height: 40.0,
+                shape: ObstacleShape::Circle,
+            }],
+            markers: vec![MarkerState { player_id: 3, x: 5.0, y: 6.0 }],
+            items: vec![ItemState { id: 1, kind: ItemKind::RapidFire, x: 7.0, y: 8.0 }],
+            scoreboard: vec![(3, 12), (70_000, 0)].into_iter().collect(),
+            team_scoreboard: vec![(1, 12)].into_iter().collect(),
+            names: vec![(3, "Ace ✈".to_string()), (70_000, String::new())].into_iter().collect(),
+            acks: vec![(3, CommandAck { seq: u32::MAX, tick: 41, dropped: true })].into_iter().collect(),
+            ..GameState::new((8000.0, 8000.0))
+        };
+        state.checksum = state.compute_checksum();
+        state
+    }
+
+    fn server_messages() -> Vec<ServerToClient> {
+        let previous = state();
+        let mut next = state();
+        next.tick += 1;
+        next.players[0].x += 4.0;
+        next.bullets.clear();
+        next.checksum = next.compute_checksum();
+
+        let standings = vec![Standing { id: 3, score: 12 }];
+        let phase = PhaseChange {
+            phase: MatchPhase::Results,
+            remaining_ms: Some(10_000),
+            standings: standings.clone(),
+            team_standings: standings.clone(),
+        };
+
+        vec![
+            ServerToClient::Id(7),
+            ServerToClient::GameState(state()),
+            ServerToClient::TeamNames(vec![(3, "Ace".to_string())].into_iter().collect()),
+            ServerToClient::Emote(EmoteEvent { player_id: 3, emote: 2 }),
+            ServerToClient::Chat(ChatEvent { player_id: 3, scope: ChatScope::Team, text: "go left".into() }),
+            ServerToClient::SpectatorChat(SpectatorChatEvent { name: "Bea".into(), text: "nice".into() }),
+            ServerToClient::QueueStatus(QueueStatus { rating: 1032.5, placement_matches_left: 0, tier: Some(RankTier::Gold) }),
+            ServerToClient::QueueStatus(QueueStatus { rating: 1000.0, placement_matches_left: 5, tier: None }),
+            ServerToClient::Appearances(
+                vec![(3, Appearance { color: Some("#ff0000".into()), team: None })].into_iter().collect(),
+            ),
+            ServerToClient::Throttled(ThrottleNotice { reason: ThrottleReason::RateLimit, retry_after_ms: 120 }),
+            ServerToClient::StateDelta(previous.diff(&next)),
+            ServerToClient::Phase(phase.clone()),
+            ServerToClient::Phase(PhaseChange { phase: MatchPhase::Lobby, remaining_ms: None, standings: vec![], team_standings: vec![] }),
+            ServerToClient::SpectatorInfo(SpectatorInfo {
+                scoreboard: vec![ScoreboardEntry { player_id: 3, name: "Ace".into(), score: 12, kills: 4, deaths: 1, team: Some(1) }],
+                kill_feed: vec![KillFeedEntry {
+                    killer: 3,
+                    killer_name: "Ace".into(),
+                    victim: 4,
+                    victim_name: "Bea".into(),
+                    ago_ms: 1500,
+                }],
+                phase: Some(phase),
+            }),
+            ServerToClient::ShuttingDown,
+            ServerToClient::Waitlist(WaitlistStatus { position: 2, waiting: 5 }),
+            ServerToClient::Events(vec![
+                GameEvent::Kill { killer: 3, victim: 4, x: 1.0, y: 2.0 },
+                GameEvent::Death { player_id: 4, x: 1.0, y: 2.0 },
+                GameEvent::Respawn { player_id: 4, x: 3.0, y: 4.0 },
+                GameEvent::ItemPickup { player_id: 3, item_id: 1, kind: ItemKind::ExtraBullet },
+                GameEvent::RoundStarted,
+                GameEvent::RoundEnded { standings },
+            ]),
+        ]
+    }
+
+    fn client_commands() -> Vec<ClientCommand> {
+        let commands = vec![
+            GameCommand::Rotate(-1.25),
+            GameCommand::Throttle(1.0),
+            GameCommand::Fire,
+            GameCommand::Deflect,
+            GameCommand::Emote(3),
+            GameCommand::Marker(100.0, 200.5),
+            GameCommand::Chat { scope: ChatScope::All, text: "gg".into() },
+            GameCommand::SwitchWeapon(Weapon::Spread),
+        ];
+        commands
+            .into_iter()
+            .flat_map(|cmd| vec![ClientCommand { cmd: cmd.clone(), seq: None }, ClientCommand { cmd, seq: Some(17) }])
+            .collect()
+    }
+
+    fn spectator_commands() -> Vec<SpectatorCommand> {
+        vec![
+            SpectatorCommand::Follow(Some(3)),
+            SpectatorCommand::Follow(None),
+            SpectatorCommand::Region(Some(ViewRegion { x: 1.0, y: 2.0, width: 300.0, height: 400.0 })),
+            SpectatorCommand::Region(None),
+            SpectatorCommand::Minimap(true),
+            SpectatorCommand::Chat("hello".into()),
+        ]
+    }
+
+    /// Decodes what was encoded, compared through JSON since the wire types
+    /// don't all implement `PartialEq`.
+    fn assert_round_trips<T: Serialize + DeserializeOwned>(value: &T) {
+        let bytes = to_vec(value).unwrap();
+        let decoded: T = from_slice(&bytes).unwrap_or_else(|e| panic!("{}: {}", serde_json::to_string(value).unwrap(), e));
+        assert_eq!(serde_json::to_value(&decoded).unwrap(), serde_json::to_value(value).unwrap());
+    }
+
+    #[test]
+    fn server_messages_round_trip() {
+        for msg in &server_messages() {
+            assert_round_trips(msg);
+        }
+    }
+
+    #[test]
+    fn client_commands_round_trip() {
+        for cmd in &client_commands() {
+            assert_round_trips(cmd);
+        }
+        for cmd in &spectator_commands() {
+            assert_round_trips(cmd);
+        }
+    }
+
+    #[test]
+    fn decoded_state_keeps_its_checksum() {
+        let decoded: GameState = from_slice(&to_vec(&state()).unwrap()).unwrap();
+        assert!(decoded.checksum_matches());
+    }
+
+    #[test]
+    fn encodes_numbers_and_lengths_at_every_width() {
+        let ints: Vec<i64> = vec![0, 127, 128, 255, 256, 65_535, 65_536, 1 << 40, -1, -32, -33, -128, -129, -40_000, -(1 << 40)];
+        assert_round_trips(&ints);
+        assert_round_trips(&vec![u64::MAX, u64::from(u32::MAX), 0]);
+        assert_round_trips(&vec![f64::MIN_POSITIVE, -0.5, 1e300]);
+
+        for len in &[0, 15, 16, 31, 32, 255, 256, 65_535, 65_536] {
+            assert_round_trips(&"x".repeat(*len));
+            assert_round_trips(&vec![1u8; *len]);
+            let map: HashMap<u32, bool> = (0..*len as u32).map(|i| (i, i % 2 == 0)).collect();
+            assert_round_trips(&map);
+        }
+    }
+
+    #[test]
+    fn rejects_every_truncation() {
+        for msg in &server_messages() {
+            let bytes = to_vec(msg).unwrap();
+            for len in 0..bytes.len() {
+                assert!(from_slice::<ServerToClient>(&bytes[..len]).is_err(), "accepted {} of {} bytes", len, bytes.len());
+            }
+        }
+        for cmd in &client_commands() {
+            let bytes = to_vec(cmd).unwrap();
+            for len in 0..bytes.len() {
+                assert!(from_slice::<ClientCommand>(&bytes[..len]).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        let fire = to_vec(&ClientCommand { cmd: GameCommand::Fire, seq: Some(1) }).unwrap();
+        let mut trailing = fire.clone();
+        trailing.push(0xc0);
+
+        let malformed: Vec<Vec<u8>> = vec![
+            trailing,
+            // 0xc1 is never used.
+            vec![0xc1],
+            // A string that isn't UTF-8.
+            vec![0x81, 0xa1, b'e', 0xa2, 0xff, 0xfe],
+            // A map claiming far more entries than there are bytes.
+            vec![0xdf, 0xff, 0xff, 0xff, 0xff, 0xa1, b'e'],
+            // An array where a command should be.
+            vec![0x91, 0x01],
+            // An unknown command.
+            vec![0x81, 0xa1, b'e', 0xa4, b'f', b'l', b'y', b'!'],
+            // A number where the throttle's float should be.
+            vec![0x82, 0xa1, b'e', 0xa8, b't', b'h', b'r', b'o', b't', b't', b'l', b'e', 0xa4, b'd', b'a', b't', b'a', 0xa1, b'x'],
+        ];
+        for bytes in &malformed {
+            assert!(from_slice::<ClientCommand>(bytes).is_err(), "accepted {:x?}", bytes);
+        }
+        assert!(from_slice::<ServerToClient>(&[0x81, 0xa1, b'e', 0xc3]).is_err());
+    }
+
+    /// `{e: "fire", seq: 1, x: [[[...]]]}`, the unknown key nested `depth` arrays deep.
+    fn fire_with_nested_key(depth: usize) -> Vec<u8> {
+        let mut bytes = vec![0x83, 0xa1, b'e', 0xa4, b'f', b'i', b'r', b'e', 0xa3, b's', b'e', b'q', 0x01, 0xa1, b'x'];
+        bytes.extend(std::iter::repeat_n(0x91, depth));
+        bytes.push(0xc0);
+        bytes
+    }
+
+    #[test]
+    fn rejects_deeply_nested_messages() {
+        let cmd: ClientCommand = from_slice(&fire_with_nested_key(MAX_DEPTH - 1)).unwrap();
+        assert_eq!(cmd.seq, Some(1));
+
+        assert!(from_slice::<ClientCommand>(&fire_with_nested_key(MAX_DEPTH + 1)).is_err());
+        assert!(from_slice::<ClientCommand>(&fire_with_nested_key(60_000)).is_err());
+        assert!(from_slice::<Value>(&vec![0x91; 60_000]).is_err());
+        assert!(from_slice::<ServerToClient>(&vec![0x81; 60_000]).is_err());
+    }
+}
@@ -0,0 +1,34 @@
+//! Plays a few hundred headless matches between the built-in bots and prints
+//! how each difficulty did. Run it with `cargo run --release --example simulate`.
+
+use std::time::Duration;
+use tokyo_protocol::GameConfig;
+use tokyo_server::{
+    bots::{house_brain, Difficulty},
+    sim::Simulation,
+};
+
+const MATCHES: u64 = 200;
+const MATCH_LENGTH: Duration = Duration::from_secs(120);
+
+fn main() {
+    let difficulties = [Difficulty::Easy, Difficulty::Normal, Difficulty::Hard];
+    let mut totals = [0u64; 3];
+
+    for seed in 0..MATCHES {
+        let config = GameConfig { bound_x: 2000.0, bound_y: 2000.0, ..GameConfig::default() };
+        let mut sim = Simulation::new(config, seed);
+        for (i, difficulty) in difficulties.iter().enumerate() {
+            sim.add_player(house_brain(*difficulty, seed * difficulties.len() as u64 + i as u64));
+        }
+
+        let state = sim.run_for(MATCH_LENGTH);
+        for (id, total) in totals.iter_mut().enumerate() {
+            *total += u64::from(state.scoreboard.get(&(id as u32)).cloned().unwrap_or_default());
+        }
+    }
+
+    for (difficulty, total) in difficulties.iter().zip(&totals) {
+        println!("{:>8}: {:.1} points per match", difficulty.to_string(), *total as f64 / MATCHES as f64);
+    }
+}
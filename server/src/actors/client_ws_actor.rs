@@ -1,72 +1,191 @@
 use crate::{
     actors::GameActor,
-    models::messages::{ClientStop, PlayerGameCommand},
-    AppState,
+    bandwidth::{DeltaEncoder, FrameBudget},
+    models::messages::{ClientStop, ConnectionId, PlayerGameCommand, PlayerLatency},
 };
 use actix::{Actor, ActorContext, Addr, AsyncContext, Handler, StreamHandler};
-use actix_web::ws::{self, CloseCode, CloseReason};
-use ratelimit_meter::{DirectRateLimiter, GCRA};
-use tokyo::models::ServerToClient;
+use actix_web_actors::ws::{self, CloseCode, CloseReason};
+use ratelimit_meter::{DirectRateLimiter, NonConformance, GCRA};
+use std::time::{Duration, Instant};
+use tokyo_protocol::{msgpack, ClientCommand, ServerToClient, ThrottleNotice, ThrottleReason, WireFormat};
 
 const ACTIONS_PER_SECOND: u32 = 22;
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(1);
+
+// How often the connection is pinged to measure its round trip time.
+const PING_INTERVAL: Duration = Duration::from_secs(1);
 
 #[derive(Debug)]
 pub struct ClientWsActor {
     game_addr: Addr<GameActor>,
     api_key: String,
     team_name: String,
+    // The team asked for in the teams mode.
+    team: Option<u32>,
+    region: Option<String>,
+    ranked: bool,
+    conn_id: ConnectionId,
+    // Known once the game actor tells us, only used to label log lines.
+    player_id: Option<u32>,
     rate_limiter: DirectRateLimiter<GCRA>,
+    // When the unanswered ping went out.
+    ping_sent: Option<Instant>,
+    frame_budget: FrameBudget,
+    // Set if the client asked for deltas instead of full game states.
+    deltas: Option<DeltaEncoder>,
+    format: WireFormat,
 }
 
 impl ClientWsActor {
-    pub fn new(game_addr: Addr<GameActor>, api_key: String, team_name: String) -> ClientWsActor {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        game_addr: Addr<GameActor>,
+        api_key: String,
+        team_name: String,
+        team: Option<u32>,
+        region: Option<String>,
+        ranked: bool,
+        deltas: bool,
+        format: WireFormat,
+        conn_id: ConnectionId,
+    ) -> ClientWsActor {
         let rate_limiter = DirectRateLimiter::<GCRA>::per_second(
             std::num::NonZeroU32::new(ACTIONS_PER_SECOND).unwrap(),
         );
 
-        ClientWsActor { game_addr, api_key, team_name, rate_limiter }
+        ClientWsActor {
+            game_addr,
+            api_key,
+            team_name,
+            team,
+            region,
+            ranked,
+            conn_id,
+            player_id: None,
+            rate_limiter,
+            ping_sent: None,
+            frame_budget: FrameBudget::new(crate::APP_CONFIG.snapshot_limits.player),
+            deltas: if deltas { Some(DeltaEncoder::default()) } else { None },
+            format,
+        }
+    }
+
+    fn encode(&self, msg: &ServerToClient) -> Vec<u8> {
+        match self.format {
+            WireFormat::Json => serde_json::to_vec(msg).unwrap(),
+            WireFormat::MsgPack => msgpack::to_vec(msg).unwrap(),
+        }
+    }
+
+    fn write(&self, bytes: Vec<u8>, ctx: &mut <Self as Actor>::Context) {
+        match self.format {
+            WireFormat::Json => ctx.text(String::from_utf8(bytes).expect("JSON is valid UTF-8")),
+            WireFormat::MsgPack => ctx.binary(bytes),
+        }
+    }
+
+    /// Applies the rate limit to a command, telling the client when it's over.
+    fn allow_command(&mut self, ctx: &mut <Self as Actor>::Context) -> bool {
+        match self.rate_limiter.check() {
+            Ok(()) => true,
+            Err(not_until) => {
+                warn!(conn_id = %self.conn_id, key = self.api_key.as_str(), player_id = ?self.player_id, "rate limited");
+                // The limiter reports when its whole one second window
+                // frees up, a single message fits in a window earlier.
+                let retry_after = not_until.wait_time_from(Instant::now() + RATE_LIMIT_WINDOW);
+                let notice = ThrottleNotice::new(ThrottleReason::RateLimit, retry_after);
+                let bytes = self.encode(&ServerToClient::Throttled(notice));
+                self.write(bytes, ctx);
+                false
+            },
+        }
+    }
+
+    fn forward_command(&self, ClientCommand { cmd, seq }: ClientCommand) {
+        self.game_addr.do_send(PlayerGameCommand { api_key: self.api_key.clone(), conn_id: self.conn_id, cmd, seq });
+    }
+
+    /// Sends a game state unless the connection is over its budget, returning
+    /// whether it went out.
+    fn send_frame(&mut self, bytes: Vec<u8>, ctx: &mut <Self as Actor>::Context) -> bool {
+        if !self.frame_budget.allow_frame(bytes.len(), Instant::now()) {
+            trace!(conn_id = %self.conn_id, player_id = ?self.player_id, "skipping a frame, over budget");
+            return false;
+        }
+        self.write(bytes, ctx);
+        true
     }
 }
 
 impl Actor for ClientWsActor {
-    type Context = ws::WebsocketContext<Self, AppState>;
+    type Context = ws::WebsocketContext<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
         self.game_addr.do_send(crate::actors::game_actor::SocketEvent::Join(
             self.api_key.clone(),
             self.team_name.clone(),
+            self.team,
+            self.region.clone(),
+            self.ranked,
+            self.conn_id,
             ctx.address(),
         ));
+
+        ctx.run_interval(PING_INTERVAL, |act, ctx| {
+            if act.ping_sent.is_none() {
+                act.ping_sent = Some(Instant::now());
+                ctx.ping(b"");
+            }
+        });
     }
 
     fn stopped(&mut self, ctx: &mut Self::Context) {
-        info!("API key {} stopped", self.api_key);
+        info!(conn_id = %self.conn_id, key = self.api_key.as_str(), player_id = ?self.player_id, "connection stopped");
         self.game_addr.do_send(crate::actors::game_actor::SocketEvent::Leave(
             self.api_key.clone(),
+            self.conn_id,
             ctx.address(),
         ));
     }
 }
 
-impl StreamHandler<ws::Message, ws::ProtocolError> for ClientWsActor {
-    fn handle(&mut self, msg: ws::Message, ctx: &mut Self::Context) {
-        match msg {
-            ws::Message::Text(cmd) => {
-                if self.rate_limiter.check().is_ok() {
-                    let cmd_result = serde_json::from_str(&cmd);
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ClientWsActor {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        let msg = match msg {
+            Ok(msg) => msg,
+            Err(e) => {
+                warn!(conn_id = %self.conn_id, key = self.api_key.as_str(), player_id = ?self.player_id, "websocket error: {}", e);
+                ctx.stop();
+                return;
+            },
+        };
 
-                    if let Ok(cmd) = cmd_result {
-                        self.game_addr
-                            .do_send(PlayerGameCommand { api_key: self.api_key.clone(), cmd });
-                    }
-                } else {
-                    warn!("API key {} got rate limited", self.api_key);
+        match msg {
+            ws::Message::Text(cmd) if self.allow_command(ctx) => {
+                if let Ok(cmd) = serde_json::from_str(&cmd) {
+                    self.forward_command(cmd);
+                }
+            },
+            // Clients that picked MessagePack send their commands in it too.
+            ws::Message::Binary(cmd) if self.allow_command(ctx) => {
+                if let Ok(cmd) = msgpack::from_slice(&cmd) {
+                    self.forward_command(cmd);
+                }
+            },
+            ws::Message::Pong(_) => {
+                if let Some(ping_sent) = self.ping_sent.take() {
+                    self.game_addr.do_send(PlayerLatency {
+                        api_key: self.api_key.clone(),
+                        conn_id: self.conn_id,
+                        rtt: ping_sent.elapsed(),
+                    });
                 }
             },
             ws::Message::Close(_) => {
-                info!("API key {} close ws", self.api_key);
+                info!(conn_id = %self.conn_id, key = self.api_key.as_str(), player_id = ?self.player_id, "close ws");
                 self.game_addr.do_send(crate::actors::game_actor::SocketEvent::Leave(
                     self.api_key.clone(),
+                    self.conn_id,
                     ctx.address(),
                 ));
                 ctx.stop();
@@ -80,7 +199,36 @@ impl Handler<ServerToClient> for ClientWsActor {
     type Result = ();
 
     fn handle(&mut self, msg: ServerToClient, ctx: &mut Self::Context) {
-        ctx.text(serde_json::to_string(&msg).unwrap());
+        if let ServerToClient::Id(player_id) = msg {
+            self.player_id = Some(player_id);
+        }
+
+        match msg {
+            ServerToClient::GameState(state) => match self.deltas.take() {
+                Some(mut deltas) => {
+                    let msg = deltas.encode(&state);
+                    if self.send_frame(self.encode(&msg), ctx) {
+                        deltas.sent(state, &msg);
+                    }
+                    self.deltas = Some(deltas);
+                },
+                None => {
+                    self.send_frame(self.encode(&ServerToClient::GameState(state)), ctx);
+                },
+            },
+            ServerToClient::ShuttingDown => {
+                self.write(self.encode(&ServerToClient::ShuttingDown), ctx);
+                ctx.close(Some(CloseReason {
+                    code: CloseCode::Away,
+                    description: Some("server shutting down".to_string()),
+                }));
+            },
+            msg => {
+                let bytes = self.encode(&msg);
+                self.frame_budget.spend(bytes.len());
+                self.write(bytes, ctx);
+            },
+        }
     }
 }
 
@@ -90,7 +238,7 @@ impl Handler<ClientStop> for ClientWsActor {
     fn handle(&mut self, _: ClientStop, ctx: &mut Self::Context) {
         ctx.close(Some(CloseReason {
             code: CloseCode::Normal,
-            description: Some("The server decided it didn't like you anymore. Or maybe you connected another client with the same API key".to_string())
+            description: Some(format!("The server decided it didn't like you anymore. Or maybe you connected another client with the same API key (connection {})", self.conn_id))
         }));
     }
 }
@@ -1,54 +1,755 @@
 use crate::{
-    actors::ClientWsActor,
-    game::{Game, TICKS_PER_SECOND},
-    models::messages::{ClientStop, PlayerGameCommand, ServerCommand},
+    actors::{ClientWsActor, SpectatorActor},
+    bots::{Bot, BotConfig},
+    game::{ConfigChange, Game, GameClock, GameDebugState, ObstacleEdit, TICKS_PER_SECOND},
+    load::{LoadLevel, LoadMonitor},
+    models::messages::{
+        ClientInfo, ClientStop, ConnectionId, GetAggregateStats, GetAllHistory, GetGameConfig, GetHistory, GetLeaderboard, GetProfile, ListClients,
+        PlayerGameCommand, PlayerLatency, Restriction, ServerCommand, SpectatorChat, SpectatorLoad, UpdateProfile,
+    },
+    persistence::{
+        history::MatchResult,
+        profiles::Profile,
+        scores::{self, ScoreRecord, ScoreRecords},
+        Stores,
+    },
+    plugins::{KillLog, MatchSummary, MatchTally},
+    ranked,
+    replay::{ReplayHeader, ReplayInput, ReplayRecorder},
+    scripting::ScriptPlugin,
+    snapshot::{self, GameSnapshot, ServerSnapshot},
+    stats,
+    wasm_bots::WasmBrains,
 };
-use actix::{Actor, Addr, AsyncContext, Context, Handler, Message};
-use futures::sync::oneshot;
+use actix::{Actor, ActorContext, Addr, AsyncContext, Context, Handler, Message, MessageResult, ResponseFuture};
+use futures::channel::oneshot;
 use spin_sleep::LoopHelper;
 use std::{
-    collections::{HashMap, HashSet},
-    sync::mpsc::{channel, Receiver, Sender},
+    collections::{HashMap, HashSet, VecDeque},
+    path::PathBuf,
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
-use tokyo::models::*;
+use tokyo_protocol::*;
+
+// How often the game loop hands a snapshot to the actor to be written to disk.
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(5);
+
+// How often the scoreboard is written to disk.
+const SCORE_AUTOSAVE_INTERVAL: Duration = Duration::from_secs(3);
+
+// Restored players whose client hasn't reconnected by then are removed.
+const RESTORE_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+// How many of the latest kills spectators are shown.
+const KILL_FEED_LENGTH: usize = 8;
+
+// How often clients waiting for a slot are reminded of their place in line.
+const WAITLIST_UPDATE_INTERVAL: Duration = Duration::from_secs(5);
+
+// How long a room other than the default one is kept around with nobody in it.
+const ROOM_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// The range of simulation speeds allowed in dev mode.
+pub const MIN_SPEED: f32 = 0.25;
+pub const MAX_SPEED: f32 = 4.0;
 
 #[derive(Debug)]
 pub struct GameActor {
     connections: HashMap<String, Addr<ClientWsActor>>,
-    spectators: HashSet<Addr<ClientWsActor>>,
+    // In the order they joined, so the newest are the first to go under load.
+    spectators: Vec<Addr<SpectatorActor>>,
+    load: LoadMonitor,
     team_names: HashMap<u32, String>,
+    regions: HashMap<u32, String>,
     cancel_chan: Option<oneshot::Sender<()>>,
     msg_tx: Sender<GameLoopCommand>,
     msg_rx: Option<Receiver<GameLoopCommand>>,
     player_id_counter: u32,
+    // The players connected right now.
     api_key_to_player_id: HashMap<String, u32>,
+    // Every API key that has played here, with the player id it gets back
+    // when it reconnects, so its score and name stay with it.
+    player_registry: HashMap<String, u32>,
     game_config: GameConfig,
+    snapshot_path: Option<PathBuf>,
+    restored_game: Option<GameSnapshot>,
+    scores_path: Option<PathBuf>,
+    saved_scores: ScoreRecords,
+    latest_scoreboard: HashMap<u32, u32>,
+    // Each player's team as of the last tick, in the teams mode.
+    latest_teams: HashMap<u32, u32>,
+    // The phase the round is in and when it was entered, when playing rounds.
+    phase: Option<(PhaseChange, Instant)>,
+    replay_dir: Option<PathBuf>,
+    bot_config: BotConfig,
+    bots: Vec<Bot>,
+    // The community brains playing the first bots, when there are any.
+    wasm_brains: Option<WasmBrains>,
+    // When each player last had an emote relayed.
+    last_emotes: HashMap<u32, Instant>,
+    // When each player last had a chat message relayed.
+    last_chats: HashMap<u32, Instant>,
+    // Each player's last measured round trip time.
+    latencies: HashMap<u32, Duration>,
+    // Muted and shadow restricted API keys, shared with the other rooms. Forgotten on restart.
+    restrictions: Arc<RwLock<HashMap<String, Restriction>>>,
+    // Ratings, match history and profiles, shared with the other rooms.
+    stores: Stores,
+    // API keys connected through the ranked queue.
+    ranked: HashSet<String>,
+    // Set for the rooms started on demand, which stop once they're empty.
+    room: Option<String>,
+    empty_at_last_check: bool,
+    // Kills and deaths per player in the current match, for the spectators' scoreboard.
+    match_tallies: HashMap<u32, (u32, u32)>,
+    // The latest kills as killer and victim, newest last, with when they reached the actor.
+    kill_feed: VecDeque<(u32, u32, Instant)>,
+    // Clients that connected while the arena was full, first in line first.
+    waitlist: VecDeque<JoinRequest>,
+    // Set once the server is going down, from then on players are kept in the
+    // game when they disconnect so the last snapshot still has them.
+    shutting_down: bool,
+}
+
+/// A client asking to play, as it connected.
+#[derive(Debug)]
+struct JoinRequest {
+    api_key: String,
+    team_name: String,
+    team: Option<u32>,
+    region: Option<String>,
+    ranked: bool,
+    conn_id: ConnectionId,
+    addr: Addr<ClientWsActor>,
 }
 
 #[derive(Debug)]
 pub enum GameLoopCommand {
-    PlayerJoined(u32),
+    // With the team they asked for, if any.
+    PlayerJoined(u32, Option<u32>),
     PlayerLeft(u32),
-    GameCommand(u32, GameCommand),
+    RestoreScore(u32, u32),
+    GameCommand(u32, GameCommand, Option<u32>),
+    PlayerLatency(u32, Duration),
+    SetSpeed(f32),
+    SetPaused(bool),
+    Step,
+    Inspect(oneshot::Sender<GameDebugState>),
+    EditObstacle(ObstacleEdit, oneshot::Sender<Option<u32>>),
+    Configure(ConfigChange),
     Reset,
+    // Hand the actor a snapshot right away, instead of waiting for the next one.
+    Snapshot,
 }
 
 impl GameActor {
-    pub fn new(config: GameConfig) -> GameActor {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        config: GameConfig,
+        snapshot_path: Option<PathBuf>,
+        restore: Option<ServerSnapshot>,
+        scores_path: Option<PathBuf>,
+        saved_scores: ScoreRecords,
+        replay_dir: Option<PathBuf>,
+        bot_config: BotConfig,
+        stores: Stores,
+        restrictions: Arc<RwLock<HashMap<String, Restriction>>>,
+        room: Option<String>,
+    ) -> GameActor {
         let (msg_tx, msg_rx) = channel();
+        let wasm_brains = bot_config.wasm_dir.as_ref().and_then(|dir| match WasmBrains::load(dir) {
+            Ok(brains) => Some(brains),
+            Err(e) => {
+                error!("{}, only the built-in bots will play", e);
+                None
+            },
+        });
 
-        GameActor {
+        let mut actor = GameActor {
             connections: HashMap::new(),
-            spectators: HashSet::new(),
+            spectators: Vec::new(),
+            load: LoadMonitor::new(),
             team_names: HashMap::new(),
+            regions: HashMap::new(),
             cancel_chan: None,
             msg_tx,
             msg_rx: Some(msg_rx),
             player_id_counter: 0,
             api_key_to_player_id: HashMap::new(),
+            player_registry: HashMap::new(),
             game_config: config,
+            snapshot_path,
+            restored_game: None,
+            scores_path,
+            saved_scores,
+            latest_scoreboard: HashMap::new(),
+            latest_teams: HashMap::new(),
+            phase: None,
+            replay_dir,
+            bot_config,
+            bots: Vec::new(),
+            wasm_brains,
+            last_emotes: HashMap::new(),
+            last_chats: HashMap::new(),
+            latencies: HashMap::new(),
+            restrictions,
+            stores,
+            ranked: HashSet::new(),
+            room,
+            empty_at_last_check: false,
+            match_tallies: HashMap::new(),
+            kill_feed: VecDeque::new(),
+            waitlist: VecDeque::new(),
+            shutting_down: false,
+        };
+
+        if let Some(restore) = restore {
+            actor.player_id_counter = restore.player_id_counter;
+            actor.player_registry = restore.player_registry;
+            actor.player_registry.extend(restore.api_key_to_player_id.iter().map(|(key, id)| (key.clone(), *id)));
+            actor.api_key_to_player_id = restore.api_key_to_player_id;
+            actor.team_names = restore.team_names;
+
+            // The previous run's bots are the players in the game without an
+            // API key, new ones take their place.
+            let state = &restore.game.state;
+            let bot_ids: Vec<u32> = state
+                .players
+                .iter()
+                .chain(state.dead.iter().map(|dead| &dead.player))
+                .map(|player| player.id)
+                .filter(|id| !actor.api_key_to_player_id.values().any(|player_id| player_id == id))
+                .collect();
+            actor.restored_game = Some(restore.game);
+
+            for player_id in bot_ids {
+                actor.team_names.remove(&player_id);
+                actor
+                    .msg_tx
+                    .send(GameLoopCommand::PlayerLeft(player_id))
+                    .expect("The game loop should always be receiving commands");
+            }
+        }
+
+        actor
+    }
+
+    /// Merges the live scoreboard into the saved scores and writes them out.
+    fn autosave_scores(&mut self) {
+        let path = match &self.scores_path {
+            Some(path) => path,
+            None => return,
+        };
+
+        for (api_key, player_id) in &self.api_key_to_player_id {
+            let record = ScoreRecord {
+                name: self.team_names.get(player_id).cloned().unwrap_or_default(),
+                score: self.latest_scoreboard.get(player_id).cloned().unwrap_or_default(),
+                region: self.regions.get(player_id).cloned(),
+            };
+            self.saved_scores.insert(api_key.clone(), record);
+        }
+
+        if let Err(e) = scores::save(path, &self.saved_scores) {
+            error!("Failed to save scores to {:?}: {}", path, e);
+        }
+    }
+
+    /// Rates the match that just ended for the ranked players still around
+    /// and tells them where they stand now. Returns how much each rating changed.
+    fn rate_match(&mut self, summary: &MatchSummary) -> HashMap<String, f32> {
+        let scores: Vec<(String, u32)> = self
+            .ranked
+            .iter()
+            .filter_map(|api_key| {
+                let player_id = self.api_key_to_player_id.get(api_key)?;
+                if !summary.players.contains(player_id) {
+                    return None;
+                }
+                let score = summary.scoreboard.get(player_id).cloned().unwrap_or_default();
+                Some((api_key.clone(), score))
+            })
+            .collect();
+
+        let deltas = {
+            let mut ratings = self.stores.ratings.lock().unwrap();
+            let deltas = ranked::rate_match(&mut ratings.records, &scores);
+            if deltas.is_empty() {
+                return deltas;
+            }
+            if let Err(e) = ratings.save() {
+                error!("Failed to save ratings: {}", e);
+            }
+            deltas
+        };
+        for api_key in deltas.keys() {
+            self.send_queue_status(api_key);
+        }
+
+        deltas
+    }
+
+    fn send_queue_status(&self, api_key: &str) {
+        let status = self.stores.ratings.lock().unwrap().records.get(api_key).cloned().unwrap_or_default().status();
+        if let Some(addr) = self.connections.get(api_key) {
+            addr.do_send(ServerToClient::QueueStatus(status));
         }
     }
+
+    /// Adds or removes bots until there are as many as the config asks for,
+    /// fewer the more people are connected if they only fill the game up. Bots
+    /// are players that don't count as connections.
+    fn balance_bots(&mut self) {
+        let wanted = if self.bot_config.fill {
+            (self.bot_config.count as usize).saturating_sub(self.connections.len())
+        } else {
+            self.bot_config.count as usize
+        };
+        let changed = self.bots.len() != wanted;
+
+        while self.bots.len() < wanted {
+            let player_id = self.player_id_counter;
+            self.player_id_counter += 1;
+            let (name, bot) = self.new_bot(player_id);
+            info!(player_id, name = name.as_str(), "adding bot");
+
+            self.msg_tx
+                .send(GameLoopCommand::PlayerJoined(player_id, None))
+                .expect("The game loop should always be receiving commands");
+            self.team_names.insert(player_id, name);
+            self.bots.push(bot);
+        }
+
+        while self.bots.len() > wanted {
+            let player_id = match self.bots.pop() {
+                Some(bot) => bot.player_id,
+                None => break,
+            };
+            info!(player_id, "removing bot");
+
+            self.msg_tx
+                .send(GameLoopCommand::PlayerLeft(player_id))
+                .expect("The game loop should always be receiving commands");
+            self.team_names.remove(&player_id);
+            self.last_emotes.remove(&player_id);
+        }
+
+        if changed {
+            self.broadcast_team_names();
+        }
+    }
+
+    /// The next bot and its name. The first ones are played by the WASM
+    /// brains, one each, the rest by the built-in brain.
+    fn new_bot(&self, player_id: u32) -> (String, Bot) {
+        let difficulty = self.bot_config.difficulty;
+        let index = self.bots.len();
+        if let Some(brains) = self.wasm_brains.as_ref().filter(|brains| index < brains.len()) {
+            match brains.brain(index) {
+                Ok((name, brain)) => {
+                    let bot = Bot::with_brain(player_id, difficulty.profile().reaction_delay, Box::new(brain));
+                    return (name.to_string(), bot);
+                },
+                Err(e) => error!("{}, a built-in bot plays instead", e),
+            }
+        }
+
+        (format!("Bot {} ({})", index + 1, difficulty), Bot::new(player_id, difficulty))
+    }
+
+    /// Passes an emote on to everyone, unless the player sent one too recently.
+    fn relay_emote(&mut self, player_id: u32, emote: u8) {
+        let now = Instant::now();
+        if emote > MAX_EMOTE {
+            return;
+        }
+        if let Some(last) = self.last_emotes.get(&player_id) {
+            if now - *last < EMOTE_INTERVAL {
+                let notice = ThrottleNotice::new(ThrottleReason::Emote, EMOTE_INTERVAL - (now - *last));
+                self.send_to_player(player_id, ServerToClient::Throttled(notice));
+                return;
+            }
+        }
+        self.last_emotes.insert(player_id, now);
+
+        let event = EmoteEvent { player_id, emote };
+        if self.is_restricted(player_id, ServerToClient::Emote(event)) {
+            return;
+        }
+
+        for addr in self.connections.values() {
+            addr.do_send(ServerToClient::Emote(event));
+        }
+        for addr in &self.spectators {
+            addr.do_send(ServerToClient::Emote(event));
+        }
+    }
+
+    /// Passes a chat message on to whoever it's meant for, unless the player
+    /// is talking too fast.
+    fn relay_chat(&mut self, player_id: u32, scope: ChatScope, mut text: String) {
+        let now = Instant::now();
+        if text.trim().is_empty() {
+            return;
+        }
+        if let Some(last) = self.last_chats.get(&player_id) {
+            if now - *last < CHAT_INTERVAL {
+                let notice = ThrottleNotice::new(ThrottleReason::Chat, CHAT_INTERVAL - (now - *last));
+                self.send_to_player(player_id, ServerToClient::Throttled(notice));
+                return;
+            }
+        }
+        self.last_chats.insert(player_id, now);
+
+        if let Some((cut, _)) = text.char_indices().nth(MAX_CHAT_LENGTH) {
+            text.truncate(cut);
+        }
+        info!(player_id, ?scope, "chat: {}", text);
+
+        let event = ChatEvent { player_id, scope, text };
+        if self.is_restricted(player_id, ServerToClient::Chat(event.clone())) {
+            return;
+        }
+
+        match scope {
+            ChatScope::All => {
+                for addr in self.connections.values() {
+                    addr.do_send(ServerToClient::Chat(event.clone()));
+                }
+                for addr in &self.spectators {
+                    addr.do_send(ServerToClient::Chat(event.clone()));
+                }
+            },
+            ChatScope::Team => {
+                let teammates = self.teammates(player_id);
+                let recipients = self
+                    .api_key_to_player_id
+                    .iter()
+                    .filter(|(_, id)| teammates.contains(id))
+                    .filter_map(|(api_key, _)| self.connections.get(api_key));
+                for addr in recipients {
+                    addr.do_send(ServerToClient::Chat(event.clone()));
+                }
+            },
+        }
+    }
+
+    /// Checks whether what the player said should go no further. Shadow
+    /// restricted players still get their own message back.
+    fn is_restricted(&self, player_id: u32, msg: ServerToClient) -> bool {
+        let restriction =
+            self.api_key_of(player_id).and_then(|api_key| self.restrictions.read().unwrap().get(api_key).copied());
+
+        match restriction {
+            Some(Restriction::Muted) => true,
+            Some(Restriction::Shadow) => {
+                self.send_to_player(player_id, msg);
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn api_key_of(&self, player_id: u32) -> Option<&String> {
+        self.api_key_to_player_id.iter().find(|(_, id)| **id == player_id).map(|(api_key, _)| api_key)
+    }
+
+    /// Sends to the player's connection, if they have one. Bots don't.
+    fn send_to_player(&self, player_id: u32, msg: ServerToClient) {
+        if let Some(addr) = self.api_key_of(player_id).and_then(|api_key| self.connections.get(api_key)) {
+            addr.do_send(msg);
+        }
+    }
+
+    /// Everyone on the player's team, including themselves. Outside the teams
+    /// mode every player is on a team of their own.
+    fn teammates(&self, player_id: u32) -> Vec<u32> {
+        match self.latest_teams.get(&player_id) {
+            Some(team) => {
+                self.latest_teams.iter().filter(|(_, other)| *other == team).map(|(id, _)| *id).collect()
+            },
+            None => vec![player_id],
+        }
+    }
+
+    /// The colors and teams from the profiles of everyone playing.
+    fn appearances(&self) -> HashMap<u32, Appearance> {
+        let profiles = self.stores.profiles.lock().unwrap();
+        self.api_key_to_player_id
+            .iter()
+            .filter_map(|(api_key, player_id)| {
+                let profile = profiles.profiles.get(api_key)?;
+                Some((*player_id, Appearance { color: profile.color.clone(), team: profile.team.clone() }))
+            })
+            .collect()
+    }
+
+    fn broadcast_team_names(&self) {
+        let appearances = self.appearances();
+        for addr in self.connections.values() {
+            addr.do_send(ServerToClient::TeamNames(self.team_names.clone()));
+            addr.do_send(ServerToClient::Appearances(appearances.clone()));
+        }
+        for addr in &self.spectators {
+            addr.do_send(ServerToClient::TeamNames(self.team_names.clone()));
+            addr.do_send(ServerToClient::Appearances(appearances.clone()));
+        }
+    }
+
+    /// The round's phase, with the time left as of now.
+    fn current_phase(&self) -> Option<PhaseChange> {
+        let (change, entered) = self.phase.as_ref()?;
+        let elapsed = entered.elapsed().as_millis() as u64;
+        Some(PhaseChange {
+            remaining_ms: change.remaining_ms.map(|remaining| remaining.saturating_sub(elapsed)),
+            ..change.clone()
+        })
+    }
+
+    /// Sends the spectators the scoreboard with everyone's name, the kill feed
+    /// and the round's phase.
+    fn send_spectator_info(&self) {
+        if self.spectators.is_empty() {
+            return;
+        }
+
+        let name = |player_id: u32| {
+            self.team_names.get(&player_id).cloned().unwrap_or_else(|| format!("player {}", player_id))
+        };
+
+        let player_ids: HashSet<u32> =
+            self.team_names.keys().chain(self.latest_scoreboard.keys()).cloned().collect();
+        let mut scoreboard: Vec<ScoreboardEntry> = player_ids
+            .into_iter()
+            .map(|player_id| {
+                let (kills, deaths) = self.match_tallies.get(&player_id).cloned().unwrap_or_default();
+                ScoreboardEntry {
+                    player_id,
+                    name: name(player_id),
+                    score: self.latest_scoreboard.get(&player_id).cloned().unwrap_or(0),
+                    kills,
+                    deaths,
+                    team: self.latest_teams.get(&player_id).cloned(),
+                }
+            })
+            .collect();
+        scoreboard.sort_by_key(|entry| (std::cmp::Reverse(entry.score), std::cmp::Reverse(entry.kills), entry.player_id));
+
+        let now = Instant::now();
+        let kill_feed = self
+            .kill_feed
+            .iter()
+            .map(|(killer, victim, at)| KillFeedEntry {
+                killer: *killer,
+                killer_name: name(*killer),
+                victim: *victim,
+                victim_name: name(*victim),
+                ago_ms: (now - *at).as_millis() as u64,
+            })
+            .collect();
+
+        let info = SpectatorInfo { scoreboard, kill_feed, phase: self.current_phase() };
+        for addr in &self.spectators {
+            addr.do_send(ServerToClient::SpectatorInfo(info.clone()));
+        }
+    }
+
+    /// Puts a client in the game, taking over the player of an earlier
+    /// connection with the same API key.
+    fn join(&mut self, request: JoinRequest) {
+        let JoinRequest { api_key, team_name, team, region, ranked, conn_id, addr } = request;
+        let key_clone = api_key.clone();
+        let addr_clone = addr.clone();
+
+        info!(
+            %conn_id,
+            key = api_key.as_str(),
+            name = team_name.as_str(),
+            ?region,
+            ranked,
+            "person joined"
+        );
+
+        let existing_client_opt = self.connections.insert(api_key, addr);
+
+        if let Some(existing_client) = existing_client_opt {
+            info!(%conn_id, key = key_clone.as_str(), "kicking out old connection");
+            existing_client.do_send(ClientStop {});
+        }
+
+        let player_id =
+            if let Some(player_id) = self.api_key_to_player_id.get(&key_clone) {
+                addr_clone.do_send(ServerToClient::Id(*player_id));
+                *player_id
+            } else {
+                // Whoever played here before gets their old player ID
+                // back, the first time around this API key gets a new one.
+                let player_id = match self.player_registry.get(&key_clone) {
+                    Some(player_id) => *player_id,
+                    None => {
+                        let player_id = self.player_id_counter;
+                        self.player_id_counter += 1;
+                        self.player_registry.insert(key_clone.clone(), player_id);
+                        player_id
+                    },
+                };
+                info!(
+                    %conn_id,
+                    key = key_clone.as_str(),
+                    player_id,
+                    "assigned player ID"
+                );
+
+                let saved_score =
+                    self.saved_scores.get(&key_clone).map(|record| record.score);
+                self.api_key_to_player_id.insert(key_clone.clone(), player_id);
+
+                self.msg_tx
+                    .send(GameLoopCommand::PlayerJoined(player_id, team))
+                    .expect("The game loop should always be receiving commands");
+
+                if let Some(score) = saved_score {
+                    self.msg_tx
+                        .send(GameLoopCommand::RestoreScore(player_id, score))
+                        .expect("The game loop should always be receiving commands");
+                }
+
+                addr_clone.do_send(ServerToClient::Id(player_id));
+                player_id
+            };
+        if let Some(phase) = self.current_phase() {
+            addr_clone.do_send(ServerToClient::Phase(phase));
+        }
+
+        match region {
+            Some(region) => self.regions.insert(player_id, region),
+            None => self.regions.remove(&player_id),
+        };
+
+        // The name from the profile wins, the one the client sent is
+        // only used the first time around.
+        let existing_name = self.stores.profiles.lock().unwrap().profiles.get(&key_clone).map(|profile| profile.name.clone());
+        let team_name = match existing_name {
+            Some(name) => name,
+            None => {
+                let name: String = team_name.chars().take(MAX_PROFILE_NAME_LENGTH).collect();
+                let profile = Profile { name: name.clone(), ..Profile::default() };
+                self.stores.profiles.lock().unwrap().profiles.insert(key_clone.clone(), profile);
+                self.save_profiles();
+                name
+            },
+        };
+
+        // Update team name and broadcast new team names list to all sockets.
+        self.team_names.insert(player_id, team_name);
+        self.broadcast_team_names();
+
+        if ranked {
+            self.ranked.insert(key_clone.clone());
+            self.send_queue_status(&key_clone);
+        } else {
+            self.ranked.remove(&key_clone);
+        }
+    }
+
+    /// Whether every player slot is taken. Bots don't take one.
+    fn arena_full(&self) -> bool {
+        self.api_key_to_player_id.len() >= self.game_config.max_players
+    }
+
+    fn wait_for_slot(&mut self, request: JoinRequest) {
+        info!(conn_id = %request.conn_id, key = request.api_key.as_str(), "arena full, waiting for a slot");
+
+        // A client reconnecting while it waits keeps its place in line.
+        match self.waitlist.iter_mut().find(|waiting| waiting.api_key == request.api_key) {
+            Some(waiting) => {
+                let old = std::mem::replace(waiting, request);
+                old.addr.do_send(ClientStop {});
+            },
+            None => self.waitlist.push_back(request),
+        }
+        self.send_waitlist_positions();
+    }
+
+    /// Lets waiting clients in, first come first served, while there's room.
+    fn admit_waiting(&mut self) {
+        let mut admitted = false;
+        while !self.arena_full() {
+            let request = match self.waitlist.pop_front() {
+                Some(request) => request,
+                None => break,
+            };
+            info!(conn_id = %request.conn_id, key = request.api_key.as_str(), "slot freed up for waiting client");
+            self.join(request);
+            admitted = true;
+        }
+
+        if admitted {
+            self.send_waitlist_positions();
+            if self.bot_config.fill {
+                self.balance_bots();
+            }
+        }
+    }
+
+    fn send_waitlist_positions(&self) {
+        let waiting = self.waitlist.len() as u32;
+        for (i, request) in self.waitlist.iter().enumerate() {
+            request.addr.do_send(ServerToClient::Waitlist(WaitlistStatus { position: i as u32 + 1, waiting }));
+        }
+    }
+
+    /// Stops a room that has been empty since the last check.
+    fn close_if_idle(&mut self, ctx: &mut Context<Self>) {
+        let empty = self.connections.is_empty() && self.spectators.is_empty() && self.waitlist.is_empty();
+        if empty && self.empty_at_last_check {
+            info!(room = ?self.room, "closing empty room");
+            ctx.stop();
+        }
+        self.empty_at_last_check = empty;
+    }
+
+    /// Shows a changed profile right away if its player is in this room.
+    fn show_profile(&mut self, api_key: &str) {
+        let player_id = match self.api_key_to_player_id.get(api_key) {
+            Some(player_id) => *player_id,
+            None => return,
+        };
+        let name = self.stores.profiles.lock().unwrap().profiles.get(api_key).map(|profile| profile.name.clone());
+        if let Some(name) = name {
+            self.team_names.insert(player_id, name);
+            self.broadcast_team_names();
+        }
+    }
+
+    fn save_profiles(&self) {
+        if let Err(e) = self.stores.profiles.lock().unwrap().save() {
+            error!("Failed to save profiles: {}", e);
+        }
+    }
+
+    /// Drops restored players whose clients never came back.
+    fn expire_restored_players(&mut self) {
+        let connections = &self.connections;
+        let msg_tx = &self.msg_tx;
+
+        self.api_key_to_player_id.retain(|api_key, player_id| {
+            if connections.contains_key(api_key) {
+                return true;
+            }
+
+            info!(key = api_key.as_str(), player_id = *player_id, "restored player never reconnected");
+            msg_tx
+                .send(GameLoopCommand::PlayerLeft(*player_id))
+                .expect("The game loop should always be receiving commands");
+            false
+        });
+        self.admit_waiting();
+    }
 }
 
 fn game_loop(
@@ -56,15 +757,67 @@ fn game_loop(
     msg_chan: Receiver<GameLoopCommand>,
     mut cancel_chan: oneshot::Receiver<()>,
     config: GameConfig,
+    restore: Option<GameSnapshot>,
+    take_snapshots: bool,
+    replay_dir: Option<PathBuf>,
 ) {
     let mut loop_helper = LoopHelper::builder().build_with_target_rate(TICKS_PER_SECOND);
 
-    let mut game = Game::new(config);
+    let seed = rand::random();
+    let restored = restore.is_some();
+    let rules_script = crate::APP_CONFIG.read_rules_script().unwrap_or_else(|e| {
+        error!("Playing without custom rules: {}", e);
+        None
+    });
+    let rules = rules_script.as_ref().and_then(|source| match ScriptPlugin::new(source) {
+        Ok(rules) => Some(rules),
+        Err(e) => {
+            error!("Playing without custom rules: {}", e);
+            None
+        },
+    });
+    let mut game = match restore {
+        Some(snapshot) => Game::restore(config.clone(), snapshot),
+        None => Game::with_seed(config.clone(), seed, GameClock::starting_at(SystemTime::now())),
+    };
+
+    let mut recorder = match replay_dir {
+        // A restored game doesn't start from the seed, so it can't be replayed.
+        Some(_) if restored => {
+            warn!("Not recording a replay of a restored game");
+            None
+        },
+        Some(dir) => start_recording(
+            &dir,
+            ReplayHeader {
+                seed,
+                config,
+                match_info: Some(game.match_info.clone()),
+                rules_script: rules.as_ref().and(rules_script),
+            },
+        ),
+        None => None,
+    };
+    let snapshot_interval_ticks = (SNAPSHOT_INTERVAL.as_secs_f32() * TICKS_PER_SECOND) as u64;
 
+    game.register_plugin(Box::new(KillLog));
+    game.register_plugin(Box::new(MatchTally::new(game_actor.clone().recipient())));
+    if let Some(rules) = rules {
+        game.register_plugin(Box::new(rules));
+    }
     game.init();
 
+    let mut tick_count: u64 = 0;
+    let mut speed: f32 = 1.0;
+    let mut paused = false;
+    let mut steps: u32 = 0;
+    let mut slowest_tick = Duration::default();
+    let mut announced_match = game.match_info.id.clone();
+
     loop {
         loop_helper.loop_start();
+        let tick_started = Instant::now();
+        let _tick_span = info_span!("tick", tick = tick_count + 1).entered();
 
         match cancel_chan.try_recv() {
             Ok(Some(_)) | Err(_) => {
@@ -73,38 +826,165 @@ fn game_loop(
             _ => {},
         }
 
+        let commands_span = info_span!("commands").entered();
         for cmd in msg_chan.try_iter() {
-            // info!("Got a message! - {:?}", cmd);
-            match cmd {
-                GameLoopCommand::PlayerJoined(id) => {
-                    game.add_player(id);
+            trace!("Got a message! - {:?}", cmd);
+            // Everything that changes the simulation goes through a `ReplayInput`
+            // so it can be recorded.
+            let mut ack = None;
+            let input = match cmd {
+                GameLoopCommand::PlayerJoined(id, None) => ReplayInput::Join(id),
+                GameLoopCommand::PlayerJoined(id, Some(team)) => ReplayInput::JoinTeam(id, team),
+                GameLoopCommand::PlayerLeft(id) => ReplayInput::Leave(id),
+                GameLoopCommand::RestoreScore(id, score) => ReplayInput::RestoreScore(id, score),
+                GameLoopCommand::GameCommand(id, cmd, seq) => {
+                    ack = seq.map(|seq| (id, seq));
+                    ReplayInput::Command(id, cmd)
+                },
+                GameLoopCommand::PlayerLatency(id, rtt) => ReplayInput::Latency(id, rtt),
+                GameLoopCommand::Configure(change) => ReplayInput::Configure(change),
+                GameLoopCommand::Reset => ReplayInput::Reset,
+                GameLoopCommand::SetPaused(pause) => {
+                    paused = pause;
+                    steps = 0;
+                    continue;
+                },
+                GameLoopCommand::Step => {
+                    steps += 1;
+                    continue;
                 },
-                GameLoopCommand::PlayerLeft(id) => {
-                    game.player_left(id);
+                GameLoopCommand::Inspect(reply) => {
+                    let _ = reply.send(game.debug_state());
+                    continue;
+                },
+                GameLoopCommand::Snapshot => {
+                    game_actor.do_send(game.snapshot());
+                    continue;
+                },
+                GameLoopCommand::EditObstacle(edit, reply) => {
+                    // Applied right here since the caller wants to know how it went.
+                    if let Some(recorder) = &mut recorder {
+                        recorder.record(&ReplayInput::EditObstacle(edit.clone()));
+                    }
+                    let _ = reply.send(game.edit_obstacle(edit));
+                    continue;
                 },
-                GameLoopCommand::GameCommand(id, cmd) => {
-                    game.handle_cmd(id, cmd);
+                GameLoopCommand::SetSpeed(new_speed) => {
+                    speed = new_speed;
+                    loop_helper.set_target_rate(TICKS_PER_SECOND * speed.max(1.0));
+                    continue;
                 },
-                GameLoopCommand::Reset => {
-                    game.reset();
+            };
+
+            if let Some(recorder) = &mut recorder {
+                recorder.record(&input);
+            }
+            let reset = matches!(input, ReplayInput::Reset);
+            let applied = input.apply(&mut game);
+
+            // The new match gets a fresh id, which a re-simulation has to be told.
+            if reset {
+                if let Some(recorder) = &mut recorder {
+                    recorder.record(&ReplayInput::MatchStarted(game.match_info.clone()));
                 }
             }
+
+            if let Some((id, seq)) = ack {
+                game.ack(id, seq, !applied);
+            }
         }
 
-        let dt = 1.0 / TICKS_PER_SECOND;
+        drop(commands_span);
+
+        let throttled = game.take_throttled();
+        if !throttled.is_empty() {
+            game_actor.do_send(PlayersThrottled(throttled));
+        }
+        let match_id = game.match_info.id.clone();
+
+        // While paused, only tick when asked to. Commands are still applied.
+        if paused {
+            if steps == 0 {
+                loop_helper.loop_sleep();
+                continue;
+            }
+            steps -= 1;
+        }
+
+        tick_count += 1;
+
+        // Slow motion takes smaller steps, fast forward takes more of them so
+        // bullets never get to skip past a player.
+        let dt = speed.min(1.0) / TICKS_PER_SECOND;
         game.tick(dt);
 
+        let phase_changes = game.take_phase_changes();
+        if !phase_changes.is_empty() {
+            game_actor.do_send(PhaseChanged(phase_changes));
+        }
+        // Resets and rounds both start new matches.
+        if game.match_info.id != announced_match {
+            announced_match = game.match_info.id.clone();
+            game_actor.do_send(MatchStarted);
+        }
+        // Rounds start new matches on their own.
+        if game.match_info.id != match_id {
+            if let Some(recorder) = &mut recorder {
+                recorder.record(&ReplayInput::MatchStarted(game.match_info.clone()));
+            }
+        }
+
+        if let Some(rec) = &mut recorder {
+            if let Err(e) = rec.finish_tick(dt, game.state.checksum) {
+                error!("Failed to write replay to {:?}, no longer recording: {}", rec.path(), e);
+                recorder = None;
+            }
+        }
+
         // Send out update packets
 
         // TODO(bschwind) - maybe put the game state behind an Arc
         //                  instead of cloning it
         game_actor.do_send(game.state.clone());
+        let events = game.take_events();
+        if !events.is_empty() {
+            game_actor.do_send(TickEvents(events));
+        }
+
+        if take_snapshots && tick_count.is_multiple_of(snapshot_interval_ticks) {
+            game_actor.do_send(game.snapshot());
+        }
+
+        slowest_tick = slowest_tick.max(tick_started.elapsed());
+        if tick_count.is_multiple_of(TICKS_PER_SECOND as u64) {
+            game_actor.do_send(LoopLoad { slowest_tick, sent_at: Instant::now() });
+            slowest_tick = Duration::default();
+        }
+
         loop_helper.loop_sleep();
     }
 
     info!("game over!");
 }
 
+fn start_recording(dir: &PathBuf, header: ReplayHeader) -> Option<ReplayRecorder> {
+    let started = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let path = dir.join(format!("replay-{}.jsonl", started));
+
+    let recorder = std::fs::create_dir_all(dir)
+        .and_then(|_| ReplayRecorder::create(path.clone(), &header));
+    match recorder {
+        Ok(recorder) => {
+            info!("Recording replay to {:?}", path);
+            Some(recorder)
+        },
+        Err(e) => {
+            error!("Failed to start recording a replay to {:?}: {}", path, e);
+            None
+        },
+    }
+}
+
 impl Actor for GameActor {
     type Context = Context<GameActor>;
 
@@ -117,19 +997,45 @@ impl Actor for GameActor {
         // to the game loop thread
         let msg_rx = self.msg_rx.take().unwrap();
 
-        let config = self.game_config;
+        let config = self.game_config.clone();
+        let restore = self.restored_game.take();
+        let take_snapshots = self.snapshot_path.is_some();
+        let replay_dir = self.replay_dir.clone();
+
+        if restore.is_some() {
+            ctx.run_later(RESTORE_GRACE_PERIOD, |actor, _ctx| actor.expire_restored_players());
+        }
+
+        if self.scores_path.is_some() {
+            ctx.run_interval(SCORE_AUTOSAVE_INTERVAL, |actor, _ctx| actor.autosave_scores());
+        }
+
+        if self.room.is_some() {
+            ctx.run_interval(ROOM_IDLE_TIMEOUT, |actor, ctx| actor.close_if_idle(ctx));
+        }
+
+        ctx.run_interval(WAITLIST_UPDATE_INTERVAL, |actor, _ctx| actor.send_waitlist_positions());
+
+        let spectator_info_rate = crate::APP_CONFIG.spectator_info_rate;
+        if spectator_info_rate > 0.0 {
+            let interval = Duration::from_secs_f32(1.0 / spectator_info_rate);
+            ctx.run_interval(interval, |actor, _ctx| actor.send_spectator_info());
+        }
+
         std::thread::spawn(move || {
-            game_loop(addr, msg_rx, cancel_rx, config);
+            game_loop(addr, msg_rx, cancel_rx, config, restore, take_snapshots, replay_dir);
         });
 
         self.cancel_chan = Some(cancel_tx);
+        self.balance_bots();
     }
 }
 
 #[derive(Debug, Message)]
+#[rtype(result = "()")]
 pub enum SocketEvent {
-    Join(String, String, Addr<ClientWsActor>),
-    Leave(String, Addr<ClientWsActor>),
+    Join(String, String, Option<u32>, Option<String>, bool, ConnectionId, Addr<ClientWsActor>),
+    Leave(String, ConnectionId, Addr<ClientWsActor>),
 }
 
 impl Handler<SocketEvent> for GameActor {
@@ -137,92 +1043,560 @@ impl Handler<SocketEvent> for GameActor {
 
     fn handle(&mut self, msg: SocketEvent, _ctx: &mut Self::Context) {
         match msg {
-            SocketEvent::Join(api_key, team_name, addr) => {
-                let key_clone = api_key.clone();
-                let addr_clone = addr.clone();
-
-                info!("person joined - {:?}", api_key);
-
-                if api_key == "SPECTATOR" {
-                    addr.do_send(ServerToClient::TeamNames(self.team_names.clone()));
-                    self.spectators.insert(addr);
+            SocketEvent::Join(api_key, team_name, team, region, ranked, conn_id, addr) => {
+                let request = JoinRequest { api_key, team_name, team, region, ranked, conn_id, addr };
+                // Someone already playing under this key takes their slot back.
+                if self.api_key_to_player_id.contains_key(&request.api_key) || !self.arena_full() {
+                    self.join(request);
                 } else {
-                    let existing_client_opt = self.connections.insert(api_key, addr);
-
-                    if let Some(existing_client) = existing_client_opt {
-                        info!("kicking out old connection");
-                        existing_client.do_send(ClientStop {});
-                    }
-
-                    let player_id =
-                        if let Some(player_id) = self.api_key_to_player_id.get(&key_clone) {
-                            addr_clone.do_send(ServerToClient::Id(*player_id));
-                            *player_id
-                        } else {
-                            // This was the first time this API key connected,
-                            // assign them a player ID and return it
-                            let player_id = self.player_id_counter;
-                            self.player_id_counter += 1;
-                            info!("API key {} gets player ID {}", key_clone, player_id);
-
-                            self.api_key_to_player_id.insert(key_clone, player_id);
+                    self.wait_for_slot(request);
+                }
+            },
+            SocketEvent::Leave(_, _, addr) if self.shutting_down => {
+                self.connections.retain(|_, client_addr| *client_addr != addr);
+                self.waitlist.retain(|waiting| waiting.addr != addr);
+                return;
+            },
+            SocketEvent::Leave(api_key, conn_id, addr) => {
+                if let Some(client_addr) = self.connections.get(&api_key) {
+                    if addr == *client_addr {
+                        info!(%conn_id, key = api_key.as_str(), "person left");
 
+                        if let Some(player_id) = self.api_key_to_player_id.get(&api_key) {
                             self.msg_tx
-                                .send(GameLoopCommand::PlayerJoined(player_id))
+                                .send(GameLoopCommand::PlayerLeft(*player_id))
                                 .expect("The game loop should always be receiving commands");
+                            self.last_emotes.remove(player_id);
+                            self.last_chats.remove(player_id);
+                            self.latencies.remove(player_id);
+                        }
 
-                            addr_clone.do_send(ServerToClient::Id(player_id));
-                            player_id
-                        };
-
-                    // Update team name and broadcast new team names list to all sockets.
-                    self.team_names.insert(player_id, team_name);
-                    for addr in self.connections.values().chain(self.spectators.iter()) {
-                        addr.do_send(ServerToClient::TeamNames(self.team_names.clone()));
+                        self.api_key_to_player_id.remove(&api_key);
+                        self.connections.remove(&api_key);
+                        self.ranked.remove(&api_key);
+                        self.admit_waiting();
                     }
+                } else if let Some(i) = self.waitlist.iter().position(|waiting| waiting.addr == addr) {
+                    info!(%conn_id, key = api_key.as_str(), "waiting client left");
+                    self.waitlist.remove(i);
+                    self.send_waitlist_positions();
                 }
             },
-            SocketEvent::Leave(api_key, addr) => {
-                if api_key == "SPECTATOR" {
-                    self.spectators.remove(&addr);
-                } else {
-                    if let Some(client_addr) = self.connections.get(&api_key) {
-                        if addr == *client_addr {
-                            info!("person left - {:?}", api_key);
-
-                            if let Some(player_id) = self.api_key_to_player_id.get(&api_key) {
-                                self.msg_tx
-                                    .send(GameLoopCommand::PlayerLeft(*player_id))
-                                    .expect("The game loop should always be receiving commands");
-                            }
-
-                            self.api_key_to_player_id.remove(&api_key);
-                            self.connections.remove(&api_key);
-                        }
-                    }
+        }
+
+        if self.bot_config.fill {
+            self.balance_bots();
+        }
+    }
+}
+
+#[derive(Debug, Message)]
+#[rtype(result = "()")]
+pub enum SpectatorEvent {
+    Join(ConnectionId, Addr<SpectatorActor>),
+    Leave(ConnectionId, Addr<SpectatorActor>),
+}
+
+impl Handler<SpectatorEvent> for GameActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: SpectatorEvent, _ctx: &mut Self::Context) {
+        match msg {
+            SpectatorEvent::Join(conn_id, addr) => {
+                info!(%conn_id, "spectator joined");
+                addr.do_send(ServerToClient::TeamNames(self.team_names.clone()));
+                addr.do_send(ServerToClient::Appearances(self.appearances()));
+                if let Some(phase) = self.current_phase() {
+                    addr.do_send(ServerToClient::Phase(phase));
                 }
+                if self.load.level() != LoadLevel::Normal {
+                    addr.do_send(SpectatorLoad::Degraded);
+                }
+                self.spectators.push(addr);
+            },
+            SpectatorEvent::Leave(conn_id, addr) => {
+                info!(%conn_id, "spectator left");
+                self.spectators.retain(|spectator| *spectator != addr);
             },
         }
     }
 }
 
+impl Handler<MatchSummary> for GameActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: MatchSummary, _ctx: &mut Self::Context) {
+        info!(match_id = msg.info.id.as_str(), players = msg.players.len(), "match ended");
+        let rating_deltas = self.rate_match(&msg);
+        let ended_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+
+        // Opponents are known by API key, bots only have their name.
+        let mut opponent_names: HashMap<u32, String> = self
+            .api_key_to_player_id
+            .iter()
+            .map(|(api_key, player_id)| (*player_id, api_key.clone()))
+            .collect();
+        for bot in &self.bots {
+            if let Some(name) = self.team_names.get(&bot.player_id) {
+                opponent_names.insert(bot.player_id, name.clone());
+            }
+        }
+
+        let mut profiles = self.stores.profiles.lock().unwrap();
+        let mut history = self.stores.history.lock().unwrap();
+        for (api_key, player_id) in &self.api_key_to_player_id {
+            if !msg.players.contains(player_id) {
+                continue;
+            }
+
+            let score = msg.scoreboard.get(player_id).cloned().unwrap_or_default();
+            let ahead = msg
+                .players
+                .iter()
+                .filter(|id| msg.scoreboard.get(id).cloned().unwrap_or_default() > score)
+                .count();
+            let tally = msg.tallies.get(player_id).cloned().unwrap_or_default();
+            let result = MatchResult {
+                match_id: Some(msg.info.id.clone()),
+                ended_at,
+                placement: ahead as u32 + 1,
+                players: msg.players.len() as u32,
+                score,
+                kills: tally.kills,
+                deaths: tally.deaths,
+                rating_delta: rating_deltas.get(api_key).cloned(),
+                season: crate::APP_CONFIG.season.clone(),
+                shots: tally.shots,
+                lives: tally.lives,
+                alive_secs: tally.alive_secs,
+                victims: tally
+                    .victims
+                    .iter()
+                    .filter_map(|(victim, kills)| Some((opponent_names.get(victim)?.clone(), *kills)))
+                    .collect(),
+                name: self.team_names.get(player_id).cloned(),
+                kill_points: tally.kill_points,
+                survival_points: tally.survival_points,
+            };
+
+            if let Some(profile) = profiles.profiles.get_mut(api_key) {
+                profile.totals.matches += 1;
+                profile.totals.wins += if result.placement == 1 { 1 } else { 0 };
+                profile.totals.kills += result.kills;
+                profile.totals.deaths += result.deaths;
+            }
+            history.matches.entry(api_key.clone()).or_default().push(result);
+        }
+
+        if let Err(e) = history.save() {
+            error!("Failed to save match history: {}", e);
+        }
+        if let Err(e) = profiles.save() {
+            error!("Failed to save profiles: {}", e);
+        }
+    }
+}
+
+impl Handler<GetHistory> for GameActor {
+    type Result = MessageResult<GetHistory>;
+
+    fn handle(&mut self, msg: GetHistory, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(self.stores.history.lock().unwrap().matches.get(&msg.0).cloned().unwrap_or_default())
+    }
+}
+
+impl Handler<GetProfile> for GameActor {
+    type Result = MessageResult<GetProfile>;
+
+    fn handle(&mut self, msg: GetProfile, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(self.stores.profiles.lock().unwrap().profiles.get(&msg.0).cloned())
+    }
+}
+
+impl Handler<UpdateProfile> for GameActor {
+    type Result = MessageResult<UpdateProfile>;
+
+    fn handle(&mut self, msg: UpdateProfile, _ctx: &mut Self::Context) -> Self::Result {
+        info!(key = msg.key.as_str(), "updating profile");
+        let profile = {
+            let mut profiles = self.stores.profiles.lock().unwrap();
+            let profile = profiles
+                .profiles
+                .entry(msg.key.clone())
+                .or_insert_with(|| Profile { name: msg.key.clone(), ..Profile::default() });
+
+            if let Some(name) = msg.name {
+                profile.name = name;
+            }
+            if let Some(color) = msg.color {
+                profile.color = color;
+            }
+            if let Some(team) = msg.team {
+                profile.team = team;
+            }
+            profile.clone()
+        };
+        self.save_profiles();
+        self.show_profile(&msg.key);
+
+        MessageResult(profile)
+    }
+}
+
+impl Handler<GetAllHistory> for GameActor {
+    type Result = MessageResult<GetAllHistory>;
+
+    fn handle(&mut self, _msg: GetAllHistory, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(self.stores.history.lock().unwrap().matches.clone())
+    }
+}
+
+impl Handler<GetAggregateStats> for GameActor {
+    type Result = MessageResult<GetAggregateStats>;
+
+    fn handle(&mut self, msg: GetAggregateStats, _ctx: &mut Self::Context) -> Self::Result {
+        let history = self.stores.history.lock().unwrap();
+        MessageResult(stats::aggregate(&history.matches, msg.season.as_ref(), msg.key.as_ref()))
+    }
+}
+
+impl Handler<GetLeaderboard> for GameActor {
+    type Result = MessageResult<GetLeaderboard>;
+
+    fn handle(&mut self, msg: GetLeaderboard, _ctx: &mut Self::Context) -> Self::Result {
+        let names: HashMap<String, String> = self
+            .stores
+            .profiles
+            .lock()
+            .unwrap()
+            .profiles
+            .iter()
+            .map(|(api_key, profile)| (api_key.clone(), profile.name.clone()))
+            .collect();
+        let history = self.stores.history.lock().unwrap();
+        MessageResult(stats::leaderboard(&history.matches, &names, msg.season.as_ref(), msg.limit))
+    }
+}
+
+impl Handler<ListClients> for GameActor {
+    type Result = MessageResult<ListClients>;
+
+    fn handle(&mut self, _msg: ListClients, _ctx: &mut Self::Context) -> Self::Result {
+        let restrictions = self.restrictions.read().unwrap();
+        let mut clients: Vec<ClientInfo> = self
+            .api_key_to_player_id
+            .iter()
+            .filter(|(api_key, _)| self.connections.contains_key(*api_key))
+            .map(|(api_key, player_id)| ClientInfo {
+                room: self.room.clone(),
+                key: api_key.clone(),
+                player_id: *player_id,
+                name: self.team_names.get(player_id).cloned().unwrap_or_default(),
+                region: self.regions.get(player_id).cloned(),
+                ranked: self.ranked.contains(api_key),
+                latency_ms: self.latencies.get(player_id).map(|rtt| rtt.as_millis() as u64),
+                restriction: restrictions.get(api_key).copied(),
+            })
+            .collect();
+        clients.sort_by_key(|client| client.player_id);
+
+        MessageResult(clients)
+    }
+}
+
+impl Handler<GetGameConfig> for GameActor {
+    type Result = MessageResult<GetGameConfig>;
+
+    fn handle(&mut self, _msg: GetGameConfig, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(self.game_config.clone())
+    }
+}
+
+impl Handler<SpectatorChat> for GameActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: SpectatorChat, _ctx: &mut Self::Context) {
+        info!(conn_id = %msg.conn_id, name = msg.name.as_str(), "spectator chat: {}", msg.text);
+
+        let event = SpectatorChatEvent { name: msg.name, text: msg.text };
+        for addr in &self.spectators {
+            addr.do_send(ServerToClient::SpectatorChat(event.clone()));
+        }
+    }
+}
+
 impl Handler<PlayerGameCommand> for GameActor {
     type Result = ();
 
     fn handle(&mut self, msg: PlayerGameCommand, _ctx: &mut Self::Context) {
+        if let Some(&player_id) = self.api_key_to_player_id.get(&msg.api_key) {
+            trace!(conn_id = %msg.conn_id, player_id, seq = ?msg.seq, "command {:?}", msg.cmd);
+            match msg.cmd {
+                GameCommand::Emote(emote) => self.relay_emote(player_id, emote),
+                GameCommand::Chat { scope, text } => self.relay_chat(player_id, scope, text),
+                cmd => {
+                    self.msg_tx
+                        .send(GameLoopCommand::GameCommand(player_id, cmd, msg.seq))
+                        .expect("The game loop should always be receiving commands");
+                },
+            }
+        }
+    }
+}
+
+impl Handler<PlayerLatency> for GameActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: PlayerLatency, _ctx: &mut Self::Context) {
         if let Some(player_id) = self.api_key_to_player_id.get(&msg.api_key) {
+            trace!(conn_id = %msg.conn_id, player_id = *player_id, "rtt {:?}", msg.rtt);
+            self.latencies.insert(*player_id, msg.rtt);
             self.msg_tx
-                .send(GameLoopCommand::GameCommand(*player_id, msg.cmd))
+                .send(GameLoopCommand::PlayerLatency(*player_id, msg.rtt))
                 .expect("The game loop should always be receiving commands");
         }
     }
 }
 
+/// Commands the game dropped for coming too soon, by player.
+#[derive(Debug, Message)]
+#[rtype(result = "()")]
+pub struct PlayersThrottled(Vec<(u32, ThrottleNotice)>);
+
+impl Handler<PlayersThrottled> for GameActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: PlayersThrottled, _ctx: &mut Self::Context) {
+        for (player_id, notice) in msg.0 {
+            self.send_to_player(player_id, ServerToClient::Throttled(notice));
+        }
+    }
+}
+
+/// The phases the round went through on the last tick, oldest first.
+#[derive(Debug, Message)]
+#[rtype(result = "()")]
+pub struct PhaseChanged(Vec<PhaseChange>);
+
+impl Handler<PhaseChanged> for GameActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: PhaseChanged, _ctx: &mut Self::Context) {
+        for change in msg.0 {
+            info!(phase = ?change.phase, remaining_ms = ?change.remaining_ms, "round phase changed");
+            for addr in self.connections.values() {
+                addr.do_send(ServerToClient::Phase(change.clone()));
+            }
+            for addr in &self.spectators {
+                addr.do_send(ServerToClient::Phase(change.clone()));
+            }
+            self.phase = Some((change, Instant::now()));
+        }
+    }
+}
+
+/// A new match started, the tallies start over.
+#[derive(Debug, Message)]
+#[rtype(result = "()")]
+pub struct MatchStarted;
+
+impl Handler<MatchStarted> for GameActor {
+    type Result = ();
+
+    fn handle(&mut self, _msg: MatchStarted, _ctx: &mut Self::Context) {
+        self.match_tallies.clear();
+        self.kill_feed.clear();
+    }
+}
+
+/// What happened during the last tick, sent after its state.
+#[derive(Debug, Message)]
+#[rtype(result = "()")]
+pub struct TickEvents(Vec<GameEvent>);
+
+impl Handler<TickEvents> for GameActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: TickEvents, _ctx: &mut Self::Context) {
+        let now = Instant::now();
+        for event in &msg.0 {
+            match *event {
+                GameEvent::Kill { killer, victim, .. } => {
+                    self.match_tallies.entry(killer).or_default().0 += 1;
+                    self.match_tallies.entry(victim).or_default().1 += 1;
+                    self.kill_feed.push_back((killer, victim, now));
+                },
+                GameEvent::Death { player_id, .. } => self.match_tallies.entry(player_id).or_default().1 += 1,
+                _ => {},
+            }
+        }
+        while self.kill_feed.len() > KILL_FEED_LENGTH {
+            self.kill_feed.pop_front();
+        }
+
+        for addr in self.connections.values() {
+            addr.do_send(ServerToClient::Events(msg.0.clone()));
+        }
+        for addr in &self.spectators {
+            addr.do_send(ServerToClient::Events(msg.0.clone()));
+        }
+    }
+}
+
+/// How the game loop has been keeping up over the last second.
+#[derive(Debug, Message)]
+#[rtype(result = "()")]
+pub struct LoopLoad {
+    slowest_tick: Duration,
+    // How long this took to reach the actor tells how far behind it is.
+    sent_at: Instant,
+}
+
+impl Handler<LoopLoad> for GameActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: LoopLoad, _ctx: &mut Self::Context) {
+        let queue_delay = msg.sent_at.elapsed();
+        let overloaded = crate::APP_CONFIG.load_shedding.is_overloaded(msg.slowest_tick, queue_delay);
+
+        let previous = self.load.level();
+        let level = self.load.report(overloaded);
+        if level != previous {
+            warn!(
+                ?level,
+                slowest_tick = ?msg.slowest_tick,
+                ?queue_delay,
+                "server load changed"
+            );
+            let load = || if level == LoadLevel::Normal { SpectatorLoad::Normal } else { SpectatorLoad::Degraded };
+            for addr in &self.spectators {
+                addr.do_send(load());
+            }
+        }
+
+        // Every second it stays this bad, the newest quarter of the spectators go.
+        if level == LoadLevel::Shedding && !self.spectators.is_empty() {
+            let keep = self.spectators.len() * 3 / 4;
+            info!(spectators = self.spectators.len() - keep, "disconnecting spectators under load");
+            for addr in self.spectators.drain(keep..) {
+                addr.do_send(SpectatorLoad::Busy);
+            }
+        }
+    }
+}
+
+/// Who's in a room, for `/rooms`.
+pub struct GetRoomInfo;
+
+#[derive(Debug, Serialize)]
+pub struct RoomInfo {
+    // Connected players, not counting bots.
+    pub players: usize,
+    pub bots: usize,
+    pub spectators: usize,
+}
+
+impl Message for GetRoomInfo {
+    type Result = RoomInfo;
+}
+
+impl Handler<GetRoomInfo> for GameActor {
+    type Result = MessageResult<GetRoomInfo>;
+
+    fn handle(&mut self, _msg: GetRoomInfo, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(RoomInfo {
+            players: self.connections.len(),
+            bots: self.bots.len(),
+            spectators: self.spectators.len(),
+        })
+    }
+}
+
+/// Asks the game loop for its full internal state.
+pub struct InspectGame;
+
+impl Message for InspectGame {
+    type Result = Result<GameDebugState, ()>;
+}
+
+impl Handler<InspectGame> for GameActor {
+    type Result = ResponseFuture<Result<GameDebugState, ()>>;
+
+    fn handle(&mut self, _msg: InspectGame, _ctx: &mut Self::Context) -> Self::Result {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.msg_tx
+            .send(GameLoopCommand::Inspect(reply_tx))
+            .expect("The game loop should always be receiving commands");
+
+        Box::pin(async move { reply_rx.await.map_err(|_| ()) })
+    }
+}
+
+/// Adds, moves or removes an obstacle, resolving to the id of the obstacle
+/// that was changed, or `None` if it doesn't exist.
+pub struct EditObstacle(pub ObstacleEdit);
+
+impl Message for EditObstacle {
+    type Result = Result<Option<u32>, ()>;
+}
+
+impl Handler<EditObstacle> for GameActor {
+    type Result = ResponseFuture<Result<Option<u32>, ()>>;
+
+    fn handle(&mut self, msg: EditObstacle, _ctx: &mut Self::Context) -> Self::Result {
+        info!("editing obstacles: {:?}", msg.0);
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.msg_tx
+            .send(GameLoopCommand::EditObstacle(msg.0, reply_tx))
+            .expect("The game loop should always be receiving commands");
+
+        Box::pin(async move { reply_rx.await.map_err(|_| ()) })
+    }
+}
+
 impl Handler<GameState> for GameActor {
     type Result = ();
 
-    fn handle(&mut self, msg: GameState, _ctx: &mut Self::Context) {
-        for addr in self.connections.values().chain(self.spectators.iter()) {
+    fn handle(&mut self, mut msg: GameState, _ctx: &mut Self::Context) {
+        let in_game = msg.players.iter().chain(msg.dead.iter().map(|corpse| &corpse.player)).map(|player| player.id);
+        msg.names = msg
+            .scoreboard
+            .keys()
+            .cloned()
+            .chain(in_game)
+            .filter_map(|player_id| Some((player_id, self.team_names.get(&player_id)?.clone())))
+            .collect();
+        self.latest_scoreboard.clone_from(&msg.scoreboard);
+        self.latest_teams = msg
+            .players
+            .iter()
+            .chain(msg.dead.iter().map(|corpse| &corpse.player))
+            .filter_map(|player| player.team.map(|team| (player.id, team)))
+            .collect();
+
+        let now = Instant::now();
+        let mut emotes = vec![];
+        for bot in &mut self.bots {
+            match bot.think(&msg, now) {
+                Some(GameCommand::Emote(emote)) => emotes.push((bot.player_id, emote)),
+                Some(cmd) => {
+                    self.msg_tx
+                        .send(GameLoopCommand::GameCommand(bot.player_id, cmd, None))
+                        .expect("The game loop should always be receiving commands");
+                },
+                None => {},
+            }
+        }
+        for (player_id, emote) in emotes {
+            self.relay_emote(player_id, emote);
+        }
+
+        let _span =
+            info_span!("broadcast", players = self.connections.len(), spectators = self.spectators.len()).entered();
+        for addr in self.connections.values() {
+            addr.do_send(ServerToClient::GameState(msg.clone()));
+        }
+        for addr in &self.spectators {
             addr.do_send(ServerToClient::GameState(msg.clone()));
         }
     }
@@ -234,9 +1608,92 @@ impl Handler<ServerCommand> for GameActor {
     fn handle(&mut self, msg: ServerCommand, _ctx: &mut Self::Context) {
         match msg {
             ServerCommand::Reset => {
+                // A reset starts everyone from zero, including the saved scores.
+                self.saved_scores.clear();
+                self.latest_scoreboard.clear();
                 self.msg_tx
                     .send(GameLoopCommand::Reset)
                     .expect("The game loop should always be receiving commands");
+            },
+            ServerCommand::Kick(api_key) => {
+                // The client's Leave event takes care of the rest of the cleanup.
+                if let Some(addr) = self.connections.get(&api_key) {
+                    info!(key = api_key.as_str(), "kicking");
+                    addr.do_send(ClientStop {});
+                }
+                if let Some(waiting) = self.waitlist.iter().find(|waiting| waiting.api_key == api_key) {
+                    info!(key = api_key.as_str(), "kicking waiting client");
+                    waiting.addr.do_send(ClientStop {});
+                }
+            },
+            ServerCommand::SetPaused(paused) => {
+                info!(paused, "pausing game");
+                self.msg_tx
+                    .send(GameLoopCommand::SetPaused(paused))
+                    .expect("The game loop should always be receiving commands");
+            },
+            ServerCommand::Step => {
+                self.msg_tx
+                    .send(GameLoopCommand::Step)
+                    .expect("The game loop should always be receiving commands");
+            },
+            ServerCommand::SetSpeed(speed) => {
+                info!(speed, "changing game speed");
+                self.msg_tx
+                    .send(GameLoopCommand::SetSpeed(speed))
+                    .expect("The game loop should always be receiving commands");
+            },
+            ServerCommand::Configure(change) => {
+                info!(?change, "changing game config");
+                change.apply_to(&mut self.game_config);
+                self.msg_tx
+                    .send(GameLoopCommand::Configure(change))
+                    .expect("The game loop should always be receiving commands");
+                self.admit_waiting();
+            },
+            ServerCommand::ProfileChanged(api_key) => self.show_profile(&api_key),
+            ServerCommand::Shutdown => {
+                info!(players = self.connections.len(), spectators = self.spectators.len(), "shutting down");
+                self.shutting_down = true;
+                self.autosave_scores();
+                self.save_profiles();
+                if self.snapshot_path.is_some() {
+                    self.msg_tx
+                        .send(GameLoopCommand::Snapshot)
+                        .expect("The game loop should always be receiving commands");
+                }
+                // The sockets close themselves once they've passed this on.
+                for addr in self.connections.values() {
+                    addr.do_send(ServerToClient::ShuttingDown);
+                }
+                for addr in &self.spectators {
+                    addr.do_send(ServerToClient::ShuttingDown);
+                }
+                for waiting in &self.waitlist {
+                    waiting.addr.do_send(ServerToClient::ShuttingDown);
+                }
+            },
+        }
+    }
+}
+
+impl Handler<GameSnapshot> for GameActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: GameSnapshot, _ctx: &mut Self::Context) {
+        if let Some(path) = &self.snapshot_path {
+            let snapshot = ServerSnapshot {
+                game: msg,
+                player_id_counter: self.player_id_counter,
+                api_key_to_player_id: self.api_key_to_player_id.clone(),
+                player_registry: self.player_registry.clone(),
+                team_names: self.team_names.clone(),
+            };
+
+            match snapshot::save(path, &snapshot) {
+                Ok(()) if self.shutting_down => info!(?path, "wrote snapshot before shutting down"),
+                Ok(()) => {},
+                Err(e) => error!("Failed to write snapshot to {:?}: {}", path, e),
             }
         }
     }
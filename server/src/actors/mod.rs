@@ -1,5 +1,13 @@
 pub mod client_ws_actor;
 pub mod game_actor;
+pub mod reload_actor;
+pub mod replay_actor;
+pub mod shutdown_actor;
+pub mod spectator_actor;
 
 pub use client_ws_actor::ClientWsActor;
 pub use game_actor::GameActor;
+pub use reload_actor::ReloadActor;
+pub use replay_actor::ReplayActor;
+pub use shutdown_actor::ShutdownActor;
+pub use spectator_actor::SpectatorActor;
@@ -0,0 +1,113 @@
+use crate::{
+    config::AppConfig,
+    game::ConfigChange,
+    models::messages::{ReloadConfig, Reloaded, ServerCommand},
+    rooms::Rooms,
+    signals::{self, Signal},
+};
+use actix::{Actor, AsyncContext, Context, Handler};
+use serde::Serialize;
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex, RwLock},
+};
+
+/// Reloads `tokyo.toml` on SIGHUP or when asked to by `/admin/reload`. The
+/// API keys and the game config values that can change while the game runs
+/// take effect right away, in every room. The rest waits for a restart.
+pub struct ReloadActor {
+    api_keys: Arc<RwLock<HashSet<String>>>,
+    rooms: Arc<Mutex<Rooms>>,
+}
+
+impl ReloadActor {
+    pub fn new(api_keys: Arc<RwLock<HashSet<String>>>, rooms: Arc<Mutex<Rooms>>) -> Self {
+        ReloadActor { api_keys, rooms }
+    }
+
+    fn reload(&mut self) -> Result<Reloaded, String> {
+        let config = AppConfig::load()?;
+        let restart_required = restart_required(&crate::APP_CONFIG, &config);
+        if !restart_required.is_empty() {
+            warn!(settings = ?restart_required, "reloaded settings only take effect after a restart");
+        }
+
+        // Whoever's key was taken away can't stay in the game.
+        let removed_keys: Vec<String> = {
+            let mut api_keys = self.api_keys.write().unwrap();
+            let removed = api_keys.difference(&config.api_keys).cloned().collect();
+            *api_keys = config.api_keys.clone();
+            removed
+        };
+
+        let mut rooms = self.rooms.lock().unwrap();
+        rooms.set_game_config(config.game_config.clone());
+        for game_addr in rooms.all() {
+            game_addr.do_send(ServerCommand::Configure(ConfigChange::live(&config.game_config)));
+            if !crate::APP_CONFIG.dev_mode {
+                for key in &removed_keys {
+                    game_addr.do_send(ServerCommand::Kick(key.clone()));
+                }
+            }
+        }
+
+        info!(api_keys = config.api_keys.len(), removed_keys = removed_keys.len(), "config reloaded");
+        Ok(Reloaded { api_keys: config.api_keys.len(), game_config: config.game_config, restart_required })
+    }
+}
+
+/// The settings that differ between the running and the reloaded config but
+/// can't be changed without restarting.
+fn restart_required(running: &AppConfig, reloaded: &AppConfig) -> Vec<&'static str> {
+    let (running_game, reloaded_game) = (&running.game_config, &reloaded.game_config);
+
+    let settings: Vec<(&'static str, bool)> = vec![
+        ("server_port", running.server_port != reloaded.server_port),
+        ("bind", running.bind != reloaded.bind),
+        ("dev_mode", running.dev_mode != reloaded.dev_mode),
+        ("admin_key", running.admin_key != reloaded.admin_key),
+        ("spectator_info_rate", running.spectator_info_rate != reloaded.spectator_info_rate),
+        ("rules_script_path", running.rules_script_path != reloaded.rules_script_path),
+        ("bots.wasm_dir", running.bots.wasm_dir != reloaded.bots.wasm_dir),
+        ("game_config.game_mode", running_game.game_mode != reloaded_game.game_mode),
+        ("game_config.teams", running_game.teams != reloaded_game.teams),
+        ("game_config.lag_compensation", running_game.lag_compensation != reloaded_game.lag_compensation),
+        ("game_config.scoring", differs(&running_game.scoring, &reloaded_game.scoring)),
+        ("game_config.item_weights", differs(&running_game.item_weights, &reloaded_game.item_weights)),
+        ("game_config.obstacles", differs(&running_game.obstacles, &reloaded_game.obstacles)),
+        ("game_config.rounds", differs(&running_game.rounds, &reloaded_game.rounds)),
+    ];
+
+    settings.into_iter().filter(|(_, changed)| *changed).map(|(name, _)| name).collect()
+}
+
+fn differs<T: Serialize>(a: &T, b: &T) -> bool {
+    serde_json::to_value(a).ok() != serde_json::to_value(b).ok()
+}
+
+impl Actor for ReloadActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        signals::subscribe(&[Signal::Hup], ctx.address().recipient());
+    }
+}
+
+impl Handler<Signal> for ReloadActor {
+    type Result = ();
+
+    fn handle(&mut self, _signal: Signal, _ctx: &mut Self::Context) {
+        info!("SIGHUP received, reloading the config");
+        if let Err(e) = self.reload() {
+            error!("Kept the running config: {}", e);
+        }
+    }
+}
+
+impl Handler<ReloadConfig> for ReloadActor {
+    type Result = Result<Reloaded, String>;
+
+    fn handle(&mut self, _msg: ReloadConfig, _ctx: &mut Self::Context) -> Self::Result {
+        self.reload()
+    }
+}
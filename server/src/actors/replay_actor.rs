@@ -0,0 +1,65 @@
+use crate::{models::messages::ConnectionId, replay::Playback};
+use actix::{Actor, ActorContext, AsyncContext, StreamHandler};
+use actix_web_actors::ws::{self, CloseCode, CloseReason};
+use std::time::Duration;
+use tokyo_protocol::ServerToClient;
+
+/// Plays a recorded match back to a spectator, re-simulating it from its
+/// inputs at the pace it was played, or `speed` times as fast. The spectator
+/// gets the same events as when watching a live game.
+pub struct ReplayActor {
+    conn_id: ConnectionId,
+    playback: Playback,
+    speed: f32,
+}
+
+impl ReplayActor {
+    pub fn new(conn_id: ConnectionId, playback: Playback, speed: f32) -> Self {
+        ReplayActor { conn_id, playback, speed }
+    }
+
+    fn schedule_next_tick(&mut self, ctx: &mut <Self as Actor>::Context) {
+        match self.playback.next_dt() {
+            Some(dt) => {
+                ctx.run_later(Duration::from_secs_f32(dt / self.speed), |actor, ctx| actor.tick(ctx));
+            },
+            None => {
+                info!(conn_id = %self.conn_id, "replay finished");
+                ctx.close(Some(CloseReason {
+                    code: CloseCode::Normal,
+                    description: Some("The replay is over".to_string()),
+                }));
+                ctx.stop();
+            },
+        }
+    }
+
+    fn tick(&mut self, ctx: &mut <Self as Actor>::Context) {
+        self.playback.step();
+
+        for phase in self.playback.game.take_phase_changes() {
+            ctx.text(serde_json::to_string(&ServerToClient::Phase(phase)).unwrap());
+        }
+        let state = ServerToClient::GameState(self.playback.game.state.clone());
+        ctx.text(serde_json::to_string(&state).unwrap());
+
+        self.schedule_next_tick(ctx);
+    }
+}
+
+impl Actor for ReplayActor {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        info!(conn_id = %self.conn_id, speed = self.speed, "replay started");
+        self.schedule_next_tick(ctx);
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ReplayActor {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        if let Ok(ws::Message::Close(_)) | Err(_) = msg {
+            ctx.stop();
+        }
+    }
+}
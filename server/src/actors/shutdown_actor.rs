@@ -0,0 +1,54 @@
+use crate::{
+    models::messages::ServerCommand,
+    rooms::Rooms,
+    signals::{self, Signal},
+};
+use actix::{Actor, AsyncContext, Context, Handler, System};
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+// How long the rooms get to tell everyone before the process exits.
+const SHUTDOWN_GRACE: Duration = Duration::from_millis(500);
+
+/// Exits on SIGINT, SIGTERM or SIGQUIT, after every room has told its players
+/// and spectators and saved what it keeps on disk. A second signal exits
+/// right away.
+pub struct ShutdownActor {
+    rooms: Arc<Mutex<Rooms>>,
+    shutting_down: bool,
+}
+
+impl ShutdownActor {
+    pub fn new(rooms: Arc<Mutex<Rooms>>) -> Self {
+        ShutdownActor { rooms, shutting_down: false }
+    }
+}
+
+impl Actor for ShutdownActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        signals::subscribe(&[Signal::Int, Signal::Term, Signal::Quit], ctx.address().recipient());
+    }
+}
+
+impl Handler<Signal> for ShutdownActor {
+    type Result = ();
+
+    fn handle(&mut self, signal: Signal, ctx: &mut Self::Context) {
+        if self.shutting_down {
+            warn!("Second stop signal, exiting right away");
+            System::current().stop();
+            return;
+        }
+        self.shutting_down = true;
+
+        info!(?signal, "stop signal received, shutting down");
+        for game_addr in self.rooms.lock().unwrap().all() {
+            game_addr.do_send(ServerCommand::Shutdown);
+        }
+        ctx.run_later(SHUTDOWN_GRACE, |_, _| System::current().stop());
+    }
+}
@@ -0,0 +1,179 @@
+use crate::{
+    actors::{game_actor::SpectatorEvent, GameActor},
+    bandwidth::FrameBudget,
+    models::messages::{ConnectionId, SpectatorChat, SpectatorLoad},
+};
+use actix::{Actor, ActorContext, Addr, AsyncContext, Handler, StreamHandler};
+use actix_web_actors::ws::{self, CloseCode, CloseReason};
+use std::time::Instant;
+use tokyo_protocol::{
+    GameState, ServerToClient, SpectatorCommand, ViewRegion, CHAT_INTERVAL, MAX_CHAT_LENGTH,
+    MAX_SPECTATOR_NAME_LENGTH,
+};
+
+// The size of the area streamed around a followed player.
+const FOLLOW_VIEW_WIDTH: f32 = 2000.0;
+const FOLLOW_VIEW_HEIGHT: f32 = 2000.0;
+
+/// A read-only connection watching the game. Spectators can narrow down what
+/// they're sent with `SpectatorCommand`s, which keeps big arenas cheap to watch.
+#[derive(Debug)]
+pub struct SpectatorActor {
+    game_addr: Addr<GameActor>,
+    conn_id: ConnectionId,
+    follow: Option<u32>,
+    region: Option<ViewRegion>,
+    minimap: bool,
+    // Shown next to this spectator's chat messages.
+    name: String,
+    last_chat: Option<Instant>,
+    frame_budget: FrameBudget,
+    // Set while the server is overloaded, forces the minimap view.
+    degraded: bool,
+}
+
+impl SpectatorActor {
+    pub fn new(
+        game_addr: Addr<GameActor>,
+        conn_id: ConnectionId,
+        follow: Option<u32>,
+        name: Option<String>,
+    ) -> Self {
+        let name = match name.as_ref().map(|name| name.trim()) {
+            Some(name) if !name.is_empty() => name.chars().take(MAX_SPECTATOR_NAME_LENGTH).collect(),
+            _ => format!("spectator-{}", &conn_id.to_string()[..4]),
+        };
+
+        SpectatorActor {
+            game_addr,
+            conn_id,
+            follow,
+            region: None,
+            minimap: false,
+            name,
+            last_chat: None,
+            frame_budget: FrameBudget::new(crate::APP_CONFIG.snapshot_limits.spectator),
+            degraded: false,
+        }
+    }
+
+    fn chat(&mut self, text: String) {
+        let now = Instant::now();
+        if text.trim().is_empty() || self.last_chat.is_some_and(|last| now - last < CHAT_INTERVAL) {
+            return;
+        }
+        self.last_chat = Some(now);
+
+        self.game_addr.do_send(SpectatorChat {
+            conn_id: self.conn_id,
+            name: self.name.clone(),
+            text: text.chars().take(MAX_CHAT_LENGTH).collect(),
+        });
+    }
+
+    /// Cuts the state down to what this spectator asked to see. The scoreboard
+    /// is always sent in full.
+    fn view(&self, mut state: GameState) -> GameState {
+        let followed = self.follow.and_then(|id| state.players.iter().find(|player| player.id == id));
+        let region = match followed {
+            Some(player) => {
+                Some(ViewRegion::centered_on(player.x, player.y, FOLLOW_VIEW_WIDTH, FOLLOW_VIEW_HEIGHT))
+            },
+            None => self.region,
+        };
+
+        if let Some(region) = region {
+            state.players.retain(|player| region.contains(player.x, player.y));
+            state.bullets.retain(|bullet| region.contains(bullet.x, bullet.y));
+            state.markers.retain(|marker| region.contains(marker.x, marker.y));
+            state.items.retain(|item| region.contains(item.x, item.y));
+        }
+
+        if self.minimap || self.degraded {
+            state.bullets.clear();
+            state.dead.clear();
+        }
+
+        state
+    }
+}
+
+impl Actor for SpectatorActor {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.game_addr.do_send(SpectatorEvent::Join(self.conn_id, ctx.address()));
+    }
+
+    fn stopped(&mut self, ctx: &mut Self::Context) {
+        self.game_addr.do_send(SpectatorEvent::Leave(self.conn_id, ctx.address()));
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for SpectatorActor {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match msg {
+            Ok(ws::Message::Text(cmd)) => match serde_json::from_str(&cmd) {
+                Ok(SpectatorCommand::Follow(follow)) => self.follow = follow,
+                Ok(SpectatorCommand::Region(region)) => self.region = region,
+                Ok(SpectatorCommand::Minimap(minimap)) => self.minimap = minimap,
+                Ok(SpectatorCommand::Chat(text)) => self.chat(text),
+                Err(e) => debug!(conn_id = %self.conn_id, "bad spectator command: {}", e),
+            },
+            Ok(ws::Message::Close(_)) | Err(_) => ctx.stop(),
+            _ => {},
+        }
+    }
+}
+
+impl Handler<ServerToClient> for SpectatorActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: ServerToClient, ctx: &mut Self::Context) {
+        match msg {
+            ServerToClient::GameState(state) => {
+                let text = serde_json::to_string(&ServerToClient::GameState(self.view(state))).unwrap();
+                if self.frame_budget.allow_frame(text.len(), Instant::now()) {
+                    ctx.text(text);
+                }
+            },
+            ServerToClient::ShuttingDown => {
+                ctx.text(serde_json::to_string(&ServerToClient::ShuttingDown).unwrap());
+                ctx.close(Some(CloseReason {
+                    code: CloseCode::Away,
+                    description: Some("server shutting down".to_string()),
+                }));
+                ctx.stop();
+            },
+            msg => {
+                let text = serde_json::to_string(&msg).unwrap();
+                self.frame_budget.spend(text.len());
+                ctx.text(text);
+            },
+        }
+    }
+}
+
+impl Handler<SpectatorLoad> for SpectatorActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: SpectatorLoad, ctx: &mut Self::Context) {
+        match msg {
+            SpectatorLoad::Normal => {
+                self.degraded = false;
+                self.frame_budget.limit_rate(None);
+            },
+            SpectatorLoad::Degraded => {
+                self.degraded = true;
+                self.frame_budget.limit_rate(Some(crate::APP_CONFIG.load_shedding.degraded_spectator_rate));
+            },
+            SpectatorLoad::Busy => {
+                ctx.close(Some(CloseReason {
+                    code: CloseCode::Again,
+                    description: Some("server busy".to_string()),
+                }));
+                ctx.stop();
+            },
+        }
+    }
+}
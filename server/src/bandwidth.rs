@@ -0,0 +1,134 @@
+use std::time::{Duration, Instant};
+use tokyo_protocol::{GameState, ServerToClient};
+
+// How many deltas go out between full game states, which get a client that
+// somehow lost track back in sync.
+const KEYFRAME_INTERVAL: u32 = 30;
+
+/// Caps on how many game states each kind of connection is sent, so a
+/// saturated venue uplink slows down the stream instead of the simulation.
+#[derive(Deserialize, Debug, Default)]
+pub struct SnapshotLimits {
+    #[serde(default)]
+    pub player: SnapshotBudget,
+    #[serde(default)]
+    pub spectator: SnapshotBudget,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+pub struct SnapshotBudget {
+    // Game states per second, at most.
+    pub max_rate: Option<f32>,
+    // Bytes per second, at most, averaged over a second.
+    pub max_bytes_per_sec: Option<u32>,
+}
+
+/// Decides which game states a connection gets to stay within its budget.
+/// Everything sent counts against the byte budget, but only game states are
+/// ever skipped, the next one replaces them anyway.
+#[derive(Debug)]
+pub struct FrameBudget {
+    // The configured rate, and the one in force right now.
+    base_interval: Option<Duration>,
+    min_interval: Option<Duration>,
+    max_bytes_per_sec: Option<f32>,
+    last_frame: Option<Instant>,
+    // Bytes that can still go out, refilled over time up to a second's worth.
+    bytes_available: f32,
+    last_refill: Instant,
+}
+
+impl FrameBudget {
+    pub fn new(budget: SnapshotBudget) -> Self {
+        let max_bytes_per_sec = budget.max_bytes_per_sec.map(|bytes| bytes as f32);
+
+        let base_interval = budget.max_rate.and_then(interval);
+        Self {
+            base_interval,
+            min_interval: base_interval,
+            max_bytes_per_sec,
+            last_frame: None,
+            bytes_available: max_bytes_per_sec.unwrap_or(0.0),
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Lowers the rate further for a while, or back to the configured one with `None`.
+    pub fn limit_rate(&mut self, max_rate: Option<f32>) {
+        self.min_interval = self.base_interval.max(max_rate.and_then(interval));
+    }
+
+    /// Whether a game state of this size can go out now. Counts it as sent if so.
+    pub fn allow_frame(&mut self, bytes: usize, now: Instant) -> bool {
+        if let (Some(min_interval), Some(last)) = (self.min_interval, self.last_frame) {
+            if now - last < min_interval {
+                return false;
+            }
+        }
+
+        self.refill(now);
+        // A frame bigger than a whole second's budget still goes out once the
+        // budget is full, or the connection would never get another one.
+        if let Some(max) = self.max_bytes_per_sec {
+            if self.bytes_available < (bytes as f32).min(max) {
+                return false;
+            }
+        }
+
+        self.last_frame = Some(now);
+        self.spend(bytes);
+        true
+    }
+
+    /// Counts a message that isn't skippable against the byte budget.
+    pub fn spend(&mut self, bytes: usize) {
+        if self.max_bytes_per_sec.is_some() {
+            self.bytes_available -= bytes as f32;
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if let Some(max) = self.max_bytes_per_sec {
+            let elapsed = (now - self.last_refill).as_secs_f32();
+            self.bytes_available = (self.bytes_available + elapsed * max).min(max);
+        }
+        self.last_refill = now;
+    }
+}
+
+/// Turns the game states sent to a connection into deltas against the last
+/// one it got, for clients that connected with `?delta=true`.
+#[derive(Debug, Default)]
+pub struct DeltaEncoder {
+    base: Option<GameState>,
+    frames_since_keyframe: u32,
+}
+
+impl DeltaEncoder {
+    pub fn encode(&self, state: &GameState) -> ServerToClient {
+        match &self.base {
+            Some(base) if self.frames_since_keyframe < KEYFRAME_INTERVAL => {
+                ServerToClient::StateDelta(base.diff(state))
+            },
+            _ => ServerToClient::GameState(state.clone()),
+        }
+    }
+
+    /// Remembers what the connection has now that `msg` went out. Skipped
+    /// frames must not be passed in, the next delta would build on them.
+    pub fn sent(&mut self, state: GameState, msg: &ServerToClient) {
+        self.frames_since_keyframe = match msg {
+            ServerToClient::GameState(_) => 0,
+            _ => self.frames_since_keyframe + 1,
+        };
+        self.base = Some(state);
+    }
+}
+
+fn interval(rate: f32) -> Option<Duration> {
+    if rate > 0.0 {
+        Some(Duration::from_secs_f32(1.0 / rate))
+    } else {
+        None
+    }
+}
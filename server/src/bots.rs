@@ -0,0 +1,327 @@
+use rand::{rngs::SmallRng, FromEntropy, Rng, SeedableRng};
+use std::{
+    collections::{HashMap, VecDeque},
+    f32::consts::PI,
+    fmt,
+    path::PathBuf,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use tokyo_protocol::{
+    BulletState, GameCommand, GameState, PlayerState, MIN_COMMAND_INTERVAL, PLAYER_RADIUS,
+};
+
+// Bullets further away than this along their path aren't worth dodging yet.
+const DODGE_LOOKAHEAD: f32 = 300.0;
+
+// Bullets passing closer than this to the bot's center are on a collision course.
+const DODGE_MARGIN: f32 = PLAYER_RADIUS * 3.0;
+
+// Close enough that raising the deflector beats trying to get out of the way.
+const DEFLECT_DISTANCE: f32 = 80.0;
+
+// Close the distance to the target until this far away, then stand and shoot.
+const CHASE_DISTANCE: f32 = 400.0;
+
+// Players further away than this go unnoticed, the bot wanders around instead.
+const SIGHT_DISTANCE: f32 = 1500.0;
+
+// How close to its waypoint a wandering bot has to get before picking the next.
+const WAYPOINT_DISTANCE: f32 = 100.0;
+const WANDER_THROTTLE: f32 = 0.5;
+
+// Fire once the bot is pointing this close to where it wants to aim.
+const AIM_TOLERANCE: f32 = 0.1;
+
+// What the bot shows after taking out its target.
+const TAUNT_EMOTE: u8 = 0;
+
+/// How good the built-in bots are, so the ones filling up a beginner game
+/// don't stomp the people they're there to play with.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Difficulty {
+    Easy,
+    #[default]
+    Normal,
+    Hard,
+}
+
+impl FromStr for Difficulty {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "easy" => Ok(Difficulty::Easy),
+            "normal" => Ok(Difficulty::Normal),
+            "hard" => Ok(Difficulty::Hard),
+            _ => Err(format!("unknown difficulty {:?}, expected easy, normal or hard", s)),
+        }
+    }
+}
+
+impl fmt::Display for Difficulty {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Difficulty::Easy => "easy",
+            Difficulty::Normal => "normal",
+            Difficulty::Hard => "hard",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The knobs behind each difficulty.
+#[derive(Debug, Clone, Copy)]
+pub struct DifficultyProfile {
+    // How long it takes the bot to act on what it sees.
+    pub reaction_delay: Duration,
+    // The most the bot's aim is off by, in radians either way.
+    pub aim_error: f32,
+    // The chance of noticing an incoming bullet and getting out of its way.
+    pub dodge_skill: f32,
+}
+
+impl Difficulty {
+    pub fn profile(self) -> DifficultyProfile {
+        match self {
+            Difficulty::Easy => DifficultyProfile {
+                reaction_delay: Duration::from_millis(600),
+                aim_error: 0.35,
+                dodge_skill: 0.0,
+            },
+            Difficulty::Normal => DifficultyProfile {
+                reaction_delay: Duration::from_millis(300),
+                aim_error: 0.15,
+                dodge_skill: 0.5,
+            },
+            Difficulty::Hard => DifficultyProfile {
+                reaction_delay: Duration::from_millis(100),
+                aim_error: 0.03,
+                dodge_skill: 0.9,
+            },
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct BotConfig {
+    // How many bots to add to the game on startup.
+    #[serde(default)]
+    pub count: u32,
+    #[serde(default)]
+    pub difficulty: Difficulty,
+    // Only fill the game up to `count` players, a bot leaves for every
+    // connected player and comes back when they go.
+    #[serde(default)]
+    pub fill: bool,
+    // WASM brains to play the first bots, see `wasm_bots`.
+    pub wasm_dir: Option<PathBuf>,
+}
+
+/// Decides what a bot does next. Brains are asked at most once per
+/// `MIN_COMMAND_INTERVAL`, the same rate clients may send commands at, and
+/// only while their player is alive.
+pub trait BotBrain: fmt::Debug {
+    fn decide(&mut self, me: &PlayerState, state: &GameState) -> Option<GameCommand>;
+}
+
+/// A server-side player. It looks at every game state the server broadcasts
+/// and asks its brain for commands, which only take effect after its
+/// reaction delay.
+#[derive(Debug)]
+pub struct Bot {
+    pub player_id: u32,
+    reaction_delay: Duration,
+    brain: Box<dyn BotBrain>,
+    last_decision: Option<Instant>,
+    // Decisions waiting out the reaction delay, oldest first.
+    planned: VecDeque<(Instant, GameCommand)>,
+}
+
+impl Bot {
+    pub fn new(player_id: u32, difficulty: Difficulty) -> Self {
+        let reaction_delay = difficulty.profile().reaction_delay;
+        Self::with_brain(player_id, reaction_delay, Box::new(HouseBrain::new(difficulty, SmallRng::from_entropy())))
+    }
+
+    pub fn with_brain(player_id: u32, reaction_delay: Duration, brain: Box<dyn BotBrain>) -> Self {
+        Self { player_id, reaction_delay, brain, last_decision: None, planned: VecDeque::new() }
+    }
+
+    /// Looks at the latest state and returns the command to send now, if any.
+    pub fn think(&mut self, state: &GameState, now: Instant) -> Option<GameCommand> {
+        let me = match state.players.iter().find(|p| p.id == self.player_id) {
+            Some(me) => me,
+            None => {
+                // Dead, whatever we were about to do no longer makes sense.
+                self.planned.clear();
+                return None;
+            },
+        };
+
+        let due = self.last_decision.is_none_or(|last| now - last >= MIN_COMMAND_INTERVAL);
+        if due {
+            self.last_decision = Some(now);
+            if let Some(cmd) = self.brain.decide(me, state) {
+                self.planned.push_back((now + self.reaction_delay, cmd));
+            }
+        }
+
+        match self.planned.front() {
+            Some((ready_at, _)) if *ready_at <= now => self.planned.pop_front().map(|(_, cmd)| cmd),
+            _ => None,
+        }
+    }
+}
+
+/// The built-in brain: chases the nearest player, shoots at it and gets out
+/// of the way of incoming bullets, as well as its difficulty allows. With
+/// nobody in sight it wanders around the arena.
+#[derive(Debug)]
+struct HouseBrain {
+    profile: DifficultyProfile,
+    rng: SmallRng,
+    // Whether the bot noticed each incoming bullet, rolled once per bullet.
+    dodge_rolls: HashMap<u32, bool>,
+    // Who the bot was last shooting at.
+    target: Option<u32>,
+    // Where the bot is headed while there's nobody to chase.
+    waypoint: Option<(f32, f32)>,
+}
+
+impl BotBrain for HouseBrain {
+    fn decide(&mut self, me: &PlayerState, state: &GameState) -> Option<GameCommand> {
+        let bullets: Vec<u32> = state.bullets.iter().map(|b| b.id).collect();
+        self.dodge_rolls.retain(|id, _| bullets.contains(id));
+
+        if let Some(target) = self.target {
+            if state.dead.iter().any(|corpse| corpse.player.id == target) {
+                self.target = None;
+                return Some(GameCommand::Emote(TAUNT_EMOTE));
+            }
+        }
+
+        if let Some(cmd) = self.dodge(me, &state.bullets) {
+            return Some(cmd);
+        }
+
+        let target = state
+            .players
+            .iter()
+            .filter(|p| p.id != me.id && (p.team.is_none() || p.team != me.team))
+            .filter(|p| distance(me, p.x, p.y) <= SIGHT_DISTANCE)
+            .min_by(|a, b| distance(me, a.x, a.y).partial_cmp(&distance(me, b.x, b.y)).unwrap());
+        let target = match target {
+            Some(target) => target,
+            None => return self.wander(me, state.bounds),
+        };
+        self.target = Some(target.id);
+        self.waypoint = None;
+
+        let error = self.profile.aim_error;
+        let aim = (target.y - me.y).atan2(target.x - me.x) + self.rng.gen_range(-error, error);
+        if angle_between(me.angle, aim).abs() > AIM_TOLERANCE {
+            return Some(GameCommand::Rotate(aim));
+        }
+
+        let throttle = if distance(me, target.x, target.y) > CHASE_DISTANCE { 0.6 } else { 0.0 };
+        if (me.throttle - throttle).abs() > 0.01 {
+            return Some(GameCommand::Throttle(throttle));
+        }
+
+        Some(GameCommand::Fire)
+    }
+
+}
+
+/// The built-in brain, rolling its dice from `seed` so it plays the same way
+/// every time, e.g. in a `Simulation`.
+pub fn house_brain(difficulty: Difficulty, seed: u64) -> Box<dyn BotBrain> {
+    Box::new(HouseBrain::new(difficulty, SmallRng::seed_from_u64(seed)))
+}
+
+impl HouseBrain {
+    fn new(difficulty: Difficulty, rng: SmallRng) -> Self {
+        Self {
+            profile: difficulty.profile(),
+            rng,
+            dodge_rolls: HashMap::new(),
+            target: None,
+            waypoint: None,
+        }
+    }
+
+    /// Heads for a random spot in the arena, then the next one once it's there.
+    fn wander(&mut self, me: &PlayerState, bounds: (f32, f32)) -> Option<GameCommand> {
+        let reached = self.waypoint.is_none_or(|(x, y)| distance(me, x, y) < WAYPOINT_DISTANCE);
+        if reached {
+            let margin = PLAYER_RADIUS * 2.0;
+            let x = self.rng.gen_range(margin, (bounds.0 - margin).max(margin + 1.0));
+            let y = self.rng.gen_range(margin, (bounds.1 - margin).max(margin + 1.0));
+            self.waypoint = Some((x, y));
+        }
+
+        let (x, y) = self.waypoint?;
+        let heading = (y - me.y).atan2(x - me.x);
+        if angle_between(me.angle, heading).abs() > AIM_TOLERANCE {
+            Some(GameCommand::Rotate(heading))
+        } else if (me.throttle - WANDER_THROTTLE).abs() > 0.01 {
+            Some(GameCommand::Throttle(WANDER_THROTTLE))
+        } else {
+            None
+        }
+    }
+
+    /// Steers out of the way of the closest bullet headed for the bot, if it
+    /// noticed it at all.
+    fn dodge(&mut self, me: &PlayerState, bullets: &[BulletState]) -> Option<GameCommand> {
+        let (bullet, along) = bullets
+            .iter()
+            .filter(|b| b.player_id != me.id)
+            .filter_map(|b| {
+                let (dx, dy) = (me.x - b.x, me.y - b.y);
+                let (vx, vy) = (b.angle.cos(), b.angle.sin());
+                let along = dx * vx + dy * vy;
+                let across = dx * vy - dy * vx;
+                if along > 0.0 && along < DODGE_LOOKAHEAD && across.abs() < DODGE_MARGIN {
+                    Some((b, along))
+                } else {
+                    None
+                }
+            })
+            .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())?;
+
+        let skill = self.profile.dodge_skill;
+        let rng = &mut self.rng;
+        let noticed = *self.dodge_rolls.entry(bullet.id).or_insert_with(|| rng.gen::<f32>() < skill);
+        if !noticed {
+            return None;
+        }
+
+        if along < DEFLECT_DISTANCE {
+            return Some(GameCommand::Deflect);
+        }
+
+        // Move sideways, away from the side of the bullet's path we're on.
+        let across = (me.x - bullet.x) * bullet.angle.sin() - (me.y - bullet.y) * bullet.angle.cos();
+        let escape = if across >= 0.0 { bullet.angle - PI / 2.0 } else { bullet.angle + PI / 2.0 };
+
+        if angle_between(me.angle, escape).abs() > 0.3 {
+            Some(GameCommand::Rotate(escape))
+        } else if me.throttle < 1.0 {
+            Some(GameCommand::Throttle(1.0))
+        } else {
+            None
+        }
+    }
+}
+
+fn distance(me: &PlayerState, x: f32, y: f32) -> f32 {
+    ((x - me.x).powi(2) + (y - me.y).powi(2)).sqrt()
+}
+
+/// The signed difference from one angle to another, between -PI and PI.
+fn angle_between(from: f32, to: f32) -> f32 {
+    (to - from + PI).rem_euclid(2.0 * PI) - PI
+}
@@ -0,0 +1,188 @@
+use std::ops::{BitOr, BitOrAssign};
+
+/// A set of collision layers. Every entity sits on some layers and has a mask
+/// of the layers it can collide with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Layers(u32);
+
+// The first 8 bits are entity kinds, the rest are free for team layers.
+const TEAM_LAYER_OFFSET: u32 = 8;
+pub const MAX_TEAMS: u32 = 32 - TEAM_LAYER_OFFSET;
+
+impl Layers {
+    pub const NONE: Layers = Layers(0);
+    pub const PLAYER: Layers = Layers(1 << 0);
+    pub const BULLET: Layers = Layers(1 << 1);
+    pub const OBSTACLE: Layers = Layers(1 << 2);
+    pub const PICKUP: Layers = Layers(1 << 3);
+    pub const ALL: Layers = Layers(!0);
+
+    /// The layer shared by all members of a team.
+    pub fn team(team: u32) -> Layers {
+        assert!(team < MAX_TEAMS, "Only {} teams fit in the collision layers", MAX_TEAMS);
+        Layers(1 << (TEAM_LAYER_OFFSET + team))
+    }
+
+    pub fn intersects(self, other: Layers) -> bool {
+        self.0 & other.0 != 0
+    }
+}
+
+impl BitOr for Layers {
+    type Output = Layers;
+
+    fn bitor(self, other: Layers) -> Layers {
+        Layers(self.0 | other.0)
+    }
+}
+
+impl BitOrAssign for Layers {
+    fn bitor_assign(&mut self, other: Layers) {
+        self.0 |= other.0;
+    }
+}
+
+/// Describes what an entity is and what it's allowed to touch. Ghosts,
+/// invulnerability and friendly fire are all just different filters.
+#[derive(Debug, Clone, Copy)]
+pub struct CollisionFilter {
+    // The layers this entity is on.
+    pub layers: Layers,
+    // The layers this entity collides with.
+    pub mask: Layers,
+    // Layers this entity never collides with, even if they're in the mask.
+    pub ignore: Layers,
+    // The player this entity belongs to (a player owns itself).
+    pub owner: Option<u32>,
+    // Don't collide with the owning player, so bullets can't hit their shooter.
+    pub passes_through_owner: bool,
+    // Don't collide with anything else of the same owner on the same layers,
+    // so a spread shot doesn't cancel itself out.
+    pub passes_through_siblings: bool,
+}
+
+impl CollisionFilter {
+    pub fn new(layers: Layers, mask: Layers) -> CollisionFilter {
+        CollisionFilter {
+            layers,
+            mask,
+            ignore: Layers::NONE,
+            owner: None,
+            passes_through_owner: false,
+            passes_through_siblings: false,
+        }
+    }
+
+    pub fn owned_by(self, owner: u32) -> CollisionFilter {
+        CollisionFilter { owner: Some(owner), ..self }
+    }
+
+    pub fn ignoring(self, ignore: Layers) -> CollisionFilter {
+        CollisionFilter { ignore: self.ignore | ignore, ..self }
+    }
+
+    pub fn passing_through_owner(self) -> CollisionFilter {
+        CollisionFilter { passes_through_owner: true, ..self }
+    }
+
+    pub fn passing_through_siblings(self) -> CollisionFilter {
+        CollisionFilter { passes_through_siblings: true, ..self }
+    }
+
+    /// Whether the two entities interact at all, regardless of where they are.
+    pub fn can_collide(&self, other: &CollisionFilter) -> bool {
+        self.mask.intersects(other.layers)
+            && other.mask.intersects(self.layers)
+            && !self.ignore.intersects(other.layers)
+            && !other.ignore.intersects(self.layers)
+            && !self.passes_through(other)
+            && !other.passes_through(self)
+    }
+
+    fn passes_through(&self, other: &CollisionFilter) -> bool {
+        if self.owner.is_none() || self.owner != other.owner {
+            return false;
+        }
+        (self.passes_through_owner && other.layers.intersects(Layers::PLAYER))
+            || (self.passes_through_siblings && other.layers.intersects(self.layers))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn player(id: u32, team: Option<u32>) -> CollisionFilter {
+        let layers = team.map_or(Layers::PLAYER, |team| Layers::PLAYER | Layers::team(team));
+        CollisionFilter::new(layers, Layers::ALL).owned_by(id)
+    }
+
+    fn bullet(owner: u32, team: Option<u32>) -> CollisionFilter {
+        let filter = CollisionFilter::new(Layers::BULLET, Layers::PLAYER | Layers::BULLET | Layers::OBSTACLE)
+            .owned_by(owner)
+            .passing_through_owner()
+            .passing_through_siblings();
+        match team {
+            Some(team) => filter.ignoring(Layers::team(team)),
+            None => filter,
+        }
+    }
+
+    fn filters() -> Vec<CollisionFilter> {
+        vec![
+            player(1, None),
+            player(2, Some(0)),
+            player(3, Some(0)),
+            player(4, Some(1)),
+            bullet(1, None),
+            bullet(2, Some(0)),
+            bullet(3, Some(0)),
+            bullet(4, Some(1)),
+            CollisionFilter::new(Layers::OBSTACLE, Layers::PLAYER | Layers::BULLET),
+            CollisionFilter::new(Layers::PICKUP, Layers::PLAYER),
+            CollisionFilter::new(Layers::PLAYER, Layers::NONE),
+        ]
+    }
+
+    #[test]
+    fn can_collide_is_symmetric() {
+        for a in filters() {
+            for b in filters() {
+                assert_eq!(a.can_collide(&b), b.can_collide(&a), "{:?} vs {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn both_masks_have_to_match() {
+        let pickup = CollisionFilter::new(Layers::PICKUP, Layers::PLAYER);
+        let ghost = CollisionFilter::new(Layers::PLAYER, Layers::NONE);
+        assert!(pickup.can_collide(&player(1, None)));
+        assert!(!pickup.can_collide(&ghost));
+        assert!(!pickup.can_collide(&bullet(1, None)));
+    }
+
+    #[test]
+    fn bullets_spare_their_shooter_siblings_and_team() {
+        assert!(!bullet(1, None).can_collide(&player(1, None)));
+        assert!(!bullet(1, None).can_collide(&bullet(1, None)));
+        assert!(bullet(1, None).can_collide(&player(2, Some(0))));
+        assert!(bullet(1, None).can_collide(&bullet(2, Some(0))));
+
+        assert!(!bullet(2, Some(0)).can_collide(&player(3, Some(0))));
+        assert!(bullet(2, Some(0)).can_collide(&player(4, Some(1))));
+        assert!(bullet(2, Some(0)).can_collide(&bullet(3, Some(0))));
+    }
+
+    #[test]
+    fn players_own_themselves_but_collide_with_each_other() {
+        assert!(player(1, None).can_collide(&player(2, Some(0))));
+        assert!(player(2, Some(0)).can_collide(&player(3, Some(0))));
+    }
+
+    #[test]
+    #[should_panic]
+    fn team_layers_are_limited() {
+        Layers::team(MAX_TEAMS);
+    }
+}
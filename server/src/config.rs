@@ -0,0 +1,267 @@
+use crate::{
+    bandwidth::{SnapshotBudget, SnapshotLimits},
+    bots::BotConfig,
+    game::ConfigChange,
+    load::LoadSheddingConfig,
+    regions::RegionConfig,
+};
+use std::{
+    collections::HashSet,
+    env, io,
+    net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+};
+use tokyo_protocol::{GameConfig, RoundConfig, WeaponConfig};
+
+const CONFIG_FILE_PATH: &str = "tokyo.toml";
+
+#[derive(Deserialize, Debug)]
+pub struct AppConfig {
+    pub server_port: Option<u16>,
+    // Addresses to listen on, e.g. ["0.0.0.0:8080", "[::]:8080"]. Defaults to
+    // all IPv4 interfaces on `server_port`.
+    #[serde(default)]
+    pub bind: Vec<SocketAddr>,
+    // Also serve on this Unix domain socket, e.g. behind nginx on the same host.
+    pub unix_socket: Option<PathBuf>,
+    pub api_keys: HashSet<String>,
+    pub dev_mode: bool,
+    // Required for /reset and the /admin endpoints, as a bearer token or the
+    // basic auth password. Without it they are only open in dev mode.
+    pub admin_key: Option<String>,
+    // Signs the links minted by /admin/spectate_link, falls back to `admin_key`.
+    pub spectate_link_secret: Option<String>,
+    // Only let spectators in through a signed link.
+    #[serde(default)]
+    pub signed_spectate_only: bool,
+    #[serde(default)]
+    pub json_logs: bool,
+    // Where to periodically write the full game state. Snapshots are off if unset.
+    pub snapshot_path: Option<PathBuf>,
+    // Resume from the snapshot on startup when there is one, like `--restore`.
+    #[serde(default)]
+    pub restore_snapshot: bool,
+    // Where to autosave scores per API key. They are merged back in on startup.
+    pub scores_path: Option<PathBuf>,
+    // Where banned keys and IPs are kept. Bans only last until a restart if unset.
+    pub ban_list_path: Option<PathBuf>,
+    // Where ranked players' ratings are kept. They only last until a restart if unset.
+    pub ratings_path: Option<PathBuf>,
+    // Where player profiles are kept. They only last until a restart if unset.
+    pub profiles_path: Option<PathBuf>,
+    // Recorded with every match result, so stats can be narrowed down to one
+    // season, e.g. "2019-spring".
+    pub season: Option<String>,
+    // Where everyone's past match results are kept, for /history.
+    pub history_path: Option<PathBuf>,
+    // Directory to record every game's inputs to, for `verify-replay`.
+    pub replay_dir: Option<PathBuf>,
+    // A rhai script with custom game rules, see `tokyo_server::scripting`.
+    pub rules_script_path: Option<PathBuf>,
+    // Let clients on the local network find the server with `tokyo::discover()`.
+    #[serde(default)]
+    pub lan_discovery: bool,
+    // Labels connections by location for the per-player stats.
+    #[serde(default)]
+    pub regions: RegionConfig,
+    // Server-side players to fill up the game with.
+    #[serde(default)]
+    pub bots: BotConfig,
+    // How many game states players and spectators are sent, at most.
+    #[serde(default)]
+    pub snapshot_limits: SnapshotLimits,
+    // How many rooms can be started with `?room=`, besides the default one.
+    #[serde(default = "default_max_rooms")]
+    pub max_rooms: usize,
+    // When to start degrading spectators to keep the game smooth for players.
+    #[serde(default)]
+    pub load_shedding: LoadSheddingConfig,
+    // How many times a second spectators get the scoreboard and kill feed, 0 turns it off.
+    #[serde(default = "default_spectator_info_rate")]
+    pub spectator_info_rate: f32,
+    pub game_config: GameConfig,
+}
+
+impl AppConfig {
+    /// Reads `tokyo.toml`, or falls back to environment variables and defaults
+    /// when there is no config file (e.g. in a container).
+    pub fn load() -> Result<AppConfig, String> {
+        let config = match std::fs::read(CONFIG_FILE_PATH) {
+            Ok(config) => toml::from_slice(&config)
+                .map_err(|e| format!("Failed to parse {}: {}", CONFIG_FILE_PATH, e))?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                println!("No {} found, configuring from the environment", CONFIG_FILE_PATH);
+                AppConfig::from_env()?
+            },
+            Err(e) => return Err(format!("Failed to read {}: {}", CONFIG_FILE_PATH, e)),
+        };
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Checks the game config holds values the game can run with, the same
+    /// ones accepted when changing it live.
+    pub fn validate(&self) -> Result<(), String> {
+        ConfigChange::live(&self.game_config).validate().map_err(|e| format!("Invalid game_config: {}", e))
+    }
+
+    /// Builds the config from the variables listed in `.env.example`.
+    pub fn from_env() -> Result<AppConfig, String> {
+        let api_keys = env::var("API_KEYS")
+            .map(|keys| {
+                keys.split(',')
+                    .map(str::trim)
+                    .filter(|key| !key.is_empty())
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let bind = match env::var("BIND") {
+            Ok(addrs) => addrs
+                .split(',')
+                .map(|addr| addr.trim().parse().map_err(|e| format!("Invalid BIND {}: {}", addr, e)))
+                .collect::<Result<_, _>>()?,
+            Err(_) => Vec::new(),
+        };
+
+        Ok(AppConfig {
+            server_port: env_var("SERVER_PORT")?,
+            bind,
+            unix_socket: env_var("UNIX_SOCKET")?,
+            api_keys,
+            dev_mode: env_var("DEV_MODE")?.unwrap_or(false),
+            admin_key: env_var("ADMIN_KEY")?,
+            spectate_link_secret: env_var("SPECTATE_LINK_SECRET")?,
+            signed_spectate_only: env_var("SIGNED_SPECTATE_ONLY")?.unwrap_or(false),
+            json_logs: env_var("JSON_LOGS")?.unwrap_or(false),
+            snapshot_path: env_var("SNAPSHOT_PATH")?,
+            restore_snapshot: env_var("RESTORE_SNAPSHOT")?.unwrap_or(false),
+            scores_path: env_var("SCORES_PATH")?,
+            ban_list_path: env_var("BAN_LIST_PATH")?,
+            ratings_path: env_var("RATINGS_PATH")?,
+            profiles_path: env_var("PROFILES_PATH")?,
+            season: env_var("SEASON")?,
+            history_path: env_var("HISTORY_PATH")?,
+            replay_dir: env_var("REPLAY_DIR")?,
+            rules_script_path: env_var("RULES_SCRIPT_PATH")?,
+            lan_discovery: env_var("LAN_DISCOVERY")?.unwrap_or(false),
+            regions: RegionConfig::default(),
+            bots: BotConfig {
+                count: env_var("BOTS")?.unwrap_or(0),
+                difficulty: env_var("BOT_DIFFICULTY")?.unwrap_or_default(),
+                fill: env_var("BOT_FILL")?.unwrap_or(false),
+                wasm_dir: env_var("BOT_WASM_DIR")?,
+            },
+            snapshot_limits: SnapshotLimits {
+                player: SnapshotBudget {
+                    max_rate: env_var("PLAYER_MAX_SNAPSHOT_RATE")?,
+                    max_bytes_per_sec: env_var("PLAYER_MAX_BYTES_PER_SEC")?,
+                },
+                spectator: SnapshotBudget {
+                    max_rate: env_var("SPECTATOR_MAX_SNAPSHOT_RATE")?,
+                    max_bytes_per_sec: env_var("SPECTATOR_MAX_BYTES_PER_SEC")?,
+                },
+            },
+            max_rooms: env_var("MAX_ROOMS")?.unwrap_or_else(default_max_rooms),
+            spectator_info_rate: env_var("SPECTATOR_INFO_RATE")?.unwrap_or_else(default_spectator_info_rate),
+            load_shedding: {
+                let defaults = LoadSheddingConfig::default();
+                LoadSheddingConfig {
+                    enabled: env_var("LOAD_SHEDDING")?.unwrap_or(defaults.enabled),
+                    max_tick_ms: env_var("MAX_TICK_MS")?.unwrap_or(defaults.max_tick_ms),
+                    max_queue_delay_ms: env_var("MAX_QUEUE_DELAY_MS")?.unwrap_or(defaults.max_queue_delay_ms),
+                    degraded_spectator_rate: env_var("DEGRADED_SPECTATOR_RATE")?
+                        .unwrap_or(defaults.degraded_spectator_rate),
+                }
+            },
+            game_config: {
+                let defaults = GameConfig::default();
+                GameConfig {
+                    bound_x: env_var("BOUND_X")?.unwrap_or(defaults.bound_x),
+                    bound_y: env_var("BOUND_Y")?.unwrap_or(defaults.bound_y),
+                    fire_rate: env_var("FIRE_RATE")?.unwrap_or(defaults.fire_rate),
+                    fire_burst: env_var("FIRE_BURST")?.unwrap_or(defaults.fire_burst),
+                    max_bullets: env_var("MAX_BULLETS")?.unwrap_or(defaults.max_bullets),
+                    weapons: WeaponConfig {
+                        standard_cooldown: env_var("STANDARD_COOLDOWN")?
+                            .unwrap_or(defaults.weapons.standard_cooldown),
+                        spread_cooldown: env_var("SPREAD_COOLDOWN")?.unwrap_or(defaults.weapons.spread_cooldown),
+                        spread_bullets: env_var("SPREAD_BULLETS")?.unwrap_or(defaults.weapons.spread_bullets),
+                        spread_angle: env_var("SPREAD_ANGLE")?.unwrap_or(defaults.weapons.spread_angle),
+                        standard_heat: env_var("STANDARD_HEAT")?.unwrap_or(defaults.weapons.standard_heat),
+                        spread_heat: env_var("SPREAD_HEAT")?.unwrap_or(defaults.weapons.spread_heat),
+                        cooling_rate: env_var("COOLING_RATE")?.unwrap_or(defaults.weapons.cooling_rate),
+                    },
+                    lag_compensation: env_var("LAG_COMPENSATION")?
+                        .unwrap_or(defaults.lag_compensation),
+                    item_spawn_rate: env_var("ITEM_SPAWN_RATE")?.unwrap_or(defaults.item_spawn_rate),
+                    max_items: env_var("MAX_ITEMS")?.unwrap_or(defaults.max_items),
+                    max_players: env_var("MAX_PLAYERS")?.unwrap_or(defaults.max_players),
+                    reflect_bullets: env_var("REFLECT_BULLETS")?.unwrap_or(defaults.reflect_bullets),
+                    game_mode: env_var("GAME_MODE")?.unwrap_or(defaults.game_mode),
+                    teams: env_var("TEAMS")?.unwrap_or(defaults.teams),
+                    friendly_fire: env_var("FRIENDLY_FIRE")?.unwrap_or(defaults.friendly_fire),
+                    rounds: RoundConfig {
+                        enabled: env_var("ROUNDS")?.unwrap_or(defaults.rounds.enabled),
+                        min_players: env_var("MIN_PLAYERS")?.unwrap_or(defaults.rounds.min_players),
+                        countdown_secs: env_var("COUNTDOWN_SECS")?.unwrap_or(defaults.rounds.countdown_secs),
+                        duration_secs: env_var("ROUND_SECS")?.unwrap_or(defaults.rounds.duration_secs),
+                        results_secs: env_var("RESULTS_SECS")?.unwrap_or(defaults.rounds.results_secs),
+                    },
+                    ..defaults
+                }
+            },
+        })
+    }
+
+    /// The source of the rules script, if there is one. Read again by every
+    /// room when it starts.
+    pub fn read_rules_script(&self) -> Result<Option<String>, String> {
+        match &self.rules_script_path {
+            Some(path) => std::fs::read_to_string(path)
+                .map(Some)
+                .map_err(|e| format!("Failed to read the rules script {:?}: {}", path, e)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn spectate_link_secret(&self) -> Option<&str> {
+        self.spectate_link_secret.as_ref().or(self.admin_key.as_ref()).map(String::as_str)
+    }
+
+    pub fn bind_addresses(&self) -> Vec<SocketAddr> {
+        if self.bind.is_empty() {
+            vec![(Ipv4Addr::UNSPECIFIED, self.server_port.unwrap_or(3000)).into()]
+        } else {
+            self.bind.clone()
+        }
+    }
+
+    /// The port clients should connect to, as announced by LAN discovery.
+    pub fn public_port(&self) -> u16 {
+        self.bind_addresses()[0].port()
+    }
+}
+
+fn default_max_rooms() -> usize {
+    16
+}
+
+fn default_spectator_info_rate() -> f32 {
+    10.0
+}
+
+/// Parses an optional environment variable, complaining about malformed values
+/// instead of silently ignoring them.
+fn env_var<T: FromStr>(name: &str) -> Result<Option<T>, String>
+where
+    T::Err: std::fmt::Display,
+{
+    match env::var(name) {
+        Ok(value) => value.parse().map(Some).map_err(|e| format!("Invalid {}: {}", name, e)),
+        Err(_) => Ok(None),
+    }
+}
@@ -0,0 +1,318 @@
+use crate::{
+    actors::GameActor,
+    export,
+    game::ConfigChange,
+    models::messages::{
+        GetAggregateStats, GetAllHistory, GetGameConfig, ListClients, ReloadConfig, Restriction,
+        ServerCommand,
+    },
+    spectate_link, AppState,
+};
+use actix::Addr;
+use actix_web::{
+    error::{ErrorInternalServerError, ErrorNotFound},
+    http::header,
+    web::{Data, Query},
+    HttpRequest, HttpResponse,
+};
+use futures::future;
+use std::net::IpAddr;
+
+// How long minted spectator links stay valid unless asked otherwise.
+const DEFAULT_SPECTATE_LINK_TTL_SECS: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Deserialize)]
+pub struct BanQuery {
+    key: Option<String>,
+    ip: Option<IpAddr>,
+}
+
+pub async fn bans_handler(state: Data<AppState>) -> Result<HttpResponse, actix_web::Error> {
+    let bans = state.bans.read().unwrap();
+    Ok(HttpResponse::Ok().json(&bans.list))
+}
+
+pub async fn ban_handler(
+    state: Data<AppState>,
+    query: Query<BanQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let BanQuery { key, ip } = query.into_inner();
+
+    if key.is_none() && ip.is_none() {
+        return Err(actix_web::error::ErrorBadRequest("Pass a key and/or an ip to ban"));
+    }
+
+    info!(?key, ?ip, "banning");
+
+    // Kick out the banned client if it's currently playing, in whichever room.
+    if let Some(key) = &key {
+        for addr in state.rooms.lock().unwrap().all() {
+            addr.do_send(ServerCommand::Kick(key.clone()));
+        }
+    }
+
+    state.bans.write().unwrap().ban(key, ip).map_err(actix_web::error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().body("done"))
+}
+
+pub async fn unban_handler(
+    state: Data<AppState>,
+    query: Query<BanQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    info!(key = ?query.key, ip = ?query.ip, "unbanning");
+
+    state
+        .bans
+        .write()
+        .unwrap()
+        .unban(query.key.as_deref(), query.ip)
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().body("done"))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct KeyQuery {
+    key: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RoomQuery {
+    room: Option<String>,
+}
+
+/// The room passed as `?room=`, or the default one.
+fn room(state: &AppState, room: Option<&str>) -> Result<Addr<GameActor>, actix_web::Error> {
+    state.rooms.lock().unwrap().get(room).ok_or_else(|| ErrorNotFound("No such room"))
+}
+
+/// The room passed as `?room=`, or every room without one.
+fn rooms(state: &AppState, room: Option<&str>) -> Result<Vec<Addr<GameActor>>, actix_web::Error> {
+    match room {
+        Some(_) => Ok(vec![self::room(state, room)?]),
+        None => Ok(state.rooms.lock().unwrap().all()),
+    }
+}
+
+/// Everyone connected to play, with their room, key, name and latency.
+pub async fn clients_handler(
+    state: Data<AppState>,
+    query: Query<RoomQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let rooms = rooms(&state, query.room.as_deref())?;
+    let clients = future::try_join_all(rooms.iter().map(|addr| addr.send(ListClients)))
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().json(clients.concat()))
+}
+
+/// Disconnects the key's client from whichever room it plays in. Unlike a ban
+/// it can connect again right away.
+pub async fn kick_handler(
+    state: Data<AppState>,
+    query: Query<KeyQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let key = query.into_inner().key;
+    for addr in state.rooms.lock().unwrap().all() {
+        addr.do_send(ServerCommand::Kick(key.clone()));
+    }
+    Ok(HttpResponse::Ok().body("done"))
+}
+
+/// Stops the game loop of every room, or of the one passed as `?room=`. Unlike
+/// `/debug/pause` it works outside of dev mode too.
+pub async fn pause_handler(
+    state: Data<AppState>,
+    query: Query<RoomQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    for addr in rooms(&state, query.room.as_deref())? {
+        addr.do_send(ServerCommand::SetPaused(true));
+    }
+    Ok(HttpResponse::Ok().body("done"))
+}
+
+pub async fn resume_handler(
+    state: Data<AppState>,
+    query: Query<RoomQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    for addr in rooms(&state, query.room.as_deref())? {
+        addr.do_send(ServerCommand::SetPaused(false));
+    }
+    Ok(HttpResponse::Ok().body("done"))
+}
+
+/// The game config of the default room as it is now, or of the one passed as `?room=`.
+pub async fn config_handler(
+    state: Data<AppState>,
+    query: Query<RoomQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let addr = room(&state, query.room.as_deref())?;
+    let config = addr.send(GetGameConfig).await.map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().json(config))
+}
+
+/// Changes any of the bounds, `fire_rate`, `fire_burst`, `max_bullets`,
+/// `item_spawn_rate`, `max_items`, `reflect_bullets` and `friendly_fire` of
+/// every room, rooms started later included, or only of the one passed as
+/// `?room=`, and returns the new config. The changes last until the server
+/// restarts or the config is reloaded.
+pub async fn update_config_handler(
+    state: Data<AppState>,
+    room: Query<RoomQuery>,
+    query: Query<ConfigChange>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let change = query.into_inner();
+    change.validate().map_err(actix_web::error::ErrorBadRequest)?;
+
+    match room.room.as_deref() {
+        Some(name) => self::room(&state, Some(name))?.do_send(ServerCommand::Configure(change)),
+        None => state.rooms.lock().unwrap().configure(&change),
+    }
+    config_handler(state, room).await
+}
+
+/// Reads the config file again. The API keys and the game config values that
+/// can be changed live take effect in every room, players whose key was
+/// removed are kicked, and the rest of the changes wait for a restart. An
+/// invalid config is rejected and the running one kept.
+pub async fn reload_handler(state: Data<AppState>) -> Result<HttpResponse, actix_web::Error> {
+    let reloaded = state.reloader.send(ReloadConfig).await.map_err(ErrorInternalServerError)?;
+    Ok(match reloaded {
+        Ok(reloaded) => HttpResponse::Ok().json(reloaded),
+        Err(e) => {
+            warn!("Kept the running config: {}", e);
+            HttpResponse::BadRequest().body(e)
+        },
+    })
+}
+
+/// Drops the key's chat messages and emotes, in whichever room it plays.
+pub async fn mute_handler(
+    state: Data<AppState>,
+    query: Query<KeyQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    restrict(&state, query.into_inner().key, Some(Restriction::Muted));
+    Ok(HttpResponse::Ok().body("done"))
+}
+
+/// Only echoes the key's chat messages and emotes back to themselves.
+pub async fn shadow_restrict_handler(
+    state: Data<AppState>,
+    query: Query<KeyQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    restrict(&state, query.into_inner().key, Some(Restriction::Shadow));
+    Ok(HttpResponse::Ok().body("done"))
+}
+
+/// Lifts a mute or shadow restriction.
+pub async fn unmute_handler(
+    state: Data<AppState>,
+    query: Query<KeyQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    restrict(&state, query.into_inner().key, None);
+    Ok(HttpResponse::Ok().body("done"))
+}
+
+fn restrict(state: &AppState, key: String, restriction: Option<Restriction>) {
+    info!(key = key.as_str(), ?restriction, "restricting");
+    let mut restrictions = state.restrictions.write().unwrap();
+    match restriction {
+        Some(restriction) => restrictions.insert(key, restriction),
+        None => restrictions.remove(&key),
+    };
+}
+
+pub async fn restrictions_handler(state: Data<AppState>) -> Result<HttpResponse, actix_web::Error> {
+    let restrictions = state.restrictions.read().unwrap();
+    Ok(HttpResponse::Ok().json(&*restrictions))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AggregateStatsQuery {
+    season: Option<String>,
+    key: Option<String>,
+}
+
+/// K/D ratios, accuracy, survival times and who killed whom, over the
+/// recorded match history.
+pub async fn aggregate_stats_handler(
+    state: Data<AppState>,
+    query: Query<AggregateStatsQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let AggregateStatsQuery { season, key } = query.into_inner();
+    let stats = state
+        .game_addr
+        .send(GetAggregateStats { season, key })
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().json(stats))
+}
+
+/// Every recorded match result as CSV, one row per player per match.
+pub async fn export_results_handler(
+    state: Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let history = state.game_addr.send(GetAllHistory).await.map_err(ErrorInternalServerError)?;
+    Ok(csv_response("results.csv", export::results_csv(&history)))
+}
+
+/// The same numbers as /stats/aggregate as CSV, one row per player.
+pub async fn export_stats_handler(
+    state: Data<AppState>,
+    query: Query<AggregateStatsQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let AggregateStatsQuery { season, key } = query.into_inner();
+    let stats = state
+        .game_addr
+        .send(GetAggregateStats { season, key })
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(csv_response("stats.csv", export::stats_csv(&stats)))
+}
+
+fn csv_response(filename: &str, csv: String) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/csv; charset=utf-8")
+        .insert_header((header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename)))
+        .body(csv)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SpectateLinkQuery {
+    ttl_secs: Option<u64>,
+    follow: Option<u32>,
+    room: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SpectateLink {
+    url: String,
+    expires: u64,
+}
+
+pub async fn spectate_link_handler(
+    req: HttpRequest,
+    query: Query<SpectateLinkQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let secret = crate::APP_CONFIG.spectate_link_secret().ok_or_else(|| {
+        actix_web::error::ErrorBadRequest("Set spectate_link_secret or admin_key to mint links")
+    })?;
+
+    let expires =
+        spectate_link::unix_now() + query.ttl_secs.unwrap_or(DEFAULT_SPECTATE_LINK_TTL_SECS);
+    let room = query.room.as_deref();
+    let sig = spectate_link::sign(secret, expires, query.follow, room);
+
+    let info = req.connection_info();
+    let mut url = format!("{}://{}/?expires={}", info.scheme(), info.host(), expires);
+    if let Some(follow) = query.follow {
+        url.push_str(&format!("&follow={}", follow));
+    }
+    if let Some(room) = room {
+        url.push_str(&format!("&room={}", room));
+    }
+    url.push_str(&format!("&sig={}", sig));
+
+    Ok(HttpResponse::Ok().json(SpectateLink { url, expires }))
+}
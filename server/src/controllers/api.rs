@@ -1,45 +1,359 @@
 use crate::{
-    actors::ClientWsActor, AppState,
-    models::messages::ServerCommand,
+    actors::{game_actor::{GetRoomInfo, MAX_SPEED, MIN_SPEED}, ClientWsActor, ReplayActor, SpectatorActor}, AppState,
+    models::messages::{ConnectionId, GetHistory, GetLeaderboard, GetProfile, ServerCommand, UpdateProfile},
+    replay::{self, Playback},
+    spectate_link::{self, SpectateParams},
 };
-use actix_web::{HttpRequest, Query, State, http::StatusCode};
+use actix_web::{
+    error::ErrorInternalServerError,
+    web::{Data, Path, Payload, Query},
+    HttpRequest, HttpResponse,
+};
+use actix_web_actors::ws;
+use futures::future;
+use tokyo_protocol::{WireFormat, MAX_PROFILE_NAME_LENGTH, PROTOCOL_VERSION};
 
 #[derive(Debug, Deserialize)]
 pub struct QueryString {
     key: String,
     name: String,
+    // Older clients don't send a version, let them in.
+    version: Option<u32>,
+    // Join the ranked queue, so the matches played count towards a rating.
+    #[serde(default)]
+    ranked: bool,
+    // Get deltas instead of full game states, see `GameStateDelta`.
+    #[serde(default)]
+    delta: bool,
+    // `msgpack` to get binary frames instead of JSON.
+    #[serde(default)]
+    format: WireFormat,
+    // Play in this room instead of the default one, starting it if needed.
+    room: Option<String>,
+    // The team to play for in the teams mode, the smallest one if not given.
+    team: Option<u32>,
 }
 
-pub fn socket_handler(
-    (req, state, query): (HttpRequest<AppState>, State<AppState>, Query<QueryString>),
+pub async fn socket_handler(
+    req: HttpRequest,
+    stream: Payload,
+    state: Data<AppState>,
+    query: Query<QueryString>,
 ) -> Result<actix_web::HttpResponse, actix_web::Error> {
-    if crate::APP_CONFIG.dev_mode || crate::APP_CONFIG.api_keys.contains(&query.key) {
-        actix_web::ws::start(
+    let conn_id = ConnectionId::new();
+
+    if query.version.is_some_and(|version| version != PROTOCOL_VERSION) {
+        warn!(%conn_id, key = query.key.as_str(), "rejected protocol version {:?}", query.version);
+        return Err(actix_web::error::ErrorBadRequest(format!(
+            "Unsupported protocol version, the server speaks version {} (connection {})",
+            PROTOCOL_VERSION, conn_id
+        )));
+    }
+
+    let ip = req.peer_addr().map(|addr| addr.ip());
+    if state.bans.read().unwrap().list.is_banned(&query.key, ip) {
+        warn!(%conn_id, key = query.key.as_str(), ?ip, "rejected banned client");
+        return Err(actix_web::error::ErrorForbidden(format!("Banned (connection {})", conn_id)));
+    }
+
+    if crate::APP_CONFIG.dev_mode || state.api_keys.read().unwrap().contains(&query.key) {
+        let game_addr = match &query.room {
+            None if query.ranked => state.rooms.lock().unwrap().join_ranked(&query.key),
+            room => state
+                .rooms
+                .lock()
+                .unwrap()
+                .join(room.as_ref().map(String::as_str))
+                .map_err(|e| actix_web::error::ErrorBadRequest(format!("{} (connection {})", e, conn_id)))?,
+        };
+
+        let region = crate::APP_CONFIG.regions.region_for(&query.key, ip);
+        info!(
+            %conn_id,
+            key = query.key.as_str(),
+            ?region,
+            room = ?query.room,
+            "websocket upgrade"
+        );
+        ws::start(
+            ClientWsActor::new(
+                game_addr,
+                query.key.clone(),
+                query.name.clone(),
+                query.team,
+                region,
+                query.ranked,
+                query.delta,
+                query.format,
+                conn_id,
+            ),
             &req,
-            ClientWsActor::new(state.game_addr.clone(), query.key.clone(), query.name.clone()),
+            stream,
         )
     } else {
-        Err(actix_web::error::ErrorBadRequest("Invalid API Key"))
+        warn!(%conn_id, key = query.key.as_str(), "rejected invalid API key");
+        Err(actix_web::error::ErrorBadRequest(format!("Invalid API Key (connection {})", conn_id)))
     }
 }
 
-pub fn spectate_handler(
-    (req, state): (HttpRequest<AppState>, State<AppState>),
+pub async fn spectate_handler(
+    req: HttpRequest,
+    stream: Payload,
+    state: Data<AppState>,
+    params: Query<SpectateParams>,
 ) -> Result<actix_web::HttpResponse, actix_web::Error> {
-    // TODO(bschwind) - Make a separate spectator actor
-    actix_web::ws::start(
-        &req,
-        ClientWsActor::new(
-            state.game_addr.clone(),
-            "SPECTATOR".to_string(),
-            "SPECTATOR".to_string(),
+    let ip = req.peer_addr().map(|addr| addr.ip());
+    if state.bans.read().unwrap().list.is_ip_banned(ip) {
+        warn!(?ip, "rejected banned spectator");
+        return Err(actix_web::error::ErrorForbidden("Banned"));
+    }
+
+    if params.sig.is_some() || crate::APP_CONFIG.signed_spectate_only {
+        let secret = crate::APP_CONFIG
+            .spectate_link_secret()
+            .ok_or_else(|| actix_web::error::ErrorForbidden("Spectator links are not configured"))?;
+        spectate_link::verify(secret, &params).map_err(actix_web::error::ErrorForbidden)?;
+    }
+
+    let game_addr = state
+        .rooms
+        .lock()
+        .unwrap()
+        .get(params.room.as_deref())
+        .ok_or_else(|| actix_web::error::ErrorNotFound("No such room"))?;
+
+    ws::start(
+        SpectatorActor::new(
+            game_addr,
+            ConnectionId::new(),
+            params.follow,
+            params.name.clone(),
         ),
+        &req,
+        stream,
     )
 }
 
-pub fn reset_handler(
-    (_req, state): (HttpRequest<AppState>, State<AppState>),
+#[derive(Debug, Deserialize)]
+pub struct ReplayQuery {
+    // How many times faster than real time to play it back.
+    speed: Option<f32>,
+}
+
+/// Spectators can't look back at what they weren't allowed to watch live.
+fn check_replays_allowed() -> Result<&'static std::path::PathBuf, actix_web::Error> {
+    if crate::APP_CONFIG.signed_spectate_only {
+        return Err(actix_web::error::ErrorForbidden("Spectating needs a signed link"));
+    }
+    crate::APP_CONFIG
+        .replay_dir
+        .as_ref()
+        .ok_or_else(|| actix_web::error::ErrorNotFound("Replays are not recorded"))
+}
+
+/// The ids of the recorded replays, for `/replay/{id}`.
+pub async fn replays_handler() -> Result<HttpResponse, actix_web::Error> {
+    let dir = check_replays_allowed()?;
+    let ids = replay::list(dir).map_err(|e| {
+        error!("Failed to list replays in {:?}: {}", dir, e);
+        actix_web::error::ErrorInternalServerError("Failed to list replays")
+    })?;
+
+    Ok(HttpResponse::Ok().json(ids))
+}
+
+/// Streams a recorded match to a spectator websocket, at the pace it was
+/// played unless asked for a different `?speed=`.
+pub async fn replay_handler(
+    req: HttpRequest,
+    stream: Payload,
+    id: Path<String>,
+    query: Query<ReplayQuery>,
 ) -> Result<actix_web::HttpResponse, actix_web::Error> {
+    let dir = check_replays_allowed()?;
+    let path = replay::path_of(dir, &id).ok_or_else(|| actix_web::error::ErrorBadRequest("Invalid replay id"))?;
+    let replay = replay::load(&path).map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => actix_web::error::ErrorNotFound("No such replay"),
+        _ => {
+            error!("Failed to load replay from {:?}: {}", path, e);
+            actix_web::error::ErrorInternalServerError("Failed to load the replay")
+        },
+    })?;
+    let speed = query.speed.unwrap_or(1.0);
+    if !(MIN_SPEED..=MAX_SPEED).contains(&speed) {
+        return Err(actix_web::error::ErrorBadRequest(format!(
+            "speed must be between {} and {}",
+            MIN_SPEED, MAX_SPEED
+        )));
+    }
+
+    ws::start(ReplayActor::new(ConnectionId::new(), Playback::new(replay), speed), &req, stream)
+}
+
+#[derive(Debug, Serialize)]
+struct RoomListing {
+    name: String,
+    players: usize,
+    bots: usize,
+    spectators: usize,
+}
+
+/// The rooms started with `?room=`, with how many are in each.
+pub async fn rooms_handler(state: Data<AppState>) -> Result<HttpResponse, actix_web::Error> {
+    let rooms = state.rooms.lock().unwrap().list();
+    let listings = rooms.into_iter().map(|(name, addr)| async move {
+        let info = addr.send(GetRoomInfo).await?;
+        Ok::<_, actix::MailboxError>(RoomListing {
+            name,
+            players: info.players,
+            bots: info.bots,
+            spectators: info.spectators,
+        })
+    });
+
+    let rooms = future::try_join_all(listings).await.map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().json(rooms))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HistoryQuery {
+    key: String,
+}
+
+/// The past matches of an API key, oldest first. Knowing the key is enough to
+/// see them, same as it is to play with it.
+pub async fn history_handler(
+    state: Data<AppState>,
+    query: Query<HistoryQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let history = state
+        .game_addr
+        .send(GetHistory(query.into_inner().key))
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().json(history))
+}
+
+const DEFAULT_LEADERBOARD_LENGTH: usize = 10;
+const MAX_LEADERBOARD_LENGTH: usize = 1000;
+
+#[derive(Debug, Deserialize)]
+pub struct LeaderboardQuery {
+    limit: Option<usize>,
+    // Defaults to the season the server is configured with, `all` for every
+    // recorded match.
+    season: Option<String>,
+}
+
+/// The best players over the recorded matches, kept across restarts when the
+/// server has a `history_path`.
+pub async fn leaderboard_handler(
+    state: Data<AppState>,
+    query: Query<LeaderboardQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let LeaderboardQuery { limit, season } = query.into_inner();
+    let season = match season {
+        Some(ref season) if season == "all" => None,
+        Some(season) => Some(season),
+        None => crate::APP_CONFIG.season.clone(),
+    };
+    let limit = limit.unwrap_or(DEFAULT_LEADERBOARD_LENGTH).min(MAX_LEADERBOARD_LENGTH);
+
+    let leaderboard = state
+        .game_addr
+        .send(GetLeaderboard { season, limit })
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().json(leaderboard))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProfileQuery {
+    key: String,
+    name: Option<String>,
+    // Empty to clear.
+    color: Option<String>,
+    team: Option<String>,
+}
+
+/// Profiles can only be seen and changed with a key that could also be used
+/// to play.
+fn check_key(req: &HttpRequest, state: &AppState, key: &str) -> Result<(), actix_web::Error> {
+    let ip = req.peer_addr().map(|addr| addr.ip());
+    if state.bans.read().unwrap().list.is_banned(key, ip) {
+        return Err(actix_web::error::ErrorForbidden("Banned"));
+    }
+    if !crate::APP_CONFIG.dev_mode && !state.api_keys.read().unwrap().contains(key) {
+        return Err(actix_web::error::ErrorForbidden("Invalid API Key"));
+    }
+    Ok(())
+}
+
+pub async fn profile_handler(
+    req: HttpRequest,
+    state: Data<AppState>,
+    query: Query<ProfileQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    check_key(&req, &state, &query.key)?;
+
+    let profile = state
+        .game_addr
+        .send(GetProfile(query.into_inner().key))
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(match profile {
+        Some(profile) => HttpResponse::Ok().json(profile),
+        None => HttpResponse::NotFound().body("No profile yet"),
+    })
+}
+
+/// Sets any of the name, color and team of a profile.
+pub async fn update_profile_handler(
+    req: HttpRequest,
+    state: Data<AppState>,
+    query: Query<ProfileQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    check_key(&req, &state, &query.key)?;
+    let ProfileQuery { key, name, color, team } = query.into_inner();
+
+    let name = match name.as_ref().map(|name| name.trim()) {
+        Some("") => return Err(actix_web::error::ErrorBadRequest("The name can't be empty")),
+        Some(name) => Some(name.chars().take(MAX_PROFILE_NAME_LENGTH).collect()),
+        None => None,
+    };
+    let color = match color {
+        Some(ref color) if color.is_empty() => Some(None),
+        Some(color) => {
+            let valid = color.len() == 7
+                && color.starts_with('#')
+                && color[1..].chars().all(|c| c.is_ascii_hexdigit());
+            if !valid {
+                return Err(actix_web::error::ErrorBadRequest("The color has to look like #12ab34"));
+            }
+            Some(Some(color))
+        },
+        None => None,
+    };
+    let team = team.map(|team| match team.trim() {
+        "" => None,
+        team => Some(team.chars().take(MAX_PROFILE_NAME_LENGTH).collect()),
+    });
+
+    // The default room saves it, the others only need to show it if the player is there.
+    let other_rooms = state.rooms.lock().unwrap().list();
+    let changed_key = key.clone();
+    let profile = state
+        .game_addr
+        .send(UpdateProfile { key, name, color, team })
+        .await
+        .map_err(ErrorInternalServerError)?;
+    for (_, addr) in other_rooms {
+        addr.do_send(ServerCommand::ProfileChanged(changed_key.clone()));
+    }
+    Ok(HttpResponse::Ok().json(profile))
+}
+
+pub async fn reset_handler(state: Data<AppState>) -> Result<HttpResponse, actix_web::Error> {
     state.game_addr.do_send(ServerCommand::Reset);
-    Ok(actix_web::HttpResponse::with_body(StatusCode::OK, "done"))
+    Ok(HttpResponse::Ok().body("done"))
 }
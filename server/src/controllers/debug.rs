@@ -0,0 +1,185 @@
+//! Tools for bot authors debugging against a local server. Everything here is
+//! behind `AdminAuth` and refuses to run outside of `dev_mode`.
+
+use crate::{
+    actors::game_actor::{EditObstacle, InspectGame, MAX_SPEED, MIN_SPEED},
+    game::ObstacleEdit,
+    models::messages::ServerCommand,
+    AppState,
+};
+use actix_web::{
+    error::ErrorInternalServerError,
+    web::{Data, Query},
+    HttpResponse,
+};
+use serde_json::{json, Map, Value};
+
+fn require_dev_mode() -> Result<(), actix_web::Error> {
+    if crate::APP_CONFIG.dev_mode {
+        Ok(())
+    } else {
+        Err(actix_web::error::ErrorForbidden("Only available in dev_mode"))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SpeedQuery {
+    factor: f32,
+}
+
+pub async fn speed_handler(
+    state: Data<AppState>,
+    query: Query<SpeedQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    require_dev_mode()?;
+
+    if !(query.factor >= MIN_SPEED && query.factor <= MAX_SPEED) {
+        return Err(actix_web::error::ErrorBadRequest(format!(
+            "factor must be between {} and {}",
+            MIN_SPEED, MAX_SPEED
+        )));
+    }
+
+    state.game_addr.do_send(ServerCommand::SetSpeed(query.factor));
+    Ok(HttpResponse::Ok().body("done"))
+}
+
+pub async fn pause_handler(state: Data<AppState>) -> Result<HttpResponse, actix_web::Error> {
+    require_dev_mode()?;
+    state.game_addr.do_send(ServerCommand::SetPaused(true));
+    Ok(HttpResponse::Ok().body("done"))
+}
+
+pub async fn resume_handler(state: Data<AppState>) -> Result<HttpResponse, actix_web::Error> {
+    require_dev_mode()?;
+    state.game_addr.do_send(ServerCommand::SetPaused(false));
+    Ok(HttpResponse::Ok().body("done"))
+}
+
+/// Runs exactly one tick of a paused game, and broadcasts its state.
+pub async fn step_handler(state: Data<AppState>) -> Result<HttpResponse, actix_web::Error> {
+    require_dev_mode()?;
+    state.game_addr.do_send(ServerCommand::Step);
+    Ok(HttpResponse::Ok().body("done"))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StateQuery {
+    #[serde(default)]
+    diff: bool,
+}
+
+/// Dumps the game's full internal state. With `?diff=true`, only what changed
+/// since the previous request is returned, as `{"/json/pointer": {"old", "new"}}`.
+pub async fn state_handler(
+    state: Data<AppState>,
+    query: Query<StateQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    require_dev_mode()?;
+
+    let debug_state = state
+        .game_addr
+        .send(InspectGame)
+        .await
+        .map_err(ErrorInternalServerError)?
+        .map_err(|_| actix_web::error::ErrorServiceUnavailable("The game loop isn't running"))?;
+    let current = serde_json::to_value(&debug_state)?;
+
+    let previous = state.last_debug_state.lock().unwrap().replace(current.clone());
+
+    if query.diff {
+        let mut changes = Map::new();
+        json_diff(previous.as_ref().unwrap_or(&Value::Null), &current, String::new(), &mut changes);
+        Ok(HttpResponse::Ok().json(Value::Object(changes)))
+    } else {
+        Ok(HttpResponse::Ok().json(current))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AddObstacleQuery {
+    x: f32,
+    y: f32,
+    width: f32,
+    height: f32,
+}
+
+/// Puts a new wall in the live arena and responds with its id.
+pub async fn add_obstacle_handler(
+    state: Data<AppState>,
+    query: Query<AddObstacleQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    if !(query.width > 0.0 && query.height > 0.0) {
+        return Err(actix_web::error::ErrorBadRequest("width and height must be positive"));
+    }
+
+    let AddObstacleQuery { x, y, width, height } = query.into_inner();
+    edit_obstacle(&state, ObstacleEdit::Add { x, y, width, height }).await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MoveObstacleQuery {
+    id: u32,
+    x: f32,
+    y: f32,
+}
+
+/// Moves a wall's top left corner.
+pub async fn move_obstacle_handler(
+    state: Data<AppState>,
+    query: Query<MoveObstacleQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let MoveObstacleQuery { id, x, y } = query.into_inner();
+    edit_obstacle(&state, ObstacleEdit::Move { id, x, y }).await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RemoveObstacleQuery {
+    id: u32,
+}
+
+pub async fn remove_obstacle_handler(
+    state: Data<AppState>,
+    query: Query<RemoveObstacleQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    edit_obstacle(&state, ObstacleEdit::Remove { id: query.id }).await
+}
+
+async fn edit_obstacle(state: &AppState, edit: ObstacleEdit) -> Result<HttpResponse, actix_web::Error> {
+    require_dev_mode()?;
+
+    let edited = state
+        .game_addr
+        .send(EditObstacle(edit))
+        .await
+        .map_err(ErrorInternalServerError)?
+        .map_err(|_| actix_web::error::ErrorServiceUnavailable("The game loop isn't running"))?;
+    match edited {
+        Some(id) => Ok(HttpResponse::Ok().json(json!({ "id": id }))),
+        None => Err(actix_web::error::ErrorNotFound("No such obstacle")),
+    }
+}
+
+fn json_diff(old: &Value, new: &Value, path: String, changes: &mut Map<String, Value>) {
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            for (key, new_value) in new {
+                let path = format!("{}/{}", path, key);
+                json_diff(old.get(key).unwrap_or(&Value::Null), new_value, path, changes);
+            }
+            for (key, old_value) in old.iter().filter(|(key, _)| !new.contains_key(*key)) {
+                json_diff(old_value, &Value::Null, format!("{}/{}", path, key), changes);
+            }
+        },
+        (Value::Array(old), Value::Array(new)) => {
+            for i in 0..old.len().max(new.len()) {
+                let path = format!("{}/{}", path, i);
+                json_diff(old.get(i).unwrap_or(&Value::Null), new.get(i).unwrap_or(&Value::Null), path, changes);
+            }
+        },
+        (old, new) if old != new => {
+            changes.insert(path, json!({ "old": old, "new": new }));
+        },
+        _ => {},
+    }
+}
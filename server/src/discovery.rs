@@ -0,0 +1,26 @@
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use tokyo_protocol::{MDNS_SERVICE_TYPE, PROTOCOL_VERSION};
+
+/// Announces the server over mDNS so workshop attendees can find it with
+/// `tokyo::discover()` instead of typing in its address. The announcement
+/// lasts as long as the returned daemon.
+pub fn start_responder(server_port: u16) -> Result<ServiceDaemon, mdns_sd::Error> {
+    // Several servers can run on one network, often on the same port.
+    let name = format!("tokyo-rs-{:08x}", rand::random::<u32>());
+    let protocol_version = PROTOCOL_VERSION.to_string();
+    let service = ServiceInfo::new(
+        MDNS_SERVICE_TYPE,
+        &name,
+        &format!("{}.local.", name),
+        (),
+        server_port,
+        &[("protocol_version", protocol_version.as_str())][..],
+    )?
+    .enable_addr_auto();
+
+    let mdns = ServiceDaemon::new()?;
+    mdns.register(service)?;
+    info!(name = name.as_str(), "announcing the server as {} over mDNS", MDNS_SERVICE_TYPE);
+
+    Ok(mdns)
+}
@@ -0,0 +1,73 @@
+//! CSV versions of the match history and stats, for spreadsheets.
+
+use crate::{persistence::history::MatchResult, stats::AggregateStats};
+use std::collections::HashMap;
+
+const RESULTS_HEADER: &str =
+    "key,match_id,season,ended_at,placement,players,score,kills,deaths,shots,lives,alive_secs,rating_delta";
+
+const STATS_HEADER: &str =
+    "key,matches,kills,deaths,kd_ratio,shots,accuracy,average_survival_secs";
+
+/// One row per player per match, in the order the matches ended.
+pub fn results_csv(history: &HashMap<String, Vec<MatchResult>>) -> String {
+    let mut rows: Vec<(&String, &MatchResult)> = history
+        .iter()
+        .flat_map(|(api_key, results)| results.iter().map(move |result| (api_key, result)))
+        .collect();
+    rows.sort_by(|a, b| (a.1.ended_at, a.0).cmp(&(b.1.ended_at, b.0)));
+
+    let mut csv = format!("{}\n", RESULTS_HEADER);
+    for (api_key, result) in rows {
+        csv.push_str(&format!(
+            "{},{},{},{},{},{},{},{},{},{},{},{},{}\n",
+            field(api_key),
+            field(result.match_id.as_ref().map_or("", String::as_str)),
+            field(result.season.as_ref().map_or("", String::as_str)),
+            result.ended_at,
+            result.placement,
+            result.players,
+            result.score,
+            result.kills,
+            result.deaths,
+            result.shots,
+            result.lives,
+            result.alive_secs,
+            result.rating_delta.map(|delta| delta.to_string()).unwrap_or_default(),
+        ));
+    }
+
+    csv
+}
+
+/// One row per player, by key.
+pub fn stats_csv(stats: &AggregateStats) -> String {
+    let mut players: Vec<_> = stats.players.iter().collect();
+    players.sort_by(|a, b| a.0.cmp(b.0));
+
+    let mut csv = format!("{}\n", STATS_HEADER);
+    for (api_key, player) in players {
+        csv.push_str(&format!(
+            "{},{},{},{},{},{},{},{}\n",
+            field(api_key),
+            player.matches,
+            player.kills,
+            player.deaths,
+            player.kd_ratio,
+            player.shots,
+            player.accuracy,
+            player.average_survival_secs,
+        ));
+    }
+
+    csv
+}
+
+/// Quotes a text field if it would otherwise break the row.
+fn field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
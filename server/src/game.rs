@@ -1,13 +1,21 @@
-use std::time::Instant;
-use std::collections::HashMap;
-use std::time::{Duration, SystemTime};
+use crate::{
+    collision::{CollisionFilter, Layers, MAX_TEAMS},
+    matches::MatchInfo,
+    plugins::{GamePlugin, Kill, Pickup},
+    scoring::ScoringEngine,
+    snapshot::GameSnapshot,
+    spatial::SpatialGrid,
+};
+use rand::{Rng, SeedableRng};
+use rand_pcg::Pcg32;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::collections::HashSet;
-use tokyo::models::{BULLET_RADIUS, BULLET_SPEED, BulletState, DeadPlayer, GameCommand, GameConfig, GameState, PLAYER_BASE_SPEED, PLAYER_RADIUS, PlayerState};
+use tokyo_protocol::{BULLET_RADIUS, BULLET_SPEED, BulletState, CommandAck, DEFLECTOR_COOLDOWN, DEFLECTOR_DURATION, DeadPlayer, GameCommand, GameConfig, GameEvent, GameMode, GameState, ITEM_EFFECT_DURATION, ITEM_RADIUS, ItemKind, ItemState, MARKER_DURATION, MarkerState, MatchPhase, ObstacleShape, ObstacleState, PLAYER_BASE_SPEED, PLAYER_RADIUS, PhaseChange, PlayerState, RAPID_FIRE_MULTIPLIER, SPEED_BOOST_MULTIPLIER, Standing, ThrottleNotice, ThrottleReason, Weapon, WeaponConfig};
 
 const DEAD_PUNISH: Duration = Duration::from_secs(3);
 
 pub const TICKS_PER_SECOND: f32 = 30.0;
-const MAX_CONCURRENT_BULLETS: usize = 4;
 
 // Time until you start accruing points for surviving
 const SURVIVAL_TIMEOUT: u64 = 10;
@@ -15,11 +23,30 @@ const SURVIVAL_TIMEOUT: u64 = 10;
 // Interval for accruing points after reaching the threshold
 const SURVIVAL_POINT_INTERVAL: u64 = 4;
 
+// How far back lag compensation is willing to rewind other players.
+const MAX_REWIND: Duration = Duration::from_millis(250);
+const MAX_REWIND_FRAMES: usize = 16;
+
+// Spawns try to keep this far away from other players and bullets.
+const SPAWN_PLAYER_CLEARANCE: f32 = 200.0;
+const SPAWN_BULLET_CLEARANCE: f32 = 100.0;
+const SPAWN_OBSTACLE_CLEARANCE: f32 = 50.0;
+const SPAWN_ATTEMPTS: usize = 16;
+
+// Revived players are spawned in the cells of this grid farthest from their killer.
+const SPAWN_GRID_SIZE: usize = 8;
+
 pub trait Triangle {
     fn x(&self) -> f32;
     fn y(&self) -> f32;
     fn angle(&self) -> f32;
     fn radius(&self) -> f32;
+    fn collision_filter(&self) -> CollisionFilter;
+
+    /// Whether the two entities are allowed to collide and actually overlap.
+    fn collides_with(&self, other: &dyn Triangle) -> bool {
+        self.collision_filter().can_collide(&other.collision_filter()) && self.is_colliding(other)
+    }
 
     fn is_colliding(&self, other: &dyn Triangle) -> bool {
         let d_x = other.x() - self.x();
@@ -48,6 +75,14 @@ impl Triangle for PlayerState {
     fn radius(&self) -> f32 {
         PLAYER_RADIUS
     }
+
+    fn collision_filter(&self) -> CollisionFilter {
+        let mut layers = Layers::PLAYER;
+        if let Some(team) = self.team {
+            layers |= Layers::team(team);
+        }
+        CollisionFilter::new(layers, Layers::ALL).owned_by(self.id)
+    }
 }
 
 impl Triangle for BulletState {
@@ -66,48 +101,747 @@ impl Triangle for BulletState {
     fn radius(&self) -> f32 {
         BULLET_RADIUS
     }
+
+    fn collision_filter(&self) -> CollisionFilter {
+        let filter = CollisionFilter::new(Layers::BULLET, Layers::PLAYER | Layers::BULLET | Layers::OBSTACLE)
+            .owned_by(self.player_id)
+            .passing_through_owner()
+            .passing_through_siblings();
+        match self.team {
+            Some(team) => filter.ignoring(Layers::team(team)),
+            None => filter,
+        }
+    }
+}
+
+impl Triangle for ItemState {
+    fn x(&self) -> f32 {
+        self.x
+    }
+
+    fn y(&self) -> f32 {
+        self.y
+    }
+
+    fn angle(&self) -> f32 {
+        0.0
+    }
+
+    fn radius(&self) -> f32 {
+        ITEM_RADIUS
+    }
+
+    fn collision_filter(&self) -> CollisionFilter {
+        CollisionFilter::new(Layers::PICKUP, Layers::PLAYER)
+    }
+}
+
+fn obstacle_filter() -> CollisionFilter {
+    CollisionFilter::new(Layers::OBSTACLE, Layers::PLAYER | Layers::BULLET)
+}
+
+/// Whether the entity is allowed to collide with the obstacle and overlaps it.
+fn hits_obstacle(entity: &dyn Triangle, obstacle: &ObstacleState) -> bool {
+    if !entity.collision_filter().can_collide(&obstacle_filter()) {
+        return false;
+    }
+
+    let (x, y) = obstacle.closest_point(entity.x(), entity.y());
+    (x - entity.x()).powi(2) + (y - entity.y()).powi(2) < entity.radius().powi(2)
+}
+
+/// The shortest way out of the obstacle for something of the given radius at
+/// `x`, `y`: where it ends up just touching the obstacle, and the direction of
+/// the obstacle's surface there.
+fn way_out(obstacle: &ObstacleState, x: f32, y: f32, radius: f32) -> ((f32, f32), (f32, f32)) {
+    let (closest_x, closest_y) = obstacle.closest_point(x, y);
+    if (closest_x, closest_y) != (x, y) {
+        let normal = normalize(x - closest_x, y - closest_y);
+        return ((closest_x + normal.0 * radius, closest_y + normal.1 * radius), normal);
+    }
+
+    // The center is inside, leave through the nearest edge.
+    if obstacle.shape == ObstacleShape::Circle {
+        let (center_x, center_y) = obstacle.center();
+        let normal = normalize(x - center_x, y - center_y);
+        let distance = obstacle.width / 2.0 + radius;
+        return ((center_x + normal.0 * distance, center_y + normal.1 * distance), normal);
+    }
+
+    let left = x - obstacle.x;
+    let right = obstacle.x + obstacle.width - x;
+    let top = y - obstacle.y;
+    let bottom = obstacle.y + obstacle.height - y;
+    let nearest = left.min(right).min(top).min(bottom);
+
+    if nearest == left {
+        ((obstacle.x - radius, y), (-1.0, 0.0))
+    } else if nearest == right {
+        ((obstacle.x + obstacle.width + radius, y), (1.0, 0.0))
+    } else if nearest == top {
+        ((x, obstacle.y - radius), (0.0, -1.0))
+    } else {
+        ((x, obstacle.y + obstacle.height + radius), (0.0, 1.0))
+    }
+}
+
+/// Moves a player that ran into the obstacle back out of it, by the shortest way.
+fn push_out_of(player: &mut PlayerState, obstacle: &ObstacleState) {
+    if !hits_obstacle(player, obstacle) {
+        return;
+    }
+
+    let ((x, y), _) = way_out(obstacle, player.x, player.y, PLAYER_RADIUS);
+    player.x = x;
+    player.y = y;
+}
+
+/// Bounces a bullet that hit the obstacle off its surface.
+fn bounce_off(bullet: &mut BulletState, obstacle: &ObstacleState) {
+    if !hits_obstacle(bullet, obstacle) {
+        return;
+    }
+
+    let ((x, y), normal) = way_out(obstacle, bullet.x, bullet.y, BULLET_RADIUS);
+    bullet.x = x;
+    bullet.y = y;
+    bullet.angle = reflect(bullet.angle, normal);
+}
+
+/// A change to the arena's walls, made through the dev-mode API while the
+/// game runs. Only rectangles can be added this way.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ObstacleEdit {
+    Add { x: f32, y: f32, width: f32, height: f32 },
+    Move { id: u32, x: f32, y: f32 },
+    Remove { id: u32 },
+}
+
+// Bounds are limited so the arena can't be made too big to simulate or too
+// small to fit a player.
+const MIN_BOUND: f32 = 100.0;
+const MAX_BOUND: f32 = 100_000.0;
+const MAX_BULLETS: usize = 64;
+
+/// Game config values that can be changed while the game runs, by an admin or
+/// by reloading the config file. Only the ones given are changed.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ConfigChange {
+    pub bound_x: Option<f32>,
+    pub bound_y: Option<f32>,
+    pub fire_rate: Option<f32>,
+    pub fire_burst: Option<f32>,
+    pub max_bullets: Option<usize>,
+    #[serde(default)]
+    pub item_spawn_rate: Option<f32>,
+    #[serde(default)]
+    pub max_items: Option<usize>,
+    #[serde(default)]
+    pub reflect_bullets: Option<bool>,
+    #[serde(default)]
+    pub friendly_fire: Option<bool>,
+    #[serde(default)]
+    pub max_players: Option<usize>,
+    // Only set by reloading, there's no way to pass it in a query string.
+    #[serde(default)]
+    pub weapons: Option<WeaponConfig>,
+}
+
+impl ConfigChange {
+    /// Everything in the config that can change while the game runs.
+    pub fn live(config: &GameConfig) -> Self {
+        Self {
+            bound_x: Some(config.bound_x),
+            bound_y: Some(config.bound_y),
+            fire_rate: Some(config.fire_rate),
+            fire_burst: Some(config.fire_burst),
+            max_bullets: Some(config.max_bullets),
+            item_spawn_rate: Some(config.item_spawn_rate),
+            max_items: Some(config.max_items),
+            reflect_bullets: Some(config.reflect_bullets),
+            friendly_fire: Some(config.friendly_fire),
+            max_players: Some(config.max_players),
+            weapons: Some(config.weapons.clone()),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let non_negative = |value: f32| value >= 0.0 && value.is_finite();
+
+        let bounds_valid = [self.bound_x, self.bound_y]
+            .iter()
+            .all(|bound| bound.is_none_or(|bound| (MIN_BOUND..=MAX_BOUND).contains(&bound)));
+        if !bounds_valid {
+            return Err(format!("Bounds must be between {} and {}", MIN_BOUND, MAX_BOUND));
+        }
+        if self.fire_rate.is_some_and(|fire_rate| !non_negative(fire_rate)) {
+            return Err("fire_rate can't be negative".to_string());
+        }
+        if self.fire_burst.is_some_and(|fire_burst| !(fire_burst >= 1.0 && fire_burst.is_finite())) {
+            return Err("fire_burst must be at least 1".to_string());
+        }
+        if self.max_bullets.is_some_and(|max_bullets| !(1..=MAX_BULLETS).contains(&max_bullets)) {
+            return Err(format!("max_bullets must be between 1 and {}", MAX_BULLETS));
+        }
+        if self.item_spawn_rate.is_some_and(|item_spawn_rate| !non_negative(item_spawn_rate)) {
+            return Err("item_spawn_rate can't be negative".to_string());
+        }
+        if self.max_players == Some(0) {
+            return Err("max_players must be at least 1".to_string());
+        }
+        if let Some(weapons) = &self.weapons {
+            let rates = [
+                weapons.standard_cooldown,
+                weapons.spread_cooldown,
+                weapons.standard_heat,
+                weapons.spread_heat,
+                weapons.cooling_rate,
+            ];
+            if !rates.iter().all(|rate| non_negative(*rate)) {
+                return Err("Weapon cooldowns, heat and cooling_rate can't be negative".to_string());
+            }
+            if !(1..=MAX_BULLETS as u32).contains(&weapons.spread_bullets) {
+                return Err(format!("spread_bullets must be between 1 and {}", MAX_BULLETS));
+            }
+            if !weapons.spread_angle.is_finite() {
+                return Err("spread_angle must be a number".to_string());
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn apply_to(&self, config: &mut GameConfig) {
+        config.bound_x = self.bound_x.unwrap_or(config.bound_x);
+        config.bound_y = self.bound_y.unwrap_or(config.bound_y);
+        config.fire_rate = self.fire_rate.unwrap_or(config.fire_rate);
+        config.fire_burst = self.fire_burst.unwrap_or(config.fire_burst);
+        config.max_bullets = self.max_bullets.unwrap_or(config.max_bullets);
+        config.item_spawn_rate = self.item_spawn_rate.unwrap_or(config.item_spawn_rate);
+        config.max_items = self.max_items.unwrap_or(config.max_items);
+        config.reflect_bullets = self.reflect_bullets.unwrap_or(config.reflect_bullets);
+        config.friendly_fire = self.friendly_fire.unwrap_or(config.friendly_fire);
+        config.max_players = self.max_players.unwrap_or(config.max_players);
+        if let Some(weapons) = &self.weapons {
+            config.weapons = weapons.clone();
+        }
+    }
+}
+
+/// Game time only moves forward when the game ticks, so simulating the same
+/// inputs again gives the same result no matter how fast it runs.
+#[derive(Debug, Clone, Copy)]
+pub struct GameClock {
+    epoch: SystemTime,
+    elapsed: Duration,
+}
+
+impl GameClock {
+    pub fn starting_at(epoch: SystemTime) -> Self {
+        Self { epoch, elapsed: Duration::from_secs(0) }
+    }
+
+    pub fn now(&self) -> SystemTime {
+        self.epoch + self.elapsed
+    }
+
+    fn advance(&mut self, dt: f32) {
+        self.elapsed += Duration::from_secs_f32(dt);
+    }
+}
+
+// Where the players were after a past tick.
+struct HistoryFrame {
+    dt: f32,
+    players: Vec<PlayerState>,
+}
+
+/// Everything `Game` keeps track of, including what isn't sent to clients.
+#[derive(Debug, Serialize)]
+pub struct GameDebugState {
+    pub state: GameState,
+    pub game_time: Duration,
+    pub bullet_id_counter: u32,
+    // Time left until each player's next survival point.
+    pub survival_times: HashMap<u32, Duration>,
+    pub fire_tokens: HashMap<u32, f32>,
+    // Time left until each player's weapon can fire again.
+    pub weapon_ready: HashMap<u32, Duration>,
+    pub latencies: HashMap<u32, Duration>,
+    pub killers: HashMap<u32, u32>,
+    // Time left until each deflector drops, and until it can be raised again.
+    pub deflector_until: HashMap<u32, Duration>,
+    pub deflector_ready: HashMap<u32, Duration>,
+    pub rewound_hits: Vec<(u32, u32)>,
+    pub history_frames: usize,
+    pub streaks: HashMap<u32, u32>,
+    // Time left until each player's marker disappears.
+    pub marker_until: HashMap<u32, Duration>,
+    // Time left on each player's item effects.
+    pub effect_until: HashMap<u32, Vec<(ItemKind, Duration)>>,
+    pub item_spawn_progress: f32,
+    pub phase: MatchPhase,
+    // Time left until the next phase.
+    pub phase_remaining: Option<Duration>,
+    pub match_info: MatchInfo,
 }
 
 pub struct Game {
     config: GameConfig,
     pub state: GameState,
-    rng: rand::rngs::ThreadRng,
+    pub match_info: MatchInfo,
+    rng: Pcg32,
+    clock: GameClock,
     bullet_id_counter: u32,
-    survival_times: HashMap<u32, Instant>,
+    survival_times: HashMap<u32, SystemTime>,
+    // Token bucket per player, each shot costs one token.
+    fire_tokens: HashMap<u32, f32>,
+    // When each player's weapon can fire again.
+    weapon_ready: HashMap<u32, SystemTime>,
+    // Recent ticks, newest last, for lag compensation.
+    history: VecDeque<HistoryFrame>,
+    // Round trip time measured by each player's connection.
+    latencies: HashMap<u32, Duration>,
+    // (shooter, target) hits found by rewinding, applied on the next tick.
+    rewound_hits: Vec<(u32, u32)>,
+    // Who shot each dead player, so they respawn away from them.
+    killers: HashMap<u32, u32>,
+    // When each raised deflector drops, and when it can be raised again.
+    deflector_until: HashMap<u32, SystemTime>,
+    deflector_ready: HashMap<u32, SystemTime>,
+    // When each player's marker disappears.
+    marker_until: HashMap<u32, SystemTime>,
+    plugins: Vec<Box<dyn GamePlugin>>,
+    // Commands dropped since the last tick, for telling the players.
+    throttled: Vec<(u32, ThrottleNotice)>,
+    scoring: ScoringEngine,
+    obstacle_id_counter: u32,
+    item_id_counter: u32,
+    // Builds up with the spawn rate every tick, an item spawns for every whole one.
+    item_spawn_progress: f32,
+    // When each of the players' item effects wears off.
+    effect_until: HashMap<u32, Vec<(ItemKind, SystemTime)>>,
+    phase: MatchPhase,
+    // When the current phase is over, unless it waits for players.
+    phase_until: Option<SystemTime>,
+    // Phases entered since the last call to `take_phase_changes`.
+    phase_changes: Vec<PhaseChange>,
+    // Kills on the last tick.
+    events: Vec<GameEvent>,
 }
 
 impl Game {
     pub fn new(config: GameConfig) -> Self {
+        Self::with_seed(config, rand::random(), GameClock::starting_at(SystemTime::now()))
+    }
+
+    /// A game whose outcome only depends on the seed and the inputs it's fed.
+    pub fn with_seed(config: GameConfig, seed: u64, clock: GameClock) -> Self {
+        let obstacles: Vec<ObstacleState> =
+            config.obstacles.iter().enumerate().map(|(id, obstacle)| obstacle.to_state(id as u32)).collect();
+
+        let team_scoreboard = match config.game_mode {
+            GameMode::Teams => (0..team_count(&config)).map(|team| (team, 0)).collect(),
+            GameMode::FreeForAll => HashMap::new(),
+        };
+
         Self {
-            state: GameState::new((config.bound_x, config.bound_y)),
-            rng: Default::default(),
+            state: GameState { obstacles, team_scoreboard, ..GameState::new((config.bound_x, config.bound_y)) },
+            match_info: MatchInfo::new(&config, clock.now()),
+            rng: Pcg32::seed_from_u64(seed),
+            clock,
             bullet_id_counter: 0,
             survival_times: HashMap::new(),
+            fire_tokens: HashMap::new(),
+            weapon_ready: HashMap::new(),
+            history: VecDeque::new(),
+            latencies: HashMap::new(),
+            rewound_hits: Vec::new(),
+            killers: HashMap::new(),
+            deflector_until: HashMap::new(),
+            deflector_ready: HashMap::new(),
+            marker_until: HashMap::new(),
+            plugins: Vec::new(),
+            throttled: Vec::new(),
+            scoring: ScoringEngine::new(config.scoring.clone()),
+            obstacle_id_counter: config.obstacles.len() as u32,
+            item_id_counter: 0,
+            item_spawn_progress: 0.0,
+            effect_until: HashMap::new(),
+            phase: if config.rounds.enabled { MatchPhase::Lobby } else { MatchPhase::Playing },
+            phase_until: None,
+            phase_changes: Vec::new(),
+            events: Vec::new(),
             config,
         }
     }
 
+    pub fn restore(config: GameConfig, snapshot: GameSnapshot) -> Self {
+        let mut game = Game::new(config);
+        let now = game.clock.now();
+
+        game.state = GameState { bounds: game.bounds(), ..snapshot.state };
+        if let Some(match_info) = snapshot.match_info {
+            game.match_info = match_info;
+        }
+        game.bullet_id_counter = snapshot.bullet_id_counter;
+        game.obstacle_id_counter =
+            game.state.obstacles.iter().map(|obstacle| obstacle.id + 1).max().unwrap_or(0);
+        game.item_id_counter = game.state.items.iter().map(|item| item.id + 1).max().unwrap_or(0);
+        game.survival_times = snapshot
+            .survival_times
+            .into_iter()
+            .map(|(player_id, remaining)| (player_id, now + remaining))
+            .collect();
+        for dead in &mut game.state.dead {
+            if let Some(remaining) = snapshot.respawn_times.get(&dead.player.id) {
+                dead.respawn = now + *remaining;
+            }
+        }
+
+        game
+    }
+
+    pub fn snapshot(&self) -> GameSnapshot {
+        let now = self.clock.now();
+
+        GameSnapshot {
+            state: self.state.clone(),
+            match_info: Some(self.match_info.clone()),
+            bullet_id_counter: self.bullet_id_counter,
+            survival_times: self
+                .survival_times
+                .iter()
+                .map(|(player_id, next_reward_time)| {
+                    (*player_id, next_reward_time.duration_since(now).unwrap_or_default())
+                })
+                .collect(),
+            respawn_times: self
+                .state
+                .dead
+                .iter()
+                .map(|dead| (dead.player.id, dead.respawn.duration_since(now).unwrap_or_default()))
+                .collect(),
+        }
+    }
+
+    pub fn debug_state(&self) -> GameDebugState {
+        let now = self.clock.now();
+        let remaining = |times: &HashMap<u32, SystemTime>| {
+            times
+                .iter()
+                .map(|(player_id, time)| (*player_id, time.duration_since(now).unwrap_or_default()))
+                .collect()
+        };
+
+        GameDebugState {
+            state: self.state.clone(),
+            game_time: self.clock.elapsed,
+            bullet_id_counter: self.bullet_id_counter,
+            survival_times: remaining(&self.survival_times),
+            fire_tokens: self.fire_tokens.clone(),
+            weapon_ready: remaining(&self.weapon_ready),
+            latencies: self.latencies.clone(),
+            killers: self.killers.clone(),
+            deflector_until: remaining(&self.deflector_until),
+            deflector_ready: remaining(&self.deflector_ready),
+            rewound_hits: self.rewound_hits.clone(),
+            history_frames: self.history.len(),
+            streaks: self.scoring.streaks().clone(),
+            marker_until: remaining(&self.marker_until),
+            effect_until: self
+                .effect_until
+                .iter()
+                .map(|(player_id, effects)| {
+                    let effects = effects
+                        .iter()
+                        .map(|(kind, until)| (*kind, until.duration_since(now).unwrap_or_default()))
+                        .collect();
+                    (*player_id, effects)
+                })
+                .collect(),
+            item_spawn_progress: self.item_spawn_progress,
+            phase: self.phase,
+            phase_remaining: self.phase_until.map(|until| until.duration_since(now).unwrap_or_default()),
+            match_info: self.match_info.clone(),
+        }
+    }
+
+    pub fn register_plugin(&mut self, plugin: Box<dyn GamePlugin>) {
+        self.plugins.push(plugin);
+    }
+
     pub fn reset(&mut self) {
-        let mut new = Game::new(self.config);
+        self.end_match();
+        self.restart();
+    }
+
+    /// Tells the plugins the match is over, unless it already was.
+    fn end_match(&mut self) {
+        if self.match_info.ended_at.is_some() {
+            return;
+        }
+
+        self.match_info.end(self.clock.now());
+        for plugin in &mut self.plugins {
+            plugin.on_match_end(&self.match_info, &self.state);
+        }
+    }
+
+    /// Starts a new match with the same players, back in the lobby if the
+    /// game is played in rounds.
+    fn restart(&mut self) {
+        // Keep the RNG and clock going so a reset replays deterministically.
+        let mut new = Game::new(self.config.clone());
+        new.rng = self.rng.clone();
+        new.clock = self.clock;
+        new.match_info = MatchInfo::new(&self.config, self.clock.now());
+        new.plugins = std::mem::take(&mut self.plugins);
+        // The layout of the arena survives a reset.
+        new.state.obstacles = std::mem::take(&mut self.state.obstacles);
+        new.obstacle_id_counter = self.obstacle_id_counter;
+        // Clients go by the tick and their acks, which have to keep counting up.
+        new.state.tick = self.state.tick;
+        new.state.acks = std::mem::take(&mut self.state.acks);
         for player in self.state.players.iter() {
-            new.add_player(player.id);
+            new.add_player(player.id, player.team);
         }
         for corpse in self.state.dead.iter() {
-            new.add_player(corpse.player.id);
+            new.add_player(corpse.player.id, corpse.player.team);
+        }
+        new.phase_changes = std::mem::take(&mut self.phase_changes);
+        *self = new;
+
+        if self.config.rounds.enabled {
+            self.enter_phase(MatchPhase::Lobby, None);
+        }
+    }
+
+    /// Moves the round along: from the lobby to the countdown once there are
+    /// enough players, then playing for the round's duration, then the
+    /// results until the next match starts.
+    fn advance_phase(&mut self, now: SystemTime) {
+        let rounds = self.config.rounds.clone();
+        let players = self.state.players.len() + self.state.dead.len();
+        let over = self.phase_until.is_some_and(|until| until <= now);
+
+        match self.phase {
+            MatchPhase::Lobby if players >= rounds.min_players => {
+                self.enter_phase(MatchPhase::Countdown, Some(Duration::from_secs_f32(rounds.countdown_secs)));
+            },
+            MatchPhase::Countdown if players < rounds.min_players => self.enter_phase(MatchPhase::Lobby, None),
+            MatchPhase::Countdown if over => {
+                // Nobody earned anything waiting.
+                for next_reward_time in self.survival_times.values_mut() {
+                    *next_reward_time = now + Duration::from_secs(SURVIVAL_TIMEOUT);
+                }
+                self.enter_phase(MatchPhase::Playing, Some(Duration::from_secs_f32(rounds.duration_secs)));
+                self.events.push(GameEvent::RoundStarted);
+            },
+            MatchPhase::Playing if over => {
+                self.end_match();
+                self.enter_phase(MatchPhase::Results, Some(Duration::from_secs_f32(rounds.results_secs)));
+                self.events.push(GameEvent::RoundEnded { standings: standings(&self.state.scoreboard) });
+            },
+            MatchPhase::Results if over => self.restart(),
+            _ => {},
         }
-        let _ = std::mem::replace(self, new);
+    }
+
+    fn enter_phase(&mut self, phase: MatchPhase, duration: Option<Duration>) {
+        self.phase = phase;
+        self.phase_until = duration.map(|duration| self.clock.now() + duration);
+
+        let (standings, team_standings) = if phase == MatchPhase::Results {
+            (standings(&self.state.scoreboard), standings(&self.state.team_scoreboard))
+        } else {
+            (vec![], vec![])
+        };
+        self.phase_changes.push(PhaseChange {
+            phase,
+            remaining_ms: duration.map(|duration| duration.as_millis() as u64),
+            standings,
+            team_standings,
+        });
+    }
+
+    /// Takes the phases entered since the last call.
+    pub fn take_phase_changes(&mut self) -> Vec<PhaseChange> {
+        std::mem::take(&mut self.phase_changes)
+    }
+
+    /// Takes what happened during the last tick.
+    pub fn take_events(&mut self) -> Vec<GameEvent> {
+        std::mem::take(&mut self.events)
     }
 
     fn bounds(&self) -> (f32, f32) {
         (self.config.bound_x, self.config.bound_y)
     }
 
-    pub fn add_player(&mut self, player_id: u32) {
+    /// Adds a player, on the team they asked for in the teams mode if there's
+    /// such a team.
+    pub fn add_player(&mut self, player_id: u32, team: Option<u32>) {
         let mut player = PlayerState::new(player_id);
-        let bounds = self.bounds();
-        player.randomize(&mut self.rng, bounds);
+        player.team = self.assign_team(team);
+        self.place_safely(&mut player, None);
         self.state.players.push(player);
-        self.survival_times.insert(player_id, Instant::now() + Duration::from_secs(SURVIVAL_TIMEOUT));
+        self.survival_times.insert(player_id, self.clock.now() + Duration::from_secs(SURVIVAL_TIMEOUT));
+        self.fire_tokens.insert(player_id, self.config.fire_burst);
+        self.match_info.add_participant(player_id);
+
+        for plugin in &mut self.plugins {
+            plugin.on_join(player_id, &mut self.state);
+        }
+        for plugin in &mut self.plugins {
+            plugin.on_spawn(player_id, &mut self.state);
+        }
+    }
+
+    /// The requested team if there's one like it, otherwise the one with the
+    /// fewest players. Nobody is on a team outside the teams mode.
+    fn assign_team(&self, requested: Option<u32>) -> Option<u32> {
+        if self.config.game_mode != GameMode::Teams {
+            return None;
+        }
+
+        let teams = team_count(&self.config);
+        if let Some(team) = requested.filter(|team| *team < teams) {
+            return Some(team);
+        }
+
+        let mut sizes = vec![0; teams as usize];
+        let everyone = self.state.players.iter().chain(self.state.dead.iter().map(|corpse| &corpse.player));
+        for team in everyone.filter_map(|player| player.team) {
+            if let Some(size) = sizes.get_mut(team as usize) {
+                *size += 1;
+            }
+        }
+        (0..teams).min_by_key(|team| sizes[*team as usize])
+    }
+
+    /// Moves the player to a random spot clear of other players and bullets,
+    /// and in the far half of the arena from `avoid` if given. Gives up after a
+    /// few tries and takes the roomiest spot it found.
+    fn place_safely(&mut self, player: &mut PlayerState, avoid: Option<(f32, f32)>) {
+        let bounds = self.bounds();
+        let far_cells = avoid.map(|avoid| self.far_cells(avoid)).unwrap_or_default();
+        let (cell_width, cell_height) =
+            (bounds.0 / SPAWN_GRID_SIZE as f32, bounds.1 / SPAWN_GRID_SIZE as f32);
+        let mut best: Option<(f32, PlayerState)> = None;
+
+        for _ in 0..SPAWN_ATTEMPTS {
+            player.randomize(&mut self.rng, bounds);
+            if !far_cells.is_empty() {
+                let (cell_x, cell_y) = far_cells[self.rng.gen_range(0, far_cells.len())];
+                player.x = (cell_x + self.rng.gen_range(0.0, cell_width)).min(bounds.0 - PLAYER_RADIUS);
+                player.y = (cell_y + self.rng.gen_range(0.0, cell_height)).min(bounds.1 - PLAYER_RADIUS);
+            }
+
+            let clearance = self.spawn_clearance(player);
+            if clearance >= 1.0 {
+                return;
+            }
+            if best.as_ref().is_none_or(|(best_clearance, _)| clearance > *best_clearance) {
+                best = Some((clearance, player.clone()));
+            }
+        }
+
+        if let Some((_, best)) = best {
+            *player = best;
+        }
+    }
+
+    /// The top-left corners of the spawn grid cells in the far half from `avoid`.
+    fn far_cells(&self, (avoid_x, avoid_y): (f32, f32)) -> Vec<(f32, f32)> {
+        let (bound_x, bound_y) = self.bounds();
+        let (cell_width, cell_height) =
+            (bound_x / SPAWN_GRID_SIZE as f32, bound_y / SPAWN_GRID_SIZE as f32);
+
+        let mut cells: Vec<(f32, (f32, f32))> = (0..SPAWN_GRID_SIZE * SPAWN_GRID_SIZE)
+            .map(|i| {
+                let corner = ((i % SPAWN_GRID_SIZE) as f32 * cell_width, (i / SPAWN_GRID_SIZE) as f32 * cell_height);
+                let center = (corner.0 + cell_width / 2.0, corner.1 + cell_height / 2.0);
+                let distance = ((center.0 - avoid_x).powi(2) + (center.1 - avoid_y).powi(2)).sqrt();
+                (distance, corner)
+            })
+            .collect();
+
+        cells.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+        cells.truncate(cells.len() / 2);
+        cells.into_iter().map(|(_, corner)| corner).collect()
+    }
+
+    /// How close the nearest threat is, relative to how far away it should be.
+    /// Anything at or above 1.0 is a safe spawn.
+    fn spawn_clearance(&self, spot: &PlayerState) -> f32 {
+        let distance = |x: f32, y: f32| ((x - spot.x).powi(2) + (y - spot.y).powi(2)).sqrt();
+
+        let players = self
+            .state
+            .players
+            .iter()
+            .filter(|other| other.id != spot.id)
+            .map(|other| distance(other.x, other.y) / SPAWN_PLAYER_CLEARANCE);
+        let bullets =
+            self.state.bullets.iter().map(|bullet| distance(bullet.x, bullet.y) / SPAWN_BULLET_CLEARANCE);
+
+        let obstacles = self.state.obstacles.iter().map(|obstacle| {
+            let (x, y) = obstacle.closest_point(spot.x, spot.y);
+            (distance(x, y) - PLAYER_RADIUS).max(0.0) / SPAWN_OBSTACLE_CLEARANCE
+        });
+
+        players.chain(bullets).chain(obstacles).fold(f32::INFINITY, f32::min)
+    }
+
+    /// Returns the id of the obstacle the edit applied to, or `None` if there
+    /// was no obstacle to move or remove.
+    pub fn edit_obstacle(&mut self, edit: ObstacleEdit) -> Option<u32> {
+        match edit {
+            ObstacleEdit::Add { x, y, width, height } => {
+                let id = self.obstacle_id_counter;
+                self.obstacle_id_counter += 1;
+                self.state.obstacles.push(ObstacleState { id, x, y, width, height, shape: ObstacleShape::Rect });
+                Some(id)
+            },
+            ObstacleEdit::Move { id, x, y } => {
+                let obstacle = self.state.obstacles.iter_mut().find(|obstacle| obstacle.id == id)?;
+                obstacle.x = x;
+                obstacle.y = y;
+                Some(id)
+            },
+            ObstacleEdit::Remove { id } => {
+                let idx = self.state.obstacles.iter().position(|obstacle| obstacle.id == id)?;
+                self.state.obstacles.remove(idx);
+                Some(id)
+            },
+        }
+    }
+
+    /// Changes the config of the running game. Players outside shrunk bounds
+    /// are moved somewhere random inside them, items outside them go away.
+    pub fn configure(&mut self, change: &ConfigChange) {
+        change.apply_to(&mut self.config);
+        let bounds = self.bounds();
+        self.state.bounds = bounds;
+
+        let outside = |x: f32, y: f32| x > bounds.0 - PLAYER_RADIUS || y > bounds.1 - PLAYER_RADIUS;
+        let players = self.state.players.iter_mut().chain(self.state.dead.iter_mut().map(|corpse| &mut corpse.player));
+        for player in players {
+            if outside(player.x, player.y) {
+                player.randomize(&mut self.rng, bounds);
+            }
+        }
+        self.state.items.retain(|item| item.x < bounds.0 && item.y < bounds.1);
+        for tokens in self.fire_tokens.values_mut() {
+            *tokens = tokens.min(self.config.fire_burst);
+        }
+    }
+
+    /// Carries over a score saved from an earlier run.
+    pub fn restore_score(&mut self, player_id: u32, score: u32) {
+        let entry = self.state.scoreboard.entry(player_id).or_default();
+        *entry = (*entry).max(score);
     }
 
     pub fn player_left(&mut self, player_id: u32) {
@@ -121,11 +855,41 @@ impl Game {
         }
 
         self.survival_times.remove(&player_id);
+        self.fire_tokens.remove(&player_id);
+        self.weapon_ready.remove(&player_id);
+        self.latencies.remove(&player_id);
+        self.state.acks.remove(&player_id);
+        self.killers.remove(&player_id);
+        self.deflector_until.remove(&player_id);
+        self.deflector_ready.remove(&player_id);
+        self.scoring.player_left(player_id);
+        self.marker_until.remove(&player_id);
+        self.state.markers.retain(|marker| marker.player_id != player_id);
+        self.effect_until.remove(&player_id);
+    }
+
+    /// Records that the player's numbered command takes effect on the coming
+    /// tick, or that it was dropped.
+    pub fn ack(&mut self, player_id: u32, seq: u32, dropped: bool) {
+        self.state.acks.insert(player_id, CommandAck { seq, tick: self.state.tick + 1, dropped });
+    }
+
+    pub fn set_latency(&mut self, player_id: u32, rtt: Duration) {
+        self.latencies.insert(player_id, rtt);
     }
 
-    pub fn handle_cmd(&mut self, player_id: u32, cmd: GameCommand) {
+    /// Returns false if the command was dropped: between rounds, while the
+    /// player is dead, or when firing or deflecting isn't possible right now.
+    pub fn handle_cmd(&mut self, player_id: u32, cmd: GameCommand) -> bool {
         // info!("Player {} sent command {:#?}", player_id, cmd);
 
+        // Hands off the controls between rounds.
+        if self.phase != MatchPhase::Playing {
+            return false;
+        }
+
+        let mut fired = vec![];
+
         if let Some(player) = self.state.players.iter_mut().find(|p| p.id == player_id) {
             match cmd {
                 GameCommand::Rotate(angle) => {
@@ -137,7 +901,59 @@ impl Game {
 
                     player.throttle = throttle;
                 },
+                GameCommand::Deflect => {
+                    let now = self.clock.now();
+                    match self.deflector_ready.get(&player.id) {
+                        Some(ready) if *ready > now => {
+                            let retry_after = ready.duration_since(now).unwrap_or_default();
+                            self.throttled.push((player.id, ThrottleNotice::new(ThrottleReason::Deflect, retry_after)));
+                            return false;
+                        },
+                        _ => {
+                            player.deflector = true;
+                            self.deflector_until.insert(player.id, now + DEFLECTOR_DURATION);
+                            self.deflector_ready.insert(player.id, now + DEFLECTOR_COOLDOWN);
+                        },
+                    }
+                },
+                // Relayed by the game actor, they don't touch the simulation.
+                GameCommand::Emote(_) | GameCommand::Chat { .. } => {},
+                GameCommand::Marker(x, y) => {
+                    let (x, y) = (x.max(0.0).min(self.config.bound_x), y.max(0.0).min(self.config.bound_y));
+                    self.state.markers.retain(|marker| marker.player_id != player_id);
+                    self.state.markers.push(MarkerState { player_id, x, y });
+                    self.marker_until.insert(player_id, self.clock.now() + MARKER_DURATION);
+                },
+                GameCommand::SwitchWeapon(weapon) => {
+                    player.weapon = weapon;
+                },
                 GameCommand::Fire => {
+                    let now = self.clock.now();
+                    if player.overheated {
+                        // Without a cooling rate the ship never cools down.
+                        let cooling_rate = self.config.weapons.cooling_rate;
+                        if cooling_rate > 0.0 {
+                            let retry_after = Duration::from_secs_f32(player.heat / cooling_rate);
+                            self.throttled.push((player.id, ThrottleNotice::new(ThrottleReason::Overheated, retry_after)));
+                        }
+                        return false;
+                    }
+                    if let Some(ready) = self.weapon_ready.get(&player.id).filter(|ready| **ready > now) {
+                        let retry_after = ready.duration_since(now).unwrap_or_default();
+                        self.throttled.push((player.id, ThrottleNotice::new(ThrottleReason::Cooldown, retry_after)));
+                        return false;
+                    }
+
+                    let tokens = self.fire_tokens.entry(player.id).or_insert(self.config.fire_burst);
+                    if *tokens < 1.0 {
+                        // Without a fire rate the tokens never come back.
+                        if self.config.fire_rate > 0.0 {
+                            let retry_after = Duration::from_secs_f32((1.0 - *tokens) / self.config.fire_rate);
+                            self.throttled.push((player.id, ThrottleNotice::new(ThrottleReason::Fire, retry_after)));
+                        }
+                        return false;
+                    }
+
                     let active_bullets = self
                         .state
                         .bullets
@@ -145,42 +961,311 @@ impl Game {
                         .filter(|bullet| bullet.player_id == player.id)
                         .count();
 
-                    if active_bullets < MAX_CONCURRENT_BULLETS {
+                    let max_bullets = if player.has_effect(ItemKind::ExtraBullet) {
+                        self.config.max_bullets + 1
+                    } else {
+                        self.config.max_bullets
+                    };
+
+                    if active_bullets >= max_bullets {
+                        return false;
+                    }
+
+                    *tokens -= 1.0;
+
+                    let weapons = &self.config.weapons;
+                    let cooldown = Duration::from_secs_f32(weapons.cooldown(player.weapon).max(0.0));
+                    self.weapon_ready.insert(player.id, now + cooldown);
+                    player.heat = (player.heat + weapons.heat(player.weapon)).min(1.0);
+                    player.overheated = player.heat >= 1.0;
+
+                    // A spread shot fans out evenly around the heading, as far as there's room for bullets.
+                    let angles: Vec<f32> = match player.weapon {
+                        Weapon::Standard => vec![player.angle],
+                        Weapon::Spread => {
+                            let count = weapons.spread_bullets.max(1) as usize;
+                            let first = -(count as f32 - 1.0) / 2.0;
+                            (0..count)
+                                .map(|i| player.angle + (first + i as f32) * weapons.spread_angle)
+                                .take(max_bullets - active_bullets)
+                                .collect()
+                        },
+                    };
+
+                    for angle in angles {
                         let bullet_id = self.bullet_id_counter;
                         self.bullet_id_counter = self.bullet_id_counter.wrapping_add(1);
 
                         let distance_from_player: f32 = 5.0;
-                        let (bullet_x, bullet_y) = angle_to_vector(player.angle);
+                        let (bullet_x, bullet_y) = angle_to_vector(angle);
 
-                        self.state.bullets.push(BulletState {
+                        fired.push(BulletState {
                             id: bullet_id,
                             player_id: player.id,
-                            angle: player.angle,
+                            angle,
                             x: player.x + (bullet_x * distance_from_player),
                             y: player.y + (bullet_y * distance_from_player),
+                            team: if self.config.friendly_fire { None } else { player.team },
                         });
                     }
                 },
             }
+        } else {
+            return false;
+        }
+
+        for bullet in fired {
+            for plugin in &mut self.plugins {
+                plugin.on_fire(&bullet, &mut self.state);
+            }
+            let bullet = if self.config.lag_compensation { self.rewind_bullet(bullet) } else { Some(bullet) };
+            self.state.bullets.extend(bullet);
+        }
+        true
+    }
+
+    /// Replays a new bullet against where the other players were when the
+    /// shooter saw them, one round trip ago. Returns the bullet if it's still
+    /// in flight, or `None` if it hit someone on the way.
+    fn rewind_bullet(&mut self, mut bullet: BulletState) -> Option<BulletState> {
+        let mut rewind = self.latencies.get(&bullet.player_id).cloned().unwrap_or_default().min(MAX_REWIND);
+
+        // The shooter's own position isn't rewound, the bullet starts where they are now.
+        let mut frames = 0;
+        for frame in self.history.iter().rev() {
+            let frame_duration = Duration::from_secs_f32(frame.dt);
+            if rewind < frame_duration {
+                break;
+            }
+            rewind -= frame_duration;
+            frames += 1;
+        }
+
+        for frame in self.history.iter().skip(self.history.len() - frames) {
+            let (vel_x, vel_y) = angle_to_vector(bullet.angle);
+            bullet.x += vel_x * BULLET_SPEED * frame.dt;
+            bullet.y += vel_y * BULLET_SPEED * frame.dt;
+
+            if self.config.reflect_bullets {
+                for obstacle in &self.state.obstacles {
+                    bounce_off(&mut bullet, obstacle);
+                }
+            } else if self.state.obstacles.iter().any(|obstacle| hits_obstacle(&bullet, obstacle)) {
+                return None;
+            }
+
+            // Deflections aren't rewound, the bullet just flies on.
+            let target = frame.players.iter().find(|player| !player.deflector && player.collides_with(&bullet));
+            if let Some(target) = target {
+                self.rewound_hits.push((bullet.player_id, target.id));
+                return None;
+            }
+        }
+
+        Some(bullet)
+    }
+
+    /// Spawns as many items as the spawn rate earned this tick, at random
+    /// spots clear of the walls.
+    fn spawn_items(&mut self, dt: f32) {
+        self.item_spawn_progress += self.config.item_spawn_rate * dt;
+
+        let kinds = self.config.item_weights.kinds();
+        let total_weight: u32 = kinds.iter().map(|(_, weight)| weight).sum();
+        if total_weight == 0 {
+            self.item_spawn_progress = 0.0;
+            return;
+        }
+
+        while self.item_spawn_progress >= 1.0 {
+            self.item_spawn_progress -= 1.0;
+            if self.state.items.len() >= self.config.max_items {
+                continue;
+            }
+
+            let mut pick = self.rng.gen_range(0, total_weight);
+            let kind = kinds
+                .iter()
+                .find(|(_, weight)| {
+                    if pick < *weight {
+                        true
+                    } else {
+                        pick -= weight;
+                        false
+                    }
+                })
+                .map(|(kind, _)| *kind)
+                .unwrap_or(ItemKind::Shield);
+
+            let (bound_x, bound_y) = self.bounds();
+            let mut item = ItemState { id: self.item_id_counter, kind, x: 0.0, y: 0.0 };
+            for _ in 0..SPAWN_ATTEMPTS {
+                item.x = self.rng.gen_range(ITEM_RADIUS, bound_x - ITEM_RADIUS);
+                item.y = self.rng.gen_range(ITEM_RADIUS, bound_y - ITEM_RADIUS);
+                if !self.state.obstacles.iter().any(|obstacle| hits_obstacle(&item, obstacle)) {
+                    self.item_id_counter = self.item_id_counter.wrapping_add(1);
+                    self.state.items.push(item);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Hands each item to the first player touching it.
+    fn pick_up_items(&mut self, now: SystemTime) {
+        let grid = SpatialGrid::new(&self.state.players);
+        let players = &mut self.state.players;
+        let effect_until = &mut self.effect_until;
+        let events = &mut self.events;
+        let mut pickups = Vec::new();
+
+        self.state.items.retain(|item| {
+            let picker = grid.candidates(item).into_iter().find(|index| players[*index].collides_with(item));
+            let player = match picker {
+                Some(index) => &mut players[index],
+                None => return true,
+            };
+
+            grant_effect(player, effect_until, item.kind, now);
+            events.push(GameEvent::ItemPickup { player_id: player.id, item_id: item.id, kind: item.kind });
+            pickups.push(Pickup { player_id: player.id, kind: item.kind });
+            false
+        });
+
+        for pickup in &pickups {
+            for plugin in &mut self.plugins {
+                plugin.on_pickup(pickup, &mut self.state);
+            }
+        }
+    }
+
+    /// Hands out the effects the plugins asked for, to players who are alive.
+    fn grant_plugin_effects(&mut self, now: SystemTime) {
+        let granted: Vec<(u32, ItemKind)> = self.plugins.iter_mut().flat_map(|plugin| plugin.take_effects()).collect();
+        for (player_id, kind) in granted {
+            if let Some(player) = self.state.players.iter_mut().find(|player| player.id == player_id) {
+                grant_effect(player, &mut self.effect_until, kind, now);
+            }
+        }
+    }
+
+    /// The shield took a bullet for the player and is used up.
+    fn break_shield(&mut self, index: usize) {
+        let player = &mut self.state.players[index];
+        player.effects.retain(|kind| *kind != ItemKind::Shield);
+        if let Some(effects) = self.effect_until.get_mut(&player.id) {
+            effects.retain(|(kind, _)| *kind != ItemKind::Shield);
         }
     }
 
     pub fn init(&mut self) {}
 
+    /// Takes the commands dropped for coming too soon since the last call.
+    pub fn take_throttled(&mut self) -> Vec<(u32, ThrottleNotice)> {
+        std::mem::take(&mut self.throttled)
+    }
+
     pub fn tick(&mut self, dt: f32) {
+        // Nobody asked for them, e.g. when verifying a replay.
+        self.throttled.clear();
+        self.events.clear();
+        self.state.tick += 1;
+        self.clock.advance(dt);
+        let now = self.clock.now();
+        self.state.time_ms = now.duration_since(UNIX_EPOCH).map(|since| since.as_millis() as u64).unwrap_or_default();
+
+        if self.config.rounds.enabled {
+            self.advance_phase(now);
+        }
+        // Everything stands still outside of a round.
+        if self.phase != MatchPhase::Playing {
+            self.state.checksum = self.state.compute_checksum();
+            return;
+        }
+
         // Revive the dead
-        let now = SystemTime::now();
-        let revived = self
+        let revived: Vec<PlayerState> = self
             .state
             .dead
-            .drain_filter(|corpse| corpse.respawn <= now)
+            .extract_if(.., |corpse| corpse.respawn <= now)
             .map(|dead| dead.player)
-            .map(|player| {
-                println!("revived player {}", player.id);
-                player
-            });
+            .collect();
 
-        self.state.players.extend(revived);
+        // One at a time, so revived players keep clear of each other too.
+        for mut player in revived {
+            info!("revived player {}", player.id);
+            player.deflector = false;
+            player.effects.clear();
+            player.heat = 0.0;
+            player.overheated = false;
+            self.effect_until.remove(&player.id);
+            let killer = self.killers.remove(&player.id);
+            let avoid = self
+                .state
+                .players
+                .iter()
+                .find(|other| Some(other.id) == killer)
+                .map(|killer| (killer.x, killer.y));
+            self.place_safely(&mut player, avoid);
+            self.events.push(GameEvent::Respawn { player_id: player.id, x: player.x, y: player.y });
+            let player_id = player.id;
+            self.state.players.push(player);
+
+            for plugin in &mut self.plugins {
+                plugin.on_spawn(player_id, &mut self.state);
+            }
+        }
+
+        // Drop deflectors that ran out
+        for player in &mut self.state.players {
+            if player.deflector && self.deflector_until.get(&player.id).is_none_or(|until| *until <= now) {
+                player.deflector = false;
+            }
+        }
+
+        // Drop item effects that wore off
+        let effect_until = &mut self.effect_until;
+        for player in &mut self.state.players {
+            let effects = effect_until.entry(player.id).or_default();
+            effects.retain(|(_, until)| *until > now);
+            player.effects.retain(|kind| effects.iter().any(|(effect, _)| effect == kind));
+            if effects.is_empty() {
+                effect_until.remove(&player.id);
+            }
+        }
+
+        // Drop markers that ran out
+        let marker_until = &mut self.marker_until;
+        self.state.markers.retain(|marker| {
+            let alive = marker_until.get(&marker.player_id).is_some_and(|until| *until > now);
+            if !alive {
+                marker_until.remove(&marker.player_id);
+            }
+            alive
+        });
+
+        // Refill the fire rate buckets
+        let (fire_rate, fire_burst) = (self.config.fire_rate, self.config.fire_burst);
+        let rapid_firing: HashSet<u32> = self
+            .state
+            .players
+            .iter()
+            .filter(|player| player.has_effect(ItemKind::RapidFire))
+            .map(|player| player.id)
+            .collect();
+        for (player_id, tokens) in &mut self.fire_tokens {
+            let rate = if rapid_firing.contains(player_id) { fire_rate * RAPID_FIRE_MULTIPLIER } else { fire_rate };
+            *tokens = (*tokens + rate * dt).min(fire_burst);
+        }
+
+        // Cool the weapons down
+        let cooling = self.config.weapons.cooling_rate * dt;
+        for player in &mut self.state.players {
+            player.heat = (player.heat - cooling).max(0.0);
+            if player.heat == 0.0 {
+                player.overheated = false;
+            }
+        }
 
         // Advance bullets
         for bullet in &mut self.state.bullets {
@@ -193,31 +1278,57 @@ impl Game {
         for player in &mut self.state.players {
             // Move the player
             let (vel_x, vel_y) = angle_to_vector(player.angle);
+            let speed = if player.has_effect(ItemKind::SpeedBoost) {
+                PLAYER_BASE_SPEED * SPEED_BOOST_MULTIPLIER
+            } else {
+                PLAYER_BASE_SPEED
+            };
 
-            player.x += vel_x * PLAYER_BASE_SPEED * player.throttle * dt;
-            player.y += vel_y * PLAYER_BASE_SPEED * player.throttle * dt;
+            player.x += vel_x * speed * player.throttle * dt;
+            player.y += vel_y * speed * player.throttle * dt;
+
+            // Walls block the way
+            for obstacle in &self.state.obstacles {
+                push_out_of(player, obstacle);
+            }
 
             // Keep the players in bounds
             player.x = player.x.max(PLAYER_RADIUS).min(self.config.bound_x - PLAYER_RADIUS);
             player.y = player.y.max(PLAYER_RADIUS).min(self.config.bound_y - PLAYER_RADIUS);
         }
 
+        self.spawn_items(dt);
+        self.pick_up_items(now);
+
         let bounds = self.bounds();
         let bound_x = bounds.0;
         let bound_y = bounds.1;
 
-        // Remove out-of-bound bullets
+        // Bullets that hit a wall bounce off, if they're set to
+        let reflect_bullets = self.config.reflect_bullets;
+        let obstacles = &self.state.obstacles;
+        if reflect_bullets {
+            for bullet in &mut self.state.bullets {
+                for obstacle in obstacles {
+                    bounce_off(bullet, obstacle);
+                }
+            }
+        }
+
+        // Remove out-of-bound bullets, and the ones that hit a wall
         self.state.bullets.retain(|b| {
             b.x > (BULLET_RADIUS)
                 && b.x < (bound_x + BULLET_RADIUS)
                 && b.y > (BULLET_RADIUS)
                 && b.y < (bound_y + BULLET_RADIUS)
+                && (reflect_bullets || !obstacles.iter().any(|obstacle| hits_obstacle(b, obstacle)))
         });
 
+        let grid = SpatialGrid::new(&self.state.bullets);
         let mut colliding_buf = HashSet::new();
         for bullet in self.state.bullets.iter() {
-            for other in self.state.bullets.iter() {
-                if bullet.id != other.id && bullet.is_colliding(other) {
+            for other in grid.candidates(bullet).into_iter().map(|index| &self.state.bullets[index]) {
+                if bullet.id != other.id && bullet.collides_with(other) {
                     colliding_buf.insert(bullet.id);
                     colliding_buf.insert(other.id);
                 }
@@ -226,75 +1337,259 @@ impl Game {
         self.state.bullets.retain(|b| { !colliding_buf.contains(&b.id) });
 
         // count collisions
+        let grid = SpatialGrid::new(&self.state.players);
         let mut colliding_buf = HashSet::new();
         for player in &self.state.players {
-            for other in &self.state.players {
-                if player.id != other.id && player.is_colliding(other) {
+            for other in grid.candidates(player).into_iter().map(|index| &self.state.players[index]) {
+                if player.id != other.id && player.collides_with(other) {
                     colliding_buf.insert(player.id);
                     colliding_buf.insert(other.id);
                 }
             }
         }
 
-        for mut player in self.state.players.drain_filter(|player| colliding_buf.contains(&player.id)) {
+        for mut player in self.state.players.extract_if(.., |player| colliding_buf.contains(&player.id)) {
+            self.events.push(GameEvent::Death { player_id: player.id, x: player.x, y: player.y });
             player.randomize(&mut self.rng, bounds);
-            self.state
-                .dead
-                .push(DeadPlayer { respawn: SystemTime::now() + DEAD_PUNISH, player });
+            self.state.dead.push(DeadPlayer { respawn: now + DEAD_PUNISH, player });
+        }
+
+        // Deflectors bounce bullets off, and whoever deflected a bullet now owns it
+        let grid = SpatialGrid::new(&self.state.players);
+        let players = &self.state.players;
+        let friendly_fire = self.config.friendly_fire;
+        for bullet in &mut self.state.bullets {
+            let deflector = grid
+                .candidates(&*bullet)
+                .into_iter()
+                .map(|index| &players[index])
+                .find(|player| player.deflector && player.collides_with(&*bullet));
+
+            if let Some(player) = deflector {
+                bullet.angle = reflect(bullet.angle, normalize(bullet.x - player.x, bullet.y - player.y));
+                bullet.player_id = player.id;
+                if !friendly_fire {
+                    bullet.team = player.team;
+                }
+            }
         }
 
         // count the dead
-        let mut hits = vec![];
         let mut used_bullets = vec![];
+        let mut killed = vec![];
+        let mut kills = vec![];
         let bounds = self.bounds();
 
-        for bullet in &mut self.state.bullets {
-            let deceased = self.state.players.drain_filter(|player| {
-                if player.is_colliding(bullet) && bullet.player_id != player.id {
-                    println!(
-                        "Player {} killed player {} at ({}, {})",
-                        bullet.player_id, player.id, bullet.x, bullet.y
-                    );
-                    hits.push(bullet.player_id);
-                    used_bullets.push(bullet.id);
-
-                    true
-                } else {
-                    false
+        for (shooter_id, target_id) in std::mem::take(&mut self.rewound_hits) {
+            if let Some(idx) = self.state.players.iter().position(|p| p.id == target_id) {
+                if self.state.players[idx].has_effect(ItemKind::Shield) {
+                    self.break_shield(idx);
+                    continue;
+                }
+                let target = self.state.players.remove(idx);
+                self.killers.insert(target_id, shooter_id);
+                kills.push(Kill {
+                    killer: shooter_id,
+                    victim: target_id,
+                    x: target.x,
+                    y: target.y,
+                    lag_compensated: true,
+                });
+                killed.push(target);
+            }
+        }
+
+        // A player can only be hit by the first bullet that reaches them, and
+        // a shield only stops the first one.
+        let grid = SpatialGrid::new(&self.state.players);
+        let mut hit = vec![];
+        let mut shielded = vec![];
+        for bullet in &self.state.bullets {
+            for index in grid.candidates(bullet) {
+                let player = &self.state.players[index];
+                if hit.contains(&index) || shielded.contains(&index) || !player.collides_with(bullet) {
+                    continue;
                 }
-            });
-            for mut player in deceased {
-                // Reset their survival time bonus
-                self.survival_times.insert(player.id, Instant::now() + Duration::from_secs(SURVIVAL_TIMEOUT));
 
-                player.randomize(&mut self.rng, bounds);
-                self.state
-                    .dead
-                    .push(DeadPlayer { respawn: SystemTime::now() + DEAD_PUNISH, player });
+                used_bullets.push(bullet.id);
+                if player.has_effect(ItemKind::Shield) {
+                    shielded.push(index);
+                } else {
+                    hit.push(index);
+                    self.killers.insert(player.id, bullet.player_id);
+                    kills.push(Kill {
+                        killer: bullet.player_id,
+                        victim: player.id,
+                        x: bullet.x,
+                        y: bullet.y,
+                        lag_compensated: false,
+                    });
+                }
             }
         }
+        for index in shielded {
+            self.break_shield(index);
+        }
+        killed.extend(hit.iter().map(|index| self.state.players[*index].clone()));
+        let players = &mut self.state.players;
+        let mut index = 0;
+        players.retain(|_| {
+            index += 1;
+            !hit.contains(&(index - 1))
+        });
+
+        for mut player in killed {
+            // Reset their survival time bonus
+            self.survival_times.insert(player.id, now + Duration::from_secs(SURVIVAL_TIMEOUT));
+
+            player.randomize(&mut self.rng, bounds);
+            self.state.dead.push(DeadPlayer { respawn: now + DEAD_PUNISH, player });
+        }
 
         // Clear out used bullets
         self.state.bullets.retain(|b| !used_bullets.contains(&b.id));
 
-        // Update the scoreboard
-        for player_id in hits {
-            *self.state.scoreboard.entry(player_id).or_default() += 1;
+        // Update the scoreboard, nobody scores for shooting a teammate
+        let scores_before = self.state.scoreboard.clone();
+        let teams: HashMap<u32, u32> = self
+            .state
+            .players
+            .iter()
+            .chain(self.state.dead.iter().map(|corpse| &corpse.player))
+            .filter_map(|player| player.team.map(|team| (player.id, team)))
+            .collect();
+        for kill in &kills {
+            if teams.contains_key(&kill.killer) && teams.get(&kill.killer) == teams.get(&kill.victim) {
+                continue;
+            }
+
+            let distance = self
+                .state
+                .players
+                .iter()
+                .find(|player| player.id == kill.killer)
+                .map(|killer| ((killer.x - kill.x).powi(2) + (killer.y - kill.y).powi(2)).sqrt());
+            self.scoring.kill(&mut self.state.scoreboard, kill.killer, kill.victim, distance);
         }
 
+        for kill in &kills {
+            for plugin in &mut self.plugins {
+                plugin.on_kill(kill, &mut self.state);
+            }
+        }
+        self.events.extend(kills.iter().map(|kill| GameEvent::Kill {
+            killer: kill.killer,
+            victim: kill.victim,
+            x: kill.x,
+            y: kill.y,
+        }));
+
         // Reward players for staying alive
         for (player_id, next_reward_time) in &mut self.survival_times {
-            if *next_reward_time <= Instant::now() {
-                *self.state.scoreboard.entry(*player_id).or_default() += 1;
+            if *next_reward_time <= now {
+                self.scoring.survived(&mut self.state.scoreboard, *player_id);
+
+                *next_reward_time = now + Duration::from_secs(SURVIVAL_POINT_INTERVAL);
+            }
+        }
+
+        for award in self.scoring.take_awards() {
+            for plugin in &mut self.plugins {
+                plugin.on_score(&award, &mut self.state);
+            }
+        }
 
-                *next_reward_time = Instant::now() + Duration::from_secs(SURVIVAL_POINT_INTERVAL);
+        // Teams get whatever their players scored
+        let team_scoreboard = &mut self.state.team_scoreboard;
+        for (player_id, score) in &self.state.scoreboard {
+            let before = scores_before.get(player_id).cloned().unwrap_or(0);
+            if let Some(team_score) = teams.get(player_id).and_then(|team| team_scoreboard.get_mut(team)) {
+                *team_score = (*team_score + score).saturating_sub(before);
             }
         }
+
+        self.history.push_back(HistoryFrame { dt, players: self.state.players.clone() });
+        if self.history.len() > MAX_REWIND_FRAMES {
+            self.history.pop_front();
+        }
+
+        for plugin in &mut self.plugins {
+            plugin.on_tick(dt, &mut self.state);
+        }
+        self.grant_plugin_effects(now);
+
+        self.state.checksum = self.state.compute_checksum();
     }
 }
 
 // TODO(jake): rewrite tests.... maybe
 
+/// Gives the player an item's effect, starting its time over if they already had it.
+fn grant_effect(
+    player: &mut PlayerState,
+    effect_until: &mut HashMap<u32, Vec<(ItemKind, SystemTime)>>,
+    kind: ItemKind,
+    now: SystemTime,
+) {
+    if !player.has_effect(kind) {
+        player.effects.push(kind);
+    }
+    let effects = effect_until.entry(player.id).or_default();
+    effects.retain(|(effect, _)| *effect != kind);
+    effects.push((kind, now + ITEM_EFFECT_DURATION));
+}
+
+/// The scores, best first, ties broken by id.
+fn standings(scoreboard: &HashMap<u32, u32>) -> Vec<Standing> {
+    let mut standings: Vec<Standing> = scoreboard.iter().map(|(id, score)| Standing { id: *id, score: *score }).collect();
+    standings.sort_by_key(|standing| (std::cmp::Reverse(standing.score), standing.id));
+    standings
+}
+
+fn team_count(config: &GameConfig) -> u32 {
+    config.teams.clamp(1, MAX_TEAMS)
+}
+
 fn angle_to_vector(angle: f32) -> (f32, f32) {
     (angle.cos(), angle.sin())
 }
+
+/// The angle after bouncing off a surface facing the `normal` direction.
+fn reflect(angle: f32, (normal_x, normal_y): (f32, f32)) -> f32 {
+    let (dir_x, dir_y) = angle_to_vector(angle);
+    let dot = dir_x * normal_x + dir_y * normal_y;
+    (dir_y - 2.0 * dot * normal_y).atan2(dir_x - 2.0 * dot * normal_x)
+}
+
+fn normalize(x: f32, y: f32) -> (f32, f32) {
+    let length = (x * x + y * y).sqrt();
+    if length > 0.0 {
+        (x / length, y / length)
+    } else {
+        (1.0, 0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resets_keep_the_tick_and_acks_counting_up() {
+        let mut game = Game::with_seed(GameConfig::default(), 7, GameClock::starting_at(UNIX_EPOCH));
+        game.add_player(0, None);
+        for _ in 0..3 {
+            game.tick(1.0 / TICKS_PER_SECOND);
+        }
+        game.ack(0, 5, false);
+
+        game.reset();
+        assert_eq!(game.state.tick, 3);
+        assert_eq!(game.state.acks[&0], CommandAck { seq: 5, tick: 4, dropped: false });
+
+        game.tick(1.0 / TICKS_PER_SECOND);
+        game.ack(0, 6, false);
+        assert_eq!(game.state.tick, 4);
+        assert_eq!(game.state.acks[&0].tick, 5);
+    }
+}
@@ -0,0 +1,24 @@
+//! The game simulation behind the server, without the server. `Game` only
+//! depends on its seed and the inputs it's fed, so [`sim::Simulation`] can
+//! play thousands of headless matches to try bot strategies against each other.
+
+#[macro_use]
+extern crate tracing;
+
+#[macro_use]
+extern crate serde_derive;
+
+pub mod bots;
+pub mod collision;
+pub mod game;
+pub mod matches;
+pub mod persistence;
+pub mod plugins;
+pub mod ranked;
+pub mod replay;
+pub mod scoring;
+pub mod scripting;
+pub mod sim;
+pub mod snapshot;
+pub mod spatial;
+pub mod wasm_bots;
@@ -0,0 +1,112 @@
+use std::time::Duration;
+
+// How many overloaded seconds in a row it takes to start disconnecting
+// spectators, and how many calm ones to lift the restrictions again.
+const SHED_AFTER_REPORTS: u32 = 3;
+const RECOVER_AFTER_REPORTS: u32 = 5;
+
+/// When the server counts as overloaded. Spectators are degraded first so the
+/// players' game stays smooth.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct LoadSheddingConfig {
+    pub enabled: bool,
+    // The longest a tick can take, in milliseconds. A tick has 33ms at most.
+    pub max_tick_ms: f32,
+    // How far behind the game loop the broadcasts can fall, in milliseconds.
+    pub max_queue_delay_ms: f32,
+    // Game states per second sent to spectators while overloaded.
+    pub degraded_spectator_rate: f32,
+}
+
+impl Default for LoadSheddingConfig {
+    fn default() -> Self {
+        Self { enabled: true, max_tick_ms: 25.0, max_queue_delay_ms: 100.0, degraded_spectator_rate: 5.0 }
+    }
+}
+
+impl LoadSheddingConfig {
+    pub fn is_overloaded(&self, tick_time: Duration, queue_delay: Duration) -> bool {
+        self.enabled
+            && (tick_time.as_secs_f32() * 1000.0 > self.max_tick_ms
+                || queue_delay.as_secs_f32() * 1000.0 > self.max_queue_delay_ms)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadLevel {
+    Normal,
+    // Spectators get fewer game states and only the minimap.
+    Degraded,
+    // On top of that, the newest spectators are disconnected.
+    Shedding,
+}
+
+/// Turns the once a second load reports into a level, with some hysteresis so
+/// spectators aren't flipped back and forth.
+#[derive(Debug)]
+pub struct LoadMonitor {
+    level: LoadLevel,
+    overloaded_reports: u32,
+    calm_reports: u32,
+}
+
+impl LoadMonitor {
+    pub fn new() -> Self {
+        Self { level: LoadLevel::Normal, overloaded_reports: 0, calm_reports: 0 }
+    }
+
+    pub fn level(&self) -> LoadLevel {
+        self.level
+    }
+
+    pub fn report(&mut self, overloaded: bool) -> LoadLevel {
+        if overloaded {
+            self.overloaded_reports += 1;
+            self.calm_reports = 0;
+            self.level = if self.overloaded_reports >= SHED_AFTER_REPORTS {
+                LoadLevel::Shedding
+            } else {
+                LoadLevel::Degraded
+            };
+        } else {
+            self.calm_reports += 1;
+            self.overloaded_reports = 0;
+            if self.calm_reports >= RECOVER_AFTER_REPORTS {
+                self.level = LoadLevel::Normal;
+            } else if self.level == LoadLevel::Shedding {
+                self.level = LoadLevel::Degraded;
+            }
+        }
+
+        self.level
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spectators_are_shed_only_while_the_load_lasts() {
+        let mut monitor = LoadMonitor::new();
+
+        assert_eq!(monitor.report(true), LoadLevel::Degraded);
+        assert_eq!(monitor.report(true), LoadLevel::Degraded);
+        assert_eq!(monitor.report(true), LoadLevel::Shedding);
+        assert_eq!(monitor.report(true), LoadLevel::Shedding);
+
+        // A calm second stops the shedding, but it takes a few to recover.
+        assert_eq!(monitor.report(false), LoadLevel::Degraded);
+        for _ in 1..RECOVER_AFTER_REPORTS - 1 {
+            assert_eq!(monitor.report(false), LoadLevel::Degraded);
+        }
+        // Being overloaded again in between starts the count over.
+        assert_eq!(monitor.report(true), LoadLevel::Degraded);
+        for _ in 0..RECOVER_AFTER_REPORTS - 1 {
+            assert_eq!(monitor.report(false), LoadLevel::Degraded);
+        }
+        assert_eq!(monitor.report(false), LoadLevel::Normal);
+        assert_eq!(monitor.level(), LoadLevel::Normal);
+    }
+}
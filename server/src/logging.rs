@@ -0,0 +1,26 @@
+//! Structured logging through `tracing`.
+//!
+//! Log calls attach fields (`info!(key = api_key.as_str(), "joined")`) and the
+//! game loop and actors open spans (`info_span!("tick", tick)`). Every line
+//! logged while a span is entered carries the span path and its fields, so
+//! output from the tick, command handling and broadcast phases can be told
+//! apart.
+
+use std::io::{self, IsTerminal};
+use tracing_subscriber::{fmt, EnvFilter};
+
+/// Sets up the global subscriber. `RUST_LOG` still controls the filtering, `json`
+/// switches the output to one JSON object per line for log shippers. Records
+/// from dependencies that log through `log`, like actix-web, end up here too.
+pub fn init(json: bool) {
+    let builder = fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal());
+
+    if json {
+        builder.json().flatten_event(true).with_current_span(false).with_span_list(true).init();
+    } else {
+        builder.init();
+    }
+}
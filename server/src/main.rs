@@ -1,42 +1,70 @@
-#![feature(drain_filter)]
-
 #[macro_use]
-extern crate log;
+extern crate tracing;
 
 #[macro_use]
 extern crate serde_derive;
 
 mod actors;
+mod bandwidth;
+mod config;
 mod controllers;
-mod game;
+mod discovery;
+mod export;
+mod load;
+mod logging;
+mod middleware;
 mod models;
+mod regions;
+mod rooms;
+mod signals;
+mod spectate_link;
+mod stats;
+
+use tokyo_server::{bots, game, persistence, plugins, ranked, replay, scripting, snapshot, wasm_bots};
 
-use crate::actors::GameActor;
+use crate::{
+    actors::{GameActor, ReloadActor, ShutdownActor},
+    config::AppConfig,
+    middleware::admin_auth,
+    models::messages::Restriction,
+    persistence::{
+        bans::BanStore, history::HistoryStore, profiles::ProfileStore, ratings::RatingStore, Stores,
+    },
+    rooms::Rooms,
+};
 use actix::{Actor, Addr, System};
-use actix_web::{http::Method, middleware::Logger, server, App};
+use actix_files::Files;
+use actix_web::{
+    dev::HttpServiceFactory,
+    middleware::{from_fn, Logger},
+    web, App, FromRequest, HttpServer, Responder,
+};
 use lazy_static::lazy_static;
 use listenfd::ListenFd;
-use tokyo::models::GameConfig;
-use std::collections::HashSet;
-
-#[derive(Deserialize, Debug)]
-pub struct AppConfig {
-    server_port: Option<u16>,
-    api_keys: HashSet<String>,
-    dev_mode: bool,
-    game_config: GameConfig,
-}
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    path::Path,
+    sync::{Arc, Mutex, RwLock},
+};
 
 pub struct AppState {
+    // The default room.
     game_addr: Addr<GameActor>,
+    rooms: Arc<Mutex<Rooms>>,
+    bans: Arc<RwLock<BanStore>>,
+    // Muted and shadow restricted API keys, in every room.
+    restrictions: Arc<RwLock<HashMap<String, Restriction>>>,
+    // Starts out as the configured keys, replaced when the config is reloaded.
+    api_keys: Arc<RwLock<HashSet<String>>>,
+    reloader: Addr<ReloadActor>,
+    // What /debug/state returned last, to diff against.
+    last_debug_state: Arc<Mutex<Option<serde_json::Value>>>,
 }
 
-const CONFIG_FILE_PATH: &str = "tokyo.toml";
-
 lazy_static! {
     static ref APP_CONFIG: AppConfig = {
-        let config = std::fs::read(CONFIG_FILE_PATH).expect("Failed to read config file");
-        let config = toml::from_slice(&config).expect("failed to parse config");
+        let config = AppConfig::load().unwrap_or_else(|e| panic!("{}", e));
         println!("Config loaded: {:?}", config);
 
         config
@@ -44,51 +72,228 @@ lazy_static! {
 }
 
 fn main() -> Result<(), String> {
+    // `verify-replay <file>` re-simulates a replay instead of starting the server.
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("verify-replay") {
+        let path = args.get(2).ok_or_else(|| "Usage: tokyo-server verify-replay <file>".to_string())?;
+        return replay::verify_file(Path::new(path));
+    }
+
     lazy_static::initialize(&APP_CONFIG);
-    env_logger::init();
+    logging::init(APP_CONFIG.json_logs);
+
+    // Announced until the server exits.
+    let _discovery = if APP_CONFIG.lan_discovery {
+        let mdns = discovery::start_responder(APP_CONFIG.public_port())
+            .map_err(|e| format!("Failed to start LAN discovery: {}", e))?;
+        Some(mdns)
+    } else {
+        None
+    };
+
+    // Start with `--restore` to resume the match from the last snapshot.
+    let restore = if std::env::args().any(|arg| arg == "--restore") {
+        let path = APP_CONFIG
+            .snapshot_path
+            .as_ref()
+            .ok_or_else(|| "--restore requires snapshot_path to be set".to_string())?;
+        let snapshot = snapshot::load(path)
+            .map_err(|e| format!("Failed to load snapshot from {:?}: {}", path, e))?;
+        info!("Restoring game from {:?}", path);
+
+        Some(snapshot)
+    } else if APP_CONFIG.restore_snapshot {
+        // Unlike `--restore`, the first start without a snapshot is fine.
+        let path = APP_CONFIG
+            .snapshot_path
+            .as_ref()
+            .ok_or_else(|| "restore_snapshot requires snapshot_path to be set".to_string())?;
+        match snapshot::load(path) {
+            Ok(snapshot) => {
+                info!("Restoring game from {:?}", path);
+                Some(snapshot)
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                info!("No snapshot at {:?} yet, starting a new game", path);
+                None
+            },
+            Err(e) => return Err(format!("Failed to load snapshot from {:?}: {}", path, e)),
+        }
+    } else {
+        None
+    };
+
+    // The rooms read the rules script themselves, but a broken one should stop the server here.
+    if let Some(source) = APP_CONFIG.read_rules_script()? {
+        scripting::ScriptPlugin::new(&source)?;
+        info!("Playing with the rules in {:?}", APP_CONFIG.rules_script_path.as_ref().unwrap());
+    }
+
+    // The same goes for the WASM bots.
+    if let Some(dir) = &APP_CONFIG.bots.wasm_dir {
+        let brains = wasm_bots::WasmBrains::load(dir)?;
+        if brains.is_empty() {
+            warn!("There are no WASM bots in {:?}", dir);
+        } else {
+            info!("Loaded {} WASM bots from {:?}: {:?}", brains.len(), dir, brains);
+        }
+    }
+
+    let saved_scores = match &APP_CONFIG.scores_path {
+        Some(path) => persistence::scores::load(path)
+            .map_err(|e| format!("Failed to load scores from {:?}: {}", path, e))?,
+        None => Default::default(),
+    };
+
+    let ratings = RatingStore::open(APP_CONFIG.ratings_path.clone())
+        .map_err(|e| format!("Failed to load ratings: {}", e))?;
 
-    let server_port = APP_CONFIG.server_port.unwrap_or(3000);
+    let history = HistoryStore::open(APP_CONFIG.history_path.clone())
+        .map_err(|e| format!("Failed to load match history: {}", e))?;
 
-    let actor_system = System::new("meetup-server");
+    let profiles = ProfileStore::open(APP_CONFIG.profiles_path.clone())
+        .map_err(|e| format!("Failed to load profiles: {}", e))?;
 
-    let game_actor = GameActor::new(APP_CONFIG.game_config);
+    let stores = Stores::new(ratings, history, profiles);
+
+    let bans = BanStore::open(APP_CONFIG.ban_list_path.clone())
+        .map_err(|e| format!("Failed to load ban list: {}", e))?;
+    let bans = Arc::new(RwLock::new(bans));
+    let restrictions = Arc::new(RwLock::new(HashMap::new()));
+
+    let game_actor = GameActor::new(
+        APP_CONFIG.game_config.clone(),
+        APP_CONFIG.snapshot_path.clone(),
+        restore,
+        APP_CONFIG.scores_path.clone(),
+        saved_scores,
+        APP_CONFIG.replay_dir.clone(),
+        APP_CONFIG.bots.clone(),
+        stores.clone(),
+        restrictions.clone(),
+        None,
+    );
+
+    let system = System::new();
+    system.block_on(async { start(game_actor, stores, bans, restrictions) })?;
+    system.run().map_err(|e| format!("The server stopped with an error: {}", e))
+}
+
+/// Starts the actors and the HTTP server on the running actix system. They run
+/// until the `ShutdownActor` stops the system.
+fn start(
+    game_actor: GameActor,
+    stores: Stores,
+    bans: Arc<RwLock<BanStore>>,
+    restrictions: Arc<RwLock<HashMap<String, Restriction>>>,
+) -> Result<(), String> {
     let game_actor_addr = game_actor.start();
+    let rooms = Arc::new(Mutex::new(Rooms::new(game_actor_addr.clone(), stores, restrictions.clone())));
+    let api_keys = Arc::new(RwLock::new(APP_CONFIG.api_keys.clone()));
+    let reloader = ReloadActor::new(api_keys.clone(), rooms.clone()).start();
+    ShutdownActor::new(rooms.clone()).start();
 
-    let mut server = server::new(move || {
-        let app_state = AppState { game_addr: game_actor_addr.clone() };
-
-        App::with_state(app_state)
-            .middleware(Logger::default())
-            .resource("/socket", |r| {
-                r.method(Method::GET).with(controllers::api::socket_handler);
-            })
-            .resource("/spectate", |r| {
-                r.method(Method::GET).with(controllers::api::spectate_handler);
-            })
-            .resource("/reset", |r| {
-                r.method(Method::GET).with(controllers::api::reset_handler);
-            })
-            .handler(
-                "/",
-                actix_web::fs::StaticFiles::new("./spectator/").unwrap().index_file("index.html"),
-            )
-            .resource("/{tail:.*}j", |r| {
-                r.method(Method::GET).with(controllers::common::index_handler)
-            })
+    let app_state = web::Data::new(AppState {
+        game_addr: game_actor_addr,
+        rooms,
+        bans,
+        restrictions,
+        api_keys,
+        reloader,
+        last_debug_state: Arc::new(Mutex::new(None)),
     });
 
+    let app_factory = move || {
+        App::new()
+            .app_data(app_state.clone())
+            .wrap(Logger::default())
+            .route("/socket", web::get().to(controllers::api::socket_handler))
+            .route("/spectate", web::get().to(controllers::api::spectate_handler))
+            .route("/replays", web::get().to(controllers::api::replays_handler))
+            .route("/replay/{id}", web::get().to(controllers::api::replay_handler))
+            .route("/rooms", web::get().to(controllers::api::rooms_handler))
+            .route("/history", web::get().to(controllers::api::history_handler))
+            .route("/leaderboard", web::get().to(controllers::api::leaderboard_handler))
+            .route("/profile", web::get().to(controllers::api::profile_handler))
+            .route("/profile/update", web::get().to(controllers::api::update_profile_handler))
+            .service(admin("/reset", controllers::api::reset_handler))
+            .service(admin("/admin/bans", controllers::admin::bans_handler))
+            .service(admin("/admin/ban", controllers::admin::ban_handler))
+            .service(admin("/admin/unban", controllers::admin::unban_handler))
+            .service(admin("/stats/aggregate", controllers::admin::aggregate_stats_handler))
+            .service(admin("/export/results.csv", controllers::admin::export_results_handler))
+            .service(admin("/export/stats.csv", controllers::admin::export_stats_handler))
+            .service(admin("/admin/clients", controllers::admin::clients_handler))
+            .service(admin("/admin/kick", controllers::admin::kick_handler))
+            .service(admin("/admin/pause", controllers::admin::pause_handler))
+            .service(admin("/admin/resume", controllers::admin::resume_handler))
+            .service(admin("/admin/config", controllers::admin::config_handler))
+            .service(admin("/admin/config/update", controllers::admin::update_config_handler))
+            .service(admin("/admin/reload", controllers::admin::reload_handler))
+            .service(admin("/admin/restrictions", controllers::admin::restrictions_handler))
+            .service(admin("/admin/mute", controllers::admin::mute_handler))
+            .service(admin("/admin/shadow_restrict", controllers::admin::shadow_restrict_handler))
+            .service(admin("/admin/unmute", controllers::admin::unmute_handler))
+            .service(admin("/admin/spectate_link", controllers::admin::spectate_link_handler))
+            .service(admin("/debug/speed", controllers::debug::speed_handler))
+            .service(admin("/debug/pause", controllers::debug::pause_handler))
+            .service(admin("/debug/resume", controllers::debug::resume_handler))
+            .service(admin("/debug/step", controllers::debug::step_handler))
+            .service(admin("/debug/state", controllers::debug::state_handler))
+            .service(admin("/debug/obstacles/add", controllers::debug::add_obstacle_handler))
+            .service(admin("/debug/obstacles/move", controllers::debug::move_obstacle_handler))
+            .service(admin("/debug/obstacles/remove", controllers::debug::remove_obstacle_handler))
+            .route("/{tail:.*}j", web::get().to(controllers::common::index_handler))
+            .service(Files::new("/", "./spectator/").index_file("index.html"))
+    };
+
+    // The ShutdownActor takes care of the stop signals, so the players hear about it first.
+    let mut server = HttpServer::new(app_factory).disable_signals();
+
+    #[cfg(unix)]
+    {
+        // For running behind a reverse proxy on the same host without exposing a TCP port.
+        if let Some(path) = &APP_CONFIG.unix_socket {
+            remove_stale_socket(path);
+            server = server.bind_uds(path).map_err(|e| format!("Failed to bind {:?}: {}", path, e))?;
+            info!("Listening on {:?}", path);
+        }
+    }
+
     // Bind to the development file descriptor if available
     // Run with: systemfd --no-pid -s http::3000 -- cargo watch -x run
     let mut listenfd = ListenFd::from_env();
-    server = if let Some(fd) = listenfd.take_tcp_listener(0).unwrap() {
-        server.listen(fd)
+    if let Some(fd) = listenfd.take_tcp_listener(0).unwrap() {
+        server = server.listen(fd).map_err(|e| format!("Failed to listen on the passed socket: {}", e))?;
     } else {
-        server.bind(format!("0.0.0.0:{}", server_port)).unwrap()
-    };
+        for addr in APP_CONFIG.bind_addresses() {
+            server = server.bind(addr).map_err(|e| format!("Failed to bind {}: {}", addr, e))?;
+            info!("Listening on {}", addr);
+        }
+    }
 
-    server.start();
-
-    let _ = actor_system.run();
+    actix::spawn(server.run());
 
     Ok(())
 }
+
+/// A GET endpoint behind `admin_auth`.
+fn admin<F, Args>(path: &str, handler: F) -> impl HttpServiceFactory
+where
+    F: actix_web::Handler<Args>,
+    Args: FromRequest + 'static,
+    F::Output: Responder + 'static,
+{
+    web::resource(path).wrap(from_fn(admin_auth)).route(web::get().to(handler))
+}
+
+/// A socket left behind by a previous run would make binding the Unix domain
+/// socket fail.
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) {
+    use std::os::unix::fs::FileTypeExt;
+
+    if std::fs::metadata(path).map(|meta| meta.file_type().is_socket()).unwrap_or(false) {
+        let _ = std::fs::remove_file(path);
+    }
+}
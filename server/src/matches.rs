@@ -0,0 +1,55 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokyo_protocol::GameConfig;
+
+/// Identifies a match and what it was played with, so the snapshots, replays
+/// and stats that came out of it can be matched up afterwards.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MatchInfo {
+    pub id: String,
+    // The arena is always an empty rectangle to start with, named after its size.
+    pub map: String,
+    pub mode: String,
+    // FNV-1a of the game config as JSON, to tell matches with different rules apart.
+    pub config_hash: String,
+    // Unix times in seconds, by the game clock.
+    pub started_at: u64,
+    pub ended_at: Option<u64>,
+    // Everyone who played in the match, including those who left early.
+    pub participants: Vec<u32>,
+}
+
+impl MatchInfo {
+    pub fn new(config: &GameConfig, started_at: SystemTime) -> Self {
+        Self {
+            id: format!("{:016x}", rand::random::<u64>()),
+            map: format!("{}x{}", config.bound_x, config.bound_y),
+            mode: config.game_mode.to_string(),
+            config_hash: config_hash(config),
+            started_at: unix_secs(started_at),
+            ended_at: None,
+            participants: Vec::new(),
+        }
+    }
+
+    pub fn add_participant(&mut self, player_id: u32) {
+        if !self.participants.contains(&player_id) {
+            self.participants.push(player_id);
+        }
+    }
+
+    pub fn end(&mut self, ended_at: SystemTime) {
+        self.ended_at = Some(unix_secs(ended_at));
+    }
+}
+
+fn config_hash(config: &GameConfig) -> String {
+    let json = serde_json::to_vec(config).unwrap_or_default();
+    let hash = json.iter().fold(0x811c_9dc5u32, |hash, byte| {
+        (hash ^ u32::from(*byte)).wrapping_mul(0x0100_0193)
+    });
+    format!("{:08x}", hash)
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
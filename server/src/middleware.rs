@@ -0,0 +1,97 @@
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header,
+    middleware::Next,
+    Error, HttpResponse,
+};
+
+/// Guards the admin endpoints with the `admin_key` from the config. The key is
+/// accepted as a bearer token or as the password of HTTP basic auth, so it
+/// works from both curl and a browser. Without an `admin_key` the endpoints are
+/// only reachable in dev mode. Wrap a resource in it with
+/// `middleware::from_fn(admin_auth)`.
+pub async fn admin_auth(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let admin_key = match &crate::APP_CONFIG.admin_key {
+        Some(admin_key) => admin_key,
+        None if crate::APP_CONFIG.dev_mode => return Ok(next.call(req).await?.map_into_left_body()),
+        None => {
+            let response = HttpResponse::Forbidden().body("Set admin_key to use the admin endpoints");
+            return Ok(req.into_response(response).map_into_right_body());
+        },
+    };
+
+    let authorized = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(credentials)
+        .is_some_and(|given| constant_time_eq(given.as_bytes(), admin_key.as_bytes()));
+
+    if authorized {
+        Ok(next.call(req).await?.map_into_left_body())
+    } else {
+        warn!("Rejected unauthorized admin request to {}", req.path());
+        let response = HttpResponse::Unauthorized()
+            .insert_header((header::WWW_AUTHENTICATE, "Basic realm=\"tokyo admin\""))
+            .finish();
+        Ok(req.into_response(response).map_into_right_body())
+    }
+}
+
+/// Pulls the key out of a `Bearer <key>` or `Basic <base64(user:key)>` header.
+fn credentials(authorization: &str) -> Option<String> {
+    let mut parts = authorization.splitn(2, ' ');
+    let scheme = parts.next()?;
+    let value = parts.next()?.trim();
+
+    if scheme.eq_ignore_ascii_case("bearer") {
+        Some(value.to_string())
+    } else if scheme.eq_ignore_ascii_case("basic") {
+        let decoded = String::from_utf8(base64::decode(value).ok()?).ok()?;
+        decoded.split_once(':').map(|(_, key)| key.to_string())
+    } else {
+        None
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_are_taken_from_bearer_and_basic_auth() {
+        assert_eq!(credentials("Bearer s3cret"), Some("s3cret".to_string()));
+        assert_eq!(credentials("bearer  s3cret "), Some("s3cret".to_string()));
+        // curl -u admin:s3cret
+        assert_eq!(credentials(&format!("Basic {}", base64::encode("admin:s3cret"))), Some("s3cret".to_string()));
+        // Only the first colon separates the user from the password.
+        assert_eq!(credentials(&format!("Basic {}", base64::encode("admin:s3:cr:et"))), Some("s3:cr:et".to_string()));
+        assert_eq!(credentials(&format!("Basic {}", base64::encode(":s3cret"))), Some("s3cret".to_string()));
+    }
+
+    #[test]
+    fn anything_else_has_no_key() {
+        assert_eq!(credentials("Digest s3cret"), None);
+        assert_eq!(credentials("s3cret"), None);
+        assert_eq!(credentials("Basic not base64!"), None);
+        assert_eq!(credentials(&format!("Basic {}", base64::encode("s3cret"))), None);
+        assert_eq!(credentials(&format!("Basic {}", base64::encode(b"\xff:\xfe"))), None);
+    }
+
+    #[test]
+    fn keys_are_compared_whole() {
+        assert!(constant_time_eq(b"s3cret", b"s3cret"));
+        assert!(!constant_time_eq(b"s3cret", b"s3creT"));
+        assert!(!constant_time_eq(b"s3cret", b"s3cre"));
+        assert!(!constant_time_eq(b"", b"s3cret"));
+        assert!(constant_time_eq(b"", b""));
+    }
+}
@@ -1,16 +1,197 @@
 use actix::Message;
-use tokyo::models::GameCommand;
+use std::{collections::HashMap, fmt, time::Duration};
+use crate::{
+    game::ConfigChange,
+    persistence::{history::MatchResult, profiles::Profile},
+    stats::{AggregateStats, LeaderboardEntry},
+};
+use tokyo_protocol::GameCommand;
 
+/// Identifies a single websocket connection in logs and error responses, so a
+/// misbehaving client can be followed from the HTTP handler through the actors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ConnectionId(u64);
+
+impl ConnectionId {
+    pub fn new() -> ConnectionId {
+        ConnectionId(rand::random())
+    }
+}
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+/// A spectator chat message, already rate limited by the spectator's actor.
 #[derive(Debug, Message)]
+#[rtype(result = "()")]
+pub struct SpectatorChat {
+    pub conn_id: ConnectionId,
+    pub name: String,
+    pub text: String,
+}
+
+#[derive(Debug, Message)]
+#[rtype(result = "()")]
 pub struct PlayerGameCommand {
     pub api_key: String,
+    pub conn_id: ConnectionId,
     pub cmd: GameCommand,
+    pub seq: Option<u32>,
+}
+
+/// Round trip time measured with websocket pings.
+#[derive(Debug, Message)]
+#[rtype(result = "()")]
+pub struct PlayerLatency {
+    pub api_key: String,
+    pub conn_id: ConnectionId,
+    pub rtt: Duration,
 }
 
 #[derive(Debug, Message)]
+#[rtype(result = "()")]
 pub struct ClientStop {}
 
+/// How a spectator is treated while the server is overloaded.
 #[derive(Debug, Message)]
+#[rtype(result = "()")]
+pub enum SpectatorLoad {
+    Normal,
+    // Fewer game states, minimap only.
+    Degraded,
+    // Disconnected to make room for the players.
+    Busy,
+}
+
+/// Asks for the past matches of an API key, oldest first.
+pub struct GetHistory(pub String);
+
+impl Message for GetHistory {
+    type Result = Vec<MatchResult>;
+}
+
+/// Asks for the profile of an API key, if it has one.
+pub struct GetProfile(pub String);
+
+impl Message for GetProfile {
+    type Result = Option<Profile>;
+}
+
+/// Changes the given parts of an API key's profile, creating it if needed.
+/// `Some(None)` clears the color or team. Resolves to the updated profile.
+pub struct UpdateProfile {
+    pub key: String,
+    pub name: Option<String>,
+    pub color: Option<Option<String>>,
+    pub team: Option<Option<String>>,
+}
+
+impl Message for UpdateProfile {
+    type Result = Profile;
+}
+
+/// Asks for the past matches of every API key.
+pub struct GetAllHistory;
+
+impl Message for GetAllHistory {
+    type Result = HashMap<String, Vec<MatchResult>>;
+}
+
+/// Asks for stats over the match history, optionally only for one season or
+/// one API key.
+pub struct GetAggregateStats {
+    pub season: Option<String>,
+    pub key: Option<String>,
+}
+
+impl Message for GetAggregateStats {
+    type Result = AggregateStats;
+}
+
+/// Asks for the best players over the match history of a season, or of all
+/// time.
+pub struct GetLeaderboard {
+    pub season: Option<String>,
+    pub limit: usize,
+}
+
+impl Message for GetLeaderboard {
+    type Result = Vec<LeaderboardEntry>;
+}
+
+#[derive(Debug, Message)]
+#[rtype(result = "()")]
 pub enum ServerCommand {
-    Reset
+    Reset,
+    // Disconnect the client using this API key, if any.
+    Kick(String),
+    // Run the simulation faster or slower than real time.
+    SetSpeed(f32),
+    // Stop or restart the tick loop.
+    SetPaused(bool),
+    // Run a single tick while paused.
+    Step,
+    // Change some of the game config while the game runs.
+    Configure(ConfigChange),
+    // The profile of this API key was changed in another room.
+    ProfileChanged(String),
+    // The server is about to exit, say goodbye to everyone.
+    Shutdown,
+}
+
+/// Moderation short of a ban, for a player's chat messages and emotes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Restriction {
+    // Nothing they say goes through.
+    Muted,
+    // What they say only goes back to themselves, so they don't notice.
+    Shadow,
+}
+
+/// Asks for the clients connected to play.
+pub struct ListClients;
+
+impl Message for ListClients {
+    type Result = Vec<ClientInfo>;
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientInfo {
+    // None for the default room.
+    pub room: Option<String>,
+    pub key: String,
+    pub player_id: u32,
+    pub name: String,
+    pub region: Option<String>,
+    pub ranked: bool,
+    // The last measured round trip time, unknown until the first pong.
+    pub latency_ms: Option<u64>,
+    pub restriction: Option<Restriction>,
+}
+
+/// Asks for the game config as it is now, with any changes made at runtime.
+pub struct GetGameConfig;
+
+impl Message for GetGameConfig {
+    type Result = tokyo_protocol::GameConfig;
+}
+
+/// Asks to read the config file again and apply what can change while the
+/// server runs.
+pub struct ReloadConfig;
+
+impl Message for ReloadConfig {
+    type Result = Result<Reloaded, String>;
+}
+
+#[derive(Debug, Serialize)]
+pub struct Reloaded {
+    pub api_keys: usize,
+    pub game_config: tokyo_protocol::GameConfig,
+    // Changed settings that are ignored until the server restarts.
+    pub restart_required: Vec<&'static str>,
 }
@@ -0,0 +1,63 @@
+use std::{collections::HashSet, io, net::IpAddr, path::PathBuf};
+
+/// API keys and IP addresses that are refused before the websocket upgrade.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BanList {
+    pub keys: HashSet<String>,
+    pub ips: HashSet<IpAddr>,
+}
+
+impl BanList {
+    pub fn is_banned(&self, key: &str, ip: Option<IpAddr>) -> bool {
+        self.keys.contains(key) || self.is_ip_banned(ip)
+    }
+
+    /// For those who don't need a key, like spectators.
+    pub fn is_ip_banned(&self, ip: Option<IpAddr>) -> bool {
+        ip.is_some_and(|ip| self.ips.contains(&ip))
+    }
+}
+
+/// A `BanList` that writes itself to disk on every change, if given a path.
+#[derive(Debug)]
+pub struct BanStore {
+    path: Option<PathBuf>,
+    pub list: BanList,
+}
+
+impl BanStore {
+    pub fn open(path: Option<PathBuf>) -> io::Result<BanStore> {
+        let list = match &path {
+            Some(path) => match super::read_json(path) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => BanList::default(),
+                result => result?,
+            },
+            None => BanList::default(),
+        };
+
+        Ok(BanStore { path, list })
+    }
+
+    pub fn ban(&mut self, key: Option<String>, ip: Option<IpAddr>) -> io::Result<()> {
+        self.list.keys.extend(key);
+        self.list.ips.extend(ip);
+        self.save()
+    }
+
+    pub fn unban(&mut self, key: Option<&str>, ip: Option<IpAddr>) -> io::Result<()> {
+        if let Some(key) = key {
+            self.list.keys.remove(key);
+        }
+        if let Some(ip) = ip {
+            self.list.ips.remove(&ip);
+        }
+        self.save()
+    }
+
+    fn save(&self) -> io::Result<()> {
+        match &self.path {
+            Some(path) => super::write_json_atomically(path, &self.list),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use std::{collections::HashMap, io, path::PathBuf};
+
+/// How one match went for one API key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MatchResult {
+    // Missing from results recorded before matches had ids.
+    #[serde(default)]
+    pub match_id: Option<String>,
+    // Unix time in seconds.
+    pub ended_at: u64,
+    // 1 for the highest score, players with the same score share a placement.
+    pub placement: u32,
+    pub players: u32,
+    pub score: u32,
+    pub kills: u32,
+    pub deaths: u32,
+    // Only for ranked matches.
+    #[serde(default)]
+    pub rating_delta: Option<f32>,
+    // The `season` the server was configured with at the time.
+    #[serde(default)]
+    pub season: Option<String>,
+    #[serde(default)]
+    pub shots: u32,
+    #[serde(default)]
+    pub lives: u32,
+    #[serde(default)]
+    pub alive_secs: f32,
+    // Kills per opponent, by API key, or by name for bots.
+    #[serde(default)]
+    pub victims: HashMap<String, u32>,
+    // The name they played under.
+    #[serde(default)]
+    pub name: Option<String>,
+    // How the score splits into points from kills (less deaths) and from
+    // staying alive. Zero for results recorded before the split was kept.
+    #[serde(default)]
+    pub kill_points: i32,
+    #[serde(default)]
+    pub survival_points: i32,
+}
+
+/// Past matches per API key, written to disk after every match if given a path.
+#[derive(Debug)]
+pub struct HistoryStore {
+    path: Option<PathBuf>,
+    pub matches: HashMap<String, Vec<MatchResult>>,
+}
+
+impl HistoryStore {
+    pub fn open(path: Option<PathBuf>) -> io::Result<HistoryStore> {
+        let matches = match &path {
+            Some(path) => match super::read_json(path) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+                result => result?,
+            },
+            None => HashMap::new(),
+        };
+
+        Ok(HistoryStore { path, matches })
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        match &self.path {
+            Some(path) => super::write_json_atomically(path, &self.matches),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,101 @@
+//! Small on-disk stores for data that has to outlive the process.
+
+use self::{history::HistoryStore, profiles::ProfileStore, ratings::RatingStore};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+pub mod bans;
+pub mod history;
+pub mod profiles;
+pub mod ratings;
+pub mod scores;
+
+/// The stores kept per API key. They're opened once and shared by every room,
+/// so a rating, profile or match history is the same whichever room it came
+/// from or is asked for.
+#[derive(Debug, Clone)]
+pub struct Stores {
+    pub ratings: Arc<Mutex<RatingStore>>,
+    pub history: Arc<Mutex<HistoryStore>>,
+    pub profiles: Arc<Mutex<ProfileStore>>,
+}
+
+impl Stores {
+    pub fn new(ratings: RatingStore, history: HistoryStore, profiles: ProfileStore) -> Self {
+        Self {
+            ratings: Arc::new(Mutex::new(ratings)),
+            history: Arc::new(Mutex::new(history)),
+            profiles: Arc::new(Mutex::new(profiles)),
+        }
+    }
+}
+
+pub fn read_json<T: DeserializeOwned>(path: &Path) -> io::Result<T> {
+    let bytes = fs::read(path)?;
+    serde_json::from_slice(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Writes to a temporary file first and renames it over the old one, so a
+/// crash mid-write never leaves a truncated file behind. Both the file and
+/// the directory are synced, otherwise a power cut can still lose the rename
+/// or leave the new name pointing at data that never hit the disk.
+pub fn write_json_atomically<T: Serialize>(path: &Path, value: &T) -> io::Result<()> {
+    let bytes = serde_json::to_vec(value)?;
+    let mut tmp_name = path.file_name().map(OsString::from).unwrap_or_default();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&bytes)?;
+    file.sync_all()?;
+    drop(file);
+
+    fs::rename(&tmp_path, path)?;
+    sync_parent_dir(path)
+}
+
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+// Directories can't be opened as files elsewhere, and the rename is as
+// durable as the platform makes it.
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::HashMap, process};
+
+    #[test]
+    fn writes_through_a_sibling_tmp_file() {
+        let dir = std::env::temp_dir().join(format!("tokyo-persistence-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("scores.v1.json");
+        let tmp_path = dir.join("scores.v1.json.tmp");
+
+        // A tmp file left over from a crash is simply replaced.
+        fs::write(&tmp_path, b"{\"half\":").unwrap();
+        let value: HashMap<String, u32> = vec![("ace".to_string(), 3)].into_iter().collect();
+        write_json_atomically(&path, &value).unwrap();
+
+        assert_eq!(read_json::<HashMap<String, u32>>(&path).unwrap(), value);
+        assert!(!tmp_path.exists());
+        assert!(!dir.join("scores.v1.tmp").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
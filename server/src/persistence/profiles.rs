@@ -0,0 +1,53 @@
+use std::{collections::HashMap, io, path::PathBuf};
+
+/// What a player is known as and how they look, kept per API key so it
+/// doesn't depend on what their client sends on every connect.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Profile {
+    pub name: String,
+    // A "#rrggbb" color for their ship.
+    #[serde(default)]
+    pub color: Option<String>,
+    // Who they play for, e.g. their company or meetup group.
+    #[serde(default)]
+    pub team: Option<String>,
+    #[serde(default)]
+    pub totals: ProfileTotals,
+}
+
+/// Added up over every match the player finished.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ProfileTotals {
+    pub matches: u32,
+    pub wins: u32,
+    pub kills: u32,
+    pub deaths: u32,
+}
+
+/// Profiles that write themselves to disk on every change, if given a path.
+#[derive(Debug)]
+pub struct ProfileStore {
+    path: Option<PathBuf>,
+    pub profiles: HashMap<String, Profile>,
+}
+
+impl ProfileStore {
+    pub fn open(path: Option<PathBuf>) -> io::Result<ProfileStore> {
+        let profiles = match &path {
+            Some(path) => match super::read_json(path) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+                result => result?,
+            },
+            None => HashMap::new(),
+        };
+
+        Ok(ProfileStore { path, profiles })
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        match &self.path {
+            Some(path) => super::write_json_atomically(path, &self.profiles),
+            None => Ok(()),
+        }
+    }
+}
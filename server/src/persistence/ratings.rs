@@ -0,0 +1,37 @@
+use std::{collections::HashMap, io, path::PathBuf};
+
+/// A ranked player's rating, kept per API key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RatingRecord {
+    pub rating: f32,
+    // Ranked matches played so far.
+    pub matches: u32,
+}
+
+/// Ratings that write themselves to disk after every match, if given a path.
+#[derive(Debug)]
+pub struct RatingStore {
+    path: Option<PathBuf>,
+    pub records: HashMap<String, RatingRecord>,
+}
+
+impl RatingStore {
+    pub fn open(path: Option<PathBuf>) -> io::Result<RatingStore> {
+        let records = match &path {
+            Some(path) => match super::read_json(path) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+                result => result?,
+            },
+            None => HashMap::new(),
+        };
+
+        Ok(RatingStore { path, records })
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        match &self.path {
+            Some(path) => super::write_json_atomically(path, &self.records),
+            None => Ok(()),
+        }
+    }
+}
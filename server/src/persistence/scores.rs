@@ -0,0 +1,24 @@
+use std::{collections::HashMap, io, path::Path};
+
+/// The last known score of an API key, kept on disk so scores survive crashes.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ScoreRecord {
+    pub name: String,
+    pub score: u32,
+    #[serde(default)]
+    pub region: Option<String>,
+}
+
+pub type ScoreRecords = HashMap<String, ScoreRecord>;
+
+/// Loads the saved scores, a missing file just means nobody has scored yet.
+pub fn load(path: &Path) -> io::Result<ScoreRecords> {
+    match super::read_json(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(ScoreRecords::new()),
+        result => result,
+    }
+}
+
+pub fn save(path: &Path, records: &ScoreRecords) -> io::Result<()> {
+    super::write_json_atomically(path, records)
+}
@@ -0,0 +1,175 @@
+use crate::{matches::MatchInfo, scoring::Award};
+use actix::{Message, Recipient};
+use std::collections::{HashMap, HashSet};
+use tokyo_protocol::{BulletState, GameState, ItemKind, ScoreEvent};
+
+/// Hooks into what happens during a match, so features that react to joins,
+/// kills and so on don't each need their own spot in `Game::tick`.
+///
+/// Plugins run on the game loop thread in the order they were registered.
+/// Anything that changes the state has to be deterministic, or recorded
+/// replays will no longer verify.
+pub trait GamePlugin: Send {
+    fn on_join(&mut self, _player_id: u32, _state: &mut GameState) {}
+
+    /// Called when a player is placed in the arena, after joining and after
+    /// every respawn.
+    fn on_spawn(&mut self, _player_id: u32, _state: &mut GameState) {}
+
+    /// Called for every bullet fired, before it moves.
+    fn on_fire(&mut self, _bullet: &BulletState, _state: &mut GameState) {}
+
+    fn on_kill(&mut self, _kill: &Kill, _state: &mut GameState) {}
+
+    /// Called after a player picked up an item and got its effect.
+    fn on_pickup(&mut self, _pickup: &Pickup, _state: &mut GameState) {}
+
+    /// Called for every change to a player's score, after kills and survival
+    /// have been scored for the tick.
+    fn on_score(&mut self, _award: &Award, _state: &mut GameState) {}
+
+    /// Called at the end of every tick, after scoring, with the game time it covered.
+    fn on_tick(&mut self, _dt: f32, _state: &mut GameState) {}
+
+    /// Called with the final state when the game is reset.
+    fn on_match_end(&mut self, _info: &MatchInfo, _state: &GameState) {}
+
+    /// Item effects to give players, as if they had picked the item up.
+    /// Collected at the end of every tick.
+    fn take_effects(&mut self) -> Vec<(u32, ItemKind)> {
+        Vec::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Kill {
+    pub killer: u32,
+    pub victim: u32,
+    // Where the victim was hit.
+    pub x: f32,
+    pub y: f32,
+    // Whether the hit was found by rewinding the victim to where the shooter saw them.
+    pub lag_compensated: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Pickup {
+    pub player_id: u32,
+    pub kind: ItemKind,
+}
+
+/// Prints joins and kills to the console.
+pub struct KillLog;
+
+impl GamePlugin for KillLog {
+    fn on_join(&mut self, player_id: u32, _state: &mut GameState) {
+        println!("Player {} joined", player_id);
+    }
+
+    fn on_kill(&mut self, kill: &Kill, _state: &mut GameState) {
+        let compensated = if kill.lag_compensated { " (lag compensated)" } else { "" };
+        println!(
+            "Player {} killed player {} at ({}, {}){}",
+            kill.killer, kill.victim, kill.x, kill.y, compensated
+        );
+    }
+
+    fn on_match_end(&mut self, info: &MatchInfo, state: &GameState) {
+        let winner = state.scoreboard.iter().max_by_key(|(id, score)| (**score, std::cmp::Reverse(**id)));
+        if let Some((player_id, score)) = winner {
+            println!("Match {} over, player {} won with {} points", info.id, player_id, score);
+        }
+    }
+}
+
+/// What one player got up to over a match.
+#[derive(Debug, Clone, Default)]
+pub struct PlayerTally {
+    pub kills: u32,
+    pub deaths: u32,
+    pub shots: u32,
+    // Times spawned, including the first.
+    pub lives: u32,
+    pub alive_secs: f32,
+    // Kills per victim.
+    pub victims: HashMap<u32, u32>,
+    // Points from kills, less those lost to deaths.
+    pub kill_points: i32,
+    pub survival_points: i32,
+}
+
+/// How a match went, sent by the game loop when the match ends.
+#[derive(Debug, Message)]
+#[rtype(result = "()")]
+pub struct MatchSummary {
+    pub info: MatchInfo,
+    // Everyone still in the game at the end, alive or not.
+    pub players: Vec<u32>,
+    pub scoreboard: HashMap<u32, u32>,
+    pub tallies: HashMap<u32, PlayerTally>,
+}
+
+/// Counts kills, deaths, shots, points and time alive over a match and hands them to
+/// the game actor when the match ends, for the match history and stats.
+pub struct MatchTally {
+    game_actor: Recipient<MatchSummary>,
+    tallies: HashMap<u32, PlayerTally>,
+    // Who was alive after the last tick, to count respawns.
+    alive: HashSet<u32>,
+}
+
+impl MatchTally {
+    pub fn new(game_actor: Recipient<MatchSummary>) -> Self {
+        Self { game_actor, tallies: HashMap::new(), alive: HashSet::new() }
+    }
+}
+
+impl GamePlugin for MatchTally {
+    fn on_fire(&mut self, bullet: &BulletState, _state: &mut GameState) {
+        self.tallies.entry(bullet.player_id).or_default().shots += 1;
+    }
+
+    fn on_kill(&mut self, kill: &Kill, _state: &mut GameState) {
+        let killer = self.tallies.entry(kill.killer).or_default();
+        killer.kills += 1;
+        *killer.victims.entry(kill.victim).or_default() += 1;
+        self.tallies.entry(kill.victim).or_default().deaths += 1;
+    }
+
+    fn on_score(&mut self, award: &Award, _state: &mut GameState) {
+        let tally = self.tallies.entry(award.player_id).or_default();
+        match award.event {
+            ScoreEvent::Kill | ScoreEvent::Death => tally.kill_points += award.points,
+            ScoreEvent::Survive => tally.survival_points += award.points,
+        }
+    }
+
+    fn on_tick(&mut self, dt: f32, state: &mut GameState) {
+        let alive: HashSet<u32> = state.players.iter().map(|player| player.id).collect();
+        for player_id in &alive {
+            let tally = self.tallies.entry(*player_id).or_default();
+            if !self.alive.contains(player_id) {
+                tally.lives += 1;
+            }
+            tally.alive_secs += dt;
+        }
+        self.alive = alive;
+    }
+
+    fn on_match_end(&mut self, info: &MatchInfo, state: &GameState) {
+        let players = state
+            .players
+            .iter()
+            .chain(state.dead.iter().map(|dead| &dead.player))
+            .map(|player| player.id)
+            .collect();
+
+        self.alive.clear();
+        self.game_actor.do_send(MatchSummary {
+            info: info.clone(),
+            players,
+            scoreboard: state.scoreboard.clone(),
+            tallies: std::mem::take(&mut self.tallies),
+        });
+    }
+}
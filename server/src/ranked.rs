@@ -0,0 +1,94 @@
+use crate::persistence::ratings::RatingRecord;
+use std::collections::HashMap;
+use tokyo_protocol::{QueueStatus, RankTier, PLACEMENT_MATCHES};
+
+pub const INITIAL_RATING: f32 = 1000.0;
+
+// How far a single match can move a rating. Placement matches move it further
+// so new players find their level quickly.
+const K_FACTOR: f32 = 32.0;
+const PLACEMENT_K_FACTOR: f32 = 64.0;
+
+impl Default for RatingRecord {
+    fn default() -> Self {
+        RatingRecord { rating: INITIAL_RATING, matches: 0 }
+    }
+}
+
+impl RatingRecord {
+    pub fn status(&self) -> QueueStatus {
+        let placement_matches_left = PLACEMENT_MATCHES.saturating_sub(self.matches);
+        let tier = if placement_matches_left == 0 {
+            Some(RankTier::for_rating(self.rating))
+        } else {
+            None
+        };
+
+        QueueStatus { rating: self.rating, placement_matches_left, tier }
+    }
+}
+
+/// The room ranked players of this rating are sent to, one per tier, so they
+/// mostly meet players of about the same rating. Placement players go by the
+/// rating they have so far, like everyone else.
+pub fn room_for_rating(rating: f32) -> String {
+    format!("ranked-{:?}", RankTier::for_rating(rating)).to_lowercase()
+}
+
+/// Rates a finished match from the final scores of the ranked players in it.
+/// Every pair of players counts as a game between the two, won by the higher
+/// score, and each player's rating moves by the average over their opponents.
+/// Returns how much each rating changed.
+pub fn rate_match(
+    records: &mut HashMap<String, RatingRecord>,
+    scores: &[(String, u32)],
+) -> HashMap<String, f32> {
+    let mut deltas = HashMap::new();
+    if scores.len() < 2 {
+        return deltas;
+    }
+
+    let ratings: Vec<f32> = scores
+        .iter()
+        .map(|(api_key, _)| records.get(api_key).map_or(INITIAL_RATING, |record| record.rating))
+        .collect();
+
+    for (i, (api_key, score)) in scores.iter().enumerate() {
+        let change: f32 = scores
+            .iter()
+            .enumerate()
+            .filter(|(j, _)| *j != i)
+            .map(|(j, (_, other_score))| {
+                let expected = 1.0 / (1.0 + 10f32.powf((ratings[j] - ratings[i]) / 400.0));
+                let actual = match score.cmp(other_score) {
+                    std::cmp::Ordering::Greater => 1.0,
+                    std::cmp::Ordering::Equal => 0.5,
+                    std::cmp::Ordering::Less => 0.0,
+                };
+                actual - expected
+            })
+            .sum::<f32>()
+            / (scores.len() - 1) as f32;
+
+        let record = records.entry(api_key.clone()).or_default();
+        let k = if record.matches < PLACEMENT_MATCHES { PLACEMENT_K_FACTOR } else { K_FACTOR };
+        record.rating += k * change;
+        record.matches += 1;
+        deltas.insert(api_key.clone(), k * change);
+    }
+
+    deltas
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn players_of_a_tier_share_a_room() {
+        assert_eq!(room_for_rating(INITIAL_RATING), "ranked-silver");
+        assert_eq!(room_for_rating(899.0), "ranked-bronze");
+        assert_eq!(room_for_rating(1250.0), room_for_rating(1100.0));
+        assert_eq!(room_for_rating(2400.0), "ranked-diamond");
+    }
+}
@@ -0,0 +1,88 @@
+use serde::{de, Deserialize, Deserializer};
+use std::{collections::HashMap, net::IpAddr, str::FromStr};
+
+/// Maps connections to a region label (e.g. the venue they play from) so
+/// standings can be broken down by location during multi-site events.
+#[derive(Deserialize, Debug, Default)]
+pub struct RegionConfig {
+    // API keys that always get a fixed label, checked before the networks.
+    #[serde(default)]
+    pub keys: HashMap<String, String>,
+    #[serde(default)]
+    pub networks: Vec<NetworkRegion>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NetworkRegion {
+    pub network: Network,
+    pub label: String,
+}
+
+impl RegionConfig {
+    /// Returns the label for a connection, the most specific network wins.
+    pub fn region_for(&self, api_key: &str, ip: Option<IpAddr>) -> Option<String> {
+        if let Some(label) = self.keys.get(api_key) {
+            return Some(label.clone());
+        }
+
+        let ip = ip?;
+        self.networks
+            .iter()
+            .filter(|region| region.network.contains(ip))
+            .max_by_key(|region| region.network.prefix_len)
+            .map(|region| region.label.clone())
+    }
+}
+
+/// An IP network in CIDR notation, like `10.0.0.0/16` or `fd00::/8`.
+#[derive(Debug, Clone, Copy)]
+pub struct Network {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Network {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(u32::from(net).into(), u32::from(ip).into(), self.prefix_len, 32)
+            },
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(u128::from(net), u128::from(ip), self.prefix_len, 128)
+            },
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: u128, ip: u128, prefix_len: u8, bits: u8) -> bool {
+    let shift = u32::from(bits - prefix_len);
+    net.checked_shr(shift).unwrap_or(0) == ip.checked_shr(shift).unwrap_or(0)
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Network, String> {
+        let mut parts = s.splitn(2, '/');
+        let addr: IpAddr =
+            parts.next().unwrap_or_default().parse().map_err(|e| format!("{}: {}", s, e))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match parts.next() {
+            Some(len) => len.parse().map_err(|e| format!("{}: {}", s, e))?,
+            None => max_len,
+        };
+
+        if prefix_len > max_len {
+            return Err(format!("{}: prefix length is too long", s));
+        }
+
+        Ok(Network { addr, prefix_len })
+    }
+}
+
+impl<'de> Deserialize<'de> for Network {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Network, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(de::Error::custom)
+    }
+}
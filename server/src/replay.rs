@@ -0,0 +1,360 @@
+use crate::{
+    game::{ConfigChange, Game, GameClock, ObstacleEdit},
+    matches::MatchInfo,
+    scripting::ScriptPlugin,
+};
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+use tokyo_protocol::{GameCommand, GameConfig};
+
+/// Everything needed to re-simulate a match exactly: the seed, the config and
+/// the inputs fed to the game before each tick, in the order they were applied.
+///
+/// On disk it's JSON lines: a `ReplayHeader`, then one `ReplayTick` per line,
+/// so the recorder can append as the match goes.
+#[derive(Debug)]
+pub struct InputReplay {
+    pub seed: u64,
+    pub config: GameConfig,
+    pub match_info: Option<MatchInfo>,
+    pub rules_script: Option<String>,
+    pub ticks: Vec<ReplayTick>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReplayHeader {
+    pub seed: u64,
+    pub config: GameConfig,
+    // The first match recorded, later ones start with a `MatchStarted` input.
+    #[serde(default)]
+    pub match_info: Option<MatchInfo>,
+    // The source of the rules script the game was played with, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rules_script: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReplayTick {
+    pub dt: f32,
+    pub inputs: Vec<ReplayInput>,
+    // The state checksum after the tick.
+    pub checksum: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ReplayInput {
+    Join(u32),
+    // A player that asked for a team when joining.
+    JoinTeam(u32, u32),
+    Leave(u32),
+    RestoreScore(u32, u32),
+    Command(u32, GameCommand),
+    Latency(u32, Duration),
+    EditObstacle(ObstacleEdit),
+    Configure(ConfigChange),
+    Reset,
+    // Doesn't change the simulation, only labels the match that follows a `Reset`.
+    MatchStarted(MatchInfo),
+}
+
+impl ReplayInput {
+    /// Returns false for a command the game dropped.
+    pub fn apply(self, game: &mut Game) -> bool {
+        match self {
+            ReplayInput::Join(id) => game.add_player(id, None),
+            ReplayInput::JoinTeam(id, team) => game.add_player(id, Some(team)),
+            ReplayInput::Leave(id) => game.player_left(id),
+            ReplayInput::RestoreScore(id, score) => game.restore_score(id, score),
+            ReplayInput::Command(id, cmd) => return game.handle_cmd(id, cmd),
+            ReplayInput::Latency(id, rtt) => game.set_latency(id, rtt),
+            ReplayInput::EditObstacle(edit) => {
+                game.edit_obstacle(edit);
+            },
+            ReplayInput::Configure(change) => game.configure(&change),
+            ReplayInput::Reset => game.reset(),
+            ReplayInput::MatchStarted(info) => game.match_info = info,
+        }
+        true
+    }
+}
+
+/// Appends a live game's inputs to a replay file, one line per tick.
+pub struct ReplayRecorder {
+    path: PathBuf,
+    file: BufWriter<File>,
+    inputs: Vec<ReplayInput>,
+}
+
+impl ReplayRecorder {
+    pub fn create(path: PathBuf, header: &ReplayHeader) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(&path)?);
+        serde_json::to_writer(&mut file, header)?;
+        file.write_all(b"\n")?;
+
+        Ok(Self { path, file, inputs: Vec::new() })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn record(&mut self, input: &ReplayInput) {
+        self.inputs.push(input.clone());
+    }
+
+    /// Writes out the inputs recorded since the last tick.
+    pub fn finish_tick(&mut self, dt: f32, checksum: u32) -> io::Result<()> {
+        let tick = ReplayTick { dt, inputs: std::mem::take(&mut self.inputs), checksum };
+        serde_json::to_writer(&mut self.file, &tick)?;
+        self.file.write_all(b"\n")?;
+        self.file.flush()
+    }
+}
+
+pub fn load(path: &Path) -> io::Result<InputReplay> {
+    let invalid = |e| io::Error::new(io::ErrorKind::InvalidData, e);
+    let mut lines = BufReader::new(File::open(path)?).lines();
+
+    let header: ReplayHeader = match lines.next() {
+        Some(line) => serde_json::from_str(&line?).map_err(invalid)?,
+        None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "empty replay")),
+    };
+
+    let mut ticks = Vec::new();
+    for line in lines {
+        let line = line?;
+        // The last line may be cut short if the server died mid-write.
+        match serde_json::from_str(&line) {
+            Ok(tick) => ticks.push(tick),
+            Err(e) if e.is_eof() => break,
+            Err(e) => return Err(invalid(e)),
+        }
+    }
+
+    Ok(InputReplay {
+        seed: header.seed,
+        config: header.config,
+        match_info: header.match_info,
+        rules_script: header.rules_script,
+        ticks,
+    })
+}
+
+#[derive(Debug)]
+pub struct Divergence {
+    pub tick: usize,
+    pub expected: u32,
+    pub actual: u32,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "tick {} diverged: recorded checksum {:08x}, simulated {:08x}",
+            self.tick, self.expected, self.actual
+        )
+    }
+}
+
+/// Re-simulates a replay one recorded tick at a time.
+pub struct Playback {
+    pub game: Game,
+    ticks: std::vec::IntoIter<ReplayTick>,
+}
+
+impl Playback {
+    pub fn new(replay: InputReplay) -> Self {
+        // Only time differences matter to the simulation, so any epoch will do.
+        let mut game = Game::with_seed(replay.config, replay.seed, GameClock::starting_at(UNIX_EPOCH));
+        if let Some(match_info) = replay.match_info {
+            game.match_info = match_info;
+        }
+        if let Some(source) = replay.rules_script {
+            match ScriptPlugin::new(&source) {
+                Ok(rules) => game.register_plugin(Box::new(rules)),
+                Err(e) => warn!("Playing back without the rules script: {}", e),
+            }
+        }
+
+        Self { game, ticks: replay.ticks.into_iter() }
+    }
+
+    /// How much game time the next tick covers, `None` once the replay is over.
+    pub fn next_dt(&self) -> Option<f32> {
+        self.ticks.as_slice().first().map(|tick| tick.dt)
+    }
+
+    /// Applies the next tick's inputs and runs it. Returns the tick as it was
+    /// recorded, `None` once the replay is over.
+    pub fn step(&mut self) -> Option<ReplayTick> {
+        let recorded = self.ticks.next()?;
+        for input in recorded.inputs.iter().cloned() {
+            input.apply(&mut self.game);
+        }
+        self.game.tick(recorded.dt);
+
+        Some(recorded)
+    }
+}
+
+/// Re-simulates the replay and compares every tick against the recording.
+pub fn verify(replay: InputReplay) -> Result<usize, Divergence> {
+    let mut playback = Playback::new(replay);
+    let mut tick_count = 0;
+
+    while let Some(recorded) = playback.step() {
+        if playback.game.state.checksum != recorded.checksum {
+            return Err(Divergence {
+                tick: tick_count,
+                expected: recorded.checksum,
+                actual: playback.game.state.checksum,
+            });
+        }
+        tick_count += 1;
+    }
+
+    Ok(tick_count)
+}
+
+/// The ids of the replays in `dir`, their file names without the extension,
+/// oldest first.
+pub fn list(dir: &Path) -> io::Result<Vec<String>> {
+    let mut ids: Vec<String> = match std::fs::read_dir(dir) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        entries => entries?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|extension| extension == "jsonl"))
+            .filter_map(|path| Some(path.file_stem()?.to_str()?.to_string()))
+            .collect(),
+    };
+    ids.sort();
+
+    Ok(ids)
+}
+
+/// Finds a replay in `dir` by id, `None` for ids that aren't a plain file name.
+pub fn path_of(dir: &Path, id: &str) -> Option<PathBuf> {
+    let valid = !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Some(dir.join(format!("{}.jsonl", id)))
+    } else {
+        None
+    }
+}
+
+/// Entry point for `tokyo-server verify-replay <file>`.
+pub fn verify_file(path: &Path) -> Result<(), String> {
+    let replay = load(path).map_err(|e| format!("Failed to load replay from {:?}: {}", path, e))?;
+
+    let tick_count = verify(replay).map_err(|e| e.to_string())?;
+    println!("{:?}: all {} ticks match", path, tick_count);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::TICKS_PER_SECOND;
+    use tokyo_protocol::GameCommand;
+
+    const SEED: u64 = 11;
+    const TICKS: usize = 300;
+
+    fn inputs_for(tick: usize) -> Vec<ReplayInput> {
+        match tick {
+            0 => vec![ReplayInput::Join(0), ReplayInput::Join(1), ReplayInput::Join(2)],
+            _ if tick.is_multiple_of(10) => (0..3)
+                .flat_map(|id| {
+                    let angle = (tick as f32 * 0.1 + id as f32).sin() * 3.0;
+                    vec![
+                        ReplayInput::Command(id, GameCommand::Rotate(angle)),
+                        ReplayInput::Command(id, GameCommand::Throttle(0.8)),
+                        ReplayInput::Command(id, GameCommand::Fire),
+                    ]
+                })
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    /// Plays the inputs on a game the way the live loop does and records them.
+    fn recorded() -> InputReplay {
+        let config = GameConfig { bound_x: 1200.0, bound_y: 1200.0, ..GameConfig::default() };
+        let mut game = Game::with_seed(config.clone(), SEED, GameClock::starting_at(UNIX_EPOCH));
+        let dt = 1.0 / TICKS_PER_SECOND;
+
+        let ticks = (0..TICKS)
+            .map(|tick| {
+                let inputs = inputs_for(tick);
+                for input in inputs.iter().cloned() {
+                    input.apply(&mut game);
+                }
+                game.tick(dt);
+                ReplayTick { dt, inputs, checksum: game.state.checksum }
+            })
+            .collect();
+
+        InputReplay { seed: SEED, config, match_info: None, rules_script: None, ticks }
+    }
+
+    #[test]
+    fn a_recorded_replay_verifies() {
+        assert_eq!(verify(recorded()).unwrap(), TICKS);
+    }
+
+    #[test]
+    fn changing_one_input_diverges() {
+        let mut replay = recorded();
+        let changed = replay.ticks[50]
+            .inputs
+            .iter_mut()
+            .find_map(|input| match input {
+                ReplayInput::Command(_, GameCommand::Throttle(throttle)) => Some(throttle),
+                _ => None,
+            })
+            .unwrap();
+        *changed = 0.2;
+
+        let divergence = verify(replay).unwrap_err();
+        assert_eq!(divergence.tick, 50);
+        assert_ne!(divergence.expected, divergence.actual);
+    }
+
+    #[test]
+    fn survives_the_trip_through_a_file() {
+        let dir = std::env::temp_dir().join(format!("tokyo-replay-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let replay = recorded();
+        let header =
+            ReplayHeader { seed: replay.seed, config: replay.config.clone(), match_info: None, rules_script: None };
+
+        let mut recorder = ReplayRecorder::create(dir.join("match.jsonl"), &header).unwrap();
+        for tick in &replay.ticks {
+            for input in &tick.inputs {
+                recorder.record(input);
+            }
+            recorder.finish_tick(tick.dt, tick.checksum).unwrap();
+        }
+
+        assert_eq!(list(&dir).unwrap(), vec!["match".to_string()]);
+        let loaded = load(&path_of(&dir, "match").unwrap()).unwrap();
+        assert_eq!(verify(loaded).unwrap(), TICKS);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn ids_are_plain_file_names() {
+        let dir = Path::new("replays");
+        assert_eq!(path_of(dir, "2019-06-01_a"), Some(dir.join("2019-06-01_a.jsonl")));
+        assert_eq!(path_of(dir, "../tokyo"), None);
+        assert_eq!(path_of(dir, ""), None);
+    }
+}
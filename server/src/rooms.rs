@@ -0,0 +1,164 @@
+use crate::{
+    actors::GameActor,
+    game::ConfigChange,
+    models::messages::{Restriction, ServerCommand},
+    persistence::{scores, Stores},
+    ranked,
+};
+use actix::{Actor, Addr};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+use tokyo_protocol::GameConfig;
+
+// Longest room name accepted in `?room=`.
+pub const MAX_ROOM_NAME_LENGTH: usize = 32;
+
+/// The running games. The default room is the one configured in `tokyo.toml`
+/// with its snapshots and replays, the others are started on demand when
+/// someone connects with `?room=`. They all share the ratings, match history,
+/// profiles and restrictions, and each keeps its own scores next to the
+/// default room's.
+#[derive(Debug)]
+pub struct Rooms {
+    default: Addr<GameActor>,
+    rooms: HashMap<String, Addr<GameActor>>,
+    // What new rooms are started with, as of the last config reload.
+    game_config: GameConfig,
+    stores: Stores,
+    restrictions: Arc<RwLock<HashMap<String, Restriction>>>,
+}
+
+impl Rooms {
+    pub fn new(
+        default: Addr<GameActor>,
+        stores: Stores,
+        restrictions: Arc<RwLock<HashMap<String, Restriction>>>,
+    ) -> Self {
+        let game_config = crate::APP_CONFIG.game_config.clone();
+        Self { default, rooms: HashMap::new(), game_config, stores, restrictions }
+    }
+
+    pub fn set_game_config(&mut self, game_config: GameConfig) {
+        self.game_config = game_config;
+    }
+
+    /// Changes the config of every running room and of the ones started later.
+    pub fn configure(&mut self, change: &ConfigChange) {
+        change.apply_to(&mut self.game_config);
+        for addr in self.all() {
+            addr.do_send(ServerCommand::Configure(change.clone()));
+        }
+    }
+
+    /// The room to play in, started if it isn't running yet.
+    pub fn join(&mut self, room: Option<&str>) -> Result<Addr<GameActor>, String> {
+        let room = match room {
+            Some(room) => room,
+            None => return Ok(self.default.clone()),
+        };
+        if let Some(addr) = self.get(Some(room)) {
+            return Ok(addr);
+        }
+
+        validate_name(room)?;
+        if self.rooms.len() >= crate::APP_CONFIG.max_rooms {
+            return Err(format!("There are {} rooms already, join one of them", self.rooms.len()));
+        }
+
+        info!(room, "starting room");
+        let scores_path = scores_path(room);
+        let saved_scores = match &scores_path {
+            Some(path) => scores::load(path).unwrap_or_else(|e| {
+                error!("Failed to load scores from {:?}, starting from zero: {}", path, e);
+                Default::default()
+            }),
+            None => Default::default(),
+        };
+        let addr = GameActor::new(
+            self.game_config.clone(),
+            None,
+            None,
+            scores_path,
+            saved_scores,
+            None,
+            crate::APP_CONFIG.bots.clone(),
+            self.stores.clone(),
+            self.restrictions.clone(),
+            Some(room.to_string()),
+        )
+        .start();
+        self.rooms.insert(room.to_string(), addr.clone());
+
+        Ok(addr)
+    }
+
+    /// The room for a ranked player that didn't ask for one, by their rating.
+    /// Falls back to the default room when there's no room left to start.
+    pub fn join_ranked(&mut self, api_key: &str) -> Addr<GameActor> {
+        let rating = self.stores.ratings.lock().unwrap().records.get(api_key).cloned().unwrap_or_default().rating;
+        let room = ranked::room_for_rating(rating);
+        self.join(Some(&room)).unwrap_or_else(|e| {
+            warn!(room = room.as_str(), "playing ranked in the default room: {}", e);
+            self.default.clone()
+        })
+    }
+
+    /// The room if it's running. Rooms stop by themselves once nobody's left.
+    pub fn get(&mut self, room: Option<&str>) -> Option<Addr<GameActor>> {
+        match room {
+            Some(room) => {
+                self.prune();
+                self.rooms.get(room).cloned()
+            },
+            None => Some(self.default.clone()),
+        }
+    }
+
+    /// Every running room but the default one, by name.
+    pub fn list(&mut self) -> Vec<(String, Addr<GameActor>)> {
+        self.prune();
+        let mut rooms: Vec<_> = self.rooms.iter().map(|(name, addr)| (name.clone(), addr.clone())).collect();
+        rooms.sort_by(|a, b| a.0.cmp(&b.0));
+        rooms
+    }
+
+    /// Every running room, the default one included.
+    pub fn all(&mut self) -> Vec<Addr<GameActor>> {
+        self.prune();
+        std::iter::once(self.default.clone()).chain(self.rooms.values().cloned()).collect()
+    }
+
+    fn prune(&mut self) {
+        self.rooms.retain(|_, addr| addr.connected());
+    }
+}
+
+/// Where a room keeps its scores, `scores.json` becomes `scores.<room>.json`.
+fn scores_path(room: &str) -> Option<PathBuf> {
+    let path = crate::APP_CONFIG.scores_path.as_ref()?;
+    let mut file_name = path.file_stem()?.to_os_string();
+    file_name.push(".");
+    file_name.push(room);
+    if let Some(extension) = path.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+    Some(path.with_file_name(file_name))
+}
+
+fn validate_name(room: &str) -> Result<(), String> {
+    let valid = !room.is_empty()
+        && room.len() <= MAX_ROOM_NAME_LENGTH
+        && room.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(format!(
+            "Room names are up to {} letters, digits, dashes and underscores",
+            MAX_ROOM_NAME_LENGTH
+        ))
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use tokyo_protocol::{ScoreEvent, ScoringRule};
+
+/// Hands out points according to the `scoring` rules in the game config.
+#[derive(Debug)]
+pub struct ScoringEngine {
+    rules: Vec<ScoringRule>,
+    // Kills since each player last died.
+    streaks: HashMap<u32, u32>,
+    // Points handed out since the last `take_awards`.
+    awards: Vec<Award>,
+}
+
+/// Points a player got, or lost, for something that happened.
+#[derive(Debug, Clone, Copy)]
+pub struct Award {
+    pub player_id: u32,
+    pub event: ScoreEvent,
+    // What the score actually changed by, scores don't go below zero.
+    pub points: i32,
+}
+
+impl ScoringEngine {
+    pub fn new(rules: Vec<ScoringRule>) -> Self {
+        Self { rules, streaks: HashMap::new(), awards: Vec::new() }
+    }
+
+    pub fn streaks(&self) -> &HashMap<u32, u32> {
+        &self.streaks
+    }
+
+    /// Scores a kill for both sides. The distance is unknown when the shooter
+    /// is no longer around, distance conditions never match then.
+    pub fn kill(
+        &mut self,
+        scoreboard: &mut HashMap<u32, u32>,
+        killer: u32,
+        victim: u32,
+        distance: Option<f32>,
+    ) {
+        *self.streaks.entry(killer).or_default() += 1;
+
+        let killer_points = self.points(ScoreEvent::Kill, killer, distance);
+        let victim_points = self.points(ScoreEvent::Death, victim, distance);
+        self.streaks.remove(&victim);
+
+        self.award(scoreboard, killer, ScoreEvent::Kill, killer_points);
+        self.award(scoreboard, victim, ScoreEvent::Death, victim_points);
+    }
+
+    pub fn survived(&mut self, scoreboard: &mut HashMap<u32, u32>, player_id: u32) {
+        let points = self.points(ScoreEvent::Survive, player_id, None);
+        self.award(scoreboard, player_id, ScoreEvent::Survive, points);
+    }
+
+    /// Takes the points handed out since the last call.
+    pub fn take_awards(&mut self) -> Vec<Award> {
+        std::mem::take(&mut self.awards)
+    }
+
+    pub fn player_left(&mut self, player_id: u32) {
+        self.streaks.remove(&player_id);
+    }
+
+    fn points(&self, event: ScoreEvent, player_id: u32, distance: Option<f32>) -> i32 {
+        let streak = self.streaks.get(&player_id).cloned().unwrap_or_default();
+
+        self.rules
+            .iter()
+            .filter(|rule| rule.event == event)
+            .filter(|rule| rule.min_streak.is_none_or(|min| streak >= min))
+            .filter(|rule| match (rule.min_distance, distance) {
+                (Some(min), Some(distance)) => distance >= min,
+                (Some(_), None) => false,
+                (None, _) => true,
+            })
+            .filter(|rule| match (rule.max_distance, distance) {
+                (Some(max), Some(distance)) => distance <= max,
+                (Some(_), None) => false,
+                (None, _) => true,
+            })
+            .map(|rule| rule.points)
+            .sum()
+    }
+
+    /// Adds (or takes away) points, scores don't go below zero.
+    fn award(&mut self, scoreboard: &mut HashMap<u32, u32>, player_id: u32, event: ScoreEvent, points: i32) {
+        if points == 0 {
+            return;
+        }
+
+        let score = scoreboard.entry(player_id).or_default();
+        let before = *score;
+        *score = if points > 0 {
+            score.saturating_add(points as u32)
+        } else {
+            score.saturating_sub(points.wrapping_neg() as u32)
+        };
+
+        let points = *score as i32 - before as i32;
+        if points != 0 {
+            self.awards.push(Award { player_id, event, points });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn engine(rules: Vec<ScoringRule>) -> (ScoringEngine, HashMap<u32, u32>) {
+        (ScoringEngine::new(rules), HashMap::new())
+    }
+
+    #[test]
+    fn default_rules_score_kills_and_survival() {
+        let (mut engine, mut scoreboard) =
+            engine(vec![ScoringRule::new(ScoreEvent::Kill, 1), ScoringRule::new(ScoreEvent::Survive, 1)]);
+        engine.kill(&mut scoreboard, 1, 2, Some(100.0));
+        engine.survived(&mut scoreboard, 1);
+
+        assert_eq!(scoreboard.get(&1), Some(&2));
+        assert_eq!(scoreboard.get(&2), None);
+        let awards: Vec<(u32, ScoreEvent, i32)> =
+            engine.take_awards().iter().map(|award| (award.player_id, award.event, award.points)).collect();
+        assert_eq!(awards, vec![(1, ScoreEvent::Kill, 1), (1, ScoreEvent::Survive, 1)]);
+        assert!(engine.take_awards().is_empty());
+    }
+
+    #[test]
+    fn scores_dont_go_below_zero() {
+        let (mut engine, mut scoreboard) = engine(vec![ScoringRule::new(ScoreEvent::Death, -3)]);
+        scoreboard.insert(2, 2);
+        engine.kill(&mut scoreboard, 1, 2, None);
+        engine.kill(&mut scoreboard, 1, 2, None);
+
+        assert_eq!(scoreboard[&2], 0);
+        // Only what the score actually changed by is reported.
+        let points: Vec<i32> = engine.take_awards().iter().map(|award| award.points).collect();
+        assert_eq!(points, vec![-2]);
+    }
+
+    #[test]
+    fn streak_bonus_counts_the_kill_being_scored() {
+        let bonus = ScoringRule { min_streak: Some(3), ..ScoringRule::new(ScoreEvent::Kill, 5) };
+        let (mut engine, mut scoreboard) = engine(vec![ScoringRule::new(ScoreEvent::Kill, 1), bonus]);
+        for victim in 2..5 {
+            engine.kill(&mut scoreboard, 1, victim, None);
+        }
+        assert_eq!(scoreboard[&1], 1 + 1 + 6);
+        assert_eq!(engine.streaks()[&1], 3);
+
+        // Dying resets the streak, leaving resets it too.
+        engine.kill(&mut scoreboard, 2, 1, None);
+        assert_eq!(engine.streaks().get(&1), None);
+        engine.player_left(2);
+        assert_eq!(engine.streaks().get(&2), None);
+    }
+
+    #[test]
+    fn distance_conditions_need_a_distance() {
+        let long_shot = ScoringRule { min_distance: Some(500.0), ..ScoringRule::new(ScoreEvent::Kill, 2) };
+        let point_blank = ScoringRule { max_distance: Some(50.0), ..ScoringRule::new(ScoreEvent::Death, -1) };
+        let (mut engine, mut scoreboard) = engine(vec![long_shot, point_blank]);
+        scoreboard.insert(2, 5);
+
+        engine.kill(&mut scoreboard, 1, 2, Some(600.0));
+        engine.kill(&mut scoreboard, 1, 2, Some(40.0));
+        engine.kill(&mut scoreboard, 1, 2, None);
+
+        assert_eq!(scoreboard[&1], 2);
+        assert_eq!(scoreboard[&2], 4);
+    }
+}
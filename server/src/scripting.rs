@@ -0,0 +1,209 @@
+//! Game rules written in [rhai](https://rhai.rs), for trying out tweaks for a
+//! special round without changing `game.rs`.
+//!
+//! A rules script defines any of these functions, which are called like the
+//! `GamePlugin` hooks of the same name:
+//!
+//! ```text
+//! fn on_tick(dt) {}
+//! fn on_kill(killer, victim) {}
+//! fn on_pickup(player, item) {}  // item is "shield", "rapid_fire", ...
+//! fn on_spawn(player) {}
+//! ```
+//!
+//! and can call back into the game with:
+//!
+//! - `players()`, the ids of the players alive right now
+//! - `score(player)` and `add_score(player, points)`, where points can be negative
+//! - `give_effect(player, item)`, the same effect as picking the item up
+//!
+//! Scripts run on the game loop thread and have no clock or randomness, so
+//! what they do stays deterministic and replays keep verifying. A call that
+//! runs for too long is cut off and logged, and the game goes on.
+
+use crate::plugins::{GamePlugin, Kill, Pickup};
+use rhai::{Array, CallFnOptions, Dynamic, Engine, EvalAltResult, FuncArgs, Scope, AST};
+use std::{
+    collections::HashMap,
+    mem,
+    sync::{Arc, Mutex},
+};
+use tokyo_protocol::{GameState, ItemKind};
+
+// How much work a single call may do before it's stopped.
+const MAX_OPERATIONS: u64 = 100_000;
+
+/// What the functions registered with the engine can see and change during a call.
+#[derive(Default)]
+struct Host {
+    // Swapped in from the game state for the duration of a call.
+    scoreboard: HashMap<u32, u32>,
+    players: Vec<u32>,
+    effects: Vec<(u32, ItemKind)>,
+}
+
+pub struct ScriptPlugin {
+    engine: Engine,
+    ast: AST,
+    scope: Scope<'static>,
+    host: Arc<Mutex<Host>>,
+}
+
+impl ScriptPlugin {
+    /// Compiles the script and runs its top level statements once.
+    pub fn new(source: &str) -> Result<Self, String> {
+        let host = Arc::new(Mutex::new(Host::default()));
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        engine.on_print(|text| info!("rules script: {}", text));
+
+        let players = host.clone();
+        engine.register_fn("players", move || -> Array {
+            players.lock().unwrap().players.iter().map(|id| Dynamic::from(*id as i64)).collect()
+        });
+        let scores = host.clone();
+        engine.register_fn("score", move |player: i64| -> i64 {
+            scores.lock().unwrap().scoreboard.get(&(player as u32)).map_or(0, |score| *score as i64)
+        });
+        let scores = host.clone();
+        engine.register_fn("add_score", move |player: i64, points: i64| {
+            if let Some(score) = scores.lock().unwrap().scoreboard.get_mut(&(player as u32)) {
+                *score = (*score as i64 + points).max(0).min(u32::MAX as i64) as u32;
+            }
+        });
+        let effects = host.clone();
+        engine.register_fn("give_effect", move |player: i64, item: &str| -> Result<(), Box<EvalAltResult>> {
+            let kind = item_kind(item).ok_or_else(|| format!("no such item: {}", item))?;
+            effects.lock().unwrap().effects.push((player as u32, kind));
+            Ok(())
+        });
+
+        let ast = engine.compile(source).map_err(|e| format!("Failed to compile the rules script: {}", e))?;
+        let mut scope = Scope::new();
+        engine
+            .run_ast_with_scope(&mut scope, &ast)
+            .map_err(|e| format!("Failed to run the rules script: {}", e))?;
+
+        Ok(Self { engine, ast, scope, host })
+    }
+
+    /// Calls the script function `name` if the script defines it, with the
+    /// game's scoreboard and players available to it.
+    fn call(&mut self, name: &str, args: impl FuncArgs, arity: usize, state: &mut GameState) {
+        if !self.ast.iter_functions().any(|f| f.name == name && f.params.len() == arity) {
+            return;
+        }
+
+        {
+            let mut host = self.host.lock().unwrap();
+            mem::swap(&mut host.scoreboard, &mut state.scoreboard);
+            host.players = state.players.iter().map(|player| player.id).collect();
+        }
+
+        let options = CallFnOptions::new().eval_ast(false);
+        let result =
+            self.engine.call_fn_with_options::<Dynamic>(options, &mut self.scope, &self.ast, name, args);
+        if let Err(e) = result {
+            warn!("rules script {} failed: {}", name, e);
+        }
+
+        mem::swap(&mut self.host.lock().unwrap().scoreboard, &mut state.scoreboard);
+    }
+}
+
+impl GamePlugin for ScriptPlugin {
+    fn on_spawn(&mut self, player_id: u32, state: &mut GameState) {
+        self.call("on_spawn", (player_id as i64,), 1, state);
+    }
+
+    fn on_kill(&mut self, kill: &Kill, state: &mut GameState) {
+        self.call("on_kill", (kill.killer as i64, kill.victim as i64), 2, state);
+    }
+
+    fn on_pickup(&mut self, pickup: &Pickup, state: &mut GameState) {
+        let item = serde_json::to_value(pickup.kind).ok().and_then(|name| name.as_str().map(String::from));
+        self.call("on_pickup", (pickup.player_id as i64, item.unwrap_or_default()), 2, state);
+    }
+
+    fn on_tick(&mut self, dt: f32, state: &mut GameState) {
+        self.call("on_tick", (dt as f64,), 1, state);
+    }
+
+    fn take_effects(&mut self) -> Vec<(u32, ItemKind)> {
+        mem::take(&mut self.host.lock().unwrap().effects)
+    }
+}
+
+fn item_kind(name: &str) -> Option<ItemKind> {
+    serde_json::from_value(serde_json::Value::String(name.to_string())).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::{Game, GameClock, TICKS_PER_SECOND};
+    use std::time::UNIX_EPOCH;
+    use tokyo_protocol::{GameConfig, PlayerState};
+
+    fn state_with_players(ids: &[u32]) -> GameState {
+        let mut state = GameState::default();
+        for id in ids {
+            state.players.push(PlayerState::new(*id));
+            state.scoreboard.insert(*id, 0);
+        }
+        state
+    }
+
+    #[test]
+    fn kills_can_be_scored_differently() {
+        let mut rules = ScriptPlugin::new(
+            "fn on_kill(killer, victim) { add_score(killer, 2 * score(victim)); add_score(victim, -5); }",
+        )
+        .unwrap();
+        let mut state = state_with_players(&[1, 2]);
+        state.scoreboard.insert(2, 3);
+
+        rules.on_kill(&Kill { killer: 1, victim: 2, x: 0.0, y: 0.0, lag_compensated: false }, &mut state);
+
+        assert_eq!(state.scoreboard[&1], 6);
+        assert_eq!(state.scoreboard[&2], 0);
+    }
+
+    #[test]
+    fn spawning_players_get_the_effects_handed_out() {
+        let config = GameConfig { bound_x: 1200.0, bound_y: 1200.0, ..GameConfig::default() };
+        let mut game = Game::with_seed(config, 7, GameClock::starting_at(UNIX_EPOCH));
+        game.register_plugin(Box::new(
+            ScriptPlugin::new(r#"fn on_spawn(player) { give_effect(player, "shield"); }"#).unwrap(),
+        ));
+
+        game.add_player(0, None);
+        game.tick(1.0 / TICKS_PER_SECOND);
+
+        assert!(game.state.players[0].has_effect(ItemKind::Shield));
+    }
+
+    #[test]
+    fn broken_calls_are_skipped() {
+        let mut rules = ScriptPlugin::new(
+            r#"
+            fn on_spawn(player) { give_effect(player, "laser"); }
+            fn on_tick(dt) { loop { add_score(1, 1); } }
+            "#,
+        )
+        .unwrap();
+        let mut state = state_with_players(&[1]);
+
+        rules.on_spawn(1, &mut state);
+        rules.on_tick(0.1, &mut state);
+
+        assert!(rules.take_effects().is_empty());
+        // The loop was cut off, but what it did so far stays.
+        assert!(state.scoreboard[&1] > 0);
+    }
+
+    #[test]
+    fn scripts_that_dont_compile_are_rejected() {
+        assert!(ScriptPlugin::new("fn on_tick(dt) {").is_err());
+    }
+}
@@ -0,0 +1,56 @@
+//! Process signals delivered to actors as messages.
+
+use actix::{Message, Recipient};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Message)]
+#[rtype(result = "()")]
+pub enum Signal {
+    Int,
+    Term,
+    Quit,
+    Hup,
+}
+
+/// Sends `recipient` a `Signal` each time the process receives one of
+/// `signals`. Has to be called from within a running actix system.
+#[cfg(unix)]
+pub fn subscribe(signals: &[Signal], recipient: Recipient<Signal>) {
+    use actix_web::rt::signal::unix::{signal, SignalKind};
+
+    for &sig in signals {
+        let kind = match sig {
+            Signal::Int => SignalKind::interrupt(),
+            Signal::Term => SignalKind::terminate(),
+            Signal::Quit => SignalKind::quit(),
+            Signal::Hup => SignalKind::hangup(),
+        };
+        let mut stream = match signal(kind) {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!(signal = ?sig, "Failed to listen for signal: {}", e);
+                continue;
+            },
+        };
+
+        let recipient = recipient.clone();
+        actix::spawn(async move {
+            while stream.recv().await.is_some() {
+                recipient.do_send(sig);
+            }
+        });
+    }
+}
+
+/// Only Ctrl-C is delivered, as `Signal::Int`, where there are no Unix signals.
+#[cfg(not(unix))]
+pub fn subscribe(signals: &[Signal], recipient: Recipient<Signal>) {
+    if !signals.contains(&Signal::Int) {
+        return;
+    }
+
+    actix::spawn(async move {
+        while actix_web::rt::signal::ctrl_c().await.is_ok() {
+            recipient.do_send(Signal::Int);
+        }
+    });
+}
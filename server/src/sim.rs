@@ -0,0 +1,134 @@
+use crate::{
+    bots::BotBrain,
+    game::{Game, GameClock, TICKS_PER_SECOND},
+};
+use std::time::{Duration, UNIX_EPOCH};
+use tokyo_protocol::{GameConfig, GameState, MIN_COMMAND_INTERVAL};
+
+/// A game run headless at full speed, with a brain playing every player.
+///
+/// Two simulations with the same config, seed and brains play out exactly the
+/// same, as long as the brains themselves are deterministic. Brains are asked
+/// at most once per `MIN_COMMAND_INTERVAL` of game time while their player is
+/// alive, and their commands take effect on the next tick.
+pub struct Simulation {
+    pub game: Game,
+    players: Vec<SimPlayer>,
+}
+
+struct SimPlayer {
+    id: u32,
+    brain: Box<dyn BotBrain>,
+    // Seconds of game time until the brain is asked again.
+    next_decision: f32,
+}
+
+impl Simulation {
+    pub fn new(config: GameConfig, seed: u64) -> Self {
+        // Only time differences matter to the simulation, so any epoch will do.
+        let mut game = Game::with_seed(config, seed, GameClock::starting_at(UNIX_EPOCH));
+        game.init();
+
+        Self { game, players: vec![] }
+    }
+
+    /// Adds a player controlled by `brain` and returns its player id.
+    pub fn add_player(&mut self, brain: Box<dyn BotBrain>) -> u32 {
+        let id = self.players.len() as u32;
+        self.game.add_player(id, None);
+        self.players.push(SimPlayer { id, brain, next_decision: 0.0 });
+
+        id
+    }
+
+    pub fn state(&self) -> &GameState {
+        &self.game.state
+    }
+
+    /// Lets the brains decide, then runs one server tick.
+    pub fn step(&mut self) {
+        let dt = 1.0 / TICKS_PER_SECOND;
+
+        for player in &mut self.players {
+            player.next_decision -= dt;
+            if player.next_decision > 0.0 {
+                continue;
+            }
+
+            let me = match self.game.state.players.iter().find(|p| p.id == player.id) {
+                Some(me) => me,
+                None => continue,
+            };
+            player.next_decision = MIN_COMMAND_INTERVAL.as_secs_f32();
+            if let Some(cmd) = player.brain.decide(me, &self.game.state) {
+                self.game.handle_cmd(player.id, cmd);
+            }
+        }
+
+        self.game.tick(dt);
+    }
+
+    /// Steps through `duration` of game time and returns the final state.
+    pub fn run_for(&mut self, duration: Duration) -> &GameState {
+        let ticks = (duration.as_secs_f32() * TICKS_PER_SECOND).round() as u64;
+        for _ in 0..ticks {
+            self.step();
+        }
+
+        &self.game.state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bots::{house_brain, Difficulty};
+
+    const TICKS: usize = 600;
+
+    fn simulation(seed: u64) -> Simulation {
+        let config = GameConfig { bound_x: 1500.0, bound_y: 1500.0, ..GameConfig::default() };
+        let mut sim = Simulation::new(config, seed);
+        for (i, difficulty) in [Difficulty::Easy, Difficulty::Normal, Difficulty::Hard].iter().enumerate() {
+            sim.add_player(house_brain(*difficulty, seed + i as u64));
+        }
+        sim
+    }
+
+    fn checksums(seed: u64) -> Vec<u32> {
+        let mut sim = simulation(seed);
+        (0..TICKS)
+            .map(|_| {
+                sim.step();
+                assert!(sim.state().checksum_matches());
+                sim.state().compute_checksum()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_plays_out_the_same_every_tick() {
+        let (first, second) = (checksums(7), checksums(7));
+        for (tick, (a, b)) in first.iter().zip(&second).enumerate() {
+            assert_eq!(a, b, "diverged on tick {}", tick + 1);
+        }
+    }
+
+    #[test]
+    fn different_seeds_play_out_differently() {
+        assert_ne!(checksums(7), checksums(8));
+    }
+
+    #[test]
+    fn run_for_steps_through_game_time() {
+        let mut sim = simulation(1);
+        let state = sim.run_for(Duration::from_secs(5)).clone();
+        let mut stepped = simulation(1);
+        for _ in 0..(5.0 * TICKS_PER_SECOND).round() as usize {
+            stepped.step();
+        }
+
+        assert_eq!(state.checksum, stepped.state().checksum);
+        assert!(!state.players.is_empty() || !state.dead.is_empty());
+    }
+}
@@ -0,0 +1,42 @@
+use crate::{matches::MatchInfo, persistence};
+use actix::Message;
+use std::{collections::HashMap, io, path::Path, time::Duration};
+use tokyo_protocol::GameState;
+
+/// Everything `Game` needs to pick a match back up where it left off.
+#[derive(Debug, Clone, Serialize, Deserialize, Message)]
+#[rtype(result = "()")]
+pub struct GameSnapshot {
+    // Older snapshots don't have one, they continue as a new match.
+    #[serde(default)]
+    pub match_info: Option<MatchInfo>,
+    pub state: GameState,
+    pub bullet_id_counter: u32,
+    // Time left until each player's next survival reward.
+    pub survival_times: HashMap<u32, Duration>,
+    // Time left until each dead player respawns. Older snapshots only have
+    // the respawn times in the state, which the downtime counts against.
+    #[serde(default)]
+    pub respawn_times: HashMap<u32, Duration>,
+}
+
+/// A `GameSnapshot` plus the bookkeeping `GameActor` keeps about who is who,
+/// so reconnecting clients get their old player back.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerSnapshot {
+    pub game: GameSnapshot,
+    pub player_id_counter: u32,
+    pub api_key_to_player_id: HashMap<String, u32>,
+    // Older snapshots only know the players that were connected.
+    #[serde(default)]
+    pub player_registry: HashMap<String, u32>,
+    pub team_names: HashMap<u32, String>,
+}
+
+pub fn load(path: &Path) -> io::Result<ServerSnapshot> {
+    persistence::read_json(path)
+}
+
+pub fn save(path: &Path, snapshot: &ServerSnapshot) -> io::Result<()> {
+    persistence::write_json_atomically(path, snapshot)
+}
@@ -0,0 +1,133 @@
+use crate::game::Triangle;
+use std::collections::HashMap;
+
+// Comfortably bigger than anything that collides, so a lookup only has to
+// look at the cells right around an entity.
+const CELL_SIZE: f32 = 64.0;
+
+/// A uniform grid over the arena, rebuilt every tick, so collision checks only
+/// look at the entities in the cells around each other instead of at every pair.
+#[derive(Debug)]
+pub struct SpatialGrid {
+    cells: HashMap<(i32, i32), Vec<usize>>,
+    // The biggest radius of anything in the grid, how far outside its cell it can reach.
+    max_radius: f32,
+}
+
+impl SpatialGrid {
+    /// Files the entities under their index in the slice, by their center.
+    pub fn new<T: Triangle>(entities: &[T]) -> Self {
+        let mut grid = SpatialGrid { cells: HashMap::new(), max_radius: 0.0 };
+        for (index, entity) in entities.iter().enumerate() {
+            grid.max_radius = grid.max_radius.max(entity.radius());
+            grid.cells.entry(cell(entity.x(), entity.y())).or_default().push(index);
+        }
+        grid
+    }
+
+    /// The indices of the entities close enough to `entity` to maybe overlap
+    /// it, in ascending order so the result doesn't depend on the grid.
+    pub fn candidates(&self, entity: &dyn Triangle) -> Vec<usize> {
+        let reach = entity.radius() + self.max_radius;
+        let (min_x, min_y) = cell(entity.x() - reach, entity.y() - reach);
+        let (max_x, max_y) = cell(entity.x() + reach, entity.y() + reach);
+
+        let mut found = vec![];
+        for x in min_x..=max_x {
+            for y in min_y..=max_y {
+                if let Some(indices) = self.cells.get(&(x, y)) {
+                    found.extend_from_slice(indices);
+                }
+            }
+        }
+        found.sort_unstable();
+        found
+    }
+}
+
+fn cell(x: f32, y: f32) -> (i32, i32) {
+    ((x / CELL_SIZE).floor() as i32, (y / CELL_SIZE).floor() as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use tokyo_protocol::{BulletState, PlayerState};
+
+    fn players(rng: &mut StdRng, count: u32) -> Vec<PlayerState> {
+        (0..count)
+            .map(|id| PlayerState {
+                x: rng.gen_range(-100.0, 900.0),
+                y: rng.gen_range(-100.0, 900.0),
+                ..PlayerState::new(id)
+            })
+            .collect()
+    }
+
+    fn bullets(rng: &mut StdRng, count: u32) -> Vec<BulletState> {
+        (0..count)
+            .map(|id| BulletState {
+                id,
+                x: rng.gen_range(-100.0, 900.0),
+                y: rng.gen_range(-100.0, 900.0),
+                ..BulletState::default()
+            })
+            .collect()
+    }
+
+    fn brute_force<T: Triangle>(entities: &[T], entity: &dyn Triangle) -> Vec<usize> {
+        (0..entities.len()).filter(|index| entity.is_colliding(&entities[*index])).collect()
+    }
+
+    fn assert_finds_everything<T: Triangle>(entities: &[T], probes: &[&dyn Triangle]) {
+        let grid = SpatialGrid::new(entities);
+        for probe in probes {
+            let candidates = grid.candidates(*probe);
+            assert!(candidates.windows(2).all(|pair| pair[0] < pair[1]));
+            for index in brute_force(entities, *probe) {
+                assert!(
+                    candidates.contains(&index),
+                    "missed {} near ({}, {})",
+                    index,
+                    probe.x(),
+                    probe.y()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn finds_every_overlap_brute_force_finds() {
+        let mut rng = StdRng::seed_from_u64(3);
+        for _ in 0..20 {
+            // Crowded enough that plenty of them overlap, across cell borders and negative cells.
+            let players = players(&mut rng, 300);
+            let bullets = bullets(&mut rng, 300);
+
+            let probes: Vec<&dyn Triangle> = players.iter().map(|p| p as &dyn Triangle).collect();
+            assert_finds_everything(&players, &probes);
+            assert_finds_everything(&bullets, &probes);
+
+            let probes: Vec<&dyn Triangle> = bullets.iter().map(|b| b as &dyn Triangle).collect();
+            assert_finds_everything(&players, &probes);
+            assert_finds_everything(&bullets, &probes);
+        }
+    }
+
+    #[test]
+    fn leaves_out_far_away_entities() {
+        let mut rng = StdRng::seed_from_u64(4);
+        let players = players(&mut rng, 300);
+        let grid = SpatialGrid::new(&players);
+
+        let total: usize = players.iter().map(|player| grid.candidates(player).len()).sum();
+        assert!(total < players.len() * players.len() / 10, "{} candidates", total);
+    }
+
+    #[test]
+    fn empty_grid_has_no_candidates() {
+        let grid = SpatialGrid::new::<PlayerState>(&[]);
+        assert!(grid.candidates(&PlayerState::new(0)).is_empty());
+    }
+}
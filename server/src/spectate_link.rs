@@ -0,0 +1,146 @@
+//! Signed spectator links, so organizers can hand out projector URLs that stop
+//! working after the event instead of leaving `/spectate` open forever.
+
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The parameters of a spectator link that are covered by the signature.
+#[derive(Debug, Deserialize)]
+pub struct SpectateParams {
+    // Unix timestamp in seconds after which the link stops working.
+    pub expires: Option<u64>,
+    // Player ID the spectator view should follow.
+    pub follow: Option<u32>,
+    pub sig: Option<String>,
+    // The room to watch instead of the default one.
+    pub room: Option<String>,
+    // What to call the spectator in the spectator chat, not part of the signature.
+    pub name: Option<String>,
+}
+
+fn mac(secret: &str, expires: u64, follow: Option<u32>, room: Option<&str>) -> HmacSha256 {
+    let mut mac = HmacSha256::new_varkey(secret.as_bytes()).expect("HMAC accepts any key length");
+    let follow = follow.map(|id| id.to_string()).unwrap_or_default();
+    mac.update(format!("expires={}&follow={}", expires, follow).as_bytes());
+    // Only added with a room, so the links to the default room stay the same.
+    if let Some(room) = room {
+        mac.update(format!("&room={}", room).as_bytes());
+    }
+    mac
+}
+
+/// Returns the hex encoded signature for a link.
+pub fn sign(secret: &str, expires: u64, follow: Option<u32>, room: Option<&str>) -> String {
+    mac(secret, expires, follow, room)
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Checks that the link was signed by us and hasn't expired yet.
+pub fn verify(secret: &str, params: &SpectateParams) -> Result<(), &'static str> {
+    let (expires, sig) = match (params.expires, &params.sig) {
+        (Some(expires), Some(sig)) => (expires, sig),
+        _ => return Err("Missing spectator link signature"),
+    };
+
+    let sig = decode_hex(sig).ok_or("Malformed spectator link signature")?;
+    mac(secret, expires, params.follow, params.room.as_deref())
+        .verify(&sig)
+        .map_err(|_| "Invalid spectator link signature")?;
+
+    if unix_now() > expires {
+        return Err("This spectator link has expired");
+    }
+
+    Ok(())
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or(0)
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| hex.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "projector";
+
+    fn signed(expires: u64, follow: Option<u32>, room: Option<&str>) -> SpectateParams {
+        SpectateParams {
+            expires: Some(expires),
+            follow,
+            sig: Some(sign(SECRET, expires, follow, room)),
+            room: room.map(String::from),
+            name: None,
+        }
+    }
+
+    #[test]
+    fn signed_links_are_accepted() {
+        let expires = unix_now() + 60;
+        assert_eq!(verify(SECRET, &signed(expires, None, None)), Ok(()));
+        assert_eq!(verify(SECRET, &signed(expires, Some(3), Some("finals"))), Ok(()));
+
+        // The name isn't signed, spectators pick it themselves.
+        let params = SpectateParams { name: Some("hall".to_string()), ..signed(expires, Some(3), None) };
+        assert_eq!(verify(SECRET, &params), Ok(()));
+    }
+
+    #[test]
+    fn expired_links_are_rejected() {
+        let params = signed(unix_now() - 1, None, None);
+        assert_eq!(verify(SECRET, &params), Err("This spectator link has expired"));
+    }
+
+    #[test]
+    fn changed_links_are_rejected() {
+        let expires = unix_now() + 60;
+        let params = signed(expires, Some(3), Some("finals"));
+
+        let changes = [
+            SpectateParams { follow: Some(4), ..signed(expires, Some(3), Some("finals")) },
+            SpectateParams { follow: None, ..signed(expires, Some(3), Some("finals")) },
+            SpectateParams { room: Some("semis".to_string()), ..signed(expires, Some(3), Some("finals")) },
+            SpectateParams { room: None, ..signed(expires, Some(3), Some("finals")) },
+            SpectateParams { expires: Some(expires + 3600), ..signed(expires, Some(3), Some("finals")) },
+        ];
+        for params in &changes {
+            assert_eq!(verify(SECRET, params), Err("Invalid spectator link signature"), "{:?}", params);
+        }
+
+        assert_eq!(verify("someone else's", &params), Err("Invalid spectator link signature"));
+    }
+
+    #[test]
+    fn malformed_signatures_are_rejected() {
+        let expires = unix_now() + 60;
+        let sig = sign(SECRET, expires, None, None);
+
+        let odd = SpectateParams { sig: Some(sig[1..].to_string()), ..signed(expires, None, None) };
+        assert_eq!(verify(SECRET, &odd), Err("Malformed spectator link signature"));
+        let not_hex = SpectateParams { sig: Some("zz".repeat(32)), ..signed(expires, None, None) };
+        assert_eq!(verify(SECRET, &not_hex), Err("Malformed spectator link signature"));
+        let short = SpectateParams { sig: Some(sig[..sig.len() - 2].to_string()), ..signed(expires, None, None) };
+        assert_eq!(verify(SECRET, &short), Err("Invalid spectator link signature"));
+
+        let unsigned = SpectateParams { sig: None, ..signed(expires, None, None) };
+        assert_eq!(verify(SECRET, &unsigned), Err("Missing spectator link signature"));
+    }
+}
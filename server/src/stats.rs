@@ -0,0 +1,151 @@
+use crate::persistence::history::MatchResult;
+use std::collections::HashMap;
+
+/// Totals over many matches for one API key.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PlayerStats {
+    pub matches: u32,
+    pub kills: u32,
+    pub deaths: u32,
+    // Kills per death, or just the kills for players who never died.
+    pub kd_ratio: f32,
+    pub shots: u32,
+    // The share of shots that killed someone.
+    pub accuracy: f32,
+    // How long a life lasted on average, in seconds.
+    pub average_survival_secs: f32,
+    #[serde(skip)]
+    lives: u32,
+    #[serde(skip)]
+    alive_secs: f32,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct AggregateStats {
+    pub players: HashMap<String, PlayerStats>,
+    // How often each player killed each opponent. Bots are listed by name.
+    pub head_to_head: HashMap<String, HashMap<String, u32>>,
+}
+
+/// Adds up the recorded matches, only those of `season` if given. With a `key`
+/// the stats are about that player: their own totals, their kills and every
+/// opponent's kills on them.
+pub fn aggregate(
+    history: &HashMap<String, Vec<MatchResult>>,
+    season: Option<&String>,
+    key: Option<&String>,
+) -> AggregateStats {
+    let mut stats = AggregateStats::default();
+
+    for (api_key, results) in history {
+        let results =
+            results.iter().filter(|result| season.is_none_or(|season| result.season.as_ref() == Some(season)));
+
+        for result in results {
+            let victims: HashMap<String, u32> = result
+                .victims
+                .iter()
+                .filter(|(victim, _)| key.is_none_or(|key| key == api_key || key == *victim))
+                .map(|(victim, kills)| (victim.clone(), *kills))
+                .collect();
+            if !victims.is_empty() {
+                let row = stats.head_to_head.entry(api_key.clone()).or_default();
+                for (victim, kills) in victims {
+                    *row.entry(victim).or_default() += kills;
+                }
+            }
+
+            if key.is_some_and(|key| key != api_key) {
+                continue;
+            }
+
+            let player = stats.players.entry(api_key.clone()).or_default();
+            player.matches += 1;
+            player.kills += result.kills;
+            player.deaths += result.deaths;
+            player.shots += result.shots;
+            player.lives += result.lives;
+            player.alive_secs += result.alive_secs;
+        }
+    }
+
+    for player in stats.players.values_mut() {
+        player.kd_ratio = player.kills as f32 / player.deaths.max(1) as f32;
+        if player.shots > 0 {
+            player.accuracy = player.kills as f32 / player.shots as f32;
+        }
+        if player.lives > 0 {
+            player.average_survival_secs = player.alive_secs / player.lives as f32;
+        }
+    }
+
+    stats
+}
+
+/// One line of the leaderboard, everything an API key scored over many matches.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LeaderboardEntry {
+    // Players with the same score share a rank.
+    pub rank: u32,
+    pub name: String,
+    pub matches: u32,
+    pub wins: u32,
+    pub score: u64,
+    pub kills: u32,
+    pub deaths: u32,
+    pub kill_points: i64,
+    pub survival_points: i64,
+}
+
+/// The best `limit` players by their total score over the recorded matches,
+/// only those of `season` if given. Players are listed by name, from their
+/// profile if they have one, so the API keys stay secret.
+pub fn leaderboard(
+    history: &HashMap<String, Vec<MatchResult>>,
+    names: &HashMap<String, String>,
+    season: Option<&String>,
+    limit: usize,
+) -> Vec<LeaderboardEntry> {
+    let mut entries: Vec<LeaderboardEntry> = history
+        .iter()
+        .filter_map(|(api_key, results)| {
+            let results: Vec<&MatchResult> = results
+                .iter()
+                .filter(|result| season.is_none_or(|season| result.season.as_ref() == Some(season)))
+                .collect();
+            if results.is_empty() {
+                return None;
+            }
+
+            let name = names
+                .get(api_key)
+                .or_else(|| results.iter().rev().find_map(|result| result.name.as_ref()))
+                .cloned()
+                .unwrap_or_else(|| "anonymous".to_string());
+            let mut entry = LeaderboardEntry { name, ..LeaderboardEntry::default() };
+            for result in results {
+                entry.matches += 1;
+                entry.wins += if result.placement == 1 { 1 } else { 0 };
+                entry.score += u64::from(result.score);
+                entry.kills += result.kills;
+                entry.deaths += result.deaths;
+                entry.kill_points += i64::from(result.kill_points);
+                entry.survival_points += i64::from(result.survival_points);
+            }
+            Some(entry)
+        })
+        .collect();
+
+    entries.sort_by(|a, b| b.score.cmp(&a.score).then(b.kills.cmp(&a.kills)).then(a.name.cmp(&b.name)));
+    entries.truncate(limit);
+
+    let mut rank = 0;
+    for index in 0..entries.len() {
+        if index == 0 || entries[index].score != entries[index - 1].score {
+            rank = index as u32 + 1;
+        }
+        entries[index].rank = rank;
+    }
+
+    entries
+}
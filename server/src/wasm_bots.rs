@@ -0,0 +1,272 @@
+//! Bot brains compiled to WebAssembly, so organizers can field house bots
+//! written by the community without trusting their code.
+//!
+//! A brain is a module that imports nothing and exports:
+//!
+//! ```text
+//! memory                         the memory the server reads and writes
+//! alloc(len: i32) -> i32         room for `len` bytes the server can write to
+//! decide(ptr: i32, len: i32) -> i64
+//! ```
+//!
+//! `decide` gets `{"me": PlayerState, "state": GameState}` as JSON at `ptr`
+//! and answers with a `GameCommand` as JSON, the same as a client would send,
+//! at the address in the upper 32 bits of the result and with the length in
+//! the lower ones. 0 means doing nothing this time.
+//!
+//! Each brain gets its own instance, with `MAX_MEMORY` bytes of memory and
+//! `FUEL_PER_DECISION` worth of instructions per call. A brain that traps, runs
+//! out of fuel or answers with something that isn't a command is benched for
+//! the rest of the game: its player stays in but stands still.
+
+use crate::bots::BotBrain;
+use std::{ffi::OsStr, fmt, fs, path::Path};
+use tokyo_protocol::{GameCommand, GameState, PlayerState};
+use wasmtime::{Config, Engine, Instance, Memory, Module, Store, StoreLimits, StoreLimitsBuilder, TypedFunc};
+
+// How much work a single decision may do before it's stopped.
+const FUEL_PER_DECISION: u64 = 10_000_000;
+
+// The most memory a brain can grow to, in bytes.
+const MAX_MEMORY: usize = 16 << 20;
+
+/// The brains found in a directory, compiled and ready to be given to bots.
+#[derive(Clone)]
+pub struct WasmBrains {
+    engine: Engine,
+    // By name, the file name without `.wasm`.
+    modules: Vec<(String, Module)>,
+}
+
+impl WasmBrains {
+    pub fn new() -> Result<Self, String> {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config).map_err(|e| format!("Failed to set up WASM: {}", e))?;
+        Ok(Self { engine, modules: Vec::new() })
+    }
+
+    /// Compiles every `.wasm` file in `dir`, in the order of their names.
+    pub fn load(dir: &Path) -> Result<Self, String> {
+        let mut paths = fs::read_dir(dir)
+            .and_then(|entries| entries.map(|entry| entry.map(|entry| entry.path())).collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("Failed to read the bot directory {:?}: {}", dir, e))?;
+        paths.retain(|path| path.extension() == Some(OsStr::new("wasm")));
+        paths.sort();
+
+        let mut brains = Self::new()?;
+        for path in paths {
+            let name = path.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+            let wasm = fs::read(&path).map_err(|e| format!("Failed to read the bot {:?}: {}", path, e))?;
+            brains.add(&name, &wasm).map_err(|e| format!("Failed to load the bot {:?}: {}", path, e))?;
+        }
+        Ok(brains)
+    }
+
+    /// Compiles a brain and checks it has the exports a brain needs and
+    /// doesn't ask for anything from the server.
+    pub fn add(&mut self, name: &str, wasm: &[u8]) -> Result<(), String> {
+        let module = Module::new(&self.engine, wasm).map_err(|e| format!("{:#}", e))?;
+        if let Some(import) = module.imports().next() {
+            let import = format!("{}::{}", import.module(), import.name());
+            return Err(format!("brains can't import anything, but it imports {}", import));
+        }
+        for export in &["memory", "alloc", "decide"] {
+            if module.get_export(export).is_none() {
+                return Err(format!("brains have to export {}", export));
+            }
+        }
+
+        self.modules.push((name.to_string(), module));
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.modules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.modules.is_empty()
+    }
+
+    /// The name of the brain at `index` and a fresh instance of it.
+    pub fn brain(&self, index: usize) -> Result<(&str, WasmBrain), String> {
+        let (name, module) = &self.modules[index];
+        let brain = WasmBrain::new(name, &self.engine, module)
+            .map_err(|e| format!("Failed to start the bot {}: {}", name, e))?;
+        Ok((name, brain))
+    }
+}
+
+impl fmt::Debug for WasmBrains {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.modules.iter().map(|(name, _)| name)).finish()
+    }
+}
+
+/// What the brain's instance can use of the server.
+struct Host {
+    limits: StoreLimits,
+}
+
+/// A running brain, with its own memory that lasts from one decision to the next.
+pub struct WasmBrain {
+    name: String,
+    store: Store<Host>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    decide: TypedFunc<(i32, i32), i64>,
+    benched: bool,
+}
+
+#[derive(Serialize)]
+struct Turn<'a> {
+    me: &'a PlayerState,
+    state: &'a GameState,
+}
+
+impl WasmBrain {
+    fn new(name: &str, engine: &Engine, module: &Module) -> Result<Self, String> {
+        let limits = StoreLimitsBuilder::new().memory_size(MAX_MEMORY).instances(1).memories(1).tables(1).build();
+        let mut store = Store::new(engine, Host { limits });
+        store.limiter(|host| &mut host.limits);
+        // Whatever the module does when it starts counts as its first decision.
+        store.set_fuel(FUEL_PER_DECISION).map_err(|e| e.to_string())?;
+
+        let instance = Instance::new(&mut store, module, &[]).map_err(|e| format!("{:#}", e))?;
+        let memory = instance.get_memory(&mut store, "memory").ok_or("memory isn't a memory")?;
+        let alloc = instance.get_typed_func(&mut store, "alloc").map_err(|e| format!("alloc: {:#}", e))?;
+        let decide = instance.get_typed_func(&mut store, "decide").map_err(|e| format!("decide: {:#}", e))?;
+
+        Ok(Self { name: name.to_string(), store, memory, alloc, decide, benched: false })
+    }
+
+    fn call(&mut self, me: &PlayerState, state: &GameState) -> Result<Option<GameCommand>, String> {
+        let turn = serde_json::to_vec(&Turn { me, state }).map_err(|e| e.to_string())?;
+        self.store.set_fuel(FUEL_PER_DECISION).map_err(|e| e.to_string())?;
+
+        let ptr = self.alloc.call(&mut self.store, turn.len() as i32).map_err(|e| format!("alloc: {:#}", e))?;
+        self.memory
+            .write(&mut self.store, ptr as u32 as usize, &turn)
+            .map_err(|_| format!("alloc gave {} bytes at {}, past the end of memory", turn.len(), ptr))?;
+
+        let answer =
+            self.decide.call(&mut self.store, (ptr, turn.len() as i32)).map_err(|e| format!("decide: {:#}", e))?;
+        if answer == 0 {
+            return Ok(None);
+        }
+
+        let (ptr, len) = ((answer as u64 >> 32) as usize, answer as u32 as usize);
+        let cmd = self
+            .memory
+            .data(&self.store)
+            .get(ptr..ptr + len)
+            .ok_or_else(|| format!("the answer at {} is past the end of memory", ptr))?;
+        serde_json::from_slice(cmd).map(Some).map_err(|e| format!("the answer isn't a command: {}", e))
+    }
+}
+
+impl BotBrain for WasmBrain {
+    fn decide(&mut self, me: &PlayerState, state: &GameState) -> Option<GameCommand> {
+        if self.benched {
+            return None;
+        }
+
+        self.call(me, state).unwrap_or_else(|e| {
+            warn!(bot = self.name.as_str(), "benching the bot: {}", e);
+            self.benched = true;
+            None
+        })
+    }
+}
+
+impl fmt::Debug for WasmBrain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WasmBrain").field("name", &self.name).field("benched", &self.benched).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Answers every turn with `{"e":"fire"}`, kept at the start of its memory.
+    const SHOOTER: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (data (i32.const 0) "{\"e\":\"fire\"}")
+          (func (export "alloc") (param i32) (result i32) i32.const 1024)
+          (func (export "decide") (param i32 i32) (result i64) i64.const 12))
+    "#;
+
+    fn brain(wat: &str) -> Result<WasmBrain, String> {
+        let mut brains = WasmBrains::new()?;
+        brains.add("test", wat.as_bytes())?;
+        brains.brain(0).map(|(_, brain)| brain)
+    }
+
+    fn decide(brain: &mut WasmBrain) -> Option<GameCommand> {
+        let mut state = GameState::default();
+        state.players.push(PlayerState::new(1));
+        brain.decide(&state.players[0], &state)
+    }
+
+    #[test]
+    fn brains_answer_with_commands() {
+        let mut brain = brain(SHOOTER).unwrap();
+
+        assert!(matches!(decide(&mut brain), Some(GameCommand::Fire)));
+        assert!(matches!(decide(&mut brain), Some(GameCommand::Fire)));
+    }
+
+    #[test]
+    fn brains_that_never_answer_are_benched() {
+        let mut brain = brain(
+            r#"
+            (module
+              (memory (export "memory") 1)
+              (func (export "alloc") (param i32) (result i32) i32.const 0)
+              (func (export "decide") (param i32 i32) (result i64) (loop (br 0)) i64.const 0))
+            "#,
+        )
+        .unwrap();
+
+        assert!(decide(&mut brain).is_none());
+        assert!(brain.benched);
+    }
+
+    #[test]
+    fn brains_cant_grow_past_the_memory_limit() {
+        let greedy = r#"
+            (module
+              (memory (export "memory") 1)
+              (func (export "alloc") (param i32) (result i32) i32.const 0)
+              (func (export "decide") (param i32 i32) (result i64)
+                (if (i32.eq (memory.grow (i32.const 1024)) (i32.const -1)) (then unreachable))
+                i64.const 0))
+        "#;
+        let huge = r#"
+            (module
+              (memory (export "memory") 1024)
+              (func (export "alloc") (param i32) (result i32) i32.const 0)
+              (func (export "decide") (param i32 i32) (result i64) i64.const 0))
+        "#;
+        assert!(brain(huge).is_err());
+
+        let mut brain = brain(greedy).unwrap();
+        assert!(decide(&mut brain).is_none());
+        assert!(brain.benched);
+    }
+
+    #[test]
+    fn brains_cant_import_anything() {
+        let wat = r#"
+            (module
+              (import "wasi_snapshot_preview1" "fd_write" (func (param i32 i32 i32 i32) (result i32)))
+              (memory (export "memory") 1)
+              (func (export "alloc") (param i32) (result i32) i32.const 0)
+              (func (export "decide") (param i32 i32) (result i64) i64.const 0))
+        "#;
+        assert!(brain(wat).is_err());
+    }
+}
@@ -1,5 +1,10 @@
 const SHIP_SIZE = 18;
 const BULLET_SIZE = 3;
+// See ITEM_RADIUS in the protocol.
+const ITEM_RADIUS = 8;
+// Ships are colored by team in the teams mode.
+const TEAM_COLORS = ["#e05d5d", "#4a69bd", "#78e08f", "#f6b93b", "#b71540", "#82ccdd"];
+const ITEM_COLORS = { shield: "#0984e3", rapid_fire: "#d63031", speed_boost: "#00b894", extra_bullet: "#fdcb6e" };
 var websocket_status = document.getElementById("websocket-status");
 var chart = document.getElementById("scoreboard");
 var c = document.getElementById("canvas");
@@ -8,20 +13,64 @@ window.onload = window.onresize = function () {
         c.height = document.body.clientHeight; //document.height is obsolete
 }
 var team_names = {};
+// Player id to the color and team from their profile.
+var appearances = {};
+// Indexed by emote number, see MAX_EMOTE in the protocol.
+const EMOTES = ["😎", "👋", "👍", "😂", "😡", "😱", "🔥", "💀"];
+const EMOTE_DURATION_MS = 2000;
+// Player id to the emote shown above their ship and when it goes away.
+var emotes = {};
+// Signed spectator links can pin the view to a single player.
+const follow_id = new URLSearchParams(window.location.search).get("follow");
+// `?replay=<id>` watches a recorded match instead, see /replays.
+const replay_id = new URLSearchParams(window.location.search).get("replay");
+// The match phase when rounds are on, and when it ends on our clock.
+var phase = null;
+var phase_until = null;
+const PHASE_LABELS = { lobby: "Waiting for players", countdown: "Starting in", playing: "", results: "Round over" };
 
 var ctx = c.getContext("2d");
 
+// Chat with the rest of the audience, only other spectators see it.
+const CHAT_LOG_LENGTH = 10;
+var spectator_socket = null;
+var chat_log = document.getElementById("chat-log");
+document.getElementById("chat-form").addEventListener("submit", function (event) {
+        event.preventDefault();
+        const input = document.getElementById("chat-input");
+        if (spectator_socket && input.value.trim() !== "") {
+                spectator_socket.send(JSON.stringify({ e: "chat", data: input.value }));
+                input.value = "";
+        }
+});
+
+function add_chat_message(message) {
+        const item = document.createElement("li");
+        item.innerHTML = `<span class="name">${sanitizeHTML(message.name)}</span>${sanitizeHTML(message.text)}`;
+        chat_log.appendChild(item);
+        while (chat_log.children.length > CHAT_LOG_LENGTH) {
+                chat_log.removeChild(chat_log.firstChild);
+        }
+}
+
 function connect(handler) {
         websocket_status.innerText = "connecting...";
         websocket_status.style.borderColor = "gray";
 
-        const socket = new WebSocket(`ws://${window.location.host}/spectate`);
+        // Pass along the signature of signed spectator links.
+        const path = replay_id ? `/replay/${encodeURIComponent(replay_id)}` : "/spectate";
+        const socket = new WebSocket(`ws://${window.location.host}${path}${window.location.search}`);
         socket.addEventListener('open', function (event) {
+                spectator_socket = socket;
                 websocket_status.innerText = "connected";
                 websocket_status.style.borderColor = "white";
         });
 
         socket.addEventListener('close', function (event) {
+                if (replay_id && event.code === 1000) {
+                        websocket_status.innerText = "replay over";
+                        return;
+                }
                 websocket_status.innerText = "disconnected";
                 websocket_status.style.borderColor = "orange";
                 setTimeout(function () {
@@ -47,6 +96,10 @@ class Ship {
                 this.x = Math.floor(obj.x);
                 this.y = Math.floor(obj.y);
                 this.angle = obj.angle;
+                this.deflector = obj.deflector;
+                this.team = obj.team;
+                this.heat = obj.heat || 0;
+                this.overheated = obj.overheated;
         }
 
         move(x, y) {
@@ -77,10 +130,24 @@ class Ship {
                 let oldFill = ctx.fillStyle;
                 ctx.beginPath();
                 ctx.arc(0, 0, 10, 0, 2 * Math.PI);
-                ctx.fillStyle = "#e05d5d";
+                const appearance = appearances[this.id];
+                if (this.team !== undefined) {
+                        ctx.fillStyle = TEAM_COLORS[this.team % TEAM_COLORS.length];
+                } else {
+                        ctx.fillStyle = (appearance && appearance.color) || "#e05d5d";
+                }
                 ctx.fill();
                 ctx.fillStyle = oldFill;
 
+                if (this.deflector) {
+                        let oldStroke = ctx.strokeStyle;
+                        ctx.beginPath();
+                        ctx.arc(0, 0, SHIP_SIZE * 1.5, 0, 2 * Math.PI);
+                        ctx.strokeStyle = "#7ed6df";
+                        ctx.stroke();
+                        ctx.strokeStyle = oldStroke;
+                }
+
                 // draw team name
                 ctx.rotate(-this.angle + Math.PI / 2.0); // please don't ask me about this math
                 oldFill = ctx.fillStyle;
@@ -92,6 +159,14 @@ class Ship {
                 ctx.fillRect(17, -3, textMeasurements.width + 6, 15);
                 ctx.fillStyle = "#ffffff";
                 ctx.fillText(team_names[this.id], 20, 0);
+
+                // draw the heat bar under the name
+                if (this.heat > 0) {
+                        ctx.fillStyle = "#000000";
+                        ctx.fillRect(17, 14, 46, 6);
+                        ctx.fillStyle = this.overheated ? "#e05d5d" : "#f0932b";
+                        ctx.fillRect(20, 15, 40 * this.heat, 4);
+                }
                 ctx.fillStyle = oldFill;
 
                 // reset transformation
@@ -133,12 +208,36 @@ class Bullet {
 }
 
 var last_drawn_scoreboard = {};
+// Kills and deaths per player, and the latest kills, from the spectator_info events.
+var tallies = {};
+var kill_feed = [];
 var initCanvas = false;
 connect(function (json) {
         if (json.e === "teamnames") {
                 team_names = json.data;
+        } else if (json.e === "appearances") {
+                appearances = json.data;
+        } else if (json.e === "spectator_chat") {
+                add_chat_message(json.data);
+        } else if (json.e === "emote") {
+                emotes[json.data.player_id] = {
+                        text: EMOTES[json.data.emote],
+                        until: Date.now() + EMOTE_DURATION_MS,
+                };
+        } else if (json.e === "spectator_info") {
+                tallies = {};
+                for (const entry of json.data.scoreboard) {
+                        tallies[entry.player_id] = entry.kills + "/" + entry.deaths;
+                }
+                kill_feed = json.data.kill_feed;
+        } else if (json.e === "phase") {
+                phase = json.data.phase;
+                phase_until = json.data.remaining_ms != null ? Date.now() + json.data.remaining_ms : null;
         } else if (json.e === "state") {
                 const data = json.data;
+                if (data.names) {
+                        Object.assign(team_names, data.names);
+                }
                 ctx.save()
                 ctx.clearRect(0, 0, c.width, c.height);
                 ctx.strokeStyle = "#ffffff";
@@ -146,10 +245,15 @@ connect(function (json) {
                 ctx.lineCap = "square";
                 ctx.lineJoin = "bevel";
 
-                scaleXRatio = c.width / data.bounds[0];
-                scaleYRatio = c.height / data.bounds[1];
-                scaleRatio = Math.min(scaleXRatio, scaleYRatio);
-                ctx.transform(scaleRatio, 0, 0, scaleRatio, 0, 0);
+                const followed = data.players.find(p => String(p.id) === follow_id);
+                if (followed) {
+                        ctx.translate(c.width / 2 - followed.x, c.height / 2 - followed.y);
+                } else {
+                        scaleXRatio = c.width / data.bounds[0];
+                        scaleYRatio = c.height / data.bounds[1];
+                        scaleRatio = Math.min(scaleXRatio, scaleYRatio);
+                        ctx.transform(scaleRatio, 0, 0, scaleRatio, 0, 0);
+                }
 
                 ctx.beginPath();
                 ctx.moveTo(0, 0);
@@ -159,32 +263,124 @@ connect(function (json) {
                 ctx.lineTo(0, 0);
                 ctx.stroke();
 
+                ctx.save();
+                ctx.fillStyle = "#636e72";
+                for (const obstacle of data.obstacles || []) {
+                        if (obstacle.shape === "circle") {
+                                ctx.beginPath();
+                                ctx.arc(obstacle.x + obstacle.width / 2, obstacle.y + obstacle.height / 2, obstacle.width / 2, 0, 2 * Math.PI);
+                                ctx.fill();
+                        } else {
+                                ctx.fillRect(obstacle.x, obstacle.y, obstacle.width, obstacle.height);
+                        }
+                }
+                ctx.restore();
+
                 for (const player of data.players) {
                         new Ship(player).draw(ctx);
                 }
 
+                ctx.save();
+                ctx.font = "20px sans-serif";
+                ctx.textAlign = "center";
+                for (const player of data.players) {
+                        const emote = emotes[player.id];
+                        if (emote && emote.until > Date.now()) {
+                                ctx.fillText(emote.text, player.x, player.y - SHIP_SIZE - 12);
+                        }
+                }
+                ctx.restore();
+
                 for (const bullet of data.bullets) {
                         new Bullet(bullet).draw(ctx);
                 }
+
+                ctx.save();
+                for (const item of data.items || []) {
+                        ctx.fillStyle = ITEM_COLORS[item.kind] || "#ffffff";
+                        ctx.beginPath();
+                        ctx.arc(item.x, item.y, ITEM_RADIUS, 0, 2 * Math.PI);
+                        ctx.fill();
+                }
+                ctx.restore();
+
+                ctx.save();
+                ctx.strokeStyle = "#00cec9";
+                ctx.lineWidth = 3;
+                for (const marker of data.markers || []) {
+                        ctx.beginPath();
+                        ctx.arc(marker.x, marker.y, 24, 0, 2 * Math.PI);
+                        ctx.moveTo(marker.x, marker.y - 32);
+                        ctx.lineTo(marker.x, marker.y + 32);
+                        ctx.moveTo(marker.x - 32, marker.y);
+                        ctx.lineTo(marker.x + 32, marker.y);
+                        ctx.stroke();
+                }
+                ctx.restore();
                 ctx.restore()
 
-                if (JSON.stringify(data.scoreboard) !== JSON.stringify(last_drawn_scoreboard)) {
-                        draw_scoreboard(data.scoreboard);
-                        last_drawn_scoreboard = data.scoreboard;
+                if (phase) {
+                        draw_phase();
+                }
+                draw_kill_feed();
+
+                const scoreboards = { players: data.scoreboard, teams: data.team_scoreboard || {}, tallies: tallies };
+                if (JSON.stringify(scoreboards) !== JSON.stringify(last_drawn_scoreboard)) {
+                        draw_scoreboard(data.scoreboard, scoreboards.teams);
+                        last_drawn_scoreboard = scoreboards;
                 }
         }
 });
 
+function draw_phase() {
+        var text = PHASE_LABELS[phase];
+        if (phase_until !== null) {
+                const seconds = Math.max(0, Math.ceil((phase_until - Date.now()) / 1000));
+                text = text ? text + " " + seconds + "s" : seconds + "s";
+        }
+        if (!text) {
+                return;
+        }
+        ctx.save();
+        ctx.font = "24px sans-serif";
+        ctx.textAlign = "center";
+        ctx.fillStyle = "#ffffff";
+        ctx.fillText(text, c.width / 2, 32);
+        ctx.restore();
+}
+
+function draw_kill_feed() {
+        ctx.save();
+        ctx.font = "16px sans-serif";
+        ctx.textAlign = "right";
+        ctx.fillStyle = "#ffffff";
+        kill_feed.forEach(function (kill, i) {
+                ctx.fillText(kill.killer_name + " \u2192 " + kill.victim_name, c.width - 16, 32 + i * 22);
+        });
+        ctx.restore();
+}
+
 function sanitizeHTML(text) {
   var element = document.createElement('div');
   element.innerText = text;
   return element.innerHTML;
 }
 
-function draw_scoreboard(scoreboard) {
+function draw_scoreboard(scoreboard, team_scoreboard) {
         var sorted_players = Object.keys(scoreboard).sort(function (a, b) { return scoreboard[b] - scoreboard[a] });
         var tableHtml = "<tbody>";
 
+        const sorted_teams = Object.keys(team_scoreboard).sort(function (a, b) { return team_scoreboard[b] - team_scoreboard[a] });
+        for (const team of sorted_teams) {
+                tableHtml += `
+            <tr class="team" style="color: ${TEAM_COLORS[team % TEAM_COLORS.length]}">
+              <td class="rank"></td>
+              <td class="name">Team ${team}</td>
+              <td class="kd"></td>
+              <td class="score">${team_scoreboard[team]}</td>
+            </tr>`;
+        }
+
         for (let i = 0; i < sorted_players.length; i++) {
                 const player_id = sorted_players[i];
                 const player_score = String(scoreboard[player_id]).padEnd(3);
@@ -193,6 +389,7 @@ function draw_scoreboard(scoreboard) {
             <tr class="rank-${i + 1}">
               <td class="rank">${i + 1}</td>
               <td class="name">${team_name}</td>
+              <td class="kd">${tallies[player_id] || ""}</td>
               <td class="score">${player_score}</td>
             </tr>`;
         }